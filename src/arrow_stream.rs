@@ -0,0 +1,182 @@
+use std::sync::Arc;
+
+use arrow_array::builder::{ArrayBuilder, BooleanBuilder, Float32Builder, Float64Builder, Int64Builder, StringBuilder, TimestampMillisecondBuilder, UInt64Builder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field as ArrowField, Schema as ArrowSchema, SchemaRef, TimeUnit};
+use serde_json::Value;
+
+use crate::marci_db::MarciDB;
+use crate::marci_decoder::{DecodeError, decode_field, unpack};
+use crate::schema::{Field, FieldType, Model, PrimitiveFieldType};
+
+/// Сколько документов попадает в один record batch
+const BATCH_ROWS: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum ArrowExportError {
+    UnknownField(String),
+    /// Связи-списки, структуры, списки значений и пользовательские типы в колонки не выгружаются
+    UnsupportedField(String),
+    Decode(DecodeError),
+    Arrow(arrow_schema::ArrowError),
+}
+
+impl std::fmt::Display for ArrowExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArrowExportError::UnknownField(name) => write!(f, "Unknown field {}", name),
+            ArrowExportError::UnsupportedField(name) => write!(f, "Field {} can not be exported as a column", name),
+            ArrowExportError::Decode(err) => write!(f, "Failed to decode document: {:?}", err),
+            ArrowExportError::Arrow(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Колонка выгрузки: поле модели (или `id`, если поля нет) и builder под его тип
+struct Column<'a> {
+    field: Option<&'a Field>,
+    builder: ColumnBuilder,
+}
+
+enum ColumnBuilder {
+    Utf8(StringBuilder),
+    Int64(Int64Builder),
+    UInt64(UInt64Builder),
+    Float32(Float32Builder),
+    Float64(Float64Builder),
+    Boolean(BooleanBuilder),
+    Timestamp(TimestampMillisecondBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(ty: &DataType) -> ColumnBuilder {
+        match ty {
+            DataType::Utf8 => ColumnBuilder::Utf8(StringBuilder::new()),
+            DataType::Int64 => ColumnBuilder::Int64(Int64Builder::new()),
+            DataType::Float32 => ColumnBuilder::Float32(Float32Builder::new()),
+            DataType::Float64 => ColumnBuilder::Float64(Float64Builder::new()),
+            DataType::Boolean => ColumnBuilder::Boolean(BooleanBuilder::new()),
+            DataType::Timestamp(_, _) => ColumnBuilder::Timestamp(TimestampMillisecondBuilder::new()),
+            _ => ColumnBuilder::UInt64(UInt64Builder::new()),
+        }
+    }
+
+    /// Значение уже декодировано и проверено по типу поля, поэтому `None` здесь означает null
+    fn append(&mut self, value: &Value) {
+        match self {
+            ColumnBuilder::Utf8(b) => b.append_option(value.as_str()),
+            ColumnBuilder::Int64(b) => b.append_option(value.as_i64()),
+            ColumnBuilder::UInt64(b) => b.append_option(value.as_u64()),
+            ColumnBuilder::Float32(b) => b.append_option(value.as_f64().map(|v| v as f32)),
+            ColumnBuilder::Float64(b) => b.append_option(value.as_f64()),
+            ColumnBuilder::Boolean(b) => b.append_option(value.as_bool()),
+            ColumnBuilder::Timestamp(b) => b.append_option(value.as_i64()),
+        }
+    }
+
+    fn append_id(&mut self, id: u64) {
+        if let ColumnBuilder::UInt64(b) = self {
+            b.append_value(id);
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Utf8(b) => Arc::new(b.finish()),
+            ColumnBuilder::Int64(b) => Arc::new(b.finish()),
+            ColumnBuilder::UInt64(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float32(b) => Arc::new(b.finish()),
+            ColumnBuilder::Float64(b) => Arc::new(b.finish()),
+            ColumnBuilder::Boolean(b) => Arc::new(b.finish()),
+            ColumnBuilder::Timestamp(b) => Arc::new(b.finish()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ColumnBuilder::Utf8(b) => b.len(),
+            ColumnBuilder::Int64(b) => b.len(),
+            ColumnBuilder::UInt64(b) => b.len(),
+            ColumnBuilder::Float32(b) => b.len(),
+            ColumnBuilder::Float64(b) => b.len(),
+            ColumnBuilder::Boolean(b) => b.len(),
+            ColumnBuilder::Timestamp(b) => b.len(),
+        }
+    }
+}
+
+/// Тип колонки для поля, None - поле не выгружается
+fn arrow_type(field: &Field) -> Option<DataType> {
+    let ty = match &field.ty {
+        FieldType::ModelRef(_) => DataType::UInt64,
+        FieldType::Primitive(primitive) => match primitive {
+            PrimitiveFieldType::String | PrimitiveFieldType::Enum(_) => DataType::Utf8,
+            PrimitiveFieldType::Int64 | PrimitiveFieldType::Int32 | PrimitiveFieldType::Int16 | PrimitiveFieldType::Int8 => DataType::Int64,
+            PrimitiveFieldType::UInt64 | PrimitiveFieldType::UInt32 | PrimitiveFieldType::UInt16 | PrimitiveFieldType::UInt8 => DataType::UInt64,
+            PrimitiveFieldType::Float => DataType::Float32,
+            PrimitiveFieldType::Double => DataType::Float64,
+            PrimitiveFieldType::Bool => DataType::Boolean,
+            PrimitiveFieldType::DateTime => DataType::Timestamp(TimeUnit::Millisecond, None),
+            PrimitiveFieldType::Custom(_) | PrimitiveFieldType::Json => return None,
+        },
+        _ => return None,
+    };
+    Some(ty)
+}
+
+/// Выгрузка колонок модели в формате Arrow IPC stream: схема, затем record batch на каждые BATCH_ROWS документов.
+/// Колонки - `id` и перечисленные поля (по умолчанию все примитивные поля и связи как id).
+/// Документы читаются в одной транзакции и декодируются прямо в колонки, минуя JSON-ответ
+pub fn export_arrow(db: &MarciDB, model: &Model, field_names: Option<&[&str]>) -> Result<Vec<u8>, ArrowExportError> {
+    let fields: Vec<&Field> = match field_names {
+        Some(names) => names.iter().filter(|name| **name != "id").map(|name| {
+            let field = model.fields.iter().find(|f| f.name == *name).ok_or_else(|| ArrowExportError::UnknownField(name.to_string()))?;
+            arrow_type(field).map(|_| field).ok_or_else(|| ArrowExportError::UnsupportedField(name.to_string()))
+        }).collect::<Result<_, _>>()?,
+        None => model.fields.iter().filter(|f| arrow_type(f).is_some()).collect(),
+    };
+
+    let mut schema_fields = vec![ArrowField::new("id", DataType::UInt64, false)];
+    let mut columns = vec![Column { field: None, builder: ColumnBuilder::new(&DataType::UInt64) }];
+    for field in fields {
+        let ty = arrow_type(field).unwrap();
+        columns.push(Column { field: Some(field), builder: ColumnBuilder::new(&ty) });
+        schema_fields.push(ArrowField::new(field.name.clone(), ty, true));
+    }
+    let schema: SchemaRef = Arc::new(ArrowSchema::new(schema_fields));
+
+    let mut writer = StreamWriter::try_new(Vec::new(), &schema).map_err(ArrowExportError::Arrow)?;
+
+    let rx = db.db.begin_read().unwrap();
+    let tree = rx.get_tree(model.name.as_bytes()).unwrap().unwrap();
+    for item in tree.iter().unwrap() {
+        let (key, data) = item.unwrap();
+        let id = u64::from_be_bytes(key.as_ref().try_into().unwrap());
+        let data = unpack(data);
+        for column in columns.iter_mut() {
+            match column.field {
+                None => column.builder.append_id(id),
+                Some(field) => {
+                    let value = decode_field(field, data.as_ref(), model.payload_offset).map_err(ArrowExportError::Decode)?;
+                    column.builder.append(&value);
+                }
+            }
+        }
+        if columns[0].builder.len() >= BATCH_ROWS {
+            write_batch(&mut writer, &schema, &mut columns)?;
+        }
+    }
+    if columns[0].builder.len() > 0 {
+        write_batch(&mut writer, &schema, &mut columns)?;
+    }
+
+    writer.finish().map_err(ArrowExportError::Arrow)?;
+    return writer.into_inner().map_err(ArrowExportError::Arrow);
+}
+
+fn write_batch(writer: &mut StreamWriter<Vec<u8>>, schema: &SchemaRef, columns: &mut [Column]) -> Result<(), ArrowExportError> {
+    let arrays: Vec<ArrayRef> = columns.iter_mut().map(|column| column.builder.finish()).collect();
+    let batch = RecordBatch::try_new(schema.clone(), arrays).map_err(ArrowExportError::Arrow)?;
+    return writer.write(&batch).map_err(ArrowExportError::Arrow);
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use hyper::{HeaderMap, Method};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::marci_db::now_millis;
+
+/// Что разрешает ключ API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Ключи API из `--api-keys` (чтение и запись) и `--read-keys` (только чтение).
+/// Без ключей сервер открыт, как раньше
+pub struct ApiKeys {
+    write: Vec<String>,
+    read: Vec<String>,
+}
+
+impl ApiKeys {
+    pub fn new(write: Vec<String>, read: Vec<String>) -> ApiKeys {
+        ApiKeys { write, read }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.write.is_empty() || !self.read.is_empty()
+    }
+
+    /// Доступ по заголовку `Authorization: Bearer <key>`. None - ключа нет или он неизвестен
+    pub fn access(&self, headers: &HeaderMap) -> Option<Access> {
+        let key = bearer_token(headers)?;
+        // Сравниваются все ключи, чтобы время ответа не зависело от того, какой подошел
+        let write = self.write.iter().fold(false, |found, known| found | constant_time_eq(known.as_bytes(), key.as_bytes()));
+        let read = self.read.iter().fold(false, |found, known| found | constant_time_eq(known.as_bytes(), key.as_bytes()));
+        match (write, read) {
+            (true, _) => Some(Access::Write),
+            (false, true) => Some(Access::Read),
+            (false, false) => None,
+        }
+    }
+}
+
+/// Служебные маршруты, которые отдают или меняют всю базу в обход `@@allow`.
+/// Им нужен доступ на запись, даже если это GET
+const ADMIN_ROUTES: &[&str] = &["$backup", "$backups", "$export", "$import", "$restore", "$replication", "$debug", "$check", "$compact"];
+
+/// Доступ, нужный запросу: чтение для GET и `findMany`/`findFirst` вне транзакции, запись для остального
+pub fn required_access(method: &Method, model_name: &str, action: &str, in_transaction: bool) -> Access {
+    if ADMIN_ROUTES.contains(&model_name) {
+        return Access::Write;
+    }
+    let reads = method == Method::GET
+        || (method == Method::POST && matches!(action, "findMany" | "findFirst") && !in_transaction);
+    if reads { Access::Read } else { Access::Write }
+}
+
+/// Claims проверенного JWT. Кладутся в extensions запроса, по ним применяются правила `@@allow`
+#[derive(Debug, Clone)]
+pub struct Claims(pub Value);
+
+/// JWT (HS256) с ролями: claim `claim` содержит роль или список ролей, у каждой роли - доступ по моделям.
+/// `*` задает доступ к моделям и служебным `$`-маршрутам, не перечисленным явно
+pub struct JwtAuth {
+    secret: Vec<u8>,
+    claim: String,
+    roles: HashMap<String, HashMap<String, Access>>,
+}
+
+#[derive(Debug)]
+pub enum JwtError {
+    Malformed,
+    /// Поддерживается только HS256
+    UnsupportedAlgorithm(String),
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+}
+
+impl std::fmt::Display for JwtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JwtError::Malformed => write!(f, "malformed token"),
+            JwtError::UnsupportedAlgorithm(alg) => write!(f, "unsupported algorithm {}", alg),
+            JwtError::InvalidSignature => write!(f, "invalid signature"),
+            JwtError::Expired => write!(f, "token expired"),
+            JwtError::NotYetValid => write!(f, "token is not valid yet"),
+        }
+    }
+}
+
+impl JwtAuth {
+    /// `permissions`: `{ "claim": "role", "roles": { "editor": { "Post": "write", "User": "read" } } }`
+    pub fn new(secret: &str, permissions: &Value) -> Result<JwtAuth, String> {
+        let claim = permissions.get("claim").and_then(|v| v.as_str()).unwrap_or("role").to_string();
+        let Some(roles) = permissions.get("roles").and_then(|v| v.as_object()) else {
+            return Err("roles object required".to_string());
+        };
+        let mut parsed = HashMap::new();
+        for (role, models) in roles {
+            let Some(models) = models.as_object() else {
+                return Err(format!("Role {} must map models to \"read\" or \"write\"", role));
+            };
+            let mut access = HashMap::new();
+            for (model, value) in models {
+                let level = match value.as_str() {
+                    Some("read") => Access::Read,
+                    Some("write") => Access::Write,
+                    _ => return Err(format!("Invalid access {} for {} in role {}", value, model, role)),
+                };
+                access.insert(model.clone(), level);
+            }
+            parsed.insert(role.clone(), access);
+        }
+        Ok(JwtAuth { secret: secret.as_bytes().to_vec(), claim, roles: parsed })
+    }
+
+    /// Проверяет подпись и сроки токена и возвращает его claims
+    pub fn verify(&self, token: &str) -> Result<Value, JwtError> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(JwtError::Malformed);
+        };
+        let header: Value = decode_part(header)?;
+        let alg = header.get("alg").and_then(|v| v.as_str()).unwrap_or("");
+        if alg != "HS256" {
+            return Err(JwtError::UnsupportedAlgorithm(alg.to_string()));
+        }
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| JwtError::Malformed)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        // Подписана часть `header.payload`
+        mac.update(&token.as_bytes()[..token.rfind('.').unwrap()]);
+        mac.verify_slice(&signature).map_err(|_| JwtError::InvalidSignature)?;
+
+        let claims: Value = decode_part(payload)?;
+        let now = now_millis() / 1000;
+        if claims.get("exp").and_then(|v| v.as_u64()).is_some_and(|exp| exp <= now) {
+            return Err(JwtError::Expired);
+        }
+        if claims.get("nbf").and_then(|v| v.as_u64()).is_some_and(|nbf| nbf > now) {
+            return Err(JwtError::NotYetValid);
+        }
+        Ok(claims)
+    }
+
+    /// Наибольший доступ к модели среди ролей из claims. None - ни одна роль не дает доступа
+    pub fn access(&self, claims: &Value, model: &str) -> Option<Access> {
+        let roles: Vec<&str> = match claims.get(&self.claim) {
+            Some(Value::String(role)) => vec![role.as_str()],
+            Some(Value::Array(roles)) => roles.iter().filter_map(|role| role.as_str()).collect(),
+            _ => vec![],
+        };
+        roles.into_iter()
+            .filter_map(|role| self.roles.get(role))
+            .filter_map(|models| models.get(model).or_else(|| models.get("*")).copied())
+            .max()
+    }
+}
+
+fn decode_part(part: &str) -> Result<Value, JwtError> {
+    let bytes = URL_SAFE_NO_PAD.decode(part).map_err(|_| JwtError::Malformed)?;
+    serde_json::from_slice(&bytes).map_err(|_| JwtError::Malformed)
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(hyper::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access() {
+        let keys = ApiKeys::new(vec!["admin".to_string()], vec!["reader".to_string()]);
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(hyper::header::AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+        assert_eq!(keys.access(&headers("Bearer admin")), Some(Access::Write));
+        assert_eq!(keys.access(&headers("bearer reader")), Some(Access::Read));
+        assert_eq!(keys.access(&headers("Bearer other")), None);
+        assert_eq!(keys.access(&headers("admin")), None);
+        assert_eq!(keys.access(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_required_access() {
+        assert_eq!(required_access(&Method::GET, "Post", "", false), Access::Read);
+        assert_eq!(required_access(&Method::POST, "Post", "findMany", false), Access::Read);
+        assert_eq!(required_access(&Method::POST, "Post", "findMany", true), Access::Write);
+        assert_eq!(required_access(&Method::POST, "Post", "create", false), Access::Write);
+        assert_eq!(required_access(&Method::GET, "$stats", "", false), Access::Read);
+        for (model_name, action) in [("$backup", ""), ("$export", ""), ("$replication", "snapshot"), ("$debug", "recent")] {
+            assert_eq!(required_access(&Method::GET, model_name, action, false), Access::Write);
+        }
+
+        // Ключ только для чтения не выгружает базу через `/$backup`
+        let keys = ApiKeys::new(vec!["admin".to_string()], vec!["reader".to_string()]);
+        let mut headers = HeaderMap::new();
+        headers.insert(hyper::header::AUTHORIZATION, "Bearer reader".parse().unwrap());
+        assert!(keys.access(&headers).unwrap() < required_access(&Method::GET, "$backup", "", false));
+    }
+
+    #[test]
+    fn test_jwt() {
+        let auth = JwtAuth::new("secret", &serde_json::json!({
+            "roles": { "editor": { "Post": "write", "User": "read" }, "viewer": { "*": "read" } }
+        })).unwrap();
+        let sign = |claims: Value| {
+            let signed = format!("{}.{}", URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#), URL_SAFE_NO_PAD.encode(claims.to_string()));
+            let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+            mac.update(signed.as_bytes());
+            format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+        };
+
+        let claims = auth.verify(&sign(serde_json::json!({ "role": ["viewer", "editor"] }))).unwrap();
+        assert_eq!(auth.access(&claims, "Post"), Some(Access::Write));
+        assert_eq!(auth.access(&claims, "User"), Some(Access::Read));
+        assert_eq!(auth.access(&claims, "Tag"), Some(Access::Read));
+
+        let expired = sign(serde_json::json!({ "role": "editor", "exp": 1 }));
+        assert!(matches!(auth.verify(&expired), Err(JwtError::Expired)));
+        let tampered = sign(serde_json::json!({ "role": "editor" })).replace(".", ".x");
+        assert!(auth.verify(&tampered).is_err());
+    }
+}
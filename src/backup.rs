@@ -0,0 +1,179 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+
+use crate::journal::{journal_seq, load_snapshot, write_snapshot};
+use crate::error::MarciError;
+use crate::marci_db::MarciDB;
+
+/// Заголовок файла бэкапа: `[magic: 8][format: u8]`, для зашифрованного далее `[nonce: 12][ciphertext]`,
+/// иначе сразу снимок в формате journal::write_snapshot
+const MAGIC: &[u8; 8] = b"MARCIBAK";
+const FORMAT_PLAIN: u8 = 0;
+const FORMAT_AES_GCM: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 1;
+const NONCE_LEN: usize = 12;
+/// Бэкапы по расписанию: `marci-<YYYYMMDD-HHMMSS>.bak`
+const FILE_PREFIX: &str = "marci-";
+const FILE_EXTENSION: &str = ".bak";
+
+#[derive(Debug)]
+pub enum BackupError {
+    InvalidFormat,
+    /// Бэкап зашифрован, а ключ не задан
+    KeyRequired,
+    /// Неверный ключ или поврежденный файл
+    DecryptFailed,
+    /// Снимок загружен, но деревья схемы или счетчики id не восстановились
+    Storage(MarciError),
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupError::InvalidFormat => write!(f, "Invalid backup format"),
+            BackupError::KeyRequired => write!(f, "Backup is encrypted, --backup-key is required"),
+            BackupError::DecryptFailed => write!(f, "Wrong backup key or damaged backup"),
+            BackupError::Storage(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// AES-256-GCM ключ для шифрования бэкапов
+pub struct BackupKey {
+    cipher: Aes256Gcm,
+}
+
+impl BackupKey {
+    /// Ключ из 64 hex-символов (32 байта)
+    pub fn from_hex(value: &str) -> BackupKey {
+        let value = value.trim();
+        if value.len() != 64 {
+            panic!("Backup key must be 64 hex characters");
+        }
+        let bytes: Vec<u8> = (0..value.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap_or_else(|_| panic!("Invalid backup key")))
+            .collect();
+        BackupKey { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)) }
+    }
+}
+
+/// Снимок всей базы. С ключом содержимое шифруется, заголовок используется как associated data
+pub fn create_backup(db: &MarciDB, key: Option<&BackupKey>) -> Result<Vec<u8>, MarciError> {
+    let rx = db.db.begin_read()?;
+    let mut snapshot = vec![];
+    write_snapshot(&rx, journal_seq(&rx)?, &mut snapshot)?;
+
+    let mut out = Vec::with_capacity(HEADER_LEN + NONCE_LEN + snapshot.len() + 16);
+    out.extend_from_slice(MAGIC);
+    let Some(key) = key else {
+        out.push(FORMAT_PLAIN);
+        out.extend_from_slice(&snapshot);
+        return Ok(out);
+    };
+
+    out.push(FORMAT_AES_GCM);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = key.cipher.encrypt(&nonce, Payload { msg: &snapshot, aad: &out[..HEADER_LEN] }).unwrap();
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Заменяет содержимое базы бэкапом, расшифровывая его при необходимости.
+/// Журнал продолжается с нового номера, чтобы реплики загрузили снимок заново
+pub fn restore_backup(db: &MarciDB, data: &[u8], key: Option<&BackupKey>) -> Result<u64, BackupError> {
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(BackupError::InvalidFormat);
+    }
+
+    let (header, body) = data.split_at(HEADER_LEN);
+    let decrypted;
+    let snapshot = match header[MAGIC.len()] {
+        FORMAT_PLAIN => body,
+        FORMAT_AES_GCM => {
+            let key = key.ok_or(BackupError::KeyRequired)?;
+            if body.len() < NONCE_LEN {
+                return Err(BackupError::InvalidFormat);
+            }
+            let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+            decrypted = key.cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+                .map_err(|_| BackupError::DecryptFailed)?;
+            &decrypted
+        }
+        _ => return Err(BackupError::InvalidFormat)
+    };
+
+    // Пропуск номера в журнале отправляет реплики на полную синхронизацию
+    let seq = db.last_seq().map_err(BackupError::Storage)? + 2;
+    load_snapshot(&db.db, snapshot, Some(seq)).ok_or(BackupError::InvalidFormat)?;
+    if let Some(cache) = &db.doc_cache {
+        cache.reset(seq);
+    }
+    // Снимок мог быть записан до появления индексов текущей схемы
+    db.ensure_trees().map_err(BackupError::Storage)?;
+    Ok(seq)
+}
+
+/// Пишет бэкап в каталог по расписанию. Файл сначала пишется во временный, чтобы в списке не было недописанных
+pub fn write_backup_file(db: &MarciDB, dir: &Path, key: Option<&BackupKey>) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let name = format!("{}{}{}", FILE_PREFIX, Utc::now().format("%Y%m%d-%H%M%S"), FILE_EXTENSION);
+    let path = dir.join(name);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, create_backup(db, key).map_err(io::Error::other)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+/// Бэкапы в каталоге от старых к новым
+fn backup_files(dir: &Path) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(FILE_PREFIX) && name.ends_with(FILE_EXTENSION) {
+            files.push((entry.path(), entry.metadata()?));
+        }
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(files)
+}
+
+/// Время последнего бэкапа по расписанию
+pub fn last_backup_time(dir: &Path) -> Option<SystemTime> {
+    let files = backup_files(dir).ok()?;
+    return files.last().and_then(|(_, meta)| meta.modified().ok());
+}
+
+/// Удаляет старые бэкапы, оставляя `keep` последних. Возвращает количество удаленных
+pub fn prune_backups(dir: &Path, keep: usize) -> io::Result<usize> {
+    let files = backup_files(dir)?;
+    let excess = files.len().saturating_sub(keep);
+    for (path, _) in &files[..excess] {
+        fs::remove_file(path)?;
+    }
+    Ok(excess)
+}
+
+pub fn list_backups(dir: &Path) -> io::Result<Value> {
+    let files = backup_files(dir)?;
+    let items: Vec<Value> = files.iter().rev().map(|(path, meta)| {
+        let created_at = meta.modified().ok().map(|time| DateTime::<Utc>::from(time).to_rfc3339());
+        json!({
+            "name": path.file_name().unwrap().to_string_lossy(),
+            "size": meta.len(),
+            "createdAt": created_at,
+        })
+    }).collect();
+    Ok(Value::Array(items))
+}
@@ -0,0 +1,396 @@
+use std::fmt::Write;
+
+use crate::schema::{Field, FieldType, Model, PrimitiveFieldType, Schema};
+
+/// Общая часть клиента: HTTP, ошибки и фильтры полей. Не зависит от схемы
+const CLIENT_PRELUDE: &str = r#"
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    /// Non-2xx answer: status and the server's error body
+    Status(u16, ApiError),
+}
+
+/// Error body `{ code, message, field?, details? }`; `code` is stable and meant for matching
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiError {
+    pub code: String,
+    pub message: String,
+    pub field: Option<String>,
+    pub details: Option<Value>,
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> ClientError {
+        ClientError::Http(err)
+    }
+}
+
+/// `{ id, sequence }` returned by insert, update and delete
+#[derive(Debug, Clone, Deserialize)]
+pub struct Written {
+    pub id: u64,
+    pub sequence: u64,
+}
+
+/// A relation in insert and update bodies
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Ref {
+    pub id: u64,
+}
+
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: impl Into<String>) -> Client {
+        Client { http: reqwest::Client::new(), base_url: base_url.into().trim_end_matches('/').to_string(), token: None }
+    }
+
+    /// API key or JWT sent as `Authorization: Bearer`
+    pub fn with_token(mut self, token: impl Into<String>) -> Client {
+        self.token = Some(token.into());
+        self
+    }
+
+    async fn post<T: DeserializeOwned>(&self, path: &str, body: &Value) -> Result<T, ClientError> {
+        let mut request = self.http.post(format!("{}{}", self.base_url, path)).json(body);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            let error = serde_json::from_str(&text)
+                .unwrap_or(ApiError { code: "unknown".to_string(), message: text, field: None, details: None });
+            return Err(ClientError::Status(status.as_u16(), error));
+        }
+        Ok(response.json().await?)
+    }
+}
+
+/// Condition on one field of type `T`
+pub struct Filter<T> {
+    value: Value,
+    _type: PhantomData<T>,
+}
+
+impl<T: Serialize> Filter<T> {
+    fn op(op: &str, value: impl Serialize) -> Filter<T> {
+        let mut condition = Map::new();
+        condition.insert(op.to_string(), serde_json::to_value(value).unwrap());
+        Filter { value: Value::Object(condition), _type: PhantomData }
+    }
+    pub fn equals(value: T) -> Filter<T> { Filter::op("equals", value) }
+    pub fn not(value: T) -> Filter<T> { Filter::op("not", value) }
+    pub fn is_in(values: Vec<T>) -> Filter<T> { Filter::op("in", values) }
+    pub fn not_in(values: Vec<T>) -> Filter<T> { Filter::op("notIn", values) }
+    pub fn lt(value: T) -> Filter<T> { Filter::op("lt", value) }
+    pub fn lte(value: T) -> Filter<T> { Filter::op("lte", value) }
+    pub fn gt(value: T) -> Filter<T> { Filter::op("gt", value) }
+    pub fn gte(value: T) -> Filter<T> { Filter::op("gte", value) }
+}
+
+impl Filter<String> {
+    pub fn contains(value: &str) -> Filter<String> { Filter::op("contains", value) }
+    pub fn starts_with(value: &str) -> Filter<String> { Filter::op("startsWith", value) }
+    pub fn ends_with(value: &str) -> Filter<String> { Filter::op("endsWith", value) }
+}
+
+/// `orderBy` direction
+#[derive(Debug, Clone, Copy)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl Order {
+    fn as_str(self) -> &'static str {
+        match self {
+            Order::Asc => "asc",
+            Order::Desc => "desc",
+        }
+    }
+}
+
+/// Arguments of findMany/findFirst: `W` is the model's where builder, `S` its select builder
+pub struct FindArgs<W, S> {
+    pub where_: Option<W>,
+    pub select: Option<S>,
+    pub order_by: Vec<(String, Order)>,
+    pub skip: Option<u64>,
+    pub take: Option<u64>,
+}
+
+impl<W, S> Default for FindArgs<W, S> {
+    fn default() -> Self {
+        FindArgs { where_: None, select: None, order_by: vec![], skip: None, take: None }
+    }
+}
+
+impl<W: Into<Value>, S: Into<Value>> FindArgs<W, S> {
+    fn into_json(self) -> Value {
+        let mut args = Map::new();
+        if let Some(where_) = self.where_ {
+            args.insert("where".to_string(), where_.into());
+        }
+        if let Some(select) = self.select {
+            args.insert("select".to_string(), select.into());
+        }
+        if !self.order_by.is_empty() {
+            let order: Vec<Value> = self.order_by.iter().map(|(field, order)| {
+                let mut item = Map::new();
+                item.insert(field.clone(), json!(order.as_str()));
+                Value::Object(item)
+            }).collect();
+            args.insert("orderBy".to_string(), Value::Array(order));
+        }
+        if let Some(skip) = self.skip {
+            args.insert("skip".to_string(), json!(skip));
+        }
+        if let Some(take) = self.take {
+            args.insert("take".to_string(), json!(take));
+        }
+        Value::Object(args)
+    }
+}
+"#;
+
+/// Модуль Rust-клиента для схемы: для каждой модели - структура документа, тела insert/update, построители
+/// where и select и асинхронные методы поверх HTTP API. Клиенту нужны reqwest (feature `json`), serde (`derive`) и serde_json
+pub fn rust_client(schema: &Schema) -> String {
+    let mut out = String::new();
+    out.push_str("// Generated by `marci-db codegen` from schema.marci. Do not edit by hand.\n");
+    out.push_str("// Requires reqwest (feature \"json\"), serde (feature \"derive\") and serde_json.\n");
+    out.push_str("#![allow(dead_code, clippy::all)]\n");
+    out.push_str(CLIENT_PRELUDE);
+
+    writeln!(out, "\nimpl Client {{").unwrap();
+    for model in &schema.models {
+        writeln!(out, "    pub fn {}(&self) -> {}Client<'_> {{ {}Client {{ client: self }} }}", rust_ident(&snake_case(&model.name)), model.name, model.name).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    for model in &schema.models {
+        model_client(&mut out, schema, model);
+    }
+    out
+}
+
+fn model_client(out: &mut String, schema: &Schema, model: &Model) {
+    let name = &model.name;
+    // Поля, которые можно записать: производные списки вычисляются по связи с другой стороны
+    let writable: Vec<&Field> = model.fields.iter().filter(|field| field.derived_from.is_none()).collect();
+
+    // Документ: набор полей зависит от select, поэтому все поля необязательные
+    writeln!(out, "\n#[derive(Debug, Clone, Default, Serialize, Deserialize)]\npub struct {} {{\n    pub id: u64,", name).unwrap();
+    for field in &model.fields {
+        let ty = match &field.ty {
+            FieldType::ModelRef(_) | FieldType::ModelRefList(_) => "Value".to_string(),
+            _ => value_type(&field.ty),
+        };
+        writeln!(out, "    #[serde(rename = \"{}\", default, skip_serializing_if = \"Option::is_none\")]", field.name).unwrap();
+        writeln!(out, "    pub {}: Option<{}>,", field_ident(field), ty).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out, "\n#[derive(Debug, Clone, Default, Serialize)]\npub struct {}Insert {{", name).unwrap();
+    for field in &writable {
+        let required = !field.is_nullable && field.default_value().is_none() && field.default_fn().is_none()
+            && matches!(field.ty, FieldType::Primitive(_) | FieldType::ModelRef(_));
+        let ty = input_type(&field.ty);
+        writeln!(out, "    #[serde(rename = \"{}\"{})]", field.name, if required { "" } else { ", skip_serializing_if = \"Option::is_none\"" }).unwrap();
+        match required {
+            true => writeln!(out, "    pub {}: {},", field_ident(field), ty).unwrap(),
+            false => writeln!(out, "    pub {}: Option<{}>,", field_ident(field), ty).unwrap(),
+        }
+    }
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out, "\n#[derive(Debug, Clone, Default, Serialize)]\npub struct {}Update {{\n    pub id: u64,", name).unwrap();
+    for field in &writable {
+        writeln!(out, "    #[serde(rename = \"{}\", skip_serializing_if = \"Option::is_none\")]", field.name).unwrap();
+        writeln!(out, "    pub {}: Option<{}>,", field_ident(field), input_type(&field.ty)).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    // where: фильтры скалярных полей и id, AND/OR/NOT из таких же построителей
+    writeln!(out, "\n#[derive(Debug, Clone, Default)]\npub struct {}Where(Map<String, Value>);\n\nimpl {}Where {{", name, name).unwrap();
+    writeln!(out, "    pub fn new() -> Self {{ Self::default() }}").unwrap();
+    writeln!(out, "    pub fn id(mut self, filter: Filter<u64>) -> Self {{ self.0.insert(\"id\".to_string(), filter.value); self }}").unwrap();
+    for field in &model.fields {
+        let ty = match &field.ty {
+            FieldType::Primitive(PrimitiveFieldType::Json) => continue,
+            FieldType::Primitive(ty) => primitive_type(ty),
+            FieldType::ModelRef(_) => "u64".to_string(),
+            _ => continue,
+        };
+        writeln!(out, "    pub fn {}(mut self, filter: Filter<{}>) -> Self {{ self.0.insert(\"{}\".to_string(), filter.value); self }}", field_ident(field), ty, field.name).unwrap();
+    }
+    for (method, key) in [("and", "AND"), ("or", "OR"), ("not", "NOT")] {
+        writeln!(out, "    pub fn {}(mut self, items: Vec<{}Where>) -> Self {{ self.0.insert(\"{}\".to_string(), Value::Array(items.into_iter().map(Value::from).collect())); self }}", method, name, key).unwrap();
+    }
+    writeln!(out, "}}\n\nimpl From<{}Where> for Value {{\n    fn from(value: {}Where) -> Value {{ Value::Object(value.0) }}\n}}", name, name).unwrap();
+
+    // select: поля по одному, связи - вложенным select связанной модели
+    writeln!(out, "\n#[derive(Debug, Clone, Default)]\npub struct {}Select(Map<String, Value>);\n\nimpl {}Select {{", name, name).unwrap();
+    writeln!(out, "    pub fn new() -> Self {{ Self::default() }}").unwrap();
+    writeln!(out, "    pub fn id(mut self) -> Self {{ self.0.insert(\"id\".to_string(), Value::Bool(true)); self }}").unwrap();
+    for field in &model.fields {
+        match &field.ty {
+            FieldType::ModelRef(index) | FieldType::ModelRefList(index) => {
+                let related = &schema.models[*index].name;
+                writeln!(out, "    pub fn {}(mut self, select: {}Select) -> Self {{ self.0.insert(\"{}\".to_string(), select.into()); self }}", field_ident(field), related, field.name).unwrap();
+            }
+            _ => writeln!(out, "    pub fn {}(mut self) -> Self {{ self.0.insert(\"{}\".to_string(), Value::Bool(true)); self }}", field_ident(field), field.name).unwrap(),
+        }
+    }
+    writeln!(out, "}}\n\nimpl From<{}Select> for Value {{\n    fn from(value: {}Select) -> Value {{ Value::Object(value.0) }}\n}}", name, name).unwrap();
+
+    writeln!(out, r#"
+pub struct {name}Client<'a> {{
+    client: &'a Client,
+}}
+
+impl {name}Client<'_> {{
+    pub async fn insert(&self, document: &{name}Insert) -> Result<Written, ClientError> {{
+        self.client.post("/{name}/insert", &serde_json::to_value(document).unwrap()).await
+    }}
+
+    pub async fn update(&self, document: &{name}Update) -> Result<Written, ClientError> {{
+        self.client.post("/{name}/update", &serde_json::to_value(document).unwrap()).await
+    }}
+
+    pub async fn delete(&self, id: u64) -> Result<Written, ClientError> {{
+        self.client.post("/{name}/delete", &json!({{ "id": id }})).await
+    }}
+
+    pub async fn find_many(&self, args: FindArgs<{name}Where, {name}Select>) -> Result<Vec<{name}>, ClientError> {{
+        self.client.post("/{name}/findMany", &args.into_json()).await
+    }}
+
+    pub async fn find_first(&self, args: FindArgs<{name}Where, {name}Select>) -> Result<Option<{name}>, ClientError> {{
+        self.client.post("/{name}/findFirst", &args.into_json()).await
+    }}
+}}"#, name = name).unwrap();
+}
+
+/// Тип значения в документе из ответа
+fn value_type(ty: &FieldType) -> String {
+    match ty {
+        FieldType::Primitive(ty) => primitive_type(ty),
+        FieldType::PrimitiveList(ty) => format!("Vec<{}>", primitive_type(ty)),
+        _ => "Value".to_string(),
+    }
+}
+
+/// Тип поля в теле insert/update: связи передаются как `{ id }`
+fn input_type(ty: &FieldType) -> String {
+    match ty {
+        FieldType::ModelRef(_) => "Ref".to_string(),
+        FieldType::ModelRefList(_) => "Vec<Ref>".to_string(),
+        _ => value_type(ty),
+    }
+}
+
+fn primitive_type(ty: &PrimitiveFieldType) -> String {
+    let ty = match ty {
+        PrimitiveFieldType::String => "String",
+        PrimitiveFieldType::Int64 => "i64",
+        PrimitiveFieldType::UInt64 => "u64",
+        PrimitiveFieldType::Int8 => "i8",
+        PrimitiveFieldType::Int16 => "i16",
+        PrimitiveFieldType::Int32 => "i32",
+        PrimitiveFieldType::UInt8 => "u8",
+        PrimitiveFieldType::UInt16 => "u16",
+        PrimitiveFieldType::UInt32 => "u32",
+        PrimitiveFieldType::Float => "f32",
+        PrimitiveFieldType::Double => "f64",
+        PrimitiveFieldType::Bool => "bool",
+        // Миллисекунды от epoch
+        PrimitiveFieldType::DateTime => "i64",
+        // Имя варианта
+        PrimitiveFieldType::Enum(_) => "String",
+        // Формат пользовательского типа знает только его кодек
+        PrimitiveFieldType::Custom(_) | PrimitiveFieldType::Json => "Value",
+    };
+    ty.to_string()
+}
+
+fn field_ident(field: &Field) -> String {
+    rust_ident(&snake_case(&field.name))
+}
+
+/// `createdAt` -> `created_at`, `URLPath` -> `url_path`
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut out = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            let after_lower = i > 0 && (chars[i - 1].is_lowercase() || chars[i - 1].is_ascii_digit());
+            let before_lower = i > 0 && chars[i - 1].is_uppercase() && chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            if after_lower || before_lower {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(*c);
+        }
+    }
+    out
+}
+
+/// Имена полей, совпадающие с ключевыми словами Rust, становятся raw-идентификаторами
+fn rust_ident(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false", "fn", "for",
+        "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct",
+        "trait", "true", "type", "unsafe", "use", "where", "while", "abstract", "become", "box", "do", "final", "gen",
+        "macro", "override", "priv", "try", "typeof", "unsized", "virtual", "yield",
+    ];
+    match name {
+        "self" | "Self" | "super" | "crate" => format!("{}_", name),
+        _ if KEYWORDS.contains(&name) => format!("r#{}", name),
+        _ => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::parse_schema;
+
+    #[test]
+    fn test_rust_client() {
+        let schema = parse_schema("
+model User {
+  name        String
+  createdAt   DateTime      @default(now())
+  type        String?
+}
+
+model Post {
+  title       String
+  author      User
+}
+").unwrap();
+        let code = rust_client(&schema);
+        assert!(code.contains("pub fn post(&self) -> PostClient<'_>"));
+        assert!(code.contains("    #[serde(rename = \"createdAt\", skip_serializing_if = \"Option::is_none\")]\n    pub created_at: Option<i64>,"));
+        assert!(code.contains("    #[serde(rename = \"author\")]\n    pub author: Ref,"));
+        assert!(code.contains("pub r#type: Option<String>"));
+        assert!(code.contains("pub fn author(mut self, select: UserSelect) -> Self"));
+        assert_eq!(snake_case("URLPath"), "url_path");
+    }
+}
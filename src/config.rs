@@ -0,0 +1,177 @@
+use std::env;
+use std::time::Duration;
+
+/// Настройки сервера. Каждое значение берется из аргумента командной строки (`--memory-budget 512M`),
+/// затем из переменной окружения (`MARCI_MEMORY_BUDGET`), иначе используется значение по умолчанию
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Адреса для прослушивания, несколько через запятую (`0.0.0.0,::`)
+    pub hosts: Vec<String>,
+    pub port: u16,
+    /// Цепочка сертификатов PEM. Вместе с `tls_key` сервер принимает только TLS-соединения (feature `tls`)
+    pub tls_cert: Option<String>,
+    /// Закрытый ключ PEM для `tls_cert`
+    pub tls_key: Option<String>,
+    /// Примерный бюджет памяти на выполняющиеся чтения в байтах, 0 - без сброса нагрузки
+    pub memory_budget: usize,
+    /// Сколько тяжелых операций (полных обходов) выполняется одновременно, 0 - без ограничения
+    pub heavy_concurrency: usize,
+    /// Сколько легких операций (чтений по id и записей) выполняется одновременно, 0 - без ограничения
+    pub light_concurrency: usize,
+    /// Адрес основного сервера (`host:port`), с ним процесс работает как реплика только для чтения
+    pub replica_of: Option<String>,
+    /// Ключ API с правом записи, который реплика передает основному серверу, если тот требует ключи
+    pub primary_key: Option<String>,
+    /// Файл блокировки, общий для основного сервера и реплик: запись принимает только ее владелец
+    pub leader_lock: Option<String>,
+    /// Ключ AES-256 в hex: им шифруются бэкапы, восстановление их расшифровывает
+    pub backup_key: Option<String>,
+    /// Каталог бэкапов по расписанию, без него расписание выключено
+    pub backup_dir: Option<String>,
+    /// Бэкап, который загружается в каталог данных при запуске, до приема запросов
+    pub restore: Option<String>,
+    pub backup_interval: Duration,
+    /// Сколько бэкапов по расписанию хранится
+    pub backup_retention: usize,
+    /// Записи журнала старше этого удаляются, отставшие реплики после этого загружают снимок
+    pub journal_retention: Option<Duration>,
+    /// При запуске проверить выборку документов и индексов и не запускаться, если что-то повреждено
+    pub verify_on_start: bool,
+    /// Сколько документов каждой модели проверяет `verify_on_start`
+    pub verify_sample: usize,
+    /// Рекомендации индексов также оценивают избирательность каждого поля обходом его модели при каждом запросе
+    pub index_lab: bool,
+    /// Сколько связанных записей один include может прочитать за запрос, 0 - без ограничения
+    pub include_limit: u64,
+    /// Потоки, которые фильтруют и декодируют один большой полный обход, 1 - обход только в потоке запроса
+    pub scan_threads: usize,
+    /// Сколько байт недавно прочитанных документов держится в памяти, 0 - кэш выключен
+    pub doc_cache: usize,
+    /// Модели (или `*`), тела запросов и ответов которых сохраняются для `/$debug/recent`
+    pub debug_bodies: Vec<String>,
+    /// Открыть базу во временном каталоге, который удаляется при остановке, вместо ./data
+    pub ephemeral: bool,
+    /// Сколько документов каждой модели создает `marci-db demo`
+    pub demo_rows: usize,
+    /// Сколько остановка ждет выполняющиеся запросы и фоновые задачи
+    pub shutdown_timeout: Duration,
+    /// Запросы дольше этого получают 503, None - без ограничения
+    pub request_timeout: Option<Duration>,
+    /// Соединения, которые столько времени не присылают заголовки запроса, закрываются
+    pub idle_timeout: Duration,
+    /// Соединения сверх этого числа закрываются сразу после приема, None - без ограничения
+    pub max_connections: Option<usize>,
+    /// Ключи с правом чтения и записи. Если задан хоть один ключ, каждый запрос несет `Authorization: Bearer <ключ>`
+    pub api_keys: Vec<String>,
+    /// Ключи только для чтения
+    pub read_keys: Vec<String>,
+    /// Секрет HS256: с ним запрос может передать JWT вместо ключа API
+    pub jwt_secret: Option<String>,
+    /// JSON-файл с доступом `read`/`write` к моделям для ролей JWT
+    pub jwt_permissions: Option<String>,
+    /// Тела запросов больше этого отклоняются с 413, 0 задает `usize::MAX`
+    pub max_body_size: usize,
+    /// Время простоя, после которого интерактивная транзакция откатывается
+    pub transaction_timeout: Duration,
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let args: Vec<String> = env::args().collect();
+
+        Config {
+            hosts: option(&args, "host").map(|v| parse_list(&v)).unwrap_or_else(|| vec!["127.0.0.1".to_string()]),
+            port: option(&args, "port").map(|v| v.trim().parse().unwrap_or_else(|_| panic!("Invalid port {}", v))).unwrap_or(3000),
+            tls_cert: option(&args, "tls-cert"),
+            tls_key: option(&args, "tls-key"),
+            memory_budget: option(&args, "memory-budget").map(|v| parse_size(&v)).unwrap_or(0),
+            heavy_concurrency: option(&args, "heavy-concurrency").map(|v| parse_number(&v))
+                .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4)),
+            light_concurrency: option(&args, "light-concurrency").map(|v| parse_number(&v)).unwrap_or(0),
+            replica_of: option(&args, "replica-of"),
+            primary_key: option(&args, "primary-key"),
+            leader_lock: option(&args, "leader-lock"),
+            backup_key: option(&args, "backup-key"),
+            backup_dir: option(&args, "backup-dir"),
+            restore: option(&args, "restore"),
+            backup_interval: option(&args, "backup-interval").map(|v| parse_duration(&v)).unwrap_or(Duration::from_secs(24 * 60 * 60)),
+            backup_retention: option(&args, "backup-retention").map(|v| parse_number(&v)).unwrap_or(7),
+            journal_retention: option(&args, "journal-retention").map(|v| parse_duration(&v)),
+            verify_on_start: flag(&args, "verify-on-start"),
+            verify_sample: option(&args, "verify-sample").map(|v| parse_number(&v)).unwrap_or(1000),
+            index_lab: flag(&args, "index-lab"),
+            include_limit: option(&args, "include-limit").map(|v| parse_number(&v) as u64).unwrap_or(0),
+            scan_threads: option(&args, "scan-threads").map(|v| parse_number(&v).max(1))
+                .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4)),
+            doc_cache: option(&args, "doc-cache").map(|v| parse_size(&v)).unwrap_or(0),
+            debug_bodies: option(&args, "debug-bodies").map(|v| parse_list(&v)).unwrap_or_default(),
+            ephemeral: flag(&args, "ephemeral"),
+            demo_rows: option(&args, "demo-rows").map(|v| parse_number(&v)).unwrap_or(10),
+            shutdown_timeout: option(&args, "shutdown-timeout").map(|v| parse_duration(&v)).unwrap_or(Duration::from_secs(30)),
+            request_timeout: option(&args, "request-timeout").map(|v| parse_duration(&v)).filter(|timeout| !timeout.is_zero()),
+            idle_timeout: option(&args, "idle-timeout").map(|v| parse_duration(&v)).unwrap_or(Duration::from_secs(30)),
+            max_connections: Some(option(&args, "max-connections").map(|v| parse_number(&v)).unwrap_or(1024)).filter(|max| *max > 0),
+            api_keys: option(&args, "api-keys").map(|v| parse_list(&v)).unwrap_or_default(),
+            read_keys: option(&args, "read-keys").map(|v| parse_list(&v)).unwrap_or_default(),
+            jwt_secret: option(&args, "jwt-secret"),
+            jwt_permissions: option(&args, "jwt-permissions"),
+            max_body_size: Some(option(&args, "max-body-size").map(|v| parse_size(&v)).unwrap_or(64 << 20)).filter(|size| *size > 0).unwrap_or(usize::MAX),
+            transaction_timeout: option(&args, "transaction-timeout").map(|v| parse_duration(&v)).unwrap_or(Duration::from_secs(10)),
+        }
+    }
+}
+
+/// Значение `--name value` или `MARCI_NAME`
+fn option(args: &[String], name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    if let Some(pos) = args.iter().position(|arg| *arg == flag) {
+        return args.get(pos + 1).cloned();
+    }
+    let env_name = format!("MARCI_{}", name.to_uppercase().replace('-', "_"));
+    env::var(env_name).ok()
+}
+
+/// Флаг `--name` без значения или `MARCI_NAME=1`/`true`
+fn flag(args: &[String], name: &str) -> bool {
+    let flag = format!("--{}", name);
+    if args.contains(&flag) {
+        return true;
+    }
+    let env_name = format!("MARCI_{}", name.to_uppercase().replace('-', "_"));
+    env::var(env_name).is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+}
+
+/// Значения через запятую, пустые пропускаются
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect()
+}
+
+fn parse_number(value: &str) -> usize {
+    value.trim().parse().unwrap_or_else(|_| panic!("Invalid number {}", value))
+}
+
+/// Размер в байтах с необязательным суффиксом K/M/G
+fn parse_size(value: &str) -> usize {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len()-1], 1 << 10),
+        Some('M') => (&value[..value.len()-1], 1 << 20),
+        Some('G') => (&value[..value.len()-1], 1 << 30),
+        _ => (value, 1)
+    };
+    number.trim().parse::<usize>().unwrap_or_else(|_| panic!("Invalid size {}", value)) * multiplier
+}
+
+/// Длительность в секундах с необязательным суффиксом s/m/h/d
+fn parse_duration(value: &str) -> Duration {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('s') => (&value[..value.len()-1], 1),
+        Some('m') => (&value[..value.len()-1], 60),
+        Some('h') => (&value[..value.len()-1], 60 * 60),
+        Some('d') => (&value[..value.len()-1], 24 * 60 * 60),
+        _ => (value, 1)
+    };
+    let seconds = number.trim().parse::<u64>().unwrap_or_else(|_| panic!("Invalid duration {}", value));
+    Duration::from_secs(seconds * multiplier)
+}
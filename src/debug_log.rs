@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use serde_json::{Value, json};
+
+use crate::marci_db::now_millis;
+
+/// Сколько последних запросов хранится
+const CAPACITY: usize = 200;
+/// Тело длиннее обрезается, чтобы буфер не занимал много памяти
+const MAX_BODY: usize = 4096;
+/// Значения ключей, в имени которых есть эти слова, заменяются на `***`
+const SECRET_KEYS: [&str; 4] = ["password", "secret", "token", "key"];
+
+/// Кольцевой буфер с телами запросов и ответов выбранных моделей (`--debug-bodies Post,User` или `*`).
+/// Нужен, чтобы воспроизвести ошибку кодирования у клиента без захвата трафика
+pub struct DebugLog {
+    models: Vec<String>,
+    entries: Mutex<VecDeque<Value>>,
+}
+
+impl DebugLog {
+    pub fn new(models: Vec<String>) -> DebugLog {
+        DebugLog { models, entries: Mutex::new(VecDeque::with_capacity(CAPACITY)) }
+    }
+
+    /// Записываются ли запросы к модели. Служебные пути (`/$...`) не записываются
+    pub fn is_enabled(&self, model: &str) -> bool {
+        !model.starts_with('$') && self.models.iter().any(|name| name == "*" || name == model)
+    }
+
+    pub fn record(&self, method: &str, uri: &str, status: u16, request: &[u8], response: &[u8]) {
+        let entry = json!({
+            "at": now_millis(),
+            "method": method,
+            "uri": uri,
+            "status": status,
+            "request": sanitize(request),
+            "response": sanitize(response),
+        });
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Записи от новых к старым
+    pub fn recent(&self) -> Value {
+        Value::Array(self.entries.lock().unwrap().iter().rev().cloned().collect())
+    }
+}
+
+/// JSON с замаскированными секретами либо текст тела, обрезанный до MAX_BODY
+fn sanitize(body: &[u8]) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    if let Ok(mut value) = serde_json::from_slice::<Value>(body) {
+        mask_secrets(&mut value);
+        let text = value.to_string();
+        if text.len() <= MAX_BODY {
+            return value;
+        }
+        return Value::String(truncate(&text));
+    }
+    Value::String(truncate(&String::from_utf8_lossy(body)))
+}
+
+fn mask_secrets(value: &mut Value) {
+    match value {
+        Value::Object(obj) => {
+            for (key, value) in obj.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if SECRET_KEYS.iter().any(|secret| key.contains(secret)) && !value.is_null() {
+                    *value = Value::String("***".to_string());
+                } else {
+                    mask_secrets(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(mask_secrets),
+        _ => {}
+    }
+}
+
+fn truncate(text: &str) -> String {
+    if text.len() <= MAX_BODY {
+        return text.to_string();
+    }
+    let mut end = MAX_BODY;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &text[..end])
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use serde_json::{Map, Value, json};
+
+use crate::error::MarciError;
+use crate::fixtures::{FixtureError, Fixtures, RandomValues};
+use crate::marci_db::{InsertError, MarciDB, now_millis};
+use crate::schema::{FieldType, PrimitiveFieldType};
+
+/// Сколько существующих документов связывается через один список связей
+const MAX_LINKS: usize = 3;
+
+/// Временный каталог данных для `marci-db demo`, свой у каждого процесса
+pub fn demo_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("marci-demo-{}", std::process::id()))
+}
+
+/// Заполняет пустую базу `rows` документами каждой модели и возвращает число вставленных.
+/// Модели заполняются по порядку схемы, поэтому связи указывают на документы уже заполненных моделей;
+/// обязательные связи на еще пустые модели `Fixtures` создает сама.
+/// Поля `@id`/`@unique` получают последовательные значения, документ с занятым ключом пропускается
+pub fn seed_demo(db: &MarciDB, rows: usize) -> Result<usize, FixtureError> {
+    let mut fixtures = Fixtures::seeded(db, now_millis());
+    let mut values = RandomValues::new(now_millis() ^ 0x5eed);
+    let mut ids: HashMap<usize, Vec<u64>> = HashMap::new();
+    let mut inserted = 0;
+
+    for (model_index, model) in db.schema.models.iter().enumerate() {
+        for i in 0..rows {
+            let mut overrides = Map::new();
+            for field in model.unique_fields() {
+                if field.default_fn().is_some() {
+                    continue;
+                }
+                let value = match field.ty {
+                    FieldType::Primitive(PrimitiveFieldType::String) => json!(format!("{}-{}", field.name, i + 1)),
+                    FieldType::Primitive(PrimitiveFieldType::Int64 | PrimitiveFieldType::UInt64) => json!(i + 1),
+                    _ => continue,
+                };
+                overrides.insert(field.name.clone(), value);
+            }
+
+            for field in model.fields.iter() {
+                if field.derived_from.is_some() {
+                    continue;
+                }
+                match field.ty {
+                    FieldType::ModelRef(target) => {
+                        let Some(targets) = ids.get(&target).filter(|targets| !targets.is_empty()) else { continue };
+                        let id = targets[values.index(targets.len())];
+                        overrides.insert(field.name.clone(), json!({ "id": id }));
+                    }
+                    FieldType::ModelRefList(target) => {
+                        let Some(targets) = ids.get(&target).filter(|targets| !targets.is_empty()) else { continue };
+                        let mut links: Vec<u64> = vec![];
+                        for _ in 0..values.index(MAX_LINKS + 1) {
+                            let id = targets[values.index(targets.len())];
+                            if !links.contains(&id) {
+                                links.push(id);
+                            }
+                        }
+                        let items = links.into_iter().map(|id| {
+                            let mut item = match field.payload() {
+                                Some(st) => values.document(&st.fields),
+                                None => json!({}),
+                            };
+                            item.as_object_mut().unwrap().insert("id".to_string(), json!(id));
+                            item
+                        }).collect();
+                        overrides.insert(field.name.clone(), Value::Array(items));
+                    }
+                    _ => {}
+                }
+            }
+
+            match fixtures.insert(model, Value::Object(overrides)) {
+                Ok(id) => {
+                    ids.entry(model_index).or_default().push(id);
+                    inserted += 1;
+                }
+                // Значение ключа уже занято документом, созданным как цель обязательной связи
+                Err(FixtureError::Insert(MarciError::Insert(InsertError::DuplicateKey(_)))) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(inserted)
+}
@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::journal::JournalOp;
+
+/// Кэш распакованных документов `(дерево, id)` с вытеснением давно не читанных (`--doc-cache 64M`).
+/// Документы кэша верны для состояния базы на номере журнала `seq`: читать и пополнять кэш могут только
+/// транзакции, открытые на этом номере. Коммит удаляет измененные им документы и сдвигает `seq`
+pub struct DocCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Номер журнала, изменения до которого уже удалены из кэша
+    seq: u64,
+    size: usize,
+    tick: u64,
+    trees: HashMap<Vec<u8>, HashMap<u64, Entry>>,
+    /// Время последнего чтения -> документ, первым вытесняется самый старый
+    lru: BTreeMap<u64, (Vec<u8>, u64)>,
+}
+
+struct Entry {
+    data: Arc<[u8]>,
+    tick: u64,
+}
+
+impl DocCache {
+    /// `capacity` - размер документов в байтах, `seq` - номер журнала, на котором открыта база
+    pub fn new(capacity: usize, seq: u64) -> DocCache {
+        let inner = Inner { seq, size: 0, tick: 0, trees: HashMap::new(), lru: BTreeMap::new() };
+        DocCache { capacity, inner: Mutex::new(inner) }
+    }
+
+    /// Документ из кэша для транзакции, открытой на номере журнала `seq`
+    pub fn get(&self, seq: u64, tree: &[u8], id: u64) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.seq != seq {
+            return None;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let entry = inner.trees.get_mut(tree)?.get_mut(&id)?;
+        let (data, last) = (entry.data.clone(), std::mem::replace(&mut entry.tick, tick));
+        let key = inner.lru.remove(&last)?;
+        inner.lru.insert(tick, key);
+        Some(data)
+    }
+
+    /// Запоминает документ, прочитанный транзакцией на номере `seq`. Документ из более старого снимка
+    /// мог измениться после него и не запоминается
+    pub fn insert(&self, seq: u64, tree: &[u8], id: u64, data: Arc<[u8]>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.seq != seq {
+            return;
+        }
+        inner.remove(tree, id);
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.size += data.len();
+        inner.trees.entry(tree.to_vec()).or_default().insert(id, Entry { data, tick });
+        inner.lru.insert(tick, (tree.to_vec(), id));
+
+        while inner.size > self.capacity {
+            let Some((_, (tree, id))) = inner.lru.pop_first() else { break };
+            inner.remove(&tree, id);
+        }
+    }
+
+    /// Удаляет документы, измененные закоммиченной записью журнала `seq`. Если записи пришли не по порядку
+    /// (следующая транзакция успела закоммититься раньше), кэш очищается целиком
+    pub fn committed(&self, seq: u64, ops: &[JournalOp]) {
+        let mut inner = self.inner.lock().unwrap();
+        if seq != inner.seq + 1 {
+            inner.clear();
+            inner.seq = inner.seq.max(seq);
+            return;
+        }
+        for op in ops {
+            match op {
+                JournalOp::Put { tree, key, .. } | JournalOp::Delete { tree, key } => {
+                    if let Ok(id) = key.as_slice().try_into().map(u64::from_be_bytes) {
+                        inner.remove(tree, id);
+                    }
+                }
+                JournalOp::DeleteRange { tree, start, end } => {
+                    let ids: Vec<u64> = inner.trees.get(tree).into_iter()
+                        .flat_map(|docs| docs.keys().copied())
+                        .filter(|id| start.as_slice() <= id.to_be_bytes().as_slice() && id.to_be_bytes().as_slice() < end.as_slice())
+                        .collect();
+                    for id in ids {
+                        inner.remove(tree, id);
+                    }
+                }
+            }
+        }
+        inner.seq = seq;
+    }
+
+    /// Очищает кэш после замены содержимого базы (снимок реплики, восстановление из бэкапа)
+    pub fn reset(&self, seq: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clear();
+        inner.seq = seq;
+    }
+
+    /// Размер документов в кэше, байт
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, tree: &[u8], id: u64) {
+        let Some(entry) = self.trees.get_mut(tree).and_then(|docs| docs.remove(&id)) else {
+            return;
+        };
+        self.size -= entry.data.len();
+        self.lru.remove(&entry.tick);
+    }
+
+    fn clear(&mut self) {
+        self.trees.clear();
+        self.lru.clear();
+        self.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doc_cache() {
+        let cache = DocCache::new(8, 1);
+        cache.insert(1, b"User", 1, Arc::from(&b"abcd"[..]));
+        cache.insert(1, b"User", 2, Arc::from(&b"efgh"[..]));
+        // Старый снимок не читает кэш
+        assert!(cache.get(0, b"User", 1).is_none());
+        assert_eq!(cache.get(1, b"User", 1).as_deref(), Some(&b"abcd"[..]));
+
+        // Документ 2 читали давно, он вытесняется первым
+        cache.insert(1, b"User", 3, Arc::from(&b"ijkl"[..]));
+        assert!(cache.get(1, b"User", 2).is_none());
+        assert_eq!(cache.size(), 8);
+
+        cache.committed(2, &[JournalOp::Put { tree: b"User".to_vec(), key: 1u64.to_be_bytes().to_vec(), value: vec![] }]);
+        assert!(cache.get(2, b"User", 1).is_none());
+        assert!(cache.get(2, b"User", 3).is_some());
+        assert!(cache.get(1, b"User", 3).is_none());
+    }
+}
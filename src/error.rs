@@ -0,0 +1,215 @@
+use std::fmt;
+
+use serde_json::{Map, Value, json};
+
+use crate::marci_db::{DeleteError, InsertError};
+use crate::marci_decoder::DecodeError;
+use crate::marci_encoder::EncodeError;
+
+/// Ошибка запроса к базе. Сбой хранилища или поврежденные данные больше не роняют задачу соединения,
+/// а возвращаются клиенту с кодом из `status`
+#[derive(Debug)]
+pub enum MarciError {
+    /// Чтение, запись или коммит транзакции в хранилище
+    Storage(canopydb::Error),
+    /// Дерева модели, структуры или индекса нет в базе
+    MissingTree(String),
+    /// Ключ дерева не разбирается в id документа
+    CorruptedKey(Vec<u8>),
+    Decode(DecodeError),
+    Insert(InsertError),
+    Delete(DeleteError),
+}
+
+impl MarciError {
+    /// HTTP-код ответа: ошибки запроса - 4xx, состояние базы - 500. Тело ответа - `ErrorBody`
+    pub fn status(&self) -> u16 {
+        match self {
+            MarciError::Storage(_) | MarciError::MissingTree(_) | MarciError::CorruptedKey(_) => 500,
+            MarciError::Decode(DecodeError::IncludeLimit { .. }) => 422,
+            MarciError::Decode(_) => 500,
+            MarciError::Insert(InsertError::CorruptedData(_)) => 500,
+            MarciError::Insert(_) => 400,
+            MarciError::Delete(DeleteError::Restricted { .. }) => 409,
+            MarciError::Delete(DeleteError::ItemNotFound(_)) => 400,
+            MarciError::Delete(DeleteError::CorruptedData(_)) => 500,
+        }
+    }
+}
+
+impl fmt::Display for MarciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", ErrorBody::from(self).message)
+    }
+}
+
+impl std::error::Error for MarciError {}
+
+impl From<canopydb::Error> for MarciError {
+    fn from(err: canopydb::Error) -> MarciError {
+        MarciError::Storage(err)
+    }
+}
+
+impl From<DecodeError> for MarciError {
+    fn from(err: DecodeError) -> MarciError {
+        MarciError::Decode(err)
+    }
+}
+
+impl From<InsertError> for MarciError {
+    fn from(err: InsertError) -> MarciError {
+        MarciError::Insert(err)
+    }
+}
+
+impl From<DeleteError> for MarciError {
+    fn from(err: DeleteError) -> MarciError {
+        MarciError::Delete(err)
+    }
+}
+
+/// Тело ответа с ошибкой `{ code, message, field?, details? }`: `code` - постоянный идентификатор ошибки
+/// для клиентов, `message` - текст для человека, `field` - поле документа, к которому относится ошибка
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+    pub field: Option<String>,
+    pub details: Option<Value>,
+}
+
+impl ErrorBody {
+    pub fn new(code: &'static str, message: impl Into<String>) -> ErrorBody {
+        ErrorBody { code, message: message.into(), field: None, details: None }
+    }
+
+    pub fn field(mut self, field: impl Into<String>) -> ErrorBody {
+        self.field = Some(field.into());
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> ErrorBody {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut obj = Map::new();
+        obj.insert("code".to_string(), self.code.into());
+        obj.insert("message".to_string(), self.message.clone().into());
+        if let Some(field) = &self.field {
+            obj.insert("field".to_string(), field.clone().into());
+        }
+        if let Some(details) = &self.details {
+            obj.insert("details".to_string(), details.clone());
+        }
+        Value::Object(obj)
+    }
+}
+
+impl From<&MarciError> for ErrorBody {
+    fn from(err: &MarciError) -> ErrorBody {
+        match err {
+            MarciError::Storage(err) => ErrorBody::new("storage_error", format!("Storage error: {:?}", err)),
+            MarciError::MissingTree(name) => ErrorBody::new("missing_tree", format!("Tree {} is missing", name))
+                .details(json!({ "tree": name })),
+            MarciError::CorruptedKey(key) => ErrorBody::new("corrupted_key", format!("Corrupted key {:02x?}", key)),
+            MarciError::Decode(err) => err.into(),
+            MarciError::Insert(err) => err.into(),
+            MarciError::Delete(err) => err.into(),
+        }
+    }
+}
+
+impl From<&EncodeError> for ErrorBody {
+    fn from(err: &EncodeError) -> ErrorBody {
+        match err {
+            EncodeError::NotAnObject => ErrorBody::new("not_an_object", "Document must be a JSON object"),
+            EncodeError::MissingField(field) => ErrorBody::new("missing_field", format!("Field {} is required", field))
+                .field(field),
+            EncodeError::TypeMismatch { field, expected } => ErrorBody::new("type_mismatch", format!("Field {} must be {}", field, expected))
+                .field(field)
+                .details(json!({ "expected": expected })),
+            EncodeError::OffsetOverflow => ErrorBody::new("document_too_large", "Document is too large"),
+            EncodeError::EmptyObject => ErrorBody::new("empty_object", "Document has no fields"),
+            EncodeError::TtlNotSupported => ErrorBody::new("ttl_not_supported", "Model has no @@ttl, $ttl is not allowed"),
+            EncodeError::Constraint { field, message } => ErrorBody::new("constraint_violation", format!("Field {} {}", field, message))
+                .field(field),
+        }
+    }
+}
+
+impl From<&InsertError> for ErrorBody {
+    fn from(err: &InsertError) -> ErrorBody {
+        match err {
+            InsertError::ForeignKeyViolation(field, id) => ErrorBody::new("foreign_key_violation", format!("Field {} references missing document {}", field, id))
+                .field(field)
+                .details(json!({ "id": id })),
+            InsertError::ItemNotFound(id) => ErrorBody::new("not_found", "Object not found")
+                .details(json!({ "id": id })),
+            InsertError::CorruptedData(id) => ErrorBody::new("corrupted_document", format!("Stored document {} is damaged", id))
+                .details(json!({ "id": id })),
+            InsertError::ListOperatorOnInsert(field) => ErrorBody::new("list_operator_on_insert", format!("push/remove on {} are not allowed in insert", field))
+                .field(field),
+            InsertError::DuplicateKey(field) => ErrorBody::new("duplicate_key", format!("Value of {} is already taken", field))
+                .field(field),
+            InsertError::KeyNotFound { field, key } => ErrorBody::new("key_not_found", format!("Field {} references missing key {}", field, key))
+                .field(field)
+                .details(json!({ "key": key })),
+            InsertError::IdRequired => ErrorBody::new("id_required", "Model with @@id(external) requires id")
+                .field("id"),
+            InsertError::DuplicateId(id) => ErrorBody::new("duplicate_id", format!("Document {} already exists", id))
+                .field("id")
+                .details(json!({ "id": id })),
+            InsertError::ReservedId => ErrorBody::new("reserved_id", format!("Id {} is reserved", u64::MAX))
+                .field("id"),
+        }
+    }
+}
+
+impl From<&DecodeError> for ErrorBody {
+    fn from(err: &DecodeError) -> ErrorBody {
+        match err {
+            DecodeError::IncludeLimit { field, limit } => ErrorBody::new("include_limit", format!("Include {} fetched more than {} related rows", field, limit))
+                .field(field)
+                .details(json!({ "limit": limit })),
+            err => ErrorBody::new("corrupted_document", format!("Failed to decode document: {:?}", err)),
+        }
+    }
+}
+
+impl From<&DeleteError> for ErrorBody {
+    fn from(err: &DeleteError) -> ErrorBody {
+        match err {
+            DeleteError::ItemNotFound(id) => ErrorBody::new("not_found", "Object not found")
+                .details(json!({ "id": id })),
+            DeleteError::Restricted { model, field, id } => ErrorBody::new("restricted", format!("Object is referenced by {} {} ({})", model, id, field))
+                .field(field)
+                .details(json!({ "model": model, "id": id })),
+            DeleteError::CorruptedData(id) => ErrorBody::new("corrupted_document", format!("Stored document {} is damaged", id))
+                .details(json!({ "id": id })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_body() {
+        let err = EncodeError::TypeMismatch { field: "age".to_string(), expected: "Int" };
+        assert_eq!(ErrorBody::from(&err).to_json(), json!({
+            "code": "type_mismatch",
+            "message": "Field age must be Int",
+            "field": "age",
+            "details": { "expected": "Int" },
+        }));
+
+        let err = MarciError::Delete(DeleteError::ItemNotFound(3));
+        assert_eq!(err.status(), 400);
+        assert_eq!(err.to_string(), "Object not found");
+        assert!(ErrorBody::from(&err).field.is_none());
+    }
+}
@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use canopydb::Transaction;
+use chrono::Utc;
+use serde_json::{Map, Value, json};
+
+use crate::error::MarciError;
+use crate::journal::journal_seq;
+use crate::marci_db::{ImportDocument, MarciDB, model_trees};
+use crate::marci_encoder::encode_document;
+use crate::marci_decoder::{DecodeError, decode_field, unpack};
+use crate::schema::{Field, FieldType, InsertedIndex, Model};
+
+/// Логическая выгрузка всей базы в JSON: документы всех моделей со связями и описание индексов.
+/// Все читается в одной транзакции, поэтому ссылки между моделями согласованы
+pub fn export_database(db: &MarciDB) -> Result<Value, MarciError> {
+    let rx = db.db.begin_read()?;
+
+    let mut models = Map::new();
+    let mut indexes = BTreeMap::new();
+    // Модели, выгрузка которых обрезана по `@@maxRows`
+    let mut capped = vec![];
+    for model in db.schema.models.iter() {
+        let mut items = vec![];
+        let is_capped = export_model(&rx, model, |obj| -> Result<(), MarciError> {
+            items.push(Value::Object(obj));
+            Ok(())
+        })?;
+        if is_capped {
+            capped.push(model.name.clone());
+        }
+        models.insert(model.name.clone(), Value::Array(items));
+
+        for field in model.fields.iter() {
+            for index in field.inserted_indexes.iter() {
+                let (tree_name, kind) = match index {
+                    InsertedIndex::Direct { tree_name } => (tree_name, "direct"),
+                    InsertedIndex::Rev { tree_name } if matches!(field.ty, FieldType::Primitive(_)) => (tree_name, "value"),
+                    InsertedIndex::Rev { tree_name } => (tree_name, "reverse"),
+                };
+                let entries = rx.get_tree(tree_name.as_bytes())?.map(|tree| tree.len()).unwrap_or(0);
+                indexes.insert(tree_name.clone(), json!({
+                    "model": model.name,
+                    "field": field.name,
+                    "kind": kind,
+                    "entries": entries,
+                }));
+            }
+        }
+        for index in model.indexes.iter() {
+            let entries = rx.get_tree(index.tree_name.as_bytes())?.map(|tree| tree.len()).unwrap_or(0);
+            let fields: Vec<&str> = index.fields.iter().map(|i| model.fields[*i].name.as_str()).collect();
+            indexes.insert(index.tree_name.clone(), json!({
+                "model": model.name,
+                "fields": fields,
+                "kind": "compound",
+                "entries": entries,
+            }));
+        }
+    }
+
+    Ok(json!({
+        "sequence": journal_seq(&rx)?,
+        "exportedAt": Utc::now().to_rfc3339(),
+        "models": models,
+        "indexes": indexes,
+        "capped": capped,
+    }))
+}
+
+/// Статистика для мониторинга роста базы: по каждой модели количество документов и их размер, следующий id,
+/// записи и байты каждого ее дерева (индексы, структуры, время жизни), плюс размер файлов каталога данных.
+/// Деревья читаются целиком в одной транзакции
+pub fn database_stats(db: &MarciDB) -> Result<Value, MarciError> {
+    let rx = db.db.begin_read()?;
+
+    let mut models = Map::new();
+    for model in db.schema.models.iter() {
+        let mut trees = Map::new();
+        let mut counters = Map::new();
+        counters.insert(model.name.clone(), db.peek_id(model.counter_idx).into());
+        for field in model.struct_fields() {
+            if let FieldType::StructList(st, counter_idx) = &field.ty {
+                counters.insert(st.name.clone(), db.peek_id(*counter_idx).into());
+            }
+        }
+        for tree_name in model_trees(model) {
+            let Some(tree) = rx.get_tree(tree_name)? else { continue };
+            let mut entries = 0u64;
+            let mut bytes = 0u64;
+            for item in tree.iter()? {
+                let (key, value) = item?;
+                entries += 1;
+                bytes += (key.len() + value.len()) as u64;
+            }
+            trees.insert(String::from_utf8_lossy(tree_name).into_owned(), json!({ "entries": entries, "bytes": bytes }));
+        }
+
+        let documents = trees.get(&model.name).cloned().unwrap_or_else(|| json!({ "entries": 0, "bytes": 0 }));
+        models.insert(model.name.clone(), json!({
+            "documents": documents["entries"],
+            "bytes": documents["bytes"],
+            "counters": counters,
+            "trees": trees,
+        }));
+    }
+
+    Ok(json!({
+        "sequence": journal_seq(&rx)?,
+        "diskSize": db.disk_size(),
+        "models": models,
+    }))
+}
+
+/// Документы модели по возрастанию id, не больше `@@maxRows`. true - выгрузка обрезана
+fn export_model<E, F>(rx: &Transaction, model: &Model, mut f: F) -> Result<bool, E>
+where
+    E: From<DecodeError>,
+    F: FnMut(Map<String, Value>) -> Result<(), E>,
+{
+    let tree = rx.get_tree(model.name.as_bytes()).unwrap().unwrap();
+    let ttl_tree = model.ttl.as_ref().map(|ttl| rx.get_tree(ttl.tree_name.as_bytes()).unwrap().unwrap());
+
+    for (count, item) in tree.iter().unwrap().enumerate() {
+        if model.max_rows().is_some_and(|max_rows| count >= max_rows) {
+            return Ok(true);
+        }
+        let (key, data) = item.unwrap();
+        let id = u64::from_be_bytes(key.as_ref().try_into().unwrap());
+        let mut obj = export_document(rx, &model.fields, model.payload_offset, id, &unpack(data))?;
+        if let Some(expires_at) = ttl_tree.as_ref().and_then(|tree| tree.get(&key).unwrap()) {
+            let expires_at = u64::from_be_bytes(expires_at.as_ref().try_into().unwrap());
+            obj.insert("$expiresAt".to_string(), expires_at.into());
+        }
+        f(obj)?;
+    }
+    Ok(false)
+}
+
+/// Ошибка выгрузки в каталог или загрузки из него
+#[derive(Debug)]
+pub enum DumpError {
+    Io(io::Error),
+    Decode(DecodeError),
+    /// Строка файла выгрузки не разбирается как JSON: файл и номер строки
+    InvalidLine(String, usize),
+    /// Документы, которые не перекодируются под текущую схему
+    Import(Vec<String>),
+}
+
+impl std::fmt::Display for DumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DumpError::Io(err) => write!(f, "{}", err),
+            DumpError::Decode(err) => write!(f, "Failed to decode document: {:?}", err),
+            DumpError::InvalidLine(file, line) => write!(f, "{}:{} is not valid JSON", file, line),
+            DumpError::Import(errors) => write!(f, "{} documents do not match the schema", errors.len()),
+        }
+    }
+}
+
+impl From<io::Error> for DumpError {
+    fn from(err: io::Error) -> Self {
+        DumpError::Io(err)
+    }
+}
+
+impl From<DecodeError> for DumpError {
+    fn from(err: DecodeError) -> Self {
+        DumpError::Decode(err)
+    }
+}
+
+/// Расширение файлов выгрузки `marci-db dump`
+const DUMP_EXTENSION: &str = ".ndjson";
+
+/// Выгрузка в каталог: файл `<Model>.ndjson` на модель, документ на строку в формате `/$export`.
+/// Все модели читаются в одной транзакции, но документы не собираются в памяти. Возвращает число документов
+pub fn dump_database(db: &MarciDB, dir: &Path) -> Result<usize, DumpError> {
+    fs::create_dir_all(dir)?;
+    let rx = db.db.begin_read().unwrap();
+
+    let mut count = 0;
+    for model in db.schema.models.iter() {
+        let mut file = BufWriter::new(File::create(dir.join(format!("{}{}", model.name, DUMP_EXTENSION)))?);
+        export_model(&rx, model, |obj| -> Result<(), DumpError> {
+            serde_json::to_writer(&mut file, &obj).map_err(io::Error::from)?;
+            file.write_all(b"\n")?;
+            count += 1;
+            Ok(())
+        })?;
+        file.flush()?;
+    }
+    Ok(count)
+}
+
+/// Загрузка каталога `marci-db dump` с заменой содержимого базы, как `/$import`.
+/// Имя модели берется из имени файла, так что выгрузку старой схемы можно загрузить с `mapping`
+pub fn load_database(db: &MarciDB, dir: &Path, mapping: &Value) -> Result<ImportReport, DumpError> {
+    let mut models = Map::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let Some(name) = file_name.strip_suffix(DUMP_EXTENSION) else { continue };
+
+        let mut items = vec![];
+        for (idx, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let item = serde_json::from_str(&line).map_err(|_| DumpError::InvalidLine(file_name.to_string(), idx + 1))?;
+            items.push(item);
+        }
+        models.insert(name.to_string(), Value::Array(items));
+    }
+
+    let export = json!({ "models": models });
+    import_database(db, &export, mapping).map_err(DumpError::Import)
+}
+
+/// Поля документа: значения, id связанных записей и вложенные структуры
+fn export_document(rx: &Transaction, fields: &[Field], payload_offset: usize, id: u64, data: &[u8]) -> Result<Map<String, Value>, DecodeError> {
+    let key = id.to_be_bytes();
+    let mut obj = Map::new();
+    obj.insert("id".to_string(), id.into());
+
+    for field in fields {
+        let value = match &field.ty {
+            FieldType::Primitive(_) | FieldType::PrimitiveList(_) | FieldType::ModelRef(_) => decode_field(field, data, payload_offset)?,
+            FieldType::ModelRefList(_) => {
+                let Some(tree_name) = &field.select_index else { continue };
+                let tree = rx.get_tree(tree_name.as_bytes()).unwrap().unwrap();
+                let Some(st) = field.payload() else {
+                    let ids: Vec<Value> = tree.prefix_keys(&key).unwrap()
+                        .map(|k| u64::from_be_bytes(k.unwrap()[8..].try_into().unwrap()).into())
+                        .collect();
+                    obj.insert(field.name.clone(), Value::Array(ids));
+                    continue;
+                };
+                // Связи с данными (`@payload`) выгружаются объектами `{ id, ...данные }`
+                let mut items = vec![];
+                for item in tree.prefix(&key).unwrap() {
+                    let (k, value) = item.unwrap();
+                    let item_id = u64::from_be_bytes(k[8..].try_into().unwrap());
+                    let mut item_obj = match value.len() >= 3 {
+                        true => export_document(rx, &st.fields, st.payload_offset, item_id, value.as_ref())?,
+                        false => Map::new(),
+                    };
+                    item_obj.insert("id".to_string(), item_id.into());
+                    items.push(Value::Object(item_obj));
+                }
+                Value::Array(items)
+            }
+            FieldType::Struct(st) => {
+                let tree = rx.get_tree(st.name.as_bytes()).unwrap().unwrap();
+                match tree.get(&key).unwrap() {
+                    Some(st_data) => {
+                        let mut st_obj = export_document(rx, &st.fields, st.payload_offset, id, st_data.as_ref())?;
+                        st_obj.remove("id");
+                        Value::Object(st_obj)
+                    }
+                    None => Value::Null
+                }
+            }
+            FieldType::StructList(st, _) => {
+                let tree = rx.get_tree(st.name.as_bytes()).unwrap().unwrap();
+                let mut items = vec![];
+                for item in tree.prefix(&key).unwrap() {
+                    let (st_key, st_data) = item.unwrap();
+                    let st_id = u64::from_be_bytes(st_key[8..].try_into().unwrap());
+                    items.push(Value::Object(export_document(rx, &st.fields, st.payload_offset, st_id, st_data.as_ref())?));
+                }
+                Value::Array(items)
+            }
+            _ => continue
+        };
+        obj.insert(field.name.clone(), value);
+    }
+    Ok(obj)
+}
+
+/// Сколько ошибок перекодирования возвращать при неудачном импорте
+const MAX_IMPORT_ERRORS: usize = 100;
+
+/// Итог импорта: количество документов и пропущенные модели и поля, которых нет в текущей схеме
+pub struct ImportReport {
+    pub imported: usize,
+    pub skipped: Vec<String>,
+}
+
+/// Заменяет содержимое базы выгрузкой `/$export`, перекодируя документы под текущую схему.
+/// `mapping` по имени модели (или структуры, `User.info`) задает переименования и значения по умолчанию:
+/// `{ "User": { "model": "Account", "fields": { "fullName": "name", "legacy": null }, "defaults": { "role": "member" } } }`.
+/// Ошибки собираются по всем документам; если они есть, база не меняется
+pub fn import_database(db: &MarciDB, export: &Value, mapping: &Value) -> Result<ImportReport, Vec<String>> {
+    let models = export.get("models").and_then(|models| models.as_object())
+        .ok_or_else(|| vec!["Export must contain a \"models\" object".to_string()])?;
+
+    let mut documents = vec![];
+    let mut errors = vec![];
+    let mut skipped = BTreeSet::new();
+    for (name, items) in models {
+        let model_mapping = mapping.get(name);
+        let target = model_mapping.and_then(|m| m.get("model")).and_then(|m| m.as_str()).unwrap_or(name);
+        let Some(model) = db.get_model(target) else {
+            skipped.insert(name.clone());
+            continue;
+        };
+
+        for item in items.as_array().map(|items| items.as_slice()).unwrap_or_default() {
+            let Some(id) = item.get("id").and_then(|id| id.as_u64()) else {
+                errors.push(format!("{}: document without id", name));
+                continue;
+            };
+            let expires_at = item.get("$expiresAt").and_then(|value| value.as_u64());
+            let json = Value::Object(convert_document(&model.fields, &model.name, item, model_mapping, mapping, &mut skipped));
+
+            let mut structs = vec![];
+            match encode_document(model, &json, &mut structs) {
+                Ok((data, _)) => documents.push(ImportDocument { model, id, data, structs, expires_at }),
+                Err(err) => errors.push(format!("{} {}: {:?}", model.name, id, err)),
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.truncate(MAX_IMPORT_ERRORS);
+        return Err(errors);
+    }
+
+    db.import(&documents).map_err(|err| vec![err.to_string()])?;
+    Ok(ImportReport { imported: documents.len(), skipped: skipped.into_iter().collect() })
+}
+
+/// Документ выгрузки в формате вставки: переименованные поля, `{ id }` для связей, значения по умолчанию
+fn convert_document(fields: &[Field], name: &str, doc: &Value, doc_mapping: Option<&Value>, mapping: &Value, skipped: &mut BTreeSet<String>) -> Map<String, Value> {
+    let renames = doc_mapping.and_then(|m| m.get("fields"));
+    let mut obj = Map::new();
+
+    for (key, value) in doc.as_object().into_iter().flatten() {
+        if key == "id" || key == "$expiresAt" {
+            continue;
+        }
+        let target = match renames.and_then(|renames| renames.get(key)) {
+            Some(Value::String(target)) => target.as_str(),
+            Some(Value::Null) => continue,
+            _ => key.as_str()
+        };
+        let Some(field) = fields.iter().find(|field| field.name == target) else {
+            skipped.insert(format!("{}.{}", name, key));
+            continue;
+        };
+
+        let value = match (&field.ty, value) {
+            (_, Value::Null) => Value::Null,
+            (FieldType::ModelRef(_), id) => json!({ "id": id }),
+            // Производные списки восстанавливаются по связи с другой стороны
+            (FieldType::ModelRefList(_), _) if field.derived_from.is_some() => continue,
+            (FieldType::ModelRefList(_), Value::Array(ids)) => ids.iter().map(|id| match id {
+                Value::Object(_) => id.clone(),
+                _ => json!({ "id": id }),
+            }).collect(),
+            (FieldType::Struct(st), _) => {
+                Value::Object(convert_document(&st.fields, &st.name, value, mapping.get(&st.name), mapping, skipped))
+            }
+            (FieldType::StructList(st, _), Value::Array(items)) => items.iter().map(|item| {
+                let mut converted = convert_document(&st.fields, &st.name, item, mapping.get(&st.name), mapping, skipped);
+                if let Some(id) = item.get("id") {
+                    converted.insert("id".to_string(), id.clone());
+                }
+                Value::Object(converted)
+            }).collect(),
+            _ => value.clone()
+        };
+        obj.insert(field.name.clone(), value);
+    }
+
+    // Значения по умолчанию для полей, которых не было в выгрузке
+    let defaults = doc_mapping.and_then(|m| m.get("defaults")).and_then(|d| d.as_object());
+    for (key, value) in defaults.into_iter().flatten() {
+        if obj.get(key).is_none_or(|current| current.is_null()) {
+            obj.insert(key.clone(), value.clone());
+        }
+    }
+    obj
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::{Request, Response};
+use serde_json::Value;
+
+use crate::marci_db::MarciDB;
+use crate::schema::{Field, FieldType, Model, ScalarType, Schema};
+
+/// Расширение сервера: авторизация, нестандартные форматы и прочее, что не должно попадать в ядро.
+/// Регистрируется при запуске (см. `extensions()` в main.rs), все методы необязательные
+pub trait Extension: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Вызывается для каждого запроса до маршрутизации. Возвращенный ответ прерывает обработку
+    fn before_request(&self, _req: &Request<Incoming>) -> Option<Response<Full<Bytes>>> {
+        None
+    }
+
+    /// Действие `/<Model>/x-<name>`. `None` - действие обрабатывается не этим расширением
+    fn action(&self, _ctx: &ActionContext) -> Option<Result<Value, String>> {
+        None
+    }
+
+    /// Пользовательские скалярные типы, на которые может ссылаться схема
+    fn scalar_types(&self) -> Vec<ScalarType> {
+        vec![]
+    }
+
+    /// Кодек для поля модели или структуры (`owner` - имя модели или структуры, например `User.info`)
+    fn field_codec(&self, _owner: &str, _field: &Field) -> Option<Arc<dyn FieldCodec>> {
+        None
+    }
+}
+
+/// Преобразование значения поля между видом в API и видом, который понимает кодировщик поля.
+/// `null` через кодек не проходит
+pub trait FieldCodec: Send + Sync {
+    fn encode(&self, value: &Value) -> Result<Value, String>;
+    fn decode(&self, value: Value) -> Value;
+}
+
+/// Поля читают реализации `Extension::action`, сам сервер их только заполняет
+#[allow(dead_code)]
+pub struct ActionContext<'a> {
+    pub db: &'a MarciDB,
+    pub model: &'a Model,
+    /// Имя действия без префикса `x-`
+    pub name: &'a str,
+    pub body: &'a Value,
+}
+
+/// Скалярные типы всех расширений. Схема ссылается на них до конца работы процесса, поэтому они не освобождаются
+pub fn scalar_types(extensions: &[Box<dyn Extension>]) -> Vec<&'static ScalarType> {
+    extensions.iter()
+        .flat_map(|extension| extension.scalar_types())
+        .map(|scalar| &*Box::leak(Box::new(scalar)))
+        .collect()
+}
+
+pub struct Extensions {
+    extensions: Vec<Box<dyn Extension>>,
+    /// (модель или структура, поле) -> кодек
+    codecs: HashMap<(String, String), Arc<dyn FieldCodec>>,
+}
+
+impl Extensions {
+    pub fn new(extensions: Vec<Box<dyn Extension>>, schema: &Schema) -> Extensions {
+        let mut codecs = HashMap::new();
+        for model in schema.models.iter() {
+            collect_codecs(&extensions, &model.name, &model.fields, &mut codecs);
+        }
+        Extensions { extensions, codecs }
+    }
+
+    /// Есть ли поля с кодеками. Без них документы findMany не проходят через `Value`
+    pub fn has_codecs(&self) -> bool {
+        !self.codecs.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.extensions.iter().map(|extension| extension.name()).collect()
+    }
+
+    pub fn before_request(&self, req: &Request<Incoming>) -> Option<Response<Full<Bytes>>> {
+        self.extensions.iter().find_map(|extension| extension.before_request(req))
+    }
+
+    pub fn action(&self, ctx: &ActionContext) -> Option<Result<Value, String>> {
+        self.extensions.iter().find_map(|extension| extension.action(ctx))
+    }
+
+    /// Тело insert/update: значения полей с кодеками заменяются тем, что пойдет в encode_document
+    pub fn encode_fields(&self, owner: &str, fields: &[Field], json: &mut Value) -> Result<(), String> {
+        if self.codecs.is_empty() {
+            return Ok(());
+        }
+        let Some(obj) = json.as_object_mut() else { return Ok(()) };
+        for field in fields {
+            let Some(value) = obj.get_mut(&field.name) else { continue };
+            if value.is_null() {
+                continue;
+            }
+            if let Some(codec) = self.codecs.get(&(owner.to_string(), field.name.clone())) {
+                *value = codec.encode(value).map_err(|err| format!("{}: {}", field.name, err))?;
+                continue;
+            }
+            match &field.ty {
+                FieldType::Struct(st) => self.encode_fields(&st.name, &st.fields, value)?,
+                FieldType::StructList(st, _) => {
+                    for item in value.as_array_mut().into_iter().flatten() {
+                        self.encode_fields(&st.name, &st.fields, item)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Декодированный документ, включая выбранные связи и структуры
+    pub fn decode_fields(&self, schema: &Schema, owner: &str, fields: &[Field], json: &mut Value) {
+        if self.codecs.is_empty() {
+            return;
+        }
+        let Some(obj) = json.as_object_mut() else { return };
+        for field in fields {
+            let Some(value) = obj.get_mut(&field.name) else { continue };
+            if value.is_null() {
+                continue;
+            }
+            if let Some(codec) = self.codecs.get(&(owner.to_string(), field.name.clone())) {
+                *value = codec.decode(value.take());
+                continue;
+            }
+            match &field.ty {
+                FieldType::ModelRef(model_index) => {
+                    let model = &schema.models[*model_index];
+                    self.decode_fields(schema, &model.name, &model.fields, value);
+                }
+                FieldType::ModelRefList(model_index) => {
+                    let model = &schema.models[*model_index];
+                    for item in value.as_array_mut().into_iter().flatten() {
+                        self.decode_fields(schema, &model.name, &model.fields, item);
+                    }
+                }
+                FieldType::Struct(st) => self.decode_fields(schema, &st.name, &st.fields, value),
+                FieldType::StructList(st, _) => {
+                    for item in value.as_array_mut().into_iter().flatten() {
+                        self.decode_fields(schema, &st.name, &st.fields, item);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn collect_codecs(extensions: &[Box<dyn Extension>], owner: &str, fields: &[Field], codecs: &mut HashMap<(String, String), Arc<dyn FieldCodec>>) {
+    for field in fields {
+        if let Some(codec) = extensions.iter().find_map(|extension| extension.field_codec(owner, field)) {
+            codecs.insert((owner.to_string(), field.name.clone()), codec);
+        }
+        match &field.ty {
+            FieldType::Struct(st) | FieldType::StructList(st, _) => collect_codecs(extensions, &st.name, &st.fields, codecs),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::marci_decoder::decode_field;
+    use crate::marci_encoder::encode_document;
+    use crate::schema::{parse_schema, parse_schema_with};
+
+    /// Цена в API в рублях, хранится в копейках
+    struct Kopecks;
+
+    impl FieldCodec for Kopecks {
+        fn encode(&self, value: &Value) -> Result<Value, String> {
+            let rubles = value.as_f64().ok_or("expected number")?;
+            Ok(json!((rubles * 100.0).round() as i64))
+        }
+        fn decode(&self, value: Value) -> Value {
+            json!(value.as_i64().unwrap_or_default() as f64 / 100.0)
+        }
+    }
+
+    struct Prices;
+
+    impl Extension for Prices {
+        fn name(&self) -> &str {
+            "prices"
+        }
+        fn field_codec(&self, owner: &str, field: &Field) -> Option<Arc<dyn FieldCodec>> {
+            (owner == "Item.details" && field.name == "price").then(|| Arc::new(Kopecks) as Arc<dyn FieldCodec>)
+        }
+    }
+
+    #[test]
+    fn test_field_codec() {
+        let schema = parse_schema("
+            model Item {
+                name     String
+                details  Details
+            }
+
+            struct Details {
+                price    Int
+            }
+        ").unwrap();
+        let model = &schema.models[0];
+        let extensions = Extensions::new(vec![Box::new(Prices)], &schema);
+
+        let mut doc = json!({ "name": "Book", "details": { "price": 12.5 } });
+        extensions.encode_fields(&model.name, &model.fields, &mut doc).unwrap();
+        assert_eq!(doc, json!({ "name": "Book", "details": { "price": 1250 } }));
+
+        extensions.decode_fields(&schema, &model.name, &model.fields, &mut doc);
+        assert_eq!(doc, json!({ "name": "Book", "details": { "price": 12.5 } }));
+
+        let mut invalid = json!({ "details": { "price": "free" } });
+        assert!(extensions.encode_fields(&model.name, &model.fields, &mut invalid).is_err());
+    }
+
+    struct Network;
+
+    impl Extension for Network {
+        fn name(&self) -> &str {
+            "network"
+        }
+        fn scalar_types(&self) -> Vec<ScalarType> {
+            vec![ScalarType {
+                name: "IPAddr".to_string(),
+                width: Some(4),
+                encode: |value| {
+                    let addr: std::net::Ipv4Addr = value.as_str().and_then(|s| s.parse().ok()).ok_or("expected IPv4 address")?;
+                    Ok(addr.octets().to_vec())
+                },
+                decode: |bytes| {
+                    let octets: [u8; 4] = bytes.try_into().map_err(|_| "expected 4 bytes".to_string())?;
+                    Ok(json!(std::net::Ipv4Addr::from(octets).to_string()))
+                },
+            }]
+        }
+    }
+
+    #[test]
+    fn test_scalar_type() {
+        let extensions: Vec<Box<dyn Extension>> = vec![Box::new(Network)];
+        let schema = parse_schema_with("
+            model Host {
+                addr     IPAddr
+                aliases  IPAddr[]
+                name     String
+            }
+        ", &scalar_types(&extensions)).unwrap();
+        let model = &schema.models[0];
+
+        let (data, _) = encode_document(model, &json!({ "addr": "10.0.0.1", "aliases": ["10.0.0.2"], "name": "db" }), &mut vec![]).unwrap();
+        assert_eq!(data.len(), model.payload_offset + 4 + (4 + 4) + 2);
+        assert_eq!(decode_field(&model.fields[0], &data, model.payload_offset).unwrap(), json!("10.0.0.1"));
+        assert_eq!(decode_field(&model.fields[1], &data, model.payload_offset).unwrap(), json!(["10.0.0.2"]));
+
+        assert!(encode_document(model, &json!({ "addr": "localhost" }), &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use serde_json::{Map, Value, json};
+
+use crate::error::MarciError;
+use crate::marci_db::{MarciDB, now_millis};
+use crate::marci_encoder::{EncodeError, encode_document};
+use crate::schema::{Attribute, Field, FieldType, Model, PrimitiveFieldType};
+
+/// Глубина вложенности обязательных связей, после которой цепочка считается циклом
+const MAX_RELATION_DEPTH: usize = 16;
+
+#[derive(Debug)]
+pub enum FixtureError {
+    Encode(EncodeError),
+    Insert(MarciError),
+    /// Обязательные связи образуют цикл, создать цели по очереди нельзя
+    RelationCycle(String),
+}
+
+/// Случайные документы по схеме для интеграционных тестов.
+/// Значения генерируются по типам полей, переданные `overrides` заменяют сгенерированные,
+/// а обязательные связи (`Model` без `?`) при вставке получают заранее созданный документ-цель
+pub struct Fixtures<'a> {
+    db: &'a MarciDB,
+    values: RandomValues,
+}
+
+impl<'a> Fixtures<'a> {
+    pub fn new(db: &'a MarciDB) -> Fixtures<'a> {
+        Fixtures::seeded(db, now_millis())
+    }
+
+    /// Одинаковый seed дает одинаковые документы
+    pub fn seeded(db: &'a MarciDB, seed: u64) -> Fixtures<'a> {
+        Fixtures { db, values: RandomValues::new(seed) }
+    }
+
+    /// Документ со случайными значениями всех полей модели, кроме связей
+    pub fn document(&mut self, model: &Model) -> Value {
+        self.values.document(&model.fields)
+    }
+
+    /// Документ с `overrides` поверх случайных значений; обязательные связи, которых нет в `overrides`, создаются в базе
+    pub fn build(&mut self, model: &Model, overrides: Value) -> Result<Value, FixtureError> {
+        self.build_at(model, overrides, 0)
+    }
+
+    /// Вставляет документ из `build`, возвращает его id
+    pub fn insert(&mut self, model: &Model, overrides: Value) -> Result<u64, FixtureError> {
+        self.insert_at(model, overrides, 0)
+    }
+
+    fn build_at(&mut self, model: &Model, overrides: Value, depth: usize) -> Result<Value, FixtureError> {
+        let mut doc = self.document(model);
+        let obj = doc.as_object_mut().unwrap();
+        if let Value::Object(overrides) = overrides {
+            obj.extend(overrides);
+        }
+
+        for field in model.fields.iter() {
+            let FieldType::ModelRef(model_index) = field.ty else { continue };
+            if field.is_nullable || field.derived_from.is_some() || obj.contains_key(&field.name) {
+                continue;
+            }
+            if depth >= MAX_RELATION_DEPTH {
+                return Err(FixtureError::RelationCycle(format!("{}.{}", model.name, field.name)));
+            }
+            let target = &self.db.schema.models[model_index];
+            let id = self.insert_at(target, Value::Null, depth + 1)?;
+            obj.insert(field.name.clone(), json!({ "id": id }));
+        }
+        Ok(doc)
+    }
+
+    fn insert_at(&mut self, model: &Model, overrides: Value, depth: usize) -> Result<u64, FixtureError> {
+        let doc = self.build_at(model, overrides, depth)?;
+        let mut structs = vec![];
+        let (data, _) = encode_document(model, &doc, &mut structs).map_err(FixtureError::Encode)?;
+        self.db.insert_data(model, &data, &structs).map_err(FixtureError::Insert)
+    }
+}
+
+/// Подгоняет случайное значение под `@min`/`@max`/`@maxLength`. Под `@matches` значение не подобрать, поле пропускается
+fn fit_constraints(field: &Field, mut value: Value) -> Option<Value> {
+    for attr in field.attributes.iter() {
+        match attr {
+            Attribute::Min(min) if value.as_f64().is_some_and(|v| v < *min) => value = json!(min.ceil()),
+            Attribute::Max(max) if value.as_f64().is_some_and(|v| v > *max) => value = json!(max.floor()),
+            Attribute::MaxLength(len) => value = json!(value.as_str()?.chars().take(*len).collect::<String>()),
+            Attribute::Matches(_) => return None,
+            _ => {}
+        }
+    }
+    // Целые поля не принимают 1.0
+    if value.as_f64().is_some_and(|v| v.fract() == 0.0) && !value.is_i64() && !value.is_u64() {
+        value = json!(value.as_f64()? as i64);
+    }
+    Some(value)
+}
+
+/// Генератор значений по типам полей, без обращения к базе
+pub struct RandomValues {
+    state: u64,
+}
+
+impl RandomValues {
+    pub fn new(seed: u64) -> RandomValues {
+        RandomValues { state: seed | 1 }
+    }
+
+    /// Значения всех полей, кроме связей и производных списков
+    pub fn document(&mut self, fields: &[Field]) -> Value {
+        let mut obj = Map::new();
+        for field in fields {
+            if field.derived_from.is_some() {
+                continue;
+            }
+            let value = match &field.ty {
+                FieldType::Primitive(ty) => self.value(ty, &field.name).and_then(|value| fit_constraints(field, value)),
+                FieldType::PrimitiveList(ty) => {
+                    let len = self.next() % 4;
+                    (0..len).map(|_| self.value(ty, &field.name).and_then(|value| fit_constraints(field, value))).collect::<Option<Vec<_>>>().map(Value::Array)
+                }
+                FieldType::Struct(st) => Some(self.document(&st.fields)),
+                FieldType::StructList(st, _) => {
+                    let len = self.next() % 3;
+                    Some(Value::Array((0..len).map(|_| self.document(&st.fields)).collect()))
+                }
+                _ => None,
+            };
+            if let Some(value) = value {
+                obj.insert(field.name.clone(), value);
+            }
+        }
+        Value::Object(obj)
+    }
+
+    /// Случайное значение типа. Пользовательские скаляры не генерируются: их формат знает только расширение
+    pub fn value(&mut self, ty: &PrimitiveFieldType, name: &str) -> Option<Value> {
+        let n = self.next();
+        let value = match ty {
+            PrimitiveFieldType::String => json!(format!("{}-{:08x}", name, n as u32)),
+            PrimitiveFieldType::Int64 => json!((n % 2000) as i64 - 1000),
+            PrimitiveFieldType::UInt64 | PrimitiveFieldType::UInt32 | PrimitiveFieldType::UInt16 => json!(n % 1000),
+            PrimitiveFieldType::Int32 | PrimitiveFieldType::Int16 => json!((n % 2000) as i64 - 1000),
+            PrimitiveFieldType::Int8 => json!((n % 200) as i64 - 100),
+            PrimitiveFieldType::UInt8 => json!(n % 200),
+            PrimitiveFieldType::Float | PrimitiveFieldType::Double => json!((n % 100_000) as f64 / 100.0),
+            PrimitiveFieldType::Bool => json!(n.is_multiple_of(2)),
+            // В пределах последнего года
+            PrimitiveFieldType::DateTime => json!(now_millis() - n % (365 * 24 * 60 * 60 * 1000)),
+            PrimitiveFieldType::Enum(enum_type) => json!(enum_type.variants[n as usize % enum_type.variants.len()]),
+            PrimitiveFieldType::Json => json!({ "key": format!("{:04x}", n as u16), "count": n % 100 }),
+            PrimitiveFieldType::Custom(_) => return None,
+        };
+        Some(value)
+    }
+
+    /// Случайный индекс в `0..len`, `len` больше нуля
+    pub fn index(&mut self, len: usize) -> usize {
+        self.next() as usize % len
+    }
+
+    /// xorshift64*: тестовым данным не нужна криптографическая случайность
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::RandomValues;
+    use crate::marci_encoder::encode_document;
+    use crate::schema::parse_schema;
+
+    #[test]
+    fn test_random_documents_encode() {
+        let schema = parse_schema("
+enum Status {
+  Draft Published
+}
+
+model Post {
+  title       String
+  views       Int
+  rating      Double?
+  status      Status
+  tags        String[]
+  meta        PostMeta
+}
+
+struct PostMeta {
+  created     DateTime
+  pinned      Bool
+}
+").unwrap();
+        let model = &schema.models[0];
+        let mut values = RandomValues::new(42);
+        for _ in 0..20 {
+            let doc = values.document(&model.fields);
+            assert!(doc["title"].as_str().unwrap().starts_with("title-"));
+            assert!(encode_document(model, &doc, &mut vec![]).is_ok());
+        }
+        assert_eq!(RandomValues::new(7).document(&model.fields)["title"], RandomValues::new(7).document(&model.fields)["title"]);
+    }
+}
@@ -0,0 +1,156 @@
+use hyper::HeaderMap;
+use hyper::header::{ACCEPT, CONTENT_TYPE, HeaderValue};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::marci_decoder::{Doc, DocKey, JsonText, write_array};
+
+/// Формат ответа с документами по заголовку `Accept`. Без заголовка или с `*/*` - JSON, как раньше
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResponseFormat {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl ResponseFormat {
+    /// Первый поддерживаемый тип из `Accept`. q-факторы не учитываются, клиенты бинарных форматов
+    /// обычно присылают один тип
+    pub fn from_headers(headers: &HeaderMap) -> ResponseFormat {
+        let Some(accept) = headers.get(ACCEPT).and_then(|v| v.to_str().ok()) else {
+            return ResponseFormat::Json;
+        };
+        for media in accept.split(',').map(|media| media.split(';').next().unwrap_or("").trim()) {
+            match media.to_ascii_lowercase().as_str() {
+                "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => return ResponseFormat::MessagePack,
+                "application/cbor" => return ResponseFormat::Cbor,
+                "application/json" => return ResponseFormat::Json,
+                _ => {}
+            }
+        }
+        ResponseFormat::Json
+    }
+
+    pub fn content_type(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            ResponseFormat::Json => "application/json",
+            ResponseFormat::MessagePack => "application/msgpack",
+            ResponseFormat::Cbor => "application/cbor",
+        })
+    }
+
+    /// Объекты MessagePack - map с именами полей, как в JSON. Кроме `Value` принимает `Doc` из findMany
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Vec<u8> {
+        match self {
+            ResponseFormat::Json => serde_json::to_vec(value).expect("JSON value is always serializable"),
+            ResponseFormat::MessagePack => rmp_serde::to_vec_named(value).expect("JSON value is always serializable"),
+            ResponseFormat::Cbor => {
+                let mut out = vec![];
+                ciborium::into_writer(value, &mut out).expect("JSON value is always serializable");
+                out
+            }
+        }
+    }
+}
+
+/// Документы ответа findMany: `Doc` кодируется в любой формат, `JsonText` - только для JSON-ответа
+pub enum Rows {
+    Docs(Vec<Doc>),
+    Json(Vec<JsonText>),
+}
+
+impl Rows {
+    pub fn len(&self) -> usize {
+        match self {
+            Rows::Docs(docs) => docs.len(),
+            Rows::Json(docs) => docs.len(),
+        }
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        match self {
+            Rows::Docs(docs) => docs.truncate(len),
+            Rows::Json(docs) => docs.truncate(len),
+        }
+    }
+
+    /// Массив документов или `{ data, meta }` с `$meta`
+    pub fn encode(self, format: ResponseFormat, meta: Option<Value>) -> Vec<u8> {
+        match self {
+            Rows::Docs(docs) => match meta {
+                Some(meta) => format.encode(&Doc::Object(vec![(DocKey::Static("data"), Doc::Array(docs)), (DocKey::Static("meta"), Doc::Value(meta))])),
+                None => format.encode(&docs),
+            },
+            // Готовый JSON-текст документов копируется в ответ как есть
+            Rows::Json(docs) => {
+                let mut out = Vec::with_capacity(docs.iter().map(|doc| doc.0.len() + 1).sum::<usize>() + 2);
+                if meta.is_some() {
+                    out.extend_from_slice(b"{\"data\":");
+                }
+                write_array(&mut out, &docs);
+                if let Some(meta) = meta {
+                    out.extend_from_slice(b",\"meta\":");
+                    serde_json::to_writer(&mut out, &meta).expect("JSON value is always serializable");
+                    out.push(b'}');
+                }
+                out
+            }
+        }
+    }
+}
+
+/// Формат тела запроса по `Content-Type`. MessagePack разбирается в тот же `Value`, что и JSON,
+/// поэтому разбор аргументов и кодирование документа от формата не зависят
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestFormat {
+    Json,
+    MessagePack,
+}
+
+impl RequestFormat {
+    pub fn from_headers(headers: &HeaderMap) -> RequestFormat {
+        let media = headers.get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(';').next())
+            .map(|v| v.trim().to_ascii_lowercase());
+        match media.as_deref() {
+            Some("application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack") => RequestFormat::MessagePack,
+            _ => RequestFormat::Json,
+        }
+    }
+
+    /// Ошибка - текст ответа 400
+    pub fn parse(self, body: &[u8]) -> Result<Value, &'static str> {
+        match self {
+            RequestFormat::Json => serde_json::from_slice(body).map_err(|_| "Failed to parse JSON"),
+            RequestFormat::MessagePack => rmp_serde::from_slice(body).map_err(|_| "Failed to parse MessagePack"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_format() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(ResponseFormat::from_headers(&headers), ResponseFormat::Json);
+        headers.insert(ACCEPT, "application/cbor;q=0.9, application/json".parse().unwrap());
+        assert_eq!(ResponseFormat::from_headers(&headers), ResponseFormat::Cbor);
+
+        let value = serde_json::json!([{ "id": 1, "name": "a" }]);
+        let packed = ResponseFormat::MessagePack.encode(&value);
+        assert_eq!(rmp_serde::from_slice::<Value>(&packed).unwrap(), value);
+        let cbor = ResponseFormat::Cbor.encode(&value);
+        assert_eq!(ciborium::from_reader::<Value, _>(cbor.as_slice()).unwrap(), value);
+
+        let rows = Rows::Json(vec![JsonText(br#"{"id":1}"#.to_vec())]);
+        assert_eq!(rows.encode(ResponseFormat::Json, Some(serde_json::json!({ "capped": false }))), br#"{"data":[{"id":1}],"meta":{"capped":false}}"#);
+
+        headers.insert(CONTENT_TYPE, "application/msgpack".parse().unwrap());
+        let format = RequestFormat::from_headers(&headers);
+        assert_eq!(format.parse(&packed).unwrap(), value);
+        assert!(format.parse(b"\xc1").is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//! Точки входа для фаззинга (`RUSTFLAGS="--cfg fuzzing"`): произвольные байты подаются в декодер
+//! и в update_data на синтетической модели. Паника здесь означает ошибку в проверке границ
+use std::sync::OnceLock;
+
+use bitvec::vec::BitVec;
+use serde_json::Value;
+
+use crate::marci_db::DecodeCtx;
+use crate::marci_decoder::{check_offsets, decode_document};
+use crate::schema::{Schema, parse_schema};
+use crate::update_data::update_data;
+
+const FUZZ_SCHEMA: &str = "
+model Item {
+  name        String
+  count       Int
+  total       UInt
+  ratio       Float
+  price       Double
+  active      Bool
+  createdAt   DateTime
+  parent      Item?
+  note        String?
+}
+";
+
+fn schema() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(|| parse_schema(FUZZ_SCHEMA).unwrap())
+}
+
+/// Декодирует произвольный буфер как документ синтетической модели со всеми выбранными полями
+pub fn fuzz_decode_document(data: &[u8]) {
+    let model = &schema().models[0];
+    let select = BitVec::repeat(true, model.fields.len() + 1);
+    let ctx: DecodeCtx<Value> = DecodeCtx {
+        id: 1,
+        data,
+        fields: &model.fields,
+        payload_offset: model.payload_offset,
+        select: &select,
+        includes: vec![],
+        expires_at: None,
+        payload: None,
+    };
+    let _ = decode_document(ctx);
+}
+
+/// Первый байт - маска измененных полей, следующие два - длина старого документа, остаток делится
+/// на старый документ и новые данные. Результат успешного обновления должен оставаться корректным
+pub fn fuzz_update_data(input: &[u8]) {
+    if input.len() < 3 {
+        return;
+    }
+    let model = &schema().models[0];
+    let mask_bits = input[0];
+    let split = (u16::from_be_bytes([input[1], input[2]]) as usize).min(input.len() - 3);
+    let (data, new_data) = input[3..].split_at(split);
+
+    let mut changed_mask = BitVec::repeat(false, model.fields.len());
+    for i in 0..changed_mask.len().min(8) {
+        changed_mask.set(i, mask_bits & (1 << i) != 0);
+    }
+
+    if let Ok(updated) = update_data(&model.fields, model.payload_offset, data, new_data, &changed_mask, &[]) {
+        check_offsets(&updated, model.payload_offset).expect("update_data produced invalid offsets");
+    }
+}
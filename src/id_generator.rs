@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::marci_db::now_millis;
+use crate::schema::IdStrategy;
+
+/// Начало отсчета времени в snowflake id (2024-01-01 UTC, мс)
+const SNOWFLAKE_EPOCH: u64 = 1_704_067_200_000;
+/// Младшие биты snowflake id - порядковый номер внутри миллисекунды
+const SNOWFLAKE_SEQ_BITS: u32 = 22;
+
+/// Выдает id новым документам модели (`@@id(...)`) и элементам списков структур
+pub trait IdGenerator: Send + Sync {
+    /// Id нового документа. `supplied` - id из тела `insert`, генератор решает, принять ли его
+    fn next_id(&self, supplied: Option<u64>) -> Result<u64, IdError>;
+    /// Id, который получит следующий документ, без его выдачи
+    fn peek(&self) -> u64;
+    /// Продолжает выдачу после `get_max_id` дерева: при открытии базы, после импорта, восстановления и репликации
+    fn reset(&self, next: u64);
+}
+
+#[derive(Debug)]
+pub enum IdError {
+    /// `@@id(external)` без id в теле запроса
+    Required,
+    /// `u64::MAX` не выдается: после него счетчику некуда продолжать
+    Reserved,
+}
+
+/// Генератор для стратегии модели. `next` - `get_max_id` дерева модели
+pub fn id_generator(strategy: IdStrategy, next: u64) -> Box<dyn IdGenerator> {
+    match strategy {
+        IdStrategy::AutoIncrement => Box::new(AutoIncrement(AtomicU64::new(next))),
+        IdStrategy::Snowflake => Box::new(Snowflake { last: AtomicU64::new(next.saturating_sub(1)) }),
+        IdStrategy::External => Box::new(External { next: AtomicU64::new(next) }),
+    }
+}
+
+/// Последовательные id, переданный клиентом id игнорируется
+pub struct AutoIncrement(AtomicU64);
+
+impl IdGenerator for AutoIncrement {
+    fn next_id(&self, _supplied: Option<u64>) -> Result<u64, IdError> {
+        Ok(self.0.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn peek(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self, next: u64) {
+        self.0.store(next, Ordering::Relaxed);
+    }
+}
+
+/// Время в мс в старших битах и номер внутри миллисекунды в младших. Id растут вместе со временем создания,
+/// но не повторяются и не уменьшаются, даже если часы сервера перевели назад
+pub struct Snowflake {
+    last: AtomicU64,
+}
+
+impl Snowflake {
+    fn candidate(last: u64) -> u64 {
+        let time = now_millis().saturating_sub(SNOWFLAKE_EPOCH) << SNOWFLAKE_SEQ_BITS;
+        time.max(last + 1)
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn next_id(&self, _supplied: Option<u64>) -> Result<u64, IdError> {
+        let mut last = self.last.load(Ordering::Relaxed);
+        loop {
+            let id = Snowflake::candidate(last);
+            match self.last.compare_exchange_weak(last, id, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return Ok(id),
+                Err(current) => last = current,
+            }
+        }
+    }
+
+    fn peek(&self) -> u64 {
+        Snowflake::candidate(self.last.load(Ordering::Relaxed))
+    }
+
+    fn reset(&self, next: u64) {
+        self.last.store(next.saturating_sub(1), Ordering::Relaxed);
+    }
+}
+
+/// Id задает клиент в теле `insert`. Генератор только помнит наибольший, чтобы `peek` показывал следующий свободный
+pub struct External {
+    next: AtomicU64,
+}
+
+impl IdGenerator for External {
+    fn next_id(&self, supplied: Option<u64>) -> Result<u64, IdError> {
+        let id = supplied.ok_or(IdError::Required)?;
+        let next = id.checked_add(1).ok_or(IdError::Reserved)?;
+        self.next.fetch_max(next, Ordering::Relaxed);
+        Ok(id)
+    }
+
+    fn peek(&self) -> u64 {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self, next: u64) {
+        self.next.store(next, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snowflake() {
+        let generator = id_generator(IdStrategy::Snowflake, 1);
+        let first = generator.next_id(None).unwrap();
+        let second = generator.next_id(None).unwrap();
+        assert!(second > first);
+        assert!(first >> SNOWFLAKE_SEQ_BITS > 0);
+
+        // Id из будущего (например, после импорта) не дает выдать меньший
+        generator.reset(u64::MAX >> 1);
+        assert_eq!(generator.next_id(None).unwrap(), u64::MAX >> 1);
+    }
+
+    #[test]
+    fn test_external() {
+        let generator = id_generator(IdStrategy::External, 1);
+        assert!(matches!(generator.next_id(None), Err(IdError::Required)));
+        assert_eq!(generator.next_id(Some(42)).unwrap(), 42);
+        assert_eq!(generator.peek(), 43);
+        assert!(matches!(generator.next_id(Some(u64::MAX)), Err(IdError::Reserved)));
+        assert_eq!(generator.peek(), 43);
+    }
+}
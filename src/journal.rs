@@ -0,0 +1,346 @@
+use std::{cell::RefCell, ops::{Deref, Range}, sync::Arc};
+
+use canopydb::{Database, Error, Transaction, Tree, WriteTransaction};
+
+use crate::doc_cache::DocCache;
+use crate::error::MarciError;
+
+/// `<seq>` -> запись со всеми изменениями одной транзакции
+pub const JOURNAL_TREE: &[u8] = b"$journal";
+/// Служебные значения (например, последний примененный номер на реплике)
+pub const META_TREE: &[u8] = b"$meta";
+
+pub const META_APPLIED_SEQ: &[u8] = b"replication.applied";
+
+const OP_PUT: u8 = 0;
+const OP_DELETE: u8 = 1;
+const OP_DELETE_RANGE: u8 = 2;
+
+#[derive(Debug, Clone)]
+pub enum JournalOp {
+  Put { tree: Vec<u8>, key: Vec<u8>, value: Vec<u8> },
+  Delete { tree: Vec<u8>, key: Vec<u8> },
+  DeleteRange { tree: Vec<u8>, start: Vec<u8>, end: Vec<u8> },
+}
+
+#[derive(Debug)]
+pub struct JournalRecord {
+  pub timestamp: u64,
+  pub ops: Vec<JournalOp>,
+}
+
+/// Транзакция записи, которая запоминает все изменения деревьев и при коммите
+/// дописывает их одной записью в журнал. Журнал читают реплики
+pub struct JournalTx {
+  tx: WriteTransaction,
+  ops: RefCell<Vec<JournalOp>>,
+  /// Кэш документов, из которого после коммита удаляются измененные документы
+  cache: Option<Arc<DocCache>>,
+}
+
+pub struct JournalTree<'a> {
+  tree: Tree<'a>,
+  name: &'a [u8],
+  ops: &'a RefCell<Vec<JournalOp>>,
+}
+
+impl JournalTx {
+  pub fn new(tx: WriteTransaction) -> JournalTx {
+    JournalTx { tx, ops: RefCell::new(vec![]), cache: None }
+  }
+
+  pub fn with_cache(mut self, cache: Option<Arc<DocCache>>) -> JournalTx {
+    self.cache = cache;
+    self
+  }
+
+  pub fn get_tree<'a>(&'a self, name: &'a [u8]) -> Result<Option<JournalTree<'a>>, Error> {
+    Ok(self.tx.get_tree(name)?.map(|tree| JournalTree { tree, name, ops: &self.ops }))
+  }
+
+  /// Фиксирует транзакцию. Возвращает номер записи в журнале (0, если ничего не изменилось)
+  pub fn commit(self, timestamp: u64) -> Result<u64, Error> {
+    let JournalTx { tx, ops, cache } = self;
+    let ops = ops.into_inner();
+    if ops.is_empty() {
+      tx.commit()?;
+      return Ok(0);
+    }
+
+    // Транзакции записи эксклюзивны, поэтому номер внутри транзакции монотонен
+    let seq = {
+      let mut journal = tx.get_tree(JOURNAL_TREE)?.expect("Journal tree must exist");
+      let seq = last_seq(&journal)? + 1;
+      journal.insert(&seq.to_be_bytes(), &encode_record(timestamp, &ops))?;
+      seq
+    };
+    tx.commit()?;
+    if let Some(cache) = cache {
+      cache.committed(seq, &ops);
+    }
+    Ok(seq)
+  }
+}
+
+impl Deref for JournalTx {
+  type Target = WriteTransaction;
+  fn deref(&self) -> &Self::Target {
+    &self.tx
+  }
+}
+
+impl<'a> Deref for JournalTree<'a> {
+  type Target = Tree<'a>;
+  fn deref(&self) -> &Self::Target {
+    &self.tree
+  }
+}
+
+impl JournalTree<'_> {
+  pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+    self.tree.insert(key, value)?;
+    self.ops.borrow_mut().push(JournalOp::Put { tree: self.name.to_vec(), key: key.to_vec(), value: value.to_vec() });
+    Ok(())
+  }
+
+  pub fn delete(&mut self, key: &[u8]) -> Result<bool, Error> {
+    let deleted = self.tree.delete(key)?;
+    if deleted {
+      self.ops.borrow_mut().push(JournalOp::Delete { tree: self.name.to_vec(), key: key.to_vec() });
+    }
+    Ok(deleted)
+  }
+
+  pub fn delete_range<K: AsRef<[u8]>>(&mut self, range: Range<K>) -> Result<(), Error> {
+    let (start, end) = (range.start.as_ref().to_vec(), range.end.as_ref().to_vec());
+    self.tree.delete_range(start.as_slice()..end.as_slice())?;
+    self.ops.borrow_mut().push(JournalOp::DeleteRange { tree: self.name.to_vec(), start, end });
+    Ok(())
+  }
+}
+
+pub fn last_seq(journal: &Tree) -> Result<u64, Error> {
+  Ok(journal.last()?.map(|(key, _)| u64::from_be_bytes(key.as_ref().try_into().unwrap())).unwrap_or(0))
+}
+
+/// Последний номер журнала, видимый в транзакции
+pub fn journal_seq(tx: &Transaction) -> Result<u64, MarciError> {
+  let journal = tx.get_tree(JOURNAL_TREE)?.ok_or_else(|| MarciError::MissingTree(String::from_utf8_lossy(JOURNAL_TREE).into_owned()))?;
+  return Ok(last_seq(&journal)?);
+}
+
+/// Номер последней записи, полученной от основного сервера
+pub fn applied_seq(tx: &Transaction) -> Option<u64> {
+  let meta = tx.get_tree(META_TREE).unwrap().expect("Meta tree must exist");
+  return meta.get(META_APPLIED_SEQ).unwrap().map(|value| u64::from_be_bytes(value.as_ref().try_into().unwrap()));
+}
+
+/// Удаляет записи журнала старше `before` (мс). Последняя запись остается, чтобы нумерация
+/// продолжилась. Реплики, которым нужны удаленные записи, загрузят снимок. Возвращает количество удаленных
+pub fn prune_journal(db: &Database, before: u64) -> Result<usize, Error> {
+  let tx = db.begin_write()?;
+  let mut removed = 0;
+  {
+    let mut journal = tx.get_tree(JOURNAL_TREE)?.expect("Journal tree must exist");
+    let last = last_seq(&journal)?;
+
+    let mut end = None;
+    for item in journal.iter()? {
+      let (key, record) = item?;
+      let seq = u64::from_be_bytes(key.as_ref().try_into().unwrap());
+      let timestamp = u64::from_be_bytes(record[..8].try_into().unwrap());
+      // Пустые записи снимков имеют нулевое время и удаляются вместе с соседними
+      if seq == last || (timestamp >= before && timestamp != 0) {
+        break;
+      }
+      end = Some(seq);
+      removed += 1;
+    }
+
+    if let Some(end) = end {
+      journal.delete_range(..=end.to_be_bytes())?;
+    }
+  }
+  tx.commit()?;
+  Ok(removed)
+}
+
+/// Применяет запись журнала основного сервера на реплике, сохраняя тот же номер. Возвращает разобранную запись
+pub fn apply_record(db: &Database, seq: u64, record: &[u8]) -> Result<JournalRecord, Error> {
+  let decoded = decode_record(record).expect("Corrupted journal record");
+
+  let tx = db.begin_write()?;
+  for op in decoded.ops.iter() {
+    match op {
+      JournalOp::Put { tree, key, value } => tx.get_or_create_tree(tree)?.insert(key, value)?,
+      JournalOp::Delete { tree, key } => { tx.get_or_create_tree(tree)?.delete(key)?; },
+      JournalOp::DeleteRange { tree, start, end } => tx.get_or_create_tree(tree)?.delete_range(start.as_slice()..end.as_slice())?,
+    }
+  }
+  tx.get_tree(JOURNAL_TREE)?.unwrap().insert(&seq.to_be_bytes(), record)?;
+  tx.get_tree(META_TREE)?.unwrap().insert(META_APPLIED_SEQ, &seq.to_be_bytes())?;
+  tx.commit()?;
+  Ok(decoded)
+}
+
+/// Снимок всех деревьев (кроме журнала) внутри одной транзакции чтения:
+/// `[seq: u64]` и далее `[tree_len: u16][tree][key_len: u32][key][value_len: u32][value]`
+pub fn write_snapshot(rx: &Transaction, seq: u64, out: &mut Vec<u8>) -> Result<(), Error> {
+  out.extend_from_slice(&seq.to_be_bytes());
+  for name in rx.list_trees()? {
+    if name.as_ref() == JOURNAL_TREE || name.as_ref() == META_TREE {
+      continue;
+    }
+    let tree = rx.get_tree(&name)?.unwrap();
+    for item in tree.iter()? {
+      let (key, value) = item?;
+      write_bytes16(out, &name);
+      write_bytes32(out, &key);
+      write_bytes32(out, &value);
+    }
+  }
+  Ok(())
+}
+
+/// Заменяет все содержимое базы снимком. Журнал продолжается с `seq` или с номера,
+/// на котором снят снимок. Возвращает этот номер
+pub fn load_snapshot(db: &Database, snapshot: &[u8], seq: Option<u64>) -> Option<u64> {
+  let mut reader = Reader { data: snapshot, pos: 0 };
+  let snapshot_seq = reader.u64()?;
+  let seq = seq.unwrap_or(snapshot_seq);
+
+  let tx = db.begin_write().unwrap();
+  for name in tx.list_trees().unwrap() {
+    tx.get_tree(&name).unwrap().unwrap().clear().unwrap();
+  }
+  while !reader.is_empty() {
+    let name = reader.bytes16()?;
+    let key = reader.bytes32()?;
+    let value = reader.bytes32()?;
+    tx.get_or_create_tree(name).unwrap().insert(key, value).unwrap();
+  }
+  // Пустая запись, чтобы нумерация журнала продолжилась с номера снимка
+  tx.get_or_create_tree(JOURNAL_TREE).unwrap().insert(&seq.to_be_bytes(), &encode_record(0, &[])).unwrap();
+  tx.get_or_create_tree(META_TREE).unwrap().insert(META_APPLIED_SEQ, &seq.to_be_bytes()).unwrap();
+  tx.commit().unwrap();
+  Some(seq)
+}
+
+fn encode_record(timestamp: u64, ops: &[JournalOp]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(12 + ops.len() * 32);
+  out.extend_from_slice(&timestamp.to_be_bytes());
+  out.extend_from_slice(&(ops.len() as u32).to_be_bytes());
+  for op in ops {
+    match op {
+      JournalOp::Put { tree, key, value } => {
+        out.push(OP_PUT);
+        write_bytes16(&mut out, tree);
+        write_bytes32(&mut out, key);
+        write_bytes32(&mut out, value);
+      }
+      JournalOp::Delete { tree, key } => {
+        out.push(OP_DELETE);
+        write_bytes16(&mut out, tree);
+        write_bytes32(&mut out, key);
+      }
+      JournalOp::DeleteRange { tree, start, end } => {
+        out.push(OP_DELETE_RANGE);
+        write_bytes16(&mut out, tree);
+        write_bytes32(&mut out, start);
+        write_bytes32(&mut out, end);
+      }
+    }
+  }
+  out
+}
+
+pub fn decode_record(data: &[u8]) -> Option<JournalRecord> {
+  let mut reader = Reader { data, pos: 0 };
+  let timestamp = reader.u64()?;
+  let count = reader.u32()? as usize;
+
+  let mut ops = Vec::with_capacity(count);
+  for _ in 0..count {
+    let kind = reader.u8()?;
+    let tree = reader.bytes16()?.to_vec();
+    let op = match kind {
+      OP_PUT => JournalOp::Put { tree, key: reader.bytes32()?.to_vec(), value: reader.bytes32()?.to_vec() },
+      OP_DELETE => JournalOp::Delete { tree, key: reader.bytes32()?.to_vec() },
+      OP_DELETE_RANGE => JournalOp::DeleteRange { tree, start: reader.bytes32()?.to_vec(), end: reader.bytes32()?.to_vec() },
+      _ => return None
+    };
+    ops.push(op);
+  }
+  Some(JournalRecord { timestamp, ops })
+}
+
+#[inline(always)]
+fn write_bytes16(out: &mut Vec<u8>, bytes: &[u8]) {
+  out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+  out.extend_from_slice(bytes);
+}
+
+#[inline(always)]
+fn write_bytes32(out: &mut Vec<u8>, bytes: &[u8]) {
+  out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+  out.extend_from_slice(bytes);
+}
+
+/// Последовательное чтение с проверкой границ
+pub struct Reader<'a> {
+  pub data: &'a [u8],
+  pub pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  pub fn is_empty(&self) -> bool {
+    self.pos >= self.data.len()
+  }
+
+  pub fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+    let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+    self.pos += len;
+    Some(bytes)
+  }
+
+  pub fn u8(&mut self) -> Option<u8> {
+    Some(self.take(1)?[0])
+  }
+
+  pub fn u32(&mut self) -> Option<u32> {
+    Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+  }
+
+  pub fn u64(&mut self) -> Option<u64> {
+    Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+  }
+
+  pub fn bytes16(&mut self) -> Option<&'a [u8]> {
+    let len = u16::from_be_bytes(self.take(2)?.try_into().ok()?) as usize;
+    self.take(len)
+  }
+
+  pub fn bytes32(&mut self) -> Option<&'a [u8]> {
+    let len = self.u32()? as usize;
+    self.take(len)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::journal::{JournalOp, decode_record, encode_record};
+
+  #[test]
+  fn test_record_roundtrip() {
+    let ops = vec![
+      JournalOp::Put { tree: b"User".to_vec(), key: 1u64.to_be_bytes().to_vec(), value: vec![1, 2, 3] },
+      JournalOp::Delete { tree: b"User".to_vec(), key: 2u64.to_be_bytes().to_vec() },
+      JournalOp::DeleteRange { tree: b"User.info".to_vec(), start: vec![0], end: vec![1] },
+    ];
+    let record = decode_record(&encode_record(42, &ops)).unwrap();
+
+    assert_eq!(record.timestamp, 42);
+    assert_eq!(format!("{:?}", record.ops), format!("{:?}", ops));
+    assert!(decode_record(&encode_record(42, &ops)[..20]).is_none());
+  }
+}
@@ -0,0 +1,171 @@
+use serde_json::{Map, Value, json};
+
+use crate::schema::{Field, FieldType, Model, PrimitiveFieldType, Schema};
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Для какого запроса строится схема: insert требует все поля без `?`, update - только `id`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyKind {
+    Insert,
+    Update,
+}
+
+/// JSON Schema (draft 2020-12) тела insert/update для модели
+pub fn model_json_schema(schema: &Schema, model: &Model, kind: BodyKind) -> Value {
+    let mut document = object_schema(schema, &model.fields, kind);
+    let properties = document["properties"].as_object_mut().unwrap();
+    if kind == BodyKind::Update {
+        properties.insert("id".to_string(), json!({ "type": "integer", "minimum": 0 }));
+        document["required"] = json!(["id"]);
+    }
+    if let Some(ttl) = &model.ttl {
+        document["properties"]["$ttl"] = json!({
+            "type": ["integer", "null"],
+            "minimum": 0,
+            "description": format!("Time to live in seconds, {} by default; null keeps the document forever", ttl.seconds),
+        });
+    }
+
+    document["$schema"] = json!(DRAFT);
+    document["title"] = json!(model.name);
+    document
+}
+
+/// JSON Schema документа в ответах findMany/findFirst: `id`, поля модели, связи - id или включенный документ.
+/// Набор полей зависит от `select`, поэтому обязателен только `id`
+pub fn model_document_schema(schema: &Schema, model: &Model) -> Value {
+    let mut properties = Map::new();
+    properties.insert("id".to_string(), json!({ "type": "integer", "minimum": 0 }));
+    for field in &model.fields {
+        let value = match &field.ty {
+            FieldType::ModelRef(model_index) => json!({
+                "anyOf": [{ "type": "integer", "minimum": 0 }, { "type": "object" }],
+                "description": format!("Id of {}, or the document itself when included", schema.models[*model_index].name),
+            }),
+            FieldType::ModelRefList(model_index) => json!({
+                "type": "array",
+                "description": format!("Included {} documents", schema.models[*model_index].name),
+            }),
+            _ => {
+                let Some(value) = field_schema(schema, field, BodyKind::Insert) else { continue };
+                value
+            }
+        };
+        let value = if field.is_nullable { json!({ "anyOf": [value, { "type": "null" }] }) } else { value };
+        properties.insert(field.name.clone(), value);
+    }
+    json!({
+        "title": model.name,
+        "type": "object",
+        "properties": properties,
+        "required": ["id"],
+    })
+}
+
+fn object_schema(schema: &Schema, fields: &[Field], kind: BodyKind) -> Value {
+    let mut properties = Map::new();
+    let mut required = vec![];
+    for field in fields {
+        // Производные списки вычисляются по связи с другой стороны и не записываются
+        if field.derived_from.is_some() {
+            continue;
+        }
+        let Some(mut value) = field_schema(schema, field, kind) else { continue };
+        if field.is_nullable {
+            value = json!({ "anyOf": [value, { "type": "null" }] });
+        } else if kind == BodyKind::Insert && matches!(field.ty, FieldType::Primitive(_) | FieldType::ModelRef(_))
+            && field.default_value().is_none() && field.default_fn().is_none() {
+            required.push(field.name.clone());
+        }
+        properties.insert(field.name.clone(), value);
+    }
+
+    let mut object = json!({
+        "type": "object",
+        "properties": properties,
+        "additionalProperties": false,
+    });
+    if !required.is_empty() {
+        object["required"] = json!(required);
+    }
+    object
+}
+
+fn field_schema(schema: &Schema, field: &Field, kind: BodyKind) -> Option<Value> {
+    let reference = json!({
+        "type": "object",
+        "properties": { "id": { "type": "integer", "minimum": 0 } },
+        "required": ["id"],
+    });
+    let value = match &field.ty {
+        FieldType::Primitive(ty) => primitive_schema(ty),
+        FieldType::PrimitiveList(ty) => {
+            let list = json!({ "type": "array", "items": primitive_schema(ty) });
+            match kind {
+                BodyKind::Insert => list,
+                BodyKind::Update => {
+                    let operand = json!({ "anyOf": [primitive_schema(ty), list] });
+                    json!({ "anyOf": [
+                        list,
+                        { "type": "object", "properties": { "push": operand }, "required": ["push"], "additionalProperties": false },
+                        { "type": "object", "properties": { "remove": operand }, "required": ["remove"], "additionalProperties": false },
+                    ] })
+                }
+            }
+        }
+        FieldType::ModelRef(model_index) => {
+            let mut reference = reference;
+            reference["description"] = json!(format!("Reference to {}", schema.models[*model_index].name));
+            reference
+        }
+        FieldType::ModelRefList(model_index) => {
+            // Поля `@payload` передаются рядом с id: `{ id, role }`
+            let mut item = reference;
+            if let Some(st) = field.payload() {
+                let payload = object_schema(schema, &st.fields, BodyKind::Insert);
+                item["properties"].as_object_mut().unwrap().extend(payload["properties"].as_object().unwrap().clone());
+                if let Some(required) = payload.get("required").and_then(Value::as_array) {
+                    item["required"].as_array_mut().unwrap().extend(required.iter().cloned());
+                }
+            }
+            json!({
+                "type": "array",
+                "items": item,
+                "description": format!("References to {}", schema.models[*model_index].name),
+            })
+        }
+        FieldType::Struct(st) => object_schema(schema, &st.fields, kind),
+        FieldType::StructList(st, _) => {
+            // Элементы с id обновляются, без id - добавляются
+            let mut item = object_schema(schema, &st.fields, kind);
+            item["properties"]["id"] = json!({ "type": "integer", "minimum": 0 });
+            json!({ "type": "array", "items": item })
+        }
+        _ => return None
+    };
+    Some(value)
+}
+
+fn primitive_schema(ty: &PrimitiveFieldType) -> Value {
+    match ty {
+        PrimitiveFieldType::String => json!({ "type": "string" }),
+        PrimitiveFieldType::Bool => json!({ "type": "boolean" }),
+        PrimitiveFieldType::Int64 => json!({ "type": "integer", "minimum": i64::MIN, "maximum": i64::MAX }),
+        PrimitiveFieldType::UInt64 => json!({ "type": "integer", "minimum": 0, "maximum": u64::MAX }),
+        PrimitiveFieldType::Int8 => json!({ "type": "integer", "minimum": i8::MIN, "maximum": i8::MAX }),
+        PrimitiveFieldType::Int16 => json!({ "type": "integer", "minimum": i16::MIN, "maximum": i16::MAX }),
+        PrimitiveFieldType::Int32 => json!({ "type": "integer", "minimum": i32::MIN, "maximum": i32::MAX }),
+        PrimitiveFieldType::UInt8 => json!({ "type": "integer", "minimum": 0, "maximum": u8::MAX }),
+        PrimitiveFieldType::UInt16 => json!({ "type": "integer", "minimum": 0, "maximum": u16::MAX }),
+        PrimitiveFieldType::UInt32 => json!({ "type": "integer", "minimum": 0, "maximum": u32::MAX }),
+        PrimitiveFieldType::Float | PrimitiveFieldType::Double => json!({ "type": "number" }),
+        // Epoch в миллисекундах или строка ISO-8601
+        PrimitiveFieldType::DateTime => json!({ "anyOf": [{ "type": "integer" }, { "type": "string", "format": "date-time" }] }),
+        // Формат пользовательского типа известен только его кодеку
+        PrimitiveFieldType::Custom(scalar) => json!({ "title": scalar.name }),
+        PrimitiveFieldType::Enum(enum_type) => json!({ "title": enum_type.name, "enum": enum_type.variants }),
+        // Любое JSON-значение
+        PrimitiveFieldType::Json => json!({}),
+    }
+}
@@ -0,0 +1,44 @@
+//! Встраиваемая MarciDB: схема, хранилище, кодирование документов и запросы без HTTP-сервера.
+//! Бинарный `marci-db` - сервер поверх этой библиотеки
+//!
+//! ```no_run
+//! use marci_db::{MarciDB, MarciQuery, MarciSelect, decode_document, encode_document, parse_schema};
+//!
+//! let schema = parse_schema("model User {\n  name String\n}").unwrap();
+//! let db = MarciDB::open(schema, std::path::Path::new("./data")).unwrap();
+//! let user = db.get_model("User").unwrap();
+//!
+//! let mut structs = vec![];
+//! let (data, _) = encode_document(user, &serde_json::json!({ "name": "Ann" }), &mut structs).unwrap();
+//! let id = db.insert_data(user, &data, &structs).unwrap();
+//!
+//! let select = MarciSelect::all(&user.fields);
+//! let users = db.find_many(user, &select, &MarciQuery::all(), decode_document).unwrap();
+//! ```
+
+// Код базы пишет `return` явно, это принятый здесь стиль
+#![allow(clippy::needless_return)]
+
+pub mod marci_db;
+pub mod error;
+pub mod schema;
+pub mod marci_encoder;
+pub mod marci_decoder;
+pub mod marci_select;
+pub mod marci_query;
+pub mod update_data;
+pub mod journal;
+pub mod doc_cache;
+pub mod id_generator;
+pub mod json_schema;
+pub mod codegen;
+pub mod fixtures;
+#[cfg(fuzzing)]
+pub mod fuzz;
+
+pub use crate::error::{ErrorBody, MarciError};
+pub use crate::marci_db::{DecodeCtx, DeleteError, InsertError, MarciDB, MarciSelect};
+pub use crate::marci_decoder::{DecodeError, Doc, decode_doc, decode_document};
+pub use crate::marci_encoder::{EncodeError, encode_document, encode_update};
+pub use crate::marci_query::{MarciQuery, MarciQueryError, parse_find_args, parse_query};
+pub use crate::schema::{Model, Schema, SchemaError, parse_schema};
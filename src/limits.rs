@@ -0,0 +1,121 @@
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+use hyper::body::Bytes;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActionClass {
+    /// Чтения по id и записи
+    Light,
+    /// Полные обходы: findMany без фильтра, выгрузки, агрегации
+    Heavy,
+}
+
+/// Отдельные лимиты на одновременные тяжелые и легкие операции, чтобы аналитика не вытесняла интерактивные запросы
+pub struct ConcurrencyLimits {
+    light: Option<Semaphore>,
+    heavy: Option<Semaphore>,
+}
+
+impl ConcurrencyLimits {
+    /// 0 означает отсутствие лимита
+    pub fn new(light: usize, heavy: usize) -> ConcurrencyLimits {
+        ConcurrencyLimits {
+            light: (light > 0).then(|| Semaphore::new(light)),
+            heavy: (heavy > 0).then(|| Semaphore::new(heavy)),
+        }
+    }
+
+    /// Ждет свободный слот для операции данного класса
+    pub async fn acquire(&self, class: ActionClass) -> Option<SemaphorePermit<'_>> {
+        let semaphore = match class {
+            ActionClass::Light => self.light.as_ref()?,
+            ActionClass::Heavy => self.heavy.as_ref()?,
+        };
+        semaphore.acquire().await.ok()
+    }
+}
+
+/// Приблизительный учет памяти, занятой декодированием и еще не отправленными ответами
+pub struct MemoryBudget {
+    limit: usize,
+    used: Arc<AtomicUsize>,
+}
+
+/// Зарезервированная часть бюджета. Освобождается при drop
+pub struct MemoryReservation {
+    used: Arc<AtomicUsize>,
+    size: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> MemoryBudget {
+        MemoryBudget { limit, used: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Бюджет превышен, новые тяжелые запросы нужно отбрасывать
+    pub fn is_exceeded(&self) -> bool {
+        self.limit != 0 && self.used() >= self.limit
+    }
+
+    pub fn reserve(&self) -> MemoryReservation {
+        MemoryReservation { used: self.used.clone(), size: AtomicUsize::new(0) }
+    }
+}
+
+impl MemoryReservation {
+    pub fn grow(&self, size: usize) {
+        self.size.fetch_add(size, Ordering::Relaxed);
+        self.used.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Заменяет оценку фактическим размером и держит резерв, пока тело ответа не будет отправлено
+    pub fn into_body(self, body: impl Into<Vec<u8>>) -> Bytes {
+        let body: Vec<u8> = body.into();
+        let size = self.size.swap(body.len(), Ordering::Relaxed);
+        self.used.fetch_add(body.len(), Ordering::Relaxed);
+        self.used.fetch_sub(size, Ordering::Relaxed);
+        Bytes::from_owner(TrackedBody { body, _reservation: self })
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.used.fetch_sub(*self.size.get_mut(), Ordering::Relaxed);
+    }
+}
+
+struct TrackedBody {
+    body: Vec<u8>,
+    _reservation: MemoryReservation,
+}
+
+impl AsRef<[u8]> for TrackedBody {
+    fn as_ref(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limits::MemoryBudget;
+
+    #[test]
+    fn test_reservation_released_with_body() {
+        let budget = MemoryBudget::new(10);
+        let reservation = budget.reserve();
+        reservation.grow(100);
+        assert!(budget.is_exceeded());
+
+        let body = reservation.into_body("12345".to_string());
+        assert_eq!(budget.used(), 5);
+        assert!(!budget.is_exceeded());
+
+        drop(body);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -1,31 +1,252 @@
+// Код сервера пишет `return` явно, как и библиотека
+#![allow(clippy::needless_return)]
+
 use std::convert::Infallible;
 use std::fs;
-use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::any::Any;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use http_body_util::{BodyExt, Full};
-use hyper::body::Bytes;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Bytes};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
-use hyper_util::rt::TokioIo;
-use serde_json::Value;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde_json::{Value, json};
 use tokio::net::TcpListener;
+// Модули библиотеки доступны серверу по тем же путям `crate::...`
+use ::marci_db::{codegen, error, fixtures, journal, json_schema, marci_db, marci_decoder, marci_encoder, marci_query, marci_select, schema};
+
+use crate::export::{DumpError, database_stats, dump_database, export_database, import_database, load_database};
+use crate::workload::Workload;
+use crate::json_schema::{BodyKind, model_json_schema};
+use crate::openapi::openapi_document;
+use crate::codegen::rust_client;
+use crate::format::{RequestFormat, ResponseFormat, Rows};
+use crate::extension::{ActionContext, Extension, Extensions, scalar_types};
+use crate::backup::{BackupError, BackupKey, create_backup, last_backup_time, list_backups, prune_backups, restore_backup, write_backup_file};
+use crate::auth::{Access, ApiKeys, Claims, JwtAuth, bearer_token, required_access};
+use crate::config::Config;
+use crate::debug_log::DebugLog;
+use crate::journal::prune_journal;
+use crate::limits::{ActionClass, ConcurrencyLimits, MemoryBudget};
+use crate::demo::{demo_dir, seed_demo};
+use crate::shutdown::{Shutdown, termination, tick};
+use crate::error::{ErrorBody, MarciError};
+use crate::marci_db::{DecodeCtx, HistoryError, MarciDB, MarciSelect, MergeStrategy, now_millis, open_database};
+use crate::marci_decoder::{DecodeError, Doc, JsonText, decode_doc, decode_document, decode_ids, decode_json};
+use crate::marci_encoder::{encode_document, encode_update};
+use crate::marci_query::{MarciFilter, MarciQuery, parse_as_of, parse_model_find_args, parse_where, value_index};
+use crate::policy::{PolicyError, allowed_where, check_create, check_includes, check_update};
+use crate::marci_select::{SelectPlans, fetched_rows, parse_returning};
+use crate::rename::{rename_in_schema, rename_model_trees};
+use crate::replication::{LAST_SEQ_HEADER, LogError, REPLICA_ID_HEADER, Replication};
+use crate::schema::{AllowOperation, FieldType, Model, PrimitiveFieldType, parse_schema_with};
+use crate::session::{SessionError, Sessions, TRANSACTION_HEADER};
+
+mod config;
+mod limits;
+mod replication;
+mod backup;
+mod export;
+mod workload;
+mod openapi;
+mod extension;
+mod format;
+mod rename;
+mod demo;
+mod shutdown;
+mod debug_log;
+mod session;
+mod auth;
+mod policy;
+#[cfg(feature = "arrow")]
+mod arrow_stream;
+#[cfg(feature = "tls")]
+mod tls;
+
+const TTL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Через сколько секунд клиенту стоит повторить отброшенный запрос
+const RETRY_AFTER_SECS: u64 = 1;
+const REPLICATION_LOG_LIMIT: usize = 1000;
+/// Как часто планировщик проверяет, не пора ли сделать бэкап и почистить журнал
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Принятые, но еще не переданные в обработку соединения со всех адресов
+const ACCEPT_QUEUE: usize = 128;
+/// Пауза после ошибки accept (кончились дескрипторы, клиент оборвал соединение), чтобы не крутить цикл впустую
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Как часто проверяется таймаут интерактивной транзакции
+const SESSION_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Номер записи в журнале, которым закончилась запись (в ответах insert/update/delete)
+const SEQUENCE_HEADER: &str = "x-sequence";
+/// Чтение должно увидеть журнал хотя бы до этого номера (read-after-write на репликах)
+const MIN_SEQUENCE_HEADER: &str = "x-min-sequence";
+/// Сколько чтение ждет, пока реплика догонит X-Min-Sequence, прежде чем отправить клиента на основной сервер
+const MIN_SEQUENCE_TIMEOUT: Duration = Duration::from_secs(2);
+const MIN_SEQUENCE_POLL: Duration = Duration::from_millis(10);
+/// Id ошибки в ответе 500 после паники обработчика, по нему запись находится в логе
+const ERROR_ID_HEADER: &str = "x-error-id";
+/// Выборка обрезана пределом `@@maxRows` модели
+const CAPPED_HEADER: &str = "x-capped";
+
+struct ServerState {
+    db: Arc<MarciDB>,
+    memory: MemoryBudget,
+    concurrency: ConcurrencyLimits,
+    replication: Arc<Replication>,
+    backup_key: Option<Arc<BackupKey>>,
+    backup_dir: Option<PathBuf>,
+    workload: Workload,
+    /// Разобранные select тел findMany/findFirst
+    select_plans: SelectPlans,
+    index_lab: bool,
+    extensions: Extensions,
+    debug: DebugLog,
+    /// Сколько запросов завершилось паникой с запуска сервера
+    panics: AtomicU64,
+    shutdown: Arc<Shutdown>,
+    sessions: Sessions,
+    request_timeout: Option<Duration>,
+    max_body_size: usize,
+    api_keys: ApiKeys,
+    jwt: Option<JwtAuth>,
+}
+
+/// HTTP/1.1 поверх принятого соединения, обычного или TLS. Соединение закрывается, если клиент
+/// не присылает заголовки запроса за `idle_timeout`, в том числе между запросами keep-alive
+async fn serve_connection<S>(stream: S, state: Arc<ServerState>, idle_timeout: Duration)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    // Use an adapter to access something implementing `tokio::io` traits as if they implement
+    // `hyper::rt` IO traits.
+    let io = TokioIo::new(stream);
+
+    // Finally, we bind the incoming connection to our `hello` service
+    if let Err(err) = http1::Builder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(idle_timeout)
+        // `service_fn` converts our function in a `Service`
+        .serve_connection(io, service_fn(move |req| {
+            handle_guarded(req, state.clone())
+        }))
+        .await
+    {
+        eprintln!("Error serving connection: {:?}", err);
+    }
+}
+
+/// Запрос выполняется отдельной задачей: паника в обработчике (например, unwrap при декодировании)
+/// превращается в ответ 500 с id ошибки, а не обрывает соединение без ответа
+async fn handle_guarded(req: Request<hyper::body::Incoming>, state: Arc<ServerState>) -> Result<Response<Full<Bytes>>, Infallible> {
+    // Во время остановки новые запросы не принимаются, в том числе по открытым keep-alive соединениям
+    if state.shutdown.is_stopping() {
+        let mut res = error(StatusCode::SERVICE_UNAVAILABLE, "Server is shutting down");
+        res.headers_mut().insert(hyper::header::CONNECTION, "close".parse().unwrap());
+        return Ok(res);
+    }
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    // Запрос считается в работе до конца задачи, даже если клиент закрыл соединение
+    let request = state.shutdown.request();
+    let task_state = state.clone();
+    let task = tokio::task::spawn(async move {
+        let _request = request;
+        handle(req, task_state).await
+    });
+    // `--request-timeout`: задача снимается в ближайшей точке ожидания, начатая запись в базу доходит до конца
+    let joined = match state.request_timeout {
+        Some(timeout) => {
+            let abort = task.abort_handle();
+            match tokio::time::timeout(timeout, task).await {
+                Ok(joined) => joined,
+                Err(_) => {
+                    abort.abort();
+                    return Ok(error(StatusCode::SERVICE_UNAVAILABLE, &format!("Request timed out after {:?}", timeout)));
+                }
+            }
+        }
+        None => task.await,
+    };
+    let err = match joined {
+        Ok(resp) => return resp,
+        Err(err) => err,
+    };
+
+    let count = state.panics.fetch_add(1, Ordering::Relaxed) + 1;
+    let error_id = format!("{:x}-{}", now_millis(), count);
+    let message = match err.try_into_panic() {
+        Ok(payload) => panic_message(&*payload),
+        Err(err) => err.to_string(),
+    };
+    eprintln!("Request {} {} failed with error {}: {}", method, uri, error_id, message);
+
+    let mut res = error(StatusCode::INTERNAL_SERVER_ERROR, &format!("Internal error {}", error_id));
+    res.headers_mut().insert(ERROR_ID_HEADER, error_id.parse().unwrap());
+    Ok(res)
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    "unknown panic".to_string()
+}
+
+async fn handle(req: Request<hyper::body::Incoming>, state: Arc<ServerState>) -> Result<Response<Full<Bytes>>, Infallible> {
+
+    if let Some(resp) = state.extensions.before_request(&req) {
+        return Ok(resp);
+    }
+
+    let claims = match authorize(&req, &state) {
+        Ok(claims) => claims,
+        Err(resp) => return Ok(resp)
+    };
+
+    // Обработчики все равно собирают тело целиком, поэтому оно читается здесь один раз с ограничением
+    // `--max-body-size`. Заявленный в Content-Length размер больше предела отклоняется, не читая тело
+    let (mut parts, body) = req.into_parts();
+    if let Some(claims) = claims {
+        parts.extensions.insert(claims);
+    }
+    let declared = parts.headers.get(hyper::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > state.max_body_size as u64) {
+        return Ok(payload_too_large(state.max_body_size));
+    }
+    let request_body = match Limited::new(body, state.max_body_size).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) if err.is::<LengthLimitError>() => return Ok(payload_too_large(state.max_body_size)),
+        Err(_) => return Ok(error(StatusCode::BAD_REQUEST, "Failed to get body")),
+    };
+
+    let model_name = parts.uri.path().get(1..).unwrap_or("").split('/').next().unwrap_or("");
+    if !state.debug.is_enabled(model_name) {
+        return route(Request::from_parts(parts, Full::new(request_body)), state).await;
+    }
 
-use crate::marci_db::{MarciDB, MarciSelect};
-use crate::marci_decoder::decode_document;
-use crate::marci_encoder::encode_document;
-use crate::marci_select::{parse_select};
-use crate::schema::parse_schema;
+    // `--debug-bodies`: тело запроса сохраняется вместе с ответом
+    let method = parts.method.to_string();
+    let uri = parts.uri.to_string();
+    let resp = route(Request::from_parts(parts, Full::new(request_body.clone())), state.clone()).await?;
 
-mod marci_db;
-mod schema;
-mod marci_encoder;
-mod marci_decoder;
-mod marci_select;
-mod update_data;
+    let (parts, body) = resp.into_parts();
+    let response_body = body.collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+    state.debug.record(&method, &uri, parts.status.as_u16(), &request_body, &response_body);
+    Ok(Response::from_parts(parts, Full::new(response_body)))
+}
+
+async fn route<B: Body<Data = Bytes>>(req: Request<B>, state: Arc<ServerState>) -> Result<Response<Full<Bytes>>, Infallible> {
 
-async fn handle(req: Request<hyper::body::Incoming>, db: Arc<MarciDB>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let db = &state.db;
 
     let path = req.uri().path();
 
@@ -33,11 +254,188 @@ async fn handle(req: Request<hyper::body::Incoming>, db: Arc<MarciDB>) -> Result
     
     let model_name = &path[1..slash_index].to_string();
 
-    let action = &path[slash_index+1..];
+    let action = path.get(slash_index+1..).unwrap_or("");
+    let claims = req.extensions().get::<Claims>().cloned();
+    let format = ResponseFormat::from_headers(req.headers());
+    let body_format = RequestFormat::from_headers(req.headers());
+
+    // Реплика принимает только чтение, все изменения приходят из журнала основного сервера
+    let is_write = is_write(model_name, action, req.method());
+    if is_write && !state.replication.can_write() {
+        let msg = if state.replication.is_replica() { "Replica is read-only" } else { "Leader lock is held by another process" };
+        return Ok(error(StatusCode::FORBIDDEN, msg));
+    }
+
+    if model_name == "$debug" && action == "recent" && req.method() == Method::GET {
+        return Ok(Response::new(Full::new(Bytes::from(state.debug.recent().to_string()))));
+    }
+
+    if model_name == "$debug" && action == "panics" && req.method() == Method::GET {
+        let body = json!({ "panics": state.panics.load(Ordering::Relaxed) });
+        return Ok(Response::new(Full::new(Bytes::from(body.to_string()))));
+    }
+
+    if model_name == "$replication" {
+        return Ok(handle_replication(&req, action, &state));
+    }
+
+    if model_name == "$tx" {
+        return Ok(handle_transaction(&req, action, &state).await);
+    }
+
+    if let Some(token) = req.headers().get(TRANSACTION_HEADER) {
+        let token = token.to_str().unwrap_or("").to_string();
+        let Some(model) = db.get_model(model_name) else {
+            return Ok(error(StatusCode::NOT_FOUND, &format!("Model {} not found", model_name)));
+        };
+        if claims.is_some() && model.allow_rules().next().is_some() {
+            return Ok(error(StatusCode::FORBIDDEN, &format!("{} has @@allow rules and is not available in a transaction", model.name)));
+        }
+        let action = action.to_string();
+        return Ok(handle_in_transaction(req, model, &action, &token, &state).await);
+    }
+
+    // Пока открыта интерактивная транзакция, записи вне ее ждут коммита или отката
+    let _writer = if is_write { Some(state.sessions.writer().await) } else { None };
+
+    if model_name == "$backup" && req.method() == Method::GET {
+        let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+        let backup_key = state.backup_key.clone();
+        return match db.clone().run(move |db| create_backup(db, backup_key.as_deref())).await {
+            Ok(body) => Ok(Response::new(Full::new(Bytes::from(body)))),
+            Err(err) => Ok(failed(err))
+        };
+    }
+
+    if model_name == "$export" && req.method() == Method::GET {
+        let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+        return match db.clone().run(export_database).await {
+            Ok(export) => Ok(Response::new(Full::new(Bytes::from(export.to_string())))),
+            Err(err) => Ok(failed(err))
+        };
+    }
+
+    if model_name == "$import" && req.method() == Method::POST {
+        let Ok(whole_body) = req.collect().await else {
+            return Ok(error(StatusCode::BAD_REQUEST, "Failed to get body"));
+        };
+        let Ok(body): Result<Value, _> = serde_json::from_slice(&whole_body.to_bytes()) else {
+            return Ok(error(StatusCode::BAD_REQUEST, "Failed to parse JSON"));
+        };
+        // Либо сама выгрузка, либо `{ "export": ..., "mapping": ... }`
+        let (export, mapping) = match body.get("export").is_some() {
+            true => (body["export"].clone(), body.get("mapping").cloned().unwrap_or(Value::Null)),
+            false => (body, Value::Null)
+        };
+
+        let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+        return match db.clone().run(move |db| import_database(db, &export, &mapping)).await {
+            Ok(report) => {
+                let body = json!({ "imported": report.imported, "skipped": report.skipped });
+                Ok(Response::new(Full::new(Bytes::from(body.to_string()))))
+            }
+            Err(errors) => Ok(error(StatusCode::BAD_REQUEST, &json!({ "errors": errors }).to_string()))
+        };
+    }
+
+    if model_name == "$suggestions" && req.method() == Method::GET {
+        let _permit = if state.index_lab { state.concurrency.acquire(ActionClass::Heavy).await } else { None };
+        let suggestions = blocking(|| state.workload.suggestions(db, state.index_lab));
+        return Ok(Response::new(Full::new(Bytes::from(suggestions.to_string()))));
+    }
+
+    if model_name == "$schema" && req.method() == Method::GET {
+        let Some(name) = action.strip_suffix("/json-schema") else {
+            return Ok(error(StatusCode::NOT_FOUND, &format!("Route {}:{} not found", req.method().as_str(), req.uri())));
+        };
+        let Some(model) = db.get_model(name) else {
+            return Ok(error(StatusCode::NOT_FOUND, &format!("Model {} not found", name)));
+        };
+        let kind = match query_value(&req, "mode") {
+            None | Some("insert") => BodyKind::Insert,
+            Some("update") => BodyKind::Update,
+            Some(mode) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Unknown mode {}", mode)))
+        };
+        let schema = model_json_schema(&db.schema, model, kind);
+        return Ok(Response::new(Full::new(Bytes::from(schema.to_string()))));
+    }
+
+    if model_name == "openapi.json" && action.is_empty() && req.method() == Method::GET {
+        let mut res = Response::new(Full::new(Bytes::from(openapi_document(&db.schema).to_string())));
+        res.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
+        return Ok(res);
+    }
+
+    if model_name == "$backups" && req.method() == Method::GET {
+        let Some(dir) = &state.backup_dir else {
+            return Ok(error(StatusCode::NOT_FOUND, "Scheduled backups are not configured"));
+        };
+        return match list_backups(dir) {
+            Ok(list) => Ok(Response::new(Full::new(Bytes::from(list.to_string())))),
+            Err(err) => Ok(error(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to list backups: {}", err)))
+        };
+    }
+
+    if model_name == "$restore" && req.method() == Method::POST {
+        let Ok(whole_body) = req.collect().await else {
+            return Ok(error(StatusCode::BAD_REQUEST, "Failed to get body"));
+        };
+
+        let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+        let (backup, backup_key) = (whole_body.to_bytes(), state.backup_key.clone());
+        let seq = match db.clone().run(move |db| restore_backup(db, &backup, backup_key.as_deref())).await {
+            Ok(seq) => seq,
+            Err(BackupError::Storage(err)) => return Ok(failed(err)),
+            Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to restore backup: {}", err)))
+        };
+        return Ok(Response::new(Full::new(Bytes::from(format!("{{ \"sequence\": {} }}", seq)))));
+    }
+
+    if model_name == "$stats" && req.method() == Method::GET {
+        let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+        return match db.clone().run(database_stats).await {
+            Ok(stats) => Ok(Response::new(Full::new(Bytes::from(stats.to_string())))),
+            Err(err) => Ok(failed(err))
+        };
+    }
+
+    // GET - только отчет, POST - отчет и исправление
+    if model_name == "$check" && matches!(*req.method(), Method::GET | Method::POST) {
+        let repair = req.method() == Method::POST;
+        let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+        let report = match db.clone().run(move |db| db.check(repair)).await {
+            Ok(report) => report,
+            Err(err) => return Ok(failed(err))
+        };
+        let body = json!({ "checked": report.checked, "problems": report.problems, "repaired": report.repaired });
+        return Ok(Response::new(Full::new(Bytes::from(body.to_string()))));
+    }
+
+    if model_name == "$compact" && req.method() == Method::POST {
+        let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+        let (before, after) = db.clone().run(|db| db.compact()).await;
+        let body = json!({ "sizeBefore": before, "sizeAfter": after, "reclaimed": before.saturating_sub(after) });
+        return Ok(Response::new(Full::new(Bytes::from(body.to_string()))));
+    }
+
     let Some(model) = db.get_model(model_name) else {
         return Ok(error(StatusCode::NOT_FOUND, &format!("Model {} not found", &path[1..slash_index])));
     };
 
+    // С правилами `@@allow` запросу с JWT доступны только действия, к которым правила применяются
+    if claims.is_some() && model.allow_rules().next().is_some() && !matches!(action, "findMany" | "findFirst" | "insert" | "update" | "delete") {
+        return Ok(error(StatusCode::FORBIDDEN, &format!("{} is not available for {} with @@allow rules", action, model.name)));
+    }
+
+    if let Some(name) = action.strip_prefix("x-") {
+        let name = name.to_string();
+        return Ok(handle_extension_action(req, model, &name, &state).await);
+    }
+
+    if matches!(action, "findMany" | "findFirst") && let Some(resp) = wait_for_sequence(&req, &state).await {
+        return Ok(resp);
+    }
+
     match (req.method(), action) {
         (&Method::POST, "insert") => {
 
@@ -46,69 +444,257 @@ async fn handle(req: Request<hyper::body::Incoming>, db: Arc<MarciDB>) -> Result
             };
                 
             // Преобразуем в &str или &[u8] и парсим JSON
-            let Ok(json_val): Result<Value, _> = serde_json::from_slice(&whole_body.to_bytes()) else {
-                return Ok(error(StatusCode::BAD_REQUEST, "Failed to parse JSON"));
+            let mut json_val = match body_format.parse(&whole_body.to_bytes()) {
+                Ok(value) => value,
+                Err(msg) => return Ok(error(StatusCode::BAD_REQUEST, msg))
             };
+            if let Some(Claims(claims)) = &claims && let Err(PolicyError::Denied) = check_create(model, &mut json_val, claims) {
+                return Ok(denied(model));
+            }
+            if let Err(err) = state.extensions.encode_fields(&model.name, &model.fields, &mut json_val) {
+                return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to encode document: {}", err)));
+            }
+            if let Err(err) = db.resolve_keys(&model.fields, &mut json_val) {
+                return Ok(failed(err));
+            }
 
             // Теперь `json_val` — ваш JSON объект, с которым можно работать
             // Например: вставка в БД и т. д.
             // db.insert(json_val.clone()); // пример
 
+            let returning = match parse_returning(&model.fields, &json_val, &db.schema) {
+                Ok(returning) => returning,
+                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to parse select: {:?}", err)))
+            };
+            if let Some(resp) = returning.as_ref().and_then(|select| restricted_includes(db, select, claims.as_ref())) {
+                return Ok(resp);
+            }
+
             let mut structs = vec![];
             let (data, _) = match encode_document(model, &json_val, &mut structs) {
                 Ok(result) => result,
-                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to encode document: {:?}", err)))
+                Err(err) => return Ok(encode_failed(&err))
             };
             
-            let new_id = match db.insert_data(model, &data, &structs) {
+            let _permit = state.concurrency.acquire(ActionClass::Light).await;
+            let new_id = match blocking(|| db.insert_data(model, &data, &structs)) {
                 Ok(result) => result,
-                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to insert document: {:?}", err))) 
+                Err(err) => return Ok(failed(err))
             };
 
             // Возвращаем успешный ответ
-            let body = Bytes::from(format!("{{ \"id\": {new_id} }}"));
-            let resp = Response::new(Full::new(body));
-            Ok(resp)
+            Ok(blocking(|| written_document(&state, model, new_id, returning.as_ref())))
         }
 
         (&Method::GET, "findMany") => {
 
+            if state.memory.is_exceeded() {
+                return Ok(overloaded());
+            }
+            let reservation = state.memory.reserve();
+
             let select = MarciSelect::all(&model.fields);
+            let ids_only = query_value(&req, "idsOnly") == Some("true");
+            let restriction = match row_filter(db, model, AllowOperation::Read, claims.as_ref()) {
+                Ok(restriction) => restriction,
+                Err(resp) => return Ok(resp)
+            };
 
-            let data = db.get_all(model, &select, | ctx | {
-                return decode_document(ctx).unwrap();
-            });
+            let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+            let result = if direct_json(&state, format, ids_only) {
+                let decode = |ctx: DecodeCtx<JsonText>| {
+                    reservation.grow(ctx.data.len());
+                    return decode_json(ctx);
+                };
+                blocking(|| read_all(db, model, &select, restriction, decode)).map(Rows::Json)
+            } else {
+                let decode = | ctx: DecodeCtx<Doc> | {
+                    if ids_only {
+                        return decode_ids(ctx.map(Doc::into_value)).map(Doc::Value);
+                    }
+                    reservation.grow(ctx.data.len());
+                    return decode_row(&state, model, ctx);
+                };
+                blocking(|| read_all(db, model, &select, restriction, decode)).map(Rows::Docs)
+            };
+            let mut data = match result {
+                Ok(data) => data,
+                Err(err) => return Ok(failed(err))
+            };
+            let capped = cap_rows(model, &mut data);
 
-            let body = Bytes::from(Value::Array(data).to_string());
-            let resp = Response::new(Full::new(body));
+            let body = reservation.into_body(data.encode(format, None));
+            let mut resp = formatted(format, body);
+            if capped {
+                resp.headers_mut().insert(CAPPED_HEADER, "true".parse().unwrap());
+            }
             Ok(resp)
         }
 
         (&Method::POST, "findMany") => {
 
+            if state.memory.is_exceeded() {
+                return Ok(overloaded());
+            }
+
             let Ok(whole_body) = req.collect().await else {
                 return Ok(error(StatusCode::BAD_REQUEST, "Failed to get body"));
             };
                 
             // Преобразуем в &str или &[u8] и парсим JSON
-            let Ok(select): Result<Value, _> = serde_json::from_slice(&whole_body.to_bytes()) else {
-                return Ok(error(StatusCode::BAD_REQUEST, "Failed to parse JSON"));
+            let select = match body_format.parse(&whole_body.to_bytes()) {
+                Ok(value) => value,
+                Err(msg) => return Ok(error(StatusCode::BAD_REQUEST, msg))
+            };
+            let with_meta = select.get("$meta").and_then(|v| v.as_bool()).is_some_and(|f| f);
+            let ids_only = select.get("idsOnly").and_then(|v| v.as_bool()).is_some_and(|f| f);
+            let explain = select.get("$explain").and_then(|v| v.as_bool()).is_some_and(|f| f);
+            let as_of = match parse_as_of(&select) {
+                Ok(as_of) => as_of,
+                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to parse query: {:?}", err)))
             };
 
-            let select = match parse_select(&model.fields, &select, &db.schema) {
+            let (select, mut query) = match parse_model_find_args(model, &select, &db.schema, &state.select_plans) {
                 Ok(result) => result,
-                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to insert document: {:?}", err))) 
+                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to parse query: {:?}", err)))
             };
+            if let Err(resp) = restrict_query(db, model, &select, &mut query, claims.as_ref()) {
+                return Ok(resp);
+            }
+            if explain {
+                return Ok(match blocking(|| db.explain(model, &query)) {
+                    Ok(plan) => formatted(format, Bytes::from(format.encode(&plan.to_json()))),
+                    Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, &format!("Failed to explain query: {:?}", err))
+                });
+            }
+            if let Some(max_rows) = model.max_rows() {
+                query.take = Some(query.take.map_or(max_rows + 1, |take| take.min(max_rows + 1)));
+            }
 
-            let data = db.get_all(model, &select, |ctx | {
-                return decode_document(ctx).unwrap();
-            });
+            // Полный обход без фильтра и чтение истории считаются тяжелыми операциями
+            let class = if query.filter.is_empty() || as_of.is_some() { ActionClass::Heavy } else { ActionClass::Light };
+            let _permit = state.concurrency.acquire(class).await;
 
-            let body = Bytes::from(Value::Array(data).to_string());
-            let resp = Response::new(Full::new(body));
+            let reservation = state.memory.reserve();
+            let result = if direct_json(&state, format, ids_only) {
+                let decode = |ctx: DecodeCtx<JsonText>| {
+                    reservation.grow(ctx.data.len());
+                    return decode_json(ctx);
+                };
+                blocking(|| read_page(db, model, &select, &query, as_of, decode)).map(|(data, cursor)| (Rows::Json(data), cursor))
+            } else {
+                let decode = |ctx: DecodeCtx<Doc>| {
+                    // Только id документов и связей, поля не декодируются
+                    if ids_only {
+                        return decode_ids(ctx.map(Doc::into_value)).map(Doc::Value);
+                    }
+                    reservation.grow(ctx.data.len());
+                    return decode_row(&state, model, ctx);
+                };
+                blocking(|| read_page(db, model, &select, &query, as_of, decode)).map(|(data, cursor)| (Rows::Docs(data), cursor))
+            };
+            let (mut data, next_cursor) = match result {
+                Ok(data) => data,
+                Err(HistoryError::Read(err)) => return Ok(failed(err)),
+                Err(HistoryError::Pruned { seq, since }) => {
+                    return Ok(error(StatusCode::UNPROCESSABLE_ENTITY, &format!("History before journal record {} ({}) was pruned", seq, since)));
+                }
+            };
+            // Статистика только по выполненным запросам: отклоненный или упавший запрос не должен влиять на рекомендации
+            state.workload.record(model, &query);
+
+            let capped = cap_rows(model, &mut data);
+            // Обрезанная страница не должна продолжаться с документа, которого клиент не получил
+            let next_cursor = if capped { None } else { next_cursor };
+
+            // `$meta: true`: ответ `{ data, meta }` с количеством прочитанных по каждой связи записей
+            // и курсором следующей страницы
+            let meta = with_meta.then(|| json!({ "includes": fetched_rows(&select, &model.fields), "capped": capped, "nextCursor": next_cursor }));
+            let body = reservation.into_body(data.encode(format, meta));
+            let mut resp = formatted(format, body);
+            if capped {
+                resp.headers_mut().insert(CAPPED_HEADER, "true".parse().unwrap());
+            }
             Ok(resp)
         }
 
+        #[cfg(feature = "arrow")]
+        (&Method::GET, "arrow") => {
+            // `?fields=a,b` - только эти колонки, иначе все примитивные поля и связи
+            let names = query_value(&req, "fields").map(percent_decode);
+            let names: Option<Vec<&str>> = names.as_deref().map(|names| names.split(',').collect());
+
+            let _permit = state.concurrency.acquire(ActionClass::Heavy).await;
+            let body = match arrow_stream::export_arrow(db, model, names.as_deref()) {
+                Ok(body) => body,
+                Err(arrow_stream::ArrowExportError::Decode(err)) => return Ok(corrupted(err)),
+                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to export columns: {}", err)))
+            };
+            let mut res = Response::new(Full::new(Bytes::from(body)));
+            res.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/vnd.apache.arrow.stream".parse().unwrap());
+            Ok(res)
+        }
+
+        (&Method::GET, "byIndex") => {
+            let Some(field_name) = query_value(&req, "field") else {
+                return Ok(error(StatusCode::BAD_REQUEST, "field parameter required"));
+            };
+            let field_name = percent_decode(field_name);
+            let Some(field) = model.fields.iter().find(|f| f.name == field_name) else {
+                return Ok(error(StatusCode::BAD_REQUEST, &format!("Field {} not found", field_name)));
+            };
+            if value_index(field).is_none() {
+                return Ok(error(StatusCode::BAD_REQUEST, &format!("Field {} is not indexed", field_name)));
+            }
+            let values: Vec<Value> = query_values(&req, "value").into_iter()
+                .map(|value| query_json(&field.ty, percent_decode(value)))
+                .collect();
+            if values.is_empty() {
+                return Ok(error(StatusCode::BAD_REQUEST, "value parameter required"));
+            }
+
+            let select = MarciSelect::all(&model.fields);
+            let _permit = state.concurrency.acquire(ActionClass::Light).await;
+            let data = match blocking(|| db.find_by_index(model, field, &values, &select, |ctx| decode_with_codecs(&state, model, ctx))) {
+                Ok(Some(data)) => data,
+                Ok(None) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Invalid value for {}", field_name))),
+                Err(err) => return Ok(failed(err))
+            };
+            Ok(formatted(format, Bytes::from(format.encode(&Value::Array(data)))))
+        }
+
+        (&Method::POST, "findFirst") => {
+
+            let Ok(whole_body) = req.collect().await else {
+                return Ok(error(StatusCode::BAD_REQUEST, "Failed to get body"));
+            };
+
+            let args = match body_format.parse(&whole_body.to_bytes()) {
+                Ok(value) => value,
+                Err(msg) => return Ok(error(StatusCode::BAD_REQUEST, msg))
+            };
+
+            let (select, mut query) = match parse_model_find_args(model, &args, &db.schema, &state.select_plans) {
+                Ok(result) => result,
+                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to parse query: {:?}", err)))
+            };
+            if let Err(resp) = restrict_query(db, model, &select, &mut query, claims.as_ref()) {
+                return Ok(resp);
+            }
+            query.take = Some(1);
+
+            let _permit = state.concurrency.acquire(ActionClass::Light).await;
+            let item = match blocking(|| db.find_many(model, &select, &query, |ctx | {
+                return decode_with_codecs(&state, model, ctx);
+            })) {
+                Ok(mut data) => data.pop().unwrap_or(Value::Null),
+                Err(err) => return Ok(failed(err))
+            };
+            state.workload.record(model, &query);
+
+            Ok(formatted(format, Bytes::from(format.encode(&item))))
+        }
+
         (&Method::POST, "update") => {
 
             let Ok(whole_body) = req.collect().await else {
@@ -116,48 +702,108 @@ async fn handle(req: Request<hyper::body::Incoming>, db: Arc<MarciDB>) -> Result
             };
                 
             // Преобразуем в &str или &[u8] и парсим JSON
-            let Ok(json_val): Result<Value, _> = serde_json::from_slice(&whole_body.to_bytes()) else {
-                return Ok(error(StatusCode::BAD_REQUEST, "Failed to parse JSON"));
+            let mut json_val = match body_format.parse(&whole_body.to_bytes()) {
+                Ok(value) => value,
+                Err(msg) => return Ok(error(StatusCode::BAD_REQUEST, msg))
             };
-            let Some(id) = json_val.get("id").and_then(|a| a.as_u64()) else {
-                return Ok(error(StatusCode::BAD_REQUEST, "ID field required"));
+            let id = match body_id(db, model, &json_val) {
+                Ok(id) => id,
+                Err(resp) => return Ok(resp)
             };
+            if let Some(Claims(claims)) = &claims && let Err(PolicyError::Denied) = check_update(model, &json_val, claims) {
+                return Ok(denied(model));
+            }
+            if let Err(resp) = check_row(db, model, id, AllowOperation::Update, claims.as_ref()) {
+                return Ok(resp);
+            }
+            if let Err(err) = state.extensions.encode_fields(&model.name, &model.fields, &mut json_val) {
+                return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to encode document: {}", err)));
+            }
+            if let Err(err) = db.resolve_keys(&model.fields, &mut json_val) {
+                return Ok(failed(err));
+            }
+
+            let returning = match parse_returning(&model.fields, &json_val, &db.schema) {
+                Ok(returning) => returning,
+                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to parse select: {:?}", err)))
+            };
+            if let Some(resp) = returning.as_ref().and_then(|select| restricted_includes(db, select, claims.as_ref())) {
+                return Ok(resp);
+            }
 
             let mut structs = vec![];
-            let (new_data, changed_mask) = match encode_document(model, &json_val, &mut structs) {
+            let (new_data, changed_mask) = match encode_update(model, &json_val, &mut structs) {
                 Ok(result) => result,
-                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to encode document: {:?}", err)))
+                Err(err) => return Ok(encode_failed(&err))
             };
 
-            let item_id = match db.update(model,  id, &new_data, changed_mask, &structs) {
+            let _permit = state.concurrency.acquire(ActionClass::Light).await;
+            let item_id = match blocking(|| db.update(model,  id, &new_data, changed_mask, &structs)) {
                 Ok(result) => result,
-                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to update document: {:?}", err))) 
+                Err(err) => return Ok(failed(err))
             };
 
-            let body = Bytes::from(format!("{{ \"id\": {} }}", item_id));
-            let resp = Response::new(Full::new(body));
-            Ok(resp)
+            Ok(blocking(|| written_document(&state, model, item_id, returning.as_ref())))
         }
 
         (&Method::POST, "delete") => {
             let Ok(whole_body) = req.collect().await else {
                 return Ok(error(StatusCode::BAD_REQUEST, "Failed to get body"));
             };
-            let Ok(json_val): Result<Value, _> = serde_json::from_slice(&whole_body.to_bytes()) else {
-                return Ok(error(StatusCode::BAD_REQUEST, "Failed to parse JSON"));
+            let json_val = match body_format.parse(&whole_body.to_bytes()) {
+                Ok(value) => value,
+                Err(msg) => return Ok(error(StatusCode::BAD_REQUEST, msg))
             };
-            let Some(id) = json_val.get("id").and_then(|a| a.as_u64()) else {
-                return Ok(error(StatusCode::BAD_REQUEST, "ID field required"));
+            let id = match body_id(db, model, &json_val) {
+                Ok(id) => id,
+                Err(resp) => return Ok(resp)
             };
+            if let Err(resp) = check_row(db, model, id, AllowOperation::Delete, claims.as_ref()) {
+                return Ok(resp);
+            }
 
-            let deleted = db.delete(model, id);
-            if !deleted {
-                return Ok(error(StatusCode::BAD_REQUEST, "Object not found"));
+            let _permit = state.concurrency.acquire(ActionClass::Light).await;
+            if let Err(err) = blocking(|| db.delete(model, id)) {
+                return Ok(failed(err));
             }
 
-            let body = Bytes::from(format!("{{ \"id\": {} }}", id));
-            let resp = Response::new(Full::new(body));
-            Ok(resp)
+            Ok(written(db, id))
+        }
+
+        (&Method::POST, "merge") => {
+            let Ok(whole_body) = req.collect().await else {
+                return Ok(error(StatusCode::BAD_REQUEST, "Failed to get body"));
+            };
+            let json_val = match body_format.parse(&whole_body.to_bytes()) {
+                Ok(value) => value,
+                Err(msg) => return Ok(error(StatusCode::BAD_REQUEST, msg))
+            };
+            let (Some(target), Some(source)) = (json_val.get("target").and_then(|a| a.as_u64()), json_val.get("source").and_then(|a| a.as_u64())) else {
+                return Ok(error(StatusCode::BAD_REQUEST, "target and source fields required"));
+            };
+            if target == source {
+                return Ok(error(StatusCode::BAD_REQUEST, "Cannot merge a document into itself"));
+            }
+            let strategy = match json_val.get("strategy").and_then(|a| a.as_str()) {
+                None | Some("keepTarget") => MergeStrategy::KeepTarget,
+                Some("fillNulls") => MergeStrategy::FillNulls,
+                Some("preferSource") => MergeStrategy::PreferSource,
+                Some(strategy) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Unknown merge strategy {}", strategy)))
+            };
+            let returning = match parse_returning(&model.fields, &json_val, &db.schema) {
+                Ok(returning) => returning,
+                Err(err) => return Ok(error(StatusCode::BAD_REQUEST, &format!("Failed to parse select: {:?}", err)))
+            };
+            if let Some(resp) = returning.as_ref().and_then(|select| restricted_includes(db, select, claims.as_ref())) {
+                return Ok(resp);
+            }
+
+            let _permit = state.concurrency.acquire(ActionClass::Light).await;
+            if let Err(err) = blocking(|| db.merge(model, target, source, strategy)) {
+                return Ok(failed(err));
+            }
+
+            Ok(blocking(|| written_document(&state, model, target, returning.as_ref())))
         }
 
         _ => {
@@ -166,49 +812,1024 @@ async fn handle(req: Request<hyper::body::Incoming>, db: Arc<MarciDB>) -> Result
     }
 }
 
-fn error(code: StatusCode, msg: &str) -> Response<Full<Bytes>> {
-    let mut res = Response::new(Full::new(Bytes::from(msg.to_string())));
-    *res.status_mut() = code;
+/// Запрос меняет базу: ждет интерактивную транзакцию и недоступен реплике или серверу без блокировки лидера
+fn is_write(model_name: &str, action: &str, method: &Method) -> bool {
+    method != Method::GET
+        && (matches!(model_name, "$tx" | "$import" | "$restore" | "$check" | "$compact")
+            || matches!(action, "insert" | "update" | "delete" | "merge")
+            || action.starts_with("x-"))
+}
+
+/// `/$tx/begin`, `/$tx/commit`, `/$tx/rollback`. Токен транзакции - в заголовке X-Transaction
+async fn handle_transaction<B>(req: &Request<B>, action: &str, state: &ServerState) -> Response<Full<Bytes>> {
+    if req.method() != Method::POST {
+        return error(StatusCode::NOT_FOUND, &format!("Route {}:{} not found", req.method().as_str(), req.uri()));
+    }
+    if action == "begin" {
+        return match state.sessions.begin(&state.db).await {
+            Ok(token) => {
+                let body = json!({ "token": token, "timeout": state.sessions.timeout().as_millis() as u64 });
+                Response::new(Full::new(Bytes::from(body.to_string())))
+            }
+            Err(err) => session_error(err)
+        };
+    }
+
+    let Some(token) = req.headers().get(TRANSACTION_HEADER).and_then(|v| v.to_str().ok()) else {
+        return error(StatusCode::BAD_REQUEST, "X-Transaction header required");
+    };
+    match action {
+        "commit" => match state.sessions.commit(token) {
+            Ok(seq) => {
+                let mut res = Response::new(Full::new(Bytes::from(format!("{{ \"sequence\": {} }}", seq))));
+                res.headers_mut().insert(SEQUENCE_HEADER, seq.into());
+                res
+            }
+            Err(err) => session_error(err)
+        },
+        "rollback" => match state.sessions.rollback(token) {
+            Ok(()) => Response::new(Full::new(Bytes::from("{ \"rolledBack\": true }"))),
+            Err(err) => session_error(err)
+        },
+        _ => error(StatusCode::NOT_FOUND, &format!("Route {}:{} not found", req.method().as_str(), req.uri()))
+    }
+}
+
+/// Запрос к модели внутри интерактивной транзакции: чтение видит ее незакоммиченные изменения,
+/// запись не коммитится до `/$tx/commit`
+async fn handle_in_transaction<B: Body<Data = Bytes>>(req: Request<B>, model: &Model, action: &str, token: &str, state: &ServerState) -> Response<Full<Bytes>> {
+    let db = &state.db;
+    if req.method() != Method::POST || !matches!(action, "findMany" | "findFirst" | "insert" | "update" | "delete") {
+        return error(StatusCode::BAD_REQUEST, &format!("{} is not available in a transaction", action));
+    }
+    let body_format = RequestFormat::from_headers(req.headers());
+    let Ok(whole_body) = req.collect().await else {
+        return error(StatusCode::BAD_REQUEST, "Failed to get body");
+    };
+    let mut json_val = match body_format.parse(&whole_body.to_bytes()) {
+        Ok(value) => value,
+        Err(msg) => return error(StatusCode::BAD_REQUEST, msg)
+    };
+
+    match action {
+        "findMany" | "findFirst" => {
+            let (select, mut query) = match parse_model_find_args(model, &json_val, &db.schema, &state.select_plans) {
+                Ok(result) => result,
+                Err(err) => return error(StatusCode::BAD_REQUEST, &format!("Failed to parse query: {:?}", err))
+            };
+            if action == "findFirst" {
+                query.take = Some(1);
+            }
+            let result = blocking(|| state.sessions.with(token, |tx| {
+                db.find_page_in(tx, model, &select, &query, |ctx| decode_with_codecs(state, model, ctx))
+            }));
+            let mut data = match result {
+                Ok(Ok((data, _))) => data,
+                Ok(Err(err)) => return failed(err),
+                Err(err) => return session_error(err)
+            };
+            let body = match action {
+                "findFirst" => data.pop().unwrap_or(Value::Null),
+                _ => Value::Array(data)
+            };
+            Response::new(Full::new(Bytes::from(body.to_string())))
+        }
+
+        "insert" | "update" => {
+            let id = match action {
+                "update" => match body_id(db, model, &json_val) {
+                    Ok(id) => Some(id),
+                    Err(resp) => return resp
+                },
+                _ => None
+            };
+            if let Err(err) = state.extensions.encode_fields(&model.name, &model.fields, &mut json_val) {
+                return error(StatusCode::BAD_REQUEST, &format!("Failed to encode document: {}", err));
+            }
+            if let Err(err) = db.resolve_keys(&model.fields, &mut json_val) {
+                return failed(err);
+            }
+            let mut structs = vec![];
+            let encoded = match id {
+                Some(_) => encode_update(model, &json_val, &mut structs),
+                None => encode_document(model, &json_val, &mut structs),
+            };
+            let (data, changed_mask) = match encoded {
+                Ok(result) => result,
+                Err(err) => return encode_failed(&err)
+            };
+            let result = blocking(|| state.sessions.with(token, |tx| match id {
+                Some(id) => db.update_in(tx, model, id, &data, changed_mask, &structs),
+                None => db.insert_data_in(tx, model, &data, &structs),
+            }));
+            match result {
+                Ok(Ok(id)) => Response::new(Full::new(Bytes::from(format!("{{ \"id\": {} }}", id)))),
+                Ok(Err(err)) => failed(err),
+                Err(err) => session_error(err)
+            }
+        }
+
+        _ => {
+            let id = match body_id(db, model, &json_val) {
+                Ok(id) => id,
+                Err(resp) => return resp
+            };
+            match blocking(|| state.sessions.with(token, |tx| db.delete_in(tx, model, id))) {
+                Ok(Ok(())) => Response::new(Full::new(Bytes::from(format!("{{ \"id\": {} }}", id)))),
+                Ok(Err(err)) => failed(err),
+                Err(err) => session_error(err)
+            }
+        }
+    }
+}
+
+/// Работа с базой в обработчике, где выборка и запрос заимствуют схему и не могут уйти в `spawn_blocking`.
+/// Поток остается занят транзакцией canopydb, но tokio на это время передает его задачи и соединения другим потокам
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    tokio::task::block_in_place(f)
+}
+
+fn session_error(err: SessionError) -> Response<Full<Bytes>> {
+    match err {
+        SessionError::Busy => error(StatusCode::CONFLICT, "Another transaction is in progress"),
+        SessionError::NotFound => error(StatusCode::NOT_FOUND, "Transaction not found or expired"),
+        SessionError::Storage(err) => failed(err),
+    }
+}
+
+fn handle_replication<B>(req: &Request<B>, action: &str, state: &ServerState) -> Response<Full<Bytes>> {
+    let replication = &state.replication;
+
+    match (req.method(), action) {
+        (&Method::GET, "") => {
+            match replication.status() {
+                Ok(status) => Response::new(Full::new(Bytes::from(status.to_string()))),
+                Err(err) => failed(err)
+            }
+        }
+
+        (&Method::GET, "log") => {
+            let after = query_param(req, "after").unwrap_or(0);
+            let limit = query_param(req, "limit").map(|v| v as usize).unwrap_or(REPLICATION_LOG_LIMIT).min(REPLICATION_LOG_LIMIT);
+            let replica_id = req.headers().get(REPLICA_ID_HEADER).and_then(|v| v.to_str().ok());
+
+            match replication.read_log(replica_id, after, limit) {
+                Ok((last, body)) => {
+                    let mut res = Response::new(Full::new(Bytes::from(body)));
+                    res.headers_mut().insert(LAST_SEQ_HEADER, last.into());
+                    res
+                }
+                Err(LogError::Pruned) => error(StatusCode::GONE, "Journal does not contain requested sequence, resync required"),
+            }
+        }
+
+        (&Method::GET, "snapshot") => {
+            match replication.snapshot() {
+                Ok(snapshot) => Response::new(Full::new(Bytes::from(snapshot))),
+                Err(err) => failed(err)
+            }
+        }
+
+        (&Method::POST, "resync") => {
+            if !replication.request_resync() {
+                return error(StatusCode::BAD_REQUEST, "Resync is only available on replicas");
+            }
+            Response::new(Full::new(Bytes::from("{ \"resync\": true }")))
+        }
+
+        _ => error(StatusCode::NOT_FOUND, &format!("Route {}:{} not found", req.method().as_str(), req.uri()))
+    }
+}
+
+/// Числовой параметр из query string
+fn query_param<B>(req: &Request<B>, name: &str) -> Option<u64> {
+    query_value(req, name)?.parse().ok()
+}
+
+fn query_value<'a, B>(req: &'a Request<B>, name: &str) -> Option<&'a str> {
+    req.uri().query()?.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Все значения параметра query string (`value=a&value=b`)
+fn query_values<'a, B>(req: &'a Request<B>, name: &str) -> Vec<&'a str> {
+    let Some(query) = req.uri().query() else { return vec![] };
+    query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+        .collect()
+}
+
+/// Раскодирует `%XX` и `+` в значении из query string
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i+1..i+3).and_then(|hex| str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'+', _) => out.push(b' '),
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 2;
+            }
+            (byte, _) => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Значение из query string в JSON для поля: строки остаются строками, остальное разбирается как JSON
+/// (`42`, `true`), а если не разбирается - тоже передается строкой (ISO-дата)
+fn query_json(ty: &FieldType, value: String) -> Value {
+    if matches!(ty, FieldType::Primitive(PrimitiveFieldType::String | PrimitiveFieldType::Enum(_))) {
+        return Value::String(value);
+    }
+    serde_json::from_str(&value).unwrap_or(Value::String(value))
+}
+
+/// id документа из тела update/delete: `id` либо значение поля `@id` модели
+#[allow(clippy::result_large_err)]
+fn body_id(db: &MarciDB, model: &Model, json: &Value) -> Result<u64, Response<Full<Bytes>>> {
+    if let Some(id) = json.get("id").and_then(|a| a.as_u64()) {
+        return Ok(id);
+    }
+    let Some(key) = model.key_field().and_then(|field| json.get(&field.name)) else {
+        return Err(error(StatusCode::BAD_REQUEST, "ID field required"));
+    };
+    match db.find_key(model, key) {
+        Ok(id) => id.ok_or_else(|| error(StatusCode::BAD_REQUEST, "Object not found")),
+        Err(err) => Err(failed(err))
+    }
+}
+
+/// Роли и доступ к моделям для JWT из файла `--jwt-permissions`. Без него сервер не запускается
+fn load_jwt_auth(secret: &str, permissions: Option<&str>) -> JwtAuth {
+    let Some(path) = permissions else {
+        eprintln!("--jwt-secret requires --jwt-permissions");
+        std::process::exit(1);
+    };
+    let permissions = fs::read(path).map_err(|err| err.to_string())
+        .and_then(|bytes| serde_json::from_slice::<Value>(&bytes).map_err(|err| err.to_string()))
+        .and_then(|permissions| JwtAuth::new(secret, &permissions));
+    match permissions {
+        Ok(jwt) => jwt,
+        Err(err) => {
+            eprintln!("Failed to load JWT permissions from {}: {}", path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_maintenance(db: &MarciDB, config: &Config, backup_dir: Option<PathBuf>, backup_key: Option<&BackupKey>) {
+    if let Some(dir) = backup_dir {
+        let due = last_backup_time(&dir)
+            .and_then(|time| SystemTime::now().duration_since(time).ok())
+            .is_none_or(|elapsed| elapsed >= config.backup_interval);
+        if due {
+            match write_backup_file(db, &dir, backup_key) {
+                Ok(path) => println!("Backup written to {}", path.display()),
+                Err(err) => eprintln!("Failed to write backup: {}", err),
+            }
+        }
+        if let Err(err) = prune_backups(&dir, config.backup_retention) {
+            eprintln!("Failed to prune backups: {}", err);
+        }
+    }
+
+    if let Some(retention) = config.journal_retention {
+        let before = now_millis().saturating_sub(retention.as_millis() as u64);
+        match prune_journal(&db.db, before) {
+            Ok(removed) if removed > 0 => println!("Pruned {} journal records", removed),
+            Ok(_) => {}
+            Err(err) => eprintln!("Failed to prune journal: {}", err),
+        }
+    }
+}
+
+/// Ошибка без поля и деталей: `code` соответствует статусу ответа
+fn error(status: StatusCode, msg: &str) -> Response<Full<Bytes>> {
+    error_body(status, ErrorBody::new(status_code(status), msg))
+}
+
+/// Ответ с ошибкой `{ code, message, field?, details? }`
+fn error_body(status: StatusCode, body: ErrorBody) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(Bytes::from(body.to_json().to_string())));
+    *res.status_mut() = status;
+    res.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
     res
 }
 
+fn status_code(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        400 => "bad_request",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        409 => "conflict",
+        410 => "gone",
+        413 => "payload_too_large",
+        422 => "unprocessable_entity",
+        503 => "unavailable",
+        307 => "redirect",
+        _ => "internal_error",
+    }
+}
+
+/// Документ не прошел кодирование: тип поля, обязательное поле, ограничения `@min`/`@max`
+fn encode_failed(err: impl Into<ErrorBody>) -> Response<Full<Bytes>> {
+    error_body(StatusCode::BAD_REQUEST, err.into())
+}
+
+/// С `--api-keys`/`--read-keys` или `--jwt-secret` каждый запрос несет `Authorization: Bearer <ключ или JWT>`.
+/// Чтение - GET и `findMany`/`findFirst`, остальное (записи, транзакции, служебные POST) требует доступа на запись.
+/// Ключ дает доступ ко всем моделям, JWT - по ролям из `--jwt-permissions`
+/// Ok(Some) - запрос с JWT, его claims нужны правилам `@@allow`
+#[allow(clippy::result_large_err)]
+fn authorize<B>(req: &Request<B>, state: &ServerState) -> Result<Option<Claims>, Response<Full<Bytes>>> {
+    if !state.api_keys.is_enabled() && state.jwt.is_none() {
+        return Ok(None);
+    }
+    let path = req.uri().path().get(1..).unwrap_or("");
+    let (model_name, action) = path.split_once('/').unwrap_or((path, ""));
+    let required = required_access(req.method(), model_name, action, req.headers().contains_key(TRANSACTION_HEADER));
+    let reads = required == Access::Read;
+
+    // Токен из трех частей через точку - JWT, иначе ключ API
+    let token = bearer_token(req.headers()).filter(|token| token.matches('.').count() == 2);
+    if let (Some(jwt), Some(token)) = (&state.jwt, token) {
+        let claims = match jwt.verify(token) {
+            Ok(claims) => claims,
+            Err(err) => return Err(unauthorized(&format!("Invalid token: {}", err)))
+        };
+        return match jwt.access(&claims, model_name) {
+            Some(access) if access >= required => Ok(Some(Claims(claims))),
+            _ => Err(error(StatusCode::FORBIDDEN, &format!("Token does not allow {} access to {}", if reads { "read" } else { "write" }, model_name)))
+        };
+    }
+
+    let Some(access) = state.api_keys.access(req.headers()) else {
+        return Err(unauthorized("Valid API key required"));
+    };
+    if access < required {
+        return Err(error(StatusCode::FORBIDDEN, "API key is read-only"));
+    }
+    Ok(None)
+}
+
+/// Условие `@@allow` на документы для операции запроса с JWT. None - запрос без JWT или у модели нет правил
+#[allow(clippy::result_large_err)]
+fn row_filter<'a>(db: &'a MarciDB, model: &'a Model, op: AllowOperation, claims: Option<&Claims>) -> Result<Option<MarciFilter<'a>>, Response<Full<Bytes>>> {
+    let Some(Claims(claims)) = claims else {
+        return Ok(None);
+    };
+    let condition = match allowed_where(model, op, claims) {
+        Ok(Some(condition)) => condition,
+        Ok(None) => return Ok(None),
+        Err(PolicyError::Denied) => return Err(denied(model))
+    };
+    // Значение claim, которое не подходит к типу поля, не совпадет ни с одним документом
+    match parse_where(&model.fields, &condition, &db.schema) {
+        Ok(filter) => Ok(Some(filter)),
+        Err(_) => Err(denied(model))
+    }
+}
+
+/// findMany/findFirst с JWT: к where запроса добавляется условие `@@allow` на чтение
+#[allow(clippy::result_large_err)]
+fn restrict_query<'a>(db: &'a MarciDB, model: &'a Model, select: &MarciSelect, query: &mut MarciQuery<'a>, claims: Option<&Claims>) -> Result<(), Response<Full<Bytes>>> {
+    if let Some(resp) = restricted_includes(db, select, claims) {
+        return Err(resp);
+    }
+    if let Some(filter) = row_filter(db, model, AllowOperation::Read, claims)? {
+        query.restrict(filter);
+    }
+    Ok(())
+}
+
+/// Связанные документы правилами не фильтруются, поэтому запрос с JWT не может включать модели с `@@allow`
+fn restricted_includes(db: &MarciDB, select: &MarciSelect, claims: Option<&Claims>) -> Option<Response<Full<Bytes>>> {
+    if claims.is_none() || check_includes(&db.schema, select).is_ok() {
+        return None;
+    }
+    Some(error(StatusCode::FORBIDDEN, "Models with @@allow rules cannot be included"))
+}
+
+/// update/delete с JWT: документ должен подходить под правило операции. Отсутствующий документ
+/// пропускается, ошибку вернет сама операция
+#[allow(clippy::result_large_err)]
+fn check_row(db: &MarciDB, model: &Model, id: u64, op: AllowOperation, claims: Option<&Claims>) -> Result<(), Response<Full<Bytes>>> {
+    let Some(filter) = row_filter(db, model, op, claims)? else {
+        return Ok(());
+    };
+    let select = MarciSelect::all(&model.fields);
+    match blocking(|| db.find_by_id(model, id, &select, |ctx| Ok(filter.matches(ctx.id, ctx.data, ctx.payload_offset)))) {
+        Ok(Some(false)) => Err(denied(model)),
+        Ok(_) => Ok(()),
+        Err(err) => Err(failed(err))
+    }
+}
+
+fn denied(model: &Model) -> Response<Full<Bytes>> {
+    error(StatusCode::FORBIDDEN, &format!("Access to {} denied by @@allow rules", model.name))
+}
+
+/// Документы в формате из `Accept`: JSON, MessagePack или CBOR
+fn formatted(format: ResponseFormat, body: Bytes) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(body));
+    res.headers_mut().insert(hyper::header::CONTENT_TYPE, format.content_type());
+    res
+}
+
+fn unauthorized(msg: &str) -> Response<Full<Bytes>> {
+    let mut res = error(StatusCode::UNAUTHORIZED, msg);
+    res.headers_mut().insert(hyper::header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+    res
+}
+
+fn payload_too_large(limit: usize) -> Response<Full<Bytes>> {
+    let mut res = error(StatusCode::PAYLOAD_TOO_LARGE, &format!("Request body exceeds {} bytes", limit));
+    res.headers_mut().insert(hyper::header::CONNECTION, "close".parse().unwrap());
+    res
+}
+
+fn overloaded() -> Response<Full<Bytes>> {
+    let mut res = error(StatusCode::SERVICE_UNAVAILABLE, "Server is overloaded, retry later");
+    res.headers_mut().insert(hyper::header::RETRY_AFTER, RETRY_AFTER_SECS.into());
+    res
+}
+
+/// Ответ на запись: id документа и номер записи в журнале, который можно передать в X-Min-Sequence.
+/// Запись уже закоммичена, поэтому последний номер журнала не меньше ее собственного
+fn written(db: &MarciDB, id: u64) -> Response<Full<Bytes>> {
+    let seq = match db.last_seq() {
+        Ok(seq) => seq,
+        Err(err) => return failed(err)
+    };
+    let mut res = Response::new(Full::new(Bytes::from(format!("{{ \"id\": {}, \"sequence\": {} }}", id, seq))));
+    res.headers_mut().insert(SEQUENCE_HEADER, seq.into());
+    res
+}
+
+/// Ответ на insert/update с `select`/`include`: записанный документ вместо `{ id, sequence }`, номер записи - в X-Sequence
+fn written_document(state: &ServerState, model: &Model, id: u64, select: Option<&MarciSelect>) -> Response<Full<Bytes>> {
+    let Some(select) = select else {
+        return written(&state.db, id);
+    };
+    let seq = match state.db.last_seq() {
+        Ok(seq) => seq,
+        Err(err) => return failed(err)
+    };
+    let doc = match state.db.find_by_id(model, id, select, |ctx| decode_with_codecs(state, model, ctx)) {
+        Ok(doc) => doc.unwrap_or(Value::Null),
+        Err(err) => return failed(err)
+    };
+    let mut res = Response::new(Full::new(Bytes::from(doc.to_string())));
+    res.headers_mut().insert(SEQUENCE_HEADER, seq.into());
+    res
+}
+
+/// Ждет, пока журнал дойдет до X-Min-Sequence. Если реплика не догнала основной сервер за MIN_SEQUENCE_TIMEOUT,
+/// клиент перенаправляется на него, иначе получает 503
+async fn wait_for_sequence<B>(req: &Request<B>, state: &ServerState) -> Option<Response<Full<Bytes>>> {
+    let value = req.headers().get(MIN_SEQUENCE_HEADER)?;
+    let Some(min_seq) = value.to_str().ok().and_then(|v| v.trim().parse::<u64>().ok()) else {
+        return Some(error(StatusCode::BAD_REQUEST, "Invalid X-Min-Sequence"));
+    };
+    // Основной сервер сам выдает номера, ждать ему нечего
+    if !state.replication.is_replica() {
+        return None;
+    }
+
+    let started = Instant::now();
+    loop {
+        match state.db.last_seq() {
+            Ok(seq) if seq >= min_seq => break,
+            Ok(_) => {}
+            Err(err) => return Some(failed(err)),
+        }
+        if started.elapsed() >= MIN_SEQUENCE_TIMEOUT {
+            return Some(match state.replication.primary() {
+                Some(primary) => {
+                    let mut res = error(StatusCode::TEMPORARY_REDIRECT, "Replica is behind X-Min-Sequence");
+                    let location = format!("http://{}{}", primary, req.uri());
+                    res.headers_mut().insert(hyper::header::LOCATION, location.parse().unwrap());
+                    res
+                }
+                None => overloaded()
+            });
+        }
+        tokio::time::sleep(MIN_SEQUENCE_POLL).await;
+    }
+    None
+}
+
+/// `/<Model>/x-<name>`: действие, зарегистрированное расширением
+async fn handle_extension_action<B: Body<Data = Bytes>>(req: Request<B>, model: &Model, name: &str, state: &ServerState) -> Response<Full<Bytes>> {
+    let Ok(whole_body) = req.collect().await else {
+        return error(StatusCode::BAD_REQUEST, "Failed to get body");
+    };
+    let bytes = whole_body.to_bytes();
+    let body = if bytes.is_empty() {
+        Value::Null
+    } else {
+        let Ok(body) = serde_json::from_slice(&bytes) else {
+            return error(StatusCode::BAD_REQUEST, "Failed to parse JSON");
+        };
+        body
+    };
+
+    let ctx = ActionContext { db: &state.db, model, name, body: &body };
+    match blocking(|| state.extensions.action(&ctx)) {
+        Some(Ok(result)) => Response::new(Full::new(Bytes::from(result.to_string()))),
+        Some(Err(err)) => error(StatusCode::BAD_REQUEST, &err),
+        None => error(StatusCode::NOT_FOUND, &format!("Action x-{} not found", name))
+    }
+}
+
+fn decode_with_codecs(state: &ServerState, model: &Model, ctx: DecodeCtx<Value>) -> Result<Value, DecodeError> {
+    let mut value = decode_document(ctx)?;
+    state.extensions.decode_fields(&state.db.schema, &model.name, &model.fields, &mut value);
+    Ok(value)
+}
+
+/// findMany в JSON без кодеков расширений и `idsOnly` пишет документы сразу JSON-текстом, минуя `Value`
+fn direct_json(state: &ServerState, format: ResponseFormat, ids_only: bool) -> bool {
+    format == ResponseFormat::Json && !ids_only && !state.extensions.has_codecs()
+}
+
+/// Документы GET findMany. С `@@maxRows` читается на один документ больше предела, чтобы понять, что выборка обрезана
+fn read_all<U, F>(db: &MarciDB, model: &Model, select: &MarciSelect, restriction: Option<MarciFilter>, decode: F) -> Result<Vec<U>, MarciError>
+where
+    U: Send,
+    F: Fn(DecodeCtx<U>) -> Result<U, DecodeError> + Sync,
+{
+    match (model.max_rows(), restriction) {
+        (None, None) => db.get_all(model, select, decode),
+        (max_rows, restriction) => {
+            let mut query = MarciQuery { take: max_rows.map(|max_rows| max_rows + 1), ..MarciQuery::all() };
+            if let Some(filter) = restriction {
+                query.restrict(filter);
+            }
+            db.find_many(model, select, &query, decode)
+        }
+    }
+}
+
+/// Страница POST findMany и курсор следующей, с `asOf` - из истории без курсора
+fn read_page<U, F>(db: &MarciDB, model: &Model, select: &MarciSelect, query: &MarciQuery, as_of: Option<u64>, decode: F) -> Result<(Vec<U>, Option<Vec<Value>>), HistoryError>
+where
+    U: Send,
+    F: Fn(DecodeCtx<U>) -> Result<U, DecodeError> + Sync,
+{
+    match as_of {
+        Some(as_of) => db.find_many_as_of(model, select, query, as_of, decode).map(|data| (data, None)),
+        None => db.find_page(model, select, query, decode).map_err(HistoryError::Read),
+    }
+}
+
+/// Документ findMany. Без кодеков расширений собирается в `Doc` без копий имен полей,
+/// иначе кодекам нужен `Value`
+fn decode_row(state: &ServerState, model: &Model, ctx: DecodeCtx<Doc>) -> Result<Doc, DecodeError> {
+    if !state.extensions.has_codecs() {
+        return decode_doc(ctx);
+    }
+    decode_with_codecs(state, model, ctx.map(Doc::into_value)).map(Doc::Value)
+}
+
+/// Расширения сервера. Встраивающее приложение добавляет сюда свои
+fn extensions() -> Vec<Box<dyn Extension>> {
+    vec![]
+}
+
+/// Обрезает выборку до `@@maxRows` модели. true - документов было больше предела
+fn cap_rows(model: &Model, data: &mut Rows) -> bool {
+    let Some(max_rows) = model.max_rows() else {
+        return false;
+    };
+    if data.len() <= max_rows {
+        return false;
+    }
+    data.truncate(max_rows);
+    true
+}
+
+/// Ошибка операции с базой: код ответа по виду ошибки, сбой хранилища - 500 вместо паники задачи соединения
+fn failed(err: MarciError) -> Response<Full<Bytes>> {
+    let status = StatusCode::from_u16(err.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    error_body(status, ErrorBody::from(&err))
+}
+
+/// Открытая база или выход с сообщением: каталог данных не открывается или занят другим процессом
+fn open_or_exit<T>(opened: std::io::Result<T>) -> T {
+    match opened {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `marci-db rename-model Old New`: переносит данные модели под новое имя и переименовывает ее в schema.marci.
+/// Запускается при остановленном сервере
+fn rename_model_command(args: &[String]) {
+    let (Some(old), Some(new)) = (args.get(2), args.get(3)) else {
+        eprintln!("Usage: marci-db rename-model <Old> <New>");
+        std::process::exit(2);
+    };
+    let (db, _data_lock) = open_or_exit(open_database());
+    match rename_model_trees(&db, old, new) {
+        Ok(count) => println!("Moved {} trees from {} to {}", count, old, new),
+        Err(err) => {
+            eprintln!("Failed to rename model: {}", err);
+            std::process::exit(1);
+        }
+    }
+    let schema = fs::read_to_string("schema.marci").unwrap();
+    fs::write("schema.marci", rename_in_schema(&schema, old, new)).unwrap();
+}
+
+fn check_command(args: &[String], db: MarciDB) {
+    let repair = args.iter().any(|arg| arg == "--repair");
+    let report = match db.check(repair) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("Failed to check database: {}", err);
+            std::process::exit(1);
+        }
+    };
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!("Checked {} documents, found {} problems, repaired {}", report.checked, report.problems.len(), report.repaired);
+    if report.problems.len() > report.repaired {
+        std::process::exit(1);
+    }
+}
+
+fn dump_command(args: &[String], db: MarciDB) {
+    let Some(dir) = args.get(2) else {
+        eprintln!("Usage: marci-db dump <dir> | marci-db load <dir> [mapping.json]");
+        std::process::exit(2);
+    };
+    if args[1] == "dump" {
+        match dump_database(&db, Path::new(dir)) {
+            Ok(count) => println!("Dumped {} documents to {}", count, dir),
+            Err(err) => {
+                eprintln!("Failed to dump database: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mapping = match args.get(3) {
+        Some(path) => serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap_or_else(|_| {
+            eprintln!("{} is not valid JSON", path);
+            std::process::exit(2);
+        }),
+        None => Value::Null,
+    };
+    match load_database(&db, Path::new(dir), &mapping) {
+        Ok(report) => {
+            println!("Loaded {} documents from {}", report.imported, dir);
+            for name in report.skipped {
+                println!("Skipped {} (not in schema)", name);
+            }
+        }
+        Err(DumpError::Import(errors)) => {
+            for err in errors {
+                eprintln!("{}", err);
+            }
+            eprintln!("Nothing was loaded");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Failed to load database: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|command| command == "rename-model") {
+        rename_model_command(&args);
+        return;
+    }
+
     // Открываем хранилище
 
-    let schema = parse_schema(&fs::read_to_string("schema.marci").unwrap());
+    let config = Config::load();
+    let extensions = extensions();
+    let schema = match parse_schema_with(&fs::read_to_string("schema.marci").unwrap(), &scalar_types(&extensions)) {
+        Ok(schema) => schema,
+        Err(err) => {
+            eprintln!("schema.marci:{}", err);
+            std::process::exit(1);
+        }
+    };
 
-    let db: Arc<MarciDB> = Arc::new(MarciDB::new(schema));
+    // `marci-db codegen [out.rs]`: модуль Rust-клиента для схемы, без пути - в stdout
+    if args.get(1).is_some_and(|command| command == "codegen") {
+        let code = rust_client(&schema);
+        match args.get(2) {
+            Some(path) => fs::write(path, code).unwrap_or_else(|err| {
+                eprintln!("Failed to write {}: {}", path, err);
+                std::process::exit(1);
+            }),
+            None => print!("{}", code),
+        }
+        return;
+    }
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    // `marci-db dump <dir>` и `marci-db load <dir> [mapping.json]`: выгрузка в NDJSON и загрузка без запуска сервера
+    if args.get(1).is_some_and(|command| command == "dump" || command == "load") {
+        dump_command(&args, open_or_exit(MarciDB::new(schema)));
+        return;
+    }
 
-    // We create a TcpListener and bind it to 127.0.0.1:3000
-    let listener = TcpListener::bind(addr).await.unwrap();
+    // `marci-db check [--repair]`: полная проверка индексов, ссылок и структур без запуска сервера
+    if args.get(1).is_some_and(|command| command == "check") {
+        check_command(&args, open_or_exit(MarciDB::new(schema)));
+        return;
+    }
 
-    // We start a loop to continuously accept incoming connections
-    loop {
-        let (stream, _) = listener.accept().await.unwrap();
+    // `marci-db demo`: временная база со сгенерированными по схеме данными
+    let demo = args.get(1).is_some_and(|command| command == "demo");
+    let mut db = match demo {
+        true => open_or_exit(MarciDB::open(schema, &demo_dir())),
+        false if config.ephemeral => MarciDB::ephemeral(schema),
+        false => open_or_exit(MarciDB::new(schema)),
+    };
+    if let Some(dir) = db.ephemeral_dir() {
+        println!("Ephemeral database in {}, removed on shutdown", dir.display());
+    }
+    if demo {
+        match seed_demo(&db, config.demo_rows) {
+            Ok(count) => println!("Demo database in {} with {} documents", demo_dir().display(), count),
+            Err(err) => {
+                eprintln!("Failed to generate demo data: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    db.include_limit = config.include_limit;
+    db.scan_threads = config.scan_threads;
+    if let Err(err) = db.enable_doc_cache(config.doc_cache) {
+        eprintln!("Failed to enable document cache: {}", err);
+        std::process::exit(1);
+    }
+
+    // `--restore`: заменяем содержимое ./data снимком до того, как начнем принимать запросы
+    if let Some(path) = &config.restore {
+        let key = config.backup_key.as_deref().map(BackupKey::from_hex);
+        let restored = fs::read(path).map_err(|err| format!("{}", err))
+            .and_then(|data| restore_backup(&db, &data, key.as_ref()).map_err(|err| err.to_string()));
+        match restored {
+            Ok(seq) => println!("Restored backup {} at journal sequence {}", path, seq),
+            Err(err) => {
+                eprintln!("Failed to restore backup {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let db: Arc<MarciDB> = Arc::new(db);
+
+    if config.verify_on_start {
+        let started = Instant::now();
+        let verify_db = db.clone();
+        let sample = config.verify_sample;
+        let (checked, problems) = tokio::task::spawn_blocking(move || verify_db.verify(sample)).await.unwrap();
+        if !problems.is_empty() {
+            for problem in &problems {
+                eprintln!("Integrity check failed: {}", problem);
+            }
+            eprintln!("Found {} problems in {} documents, refusing to start", problems.len(), checked);
+            std::process::exit(1);
+        }
+        println!("Verified {} documents in {:?}", checked, started.elapsed());
+    }
+
+    let shutdown = Arc::new(Shutdown::new());
+
+    let replication = Arc::new(Replication::new(db.clone(), config.replica_of.clone(), config.primary_key.clone(), config.leader_lock.clone()));
+    replication.try_acquire_leadership();
+    if replication.is_replica() {
+        println!("Replicating from {}", config.replica_of.as_deref().unwrap());
+    }
+    shutdown.spawn("replication", replication.clone().run(shutdown.signal()));
+
+    // Фоновая очистка документов с истекшим TTL. На реплике удаления приходят из журнала
+    if db.has_ttl() {
+        let db = db.clone();
+        let replication = replication.clone();
+        let mut stop = shutdown.signal();
+        shutdown.spawn("ttl sweeper", async move {
+            let mut interval = tokio::time::interval(TTL_SWEEP_INTERVAL);
+            while tick(&mut stop, &mut interval).await {
+                if replication.can_write() {
+                    let db = db.clone();
+                    let swept = tokio::task::spawn_blocking(move || db.sweep_expired(now_millis())).await.unwrap();
+                    if let Err(err) = swept {
+                        eprintln!("Failed to sweep expired documents: {}", err);
+                    }
+                }
+            }
+        });
+    }
+
+    // Прогреваем кэш до того, как начнем принимать запросы
+    let started = Instant::now();
+    let warm_db = db.clone();
+    let touched = tokio::task::spawn_blocking(move || warm_db.warm()).await.unwrap();
+    if touched > 0 {
+        println!("Warmed {} entries in {:?}", touched, started.elapsed());
+    }
 
-        // Use an adapter to access something implementing `tokio::io` traits as if they implement
-        // `hyper::rt` IO traits.
-        let io = TokioIo::new(stream);
+    let backup_key = config.backup_key.as_deref().map(|key| Arc::new(BackupKey::from_hex(key)));
+    let backup_dir = config.backup_dir.as_ref().map(PathBuf::from);
 
+    // Бэкапы по расписанию и очистка старых записей журнала
+    if backup_dir.is_some() || config.journal_retention.is_some() {
         let db = db.clone();
+        let config = config.clone();
+        let backup_dir = backup_dir.clone();
+        let backup_key = backup_key.clone();
+        let mut stop = shutdown.signal();
+        // Начатый бэкап дописывается до конца, следующий уже не начинается
+        shutdown.spawn("maintenance", async move {
+            let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+            while tick(&mut stop, &mut interval).await {
+                let db = db.clone();
+                let config = config.clone();
+                let backup_dir = backup_dir.clone();
+                let backup_key = backup_key.clone();
+                tokio::task::spawn_blocking(move || run_maintenance(&db, &config, backup_dir, backup_key.as_deref())).await.unwrap();
+            }
+        });
+    }
+
+    let jwt = config.jwt_secret.as_deref().map(|secret| load_jwt_auth(secret, config.jwt_permissions.as_deref()));
+
+    let state = Arc::new(ServerState {
+        db: db.clone(),
+        memory: MemoryBudget::new(config.memory_budget),
+        concurrency: ConcurrencyLimits::new(config.light_concurrency, config.heavy_concurrency),
+        replication,
+        backup_key,
+        backup_dir,
+        workload: Workload::new(),
+        select_plans: SelectPlans::default(),
+        index_lab: config.index_lab,
+        extensions: Extensions::new(extensions, &db.schema),
+        debug: DebugLog::new(config.debug_bodies.clone()),
+        panics: AtomicU64::new(0),
+        shutdown: shutdown.clone(),
+        sessions: Sessions::new(config.transaction_timeout),
+        request_timeout: config.request_timeout,
+        max_body_size: config.max_body_size,
+        api_keys: ApiKeys::new(config.api_keys.clone(), config.read_keys.clone()),
+        jwt,
+    });
+
+    // Брошенная интерактивная транзакция откатывается, иначе записи ждали бы ее бесконечно
+    {
+        let state = state.clone();
+        let mut stop = shutdown.signal();
+        shutdown.spawn("transaction expiry", async move {
+            let mut interval = tokio::time::interval(SESSION_EXPIRY_INTERVAL);
+            while tick(&mut stop, &mut interval).await {
+                if state.sessions.expire() {
+                    eprintln!("Interactive transaction rolled back after {:?} without requests", state.sessions.timeout());
+                }
+            }
+        });
+    }
+    let names = state.extensions.names();
+    if !names.is_empty() {
+        println!("Extensions: {}", names.join(", "));
+    }
+
+    // С `--tls-cert` и `--tls-key` соединения принимаются только по TLS
+    #[cfg(feature = "tls")]
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => match tls::load_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => {
+                eprintln!("Failed to load TLS certificate: {}", err);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            eprintln!("--tls-cert and --tls-key must be given together");
+            std::process::exit(1);
+        }
+    };
+    #[cfg(not(feature = "tls"))]
+    if config.tls_cert.is_some() || config.tls_key.is_some() {
+        eprintln!("TLS is not available, build with --features tls");
+        std::process::exit(1);
+    }
+
+    // Каждый адрес из `--host` слушается своей задачей, принятые соединения обрабатываются в одном цикле
+    let (accepted_tx, mut accepted_rx) = tokio::sync::mpsc::channel(ACCEPT_QUEUE);
+    let mut acceptors = vec![];
+    for host in &config.hosts {
+        let listener = match TcpListener::bind((host.as_str(), config.port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                eprintln!("Failed to listen on {}:{}: {}", host, config.port, err);
+                std::process::exit(1);
+            }
+        };
+        let addr = listener.local_addr().unwrap();
+        println!("Listening on {}", addr);
+        let accepted_tx = accepted_tx.clone();
+        acceptors.push(tokio::task::spawn(async move {
+            loop {
+                let accepted = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    // Ошибка одного accept не останавливает сервер: ждем и принимаем дальше
+                    Err(err) => {
+                        eprintln!("Failed to accept connection on {}: {}", addr, err);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                if accepted_tx.send(accepted).await.is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+    drop(accepted_tx);
+
+    // Соединения сверх `--max-connections` закрываются сразу после приема
+    let connections = Arc::new(tokio::sync::Semaphore::new(config.max_connections.unwrap_or(tokio::sync::Semaphore::MAX_PERMITS)));
+    let idle_timeout = config.idle_timeout;
+
+    let terminated = termination();
+    tokio::pin!(terminated);
+
+    // We start a loop to continuously accept incoming connections
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = accepted_rx.recv() => match accepted {
+                Some(accepted) => accepted,
+                None => break,
+            },
+            _ = &mut terminated => break,
+        };
+        let Ok(connection) = connections.clone().try_acquire_owned() else {
+            drop(stream);
+            continue;
+        };
+
+        let state = state.clone();
+        #[cfg(feature = "tls")]
+        let tls = tls.clone();
 
         // Spawn a tokio task to serve multiple connections concurrently
         tokio::task::spawn(async move {
-            // Finally, we bind the incoming connection to our `hello` service
-            if let Err(err) = http1::Builder::new()
-                // `service_fn` converts our function in a `Service`
-                .serve_connection(io, service_fn(move |req| {
-                    handle(req, db.clone())
-                }))
-                .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
+            let _connection = connection;
+            #[cfg(feature = "tls")]
+            if let Some(tls) = tls {
+                // Рукопожатие ограничено тем же таймаутом, что и ожидание заголовков
+                match tokio::time::timeout(idle_timeout, tls.accept(stream)).await {
+                    Ok(Ok(stream)) => serve_connection(stream, state, idle_timeout).await,
+                    Ok(Err(err)) => eprintln!("TLS handshake failed: {:?}", err),
+                    Err(_) => {}
+                }
+                return;
             }
+            serve_connection(stream, state, idle_timeout).await;
         });
     }
 
+    for acceptor in acceptors {
+        acceptor.abort();
+    }
+    println!("Shutting down, waiting up to {:?} for requests and background tasks", config.shutdown_timeout);
+    let report = shutdown.run(config.shutdown_timeout).await;
+    if report.is_clean() {
+        match db.last_seq() {
+            Ok(seq) => println!("Shutdown complete at journal sequence {}", seq),
+            Err(err) => eprintln!("Shutdown complete, failed to read journal sequence: {}", err),
+        }
+    } else {
+        eprintln!("Shutdown timed out: {} requests still running, unfinished tasks: {}", report.requests, report.tasks.join(", "));
+        // `exit` не вызывает drop, временный каталог удаляем сами
+        if let Some(dir) = db.ephemeral_dir() {
+            let _ = fs::remove_dir_all(dir);
+        }
+        std::process::exit(1);
+    }
 }
@@ -1,21 +1,55 @@
-use std::{collections::HashMap, sync::{Arc, atomic::{AtomicU64, Ordering}}, u64};
+use std::{borrow::Cow, collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fs::{self, File}, ops::{Deref, Range}, path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{SystemTime, UNIX_EPOCH}};
 
-use bitvec::{index, vec::BitVec};
-use canopydb::{Database, Environment, ReadTransaction, Transaction, Tree, WriteTransaction};
+use bitvec::vec::BitVec;
+use canopydb::{Database, Environment, Transaction, Tree, WriteTransaction};
 
-use crate::{schema::{Field, FieldType, InsertedIndex, Model, Schema, Struct, WithFields}, update_data::update_data};
+use crate::{doc_cache::DocCache, error::MarciError, id_generator::{IdError, IdGenerator, id_generator}, marci_decoder::{DecodeError, StoredDoc, decode_field, unpack, verify_document}, journal::{JOURNAL_TREE, JournalOp, JournalTree, JournalTx, META_TREE, decode_record, journal_seq}, marci_encoder::{encode_value, pack}, marci_query::{MarciFilter, MarciQuery, QueryPlan, SortOrder, index_lookup}, schema::{CompoundIndex, Field, FieldType, IdStrategy, InsertedIndex, PrimitiveFieldType, Model, ModelAttribute, ModelTtl, OnDelete, Schema, Struct, TriggerAction, TriggerEvent, WithFields}, update_data::{ListOp, update_data}};
 
 pub struct MarciDB {
   pub db: Database,
   pub schema: Schema,
-  counters: Vec<Arc<AtomicU64>>
+  /// Сколько связанных записей может прочитать одна связь из `include` за запрос, 0 - без ограничения
+  pub include_limit: u64,
+  /// Сколько потоков проверяют фильтр и декодируют документы большого полного обхода, 1 - без параллельного обхода
+  pub scan_threads: usize,
+  /// Кэш недавно прочитанных документов, включается `enable_doc_cache`
+  pub doc_cache: Option<Arc<DocCache>>,
+  counters: Vec<Box<dyn IdGenerator>>,
+  data_dir: PathBuf,
+  _data_lock: File,
+  /// Временный каталог удаляется после закрытия базы и файла блокировки, поэтому поле последнее
+  _ephemeral_dir: Option<EphemeralDir>,
 }
 
+/// Каталог данных временной базы (`--ephemeral`), удаляется при drop
+struct EphemeralDir(PathBuf);
+
+impl Drop for EphemeralDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
+
+/// Счетчики id при закрытии базы сохраняются в `$meta`: `counter.<дерево>` -> следующий id
+const META_COUNTER_PREFIX: &[u8] = b"counter.";
+/// Отметка, что счетчики сохранены при закрытии и после этого база не менялась
+const META_COUNTERS_SAVED: &[u8] = b"counters.saved";
+
+/// Номер временной базы в процессе, чтобы тесты не делили каталог
+static EPHEMERAL_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Сколько документов обхода приходится минимум на один поток: меньшие наборы быстрее обработать в одном
+const PARALLEL_SCAN_ROWS: usize = 4096;
+
 pub struct MarciSelectInclude<'a> {
   pub field_index: usize,
-  pub model: &'a dyn WithFields,
+  pub model: &'a (dyn WithFields + Sync),
   pub select: MarciSelect<'a>,
+  /// where/orderBy/skip/take для списков связанных записей
+  pub query: Option<MarciQuery<'a>>,
   pub binding: MarciSelectBinding<'a>,
+  /// Сколько связанных записей прочитано по этой связи за запрос (для всех родительских документов)
+  pub fetched: AtomicU64,
 }
 
 pub enum MarciSelectBinding<'a> {
@@ -23,6 +57,9 @@ pub enum MarciSelectBinding<'a> {
   Many(&'a[u8]),
   OneStruct(),
   ManyStruct(),
+  /// `{ _count: true }`: только количество связанных записей
+  Count(&'a[u8]),
+  CountStruct(),
 }
 
 pub struct MarciSelectVirtual<'a> {
@@ -34,7 +71,8 @@ pub struct MarciSelectVirtual<'a> {
 
 pub struct MarciSelect<'a> {
   pub select: BitVec,
-  pub includes: Vec<MarciSelectInclude<'a>>
+  pub includes: Vec<MarciSelectInclude<'a>>,
+  pub expires_at: bool
 }
 
 pub struct DecodeCtx<'a, U> {
@@ -44,6 +82,18 @@ pub struct DecodeCtx<'a, U> {
   pub payload_offset: usize,
   pub select: &'a BitVec,
   pub includes: Vec<IncludeResult<U>>,
+  pub expires_at: Option<u64>,
+  /// Данные связи (`@payload`), по которой прочитан документ
+  pub payload: Option<U>,
+}
+
+impl<'a, U> DecodeCtx<'a, U> {
+  /// Тот же документ с другим типом уже декодированных связей и `@payload`
+  pub fn map<V>(self, f: impl Fn(U) -> V) -> DecodeCtx<'a, V> {
+    let DecodeCtx { id, data, fields, payload_offset, select, includes, expires_at, payload } = self;
+    let includes = includes.into_iter().map(|include| include.map(&f)).collect();
+    return DecodeCtx { id, data, fields, payload_offset, select, includes, expires_at, payload: payload.map(&f) };
+  }
 }
 
 #[derive(Debug)]
@@ -67,7 +117,11 @@ pub enum InsertStruct<'a> {
     Connect {
         field: &'a Field,
         ref_model: usize,
-        ids: Vec<u64>
+        ids: Vec<u64>,
+        /// Данные связей по порядку `ids`, если у поля есть `@payload`
+        payloads: Vec<Vec<u8>>,
+        /// None - список заменяется, Push/Remove - `connect`/`disconnect`
+        op: Option<ListOp>,
     },
     Update {
         st: &'a Struct,
@@ -82,26 +136,247 @@ pub enum InsertStruct<'a> {
         counter_idx: usize,
         data: Vec<u8>,
     },
+    Ttl {
+        seconds: Option<u64>
+    },
+    /// `id` из тела `insert`, используется моделями с `@@id(external)`
+    Id(u64),
+    /// `push`/`remove` для списка примитивов, элементы лежат в самом поле нового документа
+    ListOp {
+        field: &'a Field,
+        op: ListOp,
+    },
 }
 
 
 #[derive(Debug)]
 pub enum InsertError {
   ForeignKeyViolation(String, u64),
-  ItemNotFound(u64)
+  ItemNotFound(u64),
+  /// Сохраненный документ поврежден
+  CorruptedData(u64),
+  /// `push`/`remove` при вставке, когда списка еще нет
+  ListOperatorOnInsert(String),
+  /// Значение поля `@id` уже занято другим документом
+  DuplicateKey(String),
+  /// Связь указана ключом `@id`, которого нет у целевой модели
+  KeyNotFound { field: String, key: serde_json::Value },
+  /// `@@id(external)`: в теле `insert` нет `id`
+  IdRequired,
+  /// Документ с переданным `id` уже существует
+  DuplicateId(u64),
+  /// `@@id(external)`: `id` равен `u64::MAX`
+  ReservedId,
+}
+
+/// Какие значения полей source попадают в target при слиянии документов
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeStrategy {
+  /// Поля target не меняются
+  KeepTarget,
+  /// Пустые поля target заполняются значениями source
+  FillNulls,
+  /// Непустые поля source заменяют значения target
+  PreferSource,
+}
+
+#[derive(Debug)]
+pub enum DeleteError {
+  ItemNotFound(u64),
+  /// На документ ссылается `model.field` документа `id` с `@onDelete(restrict)`
+  Restricted { model: String, field: String, id: u64 },
+  CorruptedData(u64),
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+  /// Журнал удален до записи `seq` (время `since`), восстановить состояние на `asOf` нельзя
+  Pruned { seq: u64, since: u64 },
+  Read(MarciError),
+}
+
+/// Итог полной проверки `check`: количество документов, найденные несогласованности и сколько из них исправлено
+pub struct CheckReport {
+  pub checked: usize,
+  pub problems: Vec<String>,
+  pub repaired: usize,
+}
+
+/// Несогласованность, найденная `check`, и ее исправление, если оно однозначно
+struct Inconsistency {
+  message: String,
+  fix: Option<Fix>,
+}
+
+enum Fix {
+  /// Запись индекса или строка структуры, которая ни на что не указывает
+  Delete { tree: Vec<u8>, key: Vec<u8> },
+  /// Недостающая запись индекса документа
+  Insert { tree: Vec<u8>, key: Vec<u8> },
+}
+
+/// Документ выгрузки, перекодированный под текущую схему
+pub struct ImportDocument<'a> {
+  pub model: &'a Model,
+  pub id: u64,
+  pub data: Vec<u8>,
+  pub structs: Vec<InsertStruct<'a>>,
+  pub expires_at: Option<u64>,
+}
+
+/// Транзакция, в которой читаются документы ответа. `cache_seq` - номер журнала ее снимка, если документы можно
+/// брать из кэша: транзакция записи видит свои незакоммиченные изменения и читает только из деревьев
+struct ReadView<'a> {
+  rx: &'a Transaction,
+  cache_seq: Option<u64>,
+  /// Документы связей, заранее прочитанные для страницы ответа
+  batch: Option<&'a IncludeBatch>,
+}
+
+impl<'a> ReadView<'a> {
+  fn uncached(rx: &'a Transaction) -> ReadView<'a> {
+    return ReadView { rx, cache_seq: None, batch: None };
+  }
+
+  fn with_batch<'b>(&'b self, batch: &'b IncludeBatch) -> ReadView<'b> {
+    return ReadView { rx: self.rx, cache_seq: self.cache_seq, batch: Some(batch) };
+  }
+}
+
+/// Связи `include`, прочитанные одним проходом для всех документов страницы (`prefetch_includes`).
+/// Чего здесь нет, `process_related` читает из деревьев как обычно
+#[derive(Default)]
+struct IncludeBatch {
+  /// Дерево модели -> id -> документ, None - документа нет
+  docs: HashMap<Vec<u8>, HashMap<u64, Option<SharedDoc>>>,
+  /// Дерево связи -> id родителя -> записи связи
+  entries: HashMap<Vec<u8>, HashMap<u64, Vec<RelationEntry>>>,
+}
+
+/// Документ, общий для всех строк страницы, которые на него ссылаются
+type SharedDoc = Arc<[u8]>;
+/// id связанного документа и данные связи (`@payload`)
+type RelationEntry = (u64, Vec<u8>);
+
+impl Deref for ReadView<'_> {
+  type Target = Transaction;
+  fn deref(&self) -> &Transaction {
+    self.rx
+  }
+}
+
+/// Документ после фильтра: как он лежит в базе или только выбранные поля (см. `Projection`)
+pub enum Row<D> {
+  Stored(D),
+  Projected(Vec<u8>),
+}
+
+/// Страница документов и ключи сортировки последнего, если страница заполнена до `take`
+type Page<D> = (Vec<(u64, Row<D>)>, Option<Vec<serde_json::Value>>);
+
+impl<D: AsRef<[u8]>> AsRef<[u8]> for Row<D> {
+  fn as_ref(&self) -> &[u8] {
+    match self {
+      Row::Stored(data) => data.as_ref(),
+      Row::Projected(data) => data.as_slice(),
+    }
+  }
+}
+
+/// Поля, которые нужны ответу: выбранные значения и связи, по которым читаются вложенные документы.
+/// Документы, которые держатся в памяти до конца сортировки, урезаются до этих полей
+pub struct Projection<'a> {
+  fields: Vec<&'a Field>,
+}
+
+impl<'a> Projection<'a> {
+  /// None - ответу нужны все значения документа, урезать нечего
+  pub fn new(fields: &'a [Field], select: &MarciSelect) -> Option<Projection<'a>> {
+    let stored = fields.iter().filter(|field| matches!(field.ty, FieldType::Primitive(_) | FieldType::PrimitiveList(_) | FieldType::ModelRef(_))).count();
+    let kept: Vec<&Field> = fields.iter().enumerate().filter(|(index, field)| {
+      let selected = select.select.get(index + 1).is_some_and(|bit| *bit) && matches!(field.ty, FieldType::Primitive(_) | FieldType::PrimitiveList(_));
+      let included = select.includes.iter().any(|include| include.field_index == *index && matches!(include.binding, MarciSelectBinding::One(_)));
+      selected || included
+    }).map(|(_, field)| field).collect();
+    if kept.len() == stored {
+      return None;
+    }
+    return Some(Projection { fields: kept });
+  }
+
+  /// Копия документа, в которой остались только значения нужных полей, остальные offset-ы обнулены.
+  /// Таблица offset-ов сохраняет длину исходной, поэтому поля, добавленные в схему позже, по-прежнему читаются как `@default`
+  pub fn apply(&self, data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let stored = stored_payload_offset(data)?;
+    let mut out = data.get(..stored).ok_or(DecodeError::BufferTooSmall)?.to_vec();
+    out[3..].fill(0);
+    // Значения дописываются в порядке полей, как этого ожидает get_end
+    for field in self.fields.iter().filter(|field| field.offset_pos + 4 <= stored) {
+      let offset = get_offset(data, field.offset_pos)?;
+      if offset == 0 {
+        continue;
+      }
+      let end = get_end(data, field.offset_pos, stored);
+      let value = data.get(offset..end).ok_or(DecodeError::OffsetOutOfRange)?;
+      let start = out.len();
+      set_offset(&mut out, field.offset_pos, start);
+      out.extend_from_slice(value);
+    }
+    return Ok(out);
+  }
 }
 
 pub enum IncludeResult<U> {
   None(usize),
   One(usize,U),
-  Many(usize,Vec<U>)
+  Many(usize,Vec<U>),
+  Count(usize,u64)
+}
+
+impl<U> IncludeResult<U> {
+  pub fn map<V>(self, f: impl Fn(U) -> V) -> IncludeResult<V> {
+    return match self {
+      IncludeResult::None(field_index) => IncludeResult::None(field_index),
+      IncludeResult::One(field_index, val) => IncludeResult::One(field_index, f(val)),
+      IncludeResult::Many(field_index, val) => IncludeResult::Many(field_index, val.into_iter().map(f).collect()),
+      IncludeResult::Count(field_index, count) => IncludeResult::Count(field_index, count),
+    };
+  }
+}
+
+impl Drop for MarciDB {
+  fn drop(&mut self) {
+    if let Err(err) = self.persist_counters() {
+      eprintln!("Failed to save id counters: {}", err);
+    }
+  }
 }
 
 impl MarciDB {
 
-  pub fn new(mut schema: Schema) -> MarciDB {
-    let env = Environment::new("./data").unwrap(); 
-    let db = env.get_or_create_database("mydb.db").unwrap();
+  pub fn new(schema: Schema) -> std::io::Result<MarciDB> {
+    return MarciDB::open(schema, Path::new(DATA_DIR));
+  }
+
+  /// База в новом временном каталоге, который удаляется вместе с ней. Для тестов и CI, где ./data не нужен
+  pub fn ephemeral(schema: Schema) -> MarciDB {
+    let seq = EPHEMERAL_SEQ.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("marci-ephemeral-{}-{}", std::process::id(), seq));
+    let _ = fs::remove_dir_all(&dir);
+    let mut db = MarciDB::open(schema, &dir).unwrap();
+    db._ephemeral_dir = Some(EphemeralDir(dir));
+    return db;
+  }
+
+  /// Каталог временной базы; удаляется при drop, но не при `process::exit`
+  pub fn ephemeral_dir(&self) -> Option<&Path> {
+    return self._ephemeral_dir.as_ref().map(|dir| dir.0.as_path());
+  }
+
+  /// База в другом каталоге данных (`marci-db demo` открывает временный).
+  /// Ошибка - каталог не открывается или занят другим процессом
+  pub fn open(mut schema: Schema, dir: &Path) -> std::io::Result<MarciDB> {
+    let (db, data_lock) = open_database_in(dir)?;
 
     let mut counters = Vec::with_capacity(schema.models.len());
 
@@ -110,179 +385,375 @@ impl MarciDB {
       model_names.insert(idx, model.name.clone());
     }
 
-    let tx = db.begin_write().unwrap();
+    let tx = db.begin_write().map_err(std::io::Error::other)?;
+    create_trees(&tx, &schema);
+    let saved = take_saved_counters(&tx).map_err(std::io::Error::other)?;
     for model in schema.models.iter_mut() {
-      let tree = tx.get_or_create_tree(model.name.as_bytes()).unwrap();
-
-      let max_id = get_max_id(&tree);
+      let max_id = next_tree_id(&tx, &saved, model.name.as_bytes());
       model.counter_idx = counters.len();
-      counters.push(Arc::new(AtomicU64::new(max_id)));
+      counters.push(id_generator(model.id_strategy(), max_id));
 
       for field in model.fields.iter_mut() {
-        for index in &field.inserted_indexes {
-          match index {
-            InsertedIndex::Direct { tree_name } => {
-              tx.get_or_create_tree(tree_name.as_bytes()).unwrap();
-            },
-            InsertedIndex::Rev { tree_name: _ } => {},
-          };
-        }
-
-        if let FieldType::Struct(st) = &field.ty {
-          tx.get_or_create_tree(st.name.as_bytes()).unwrap();
-        }
-        if let FieldType::StructList(ref st, ref mut counter_idx) = field.ty {
-          let tree = tx.get_or_create_tree(st.name.as_bytes()).unwrap();
-          let max_id = get_max_id(&tree);
-          *counter_idx = counters.len();
-          counters.push(Arc::new(AtomicU64::new(max_id)));
-        }
+        assign_struct_counters(&tx, &saved, &mut field.ty, &mut counters);
       }
     }
-    tx.commit().unwrap();
+    tx.commit().map_err(std::io::Error::other)?;
 
-    MarciDB {
+    Ok(MarciDB {
       db,
       schema,
-      counters
+      include_limit: 0,
+      scan_threads: 1,
+      doc_cache: None,
+      counters,
+      data_dir: dir.to_path_buf(),
+      _data_lock: data_lock,
+      _ephemeral_dir: None,
+    })
+  }
+
+  /// Асинхронный фасад: `f` выполняется в пуле блокирующих потоков tokio, чтобы транзакции и обходы canopydb
+  /// не занимали потоки, которые обслуживают соединения. Паника в `f` продолжается в вызывающей задаче
+  pub async fn run<T, F>(self: Arc<Self>, f: F) -> T
+  where
+    F: FnOnce(&MarciDB) -> T + Send + 'static,
+    T: Send + 'static,
+  {
+    match tokio::task::spawn_blocking(move || f(&self)).await {
+      Ok(result) => result,
+      Err(err) => std::panic::resume_unwind(err.into_panic()),
     }
   }
-  
-  pub fn next_id(&self, model: &Model) -> u64 {
-    self.counters[model.counter_idx].fetch_add(1, Ordering::Relaxed)
+
+  /// Транзакция записи с журналированием изменений для реплик.
+  /// Пока она открыта, остальные записи ждут ее коммита или отката
+  pub fn begin_write(&self) -> Result<JournalTx, MarciError> {
+    return Ok(JournalTx::new(self.db.begin_write()?).with_cache(self.doc_cache.clone()));
+  }
+
+  /// Включает кэш документов размером `capacity` байт: повторные чтения документа по id (`include` связи,
+  /// `byIndex`, документ после записи) не ходят в дерево, пока документ не изменится
+  pub fn enable_doc_cache(&mut self, capacity: usize) -> Result<(), MarciError> {
+    self.doc_cache = if capacity > 0 { Some(Arc::new(DocCache::new(capacity, self.last_seq()?))) } else { None };
+    return Ok(());
+  }
+
+  /// Транзакция чтения ответа. Если включен кэш, запоминается номер журнала ее снимка
+  fn read_view<'a>(&self, rx: &'a Transaction) -> Result<ReadView<'a>, MarciError> {
+    let cache_seq = if self.doc_cache.is_some() { Some(journal_seq(rx)?) } else { None };
+    return Ok(ReadView { rx, cache_seq, batch: None });
+  }
+
+  /// Документ модели по id: из кэша, если транзакция открыта на его номере журнала, иначе из дерева
+  fn read_doc(&self, rx: &ReadView, tree: &Tree, name: &[u8], id: u64) -> Result<Option<StoredDoc<impl AsRef<[u8]>>>, MarciError> {
+    if let Some(data) = rx.batch.and_then(|batch| batch.docs.get(name)).and_then(|docs| docs.get(&id)) {
+      return Ok(data.clone().map(StoredDoc::Cached));
+    }
+    let (Some(cache), Some(seq)) = (&self.doc_cache, rx.cache_seq) else {
+      return Ok(tree.get(&id.to_be_bytes())?.map(unpack));
+    };
+    if let Some(data) = cache.get(seq, name, id) {
+      return Ok(Some(StoredDoc::Cached(data)));
+    }
+    let Some(data) = tree.get(&id.to_be_bytes())?.map(unpack) else {
+      return Ok(None);
+    };
+    let data: Arc<[u8]> = Arc::from(data.as_ref());
+    cache.insert(seq, name, id, data.clone());
+    return Ok(Some(StoredDoc::Cached(data)));
+  }
+
+  /// Переписывает файл базы без страниц, освободившихся после обновлений и удалений.
+  /// Возвращает размер каталога данных до и после сжатия
+  pub fn compact(&self) -> (u64, u64) {
+    let before = dir_size(&self.data_dir);
+    self.db.compact().unwrap();
+    return (before, dir_size(&self.data_dir));
+  }
+
+  /// Размер файлов каталога данных
+  pub fn disk_size(&self) -> u64 {
+    return dir_size(&self.data_dir);
+  }
+
+  /// Номер последней записи в журнале
+  pub fn last_seq(&self) -> Result<u64, MarciError> {
+    let rx = self.db.begin_read()?;
+    return journal_seq(&rx);
   }
+
+  /// Id нового документа по `@@id(...)` модели. `supplied` - id из тела запроса
+  pub fn next_id(&self, model: &Model, supplied: Option<u64>) -> Result<u64, InsertError> {
+    return self.counters[model.counter_idx].next_id(supplied).map_err(|err| match err {
+      IdError::Required => InsertError::IdRequired,
+      IdError::Reserved => InsertError::ReservedId,
+    });
+  }
+  /// Id, который получит следующий документ (или элемент списка структур) счетчика
+  pub fn peek_id(&self, counter_idx: usize) -> u64 {
+    self.counters[counter_idx].peek()
+  }
+  /// Id нового элемента списка структур, они всегда последовательные
   pub fn next_idc(&self, counter_idx: usize) -> u64 {
-    self.counters[counter_idx].fetch_add(1, Ordering::Relaxed)
+    self.counters[counter_idx].next_id(None).unwrap()
   }
   
   pub fn get_model(&self, name: &str) -> Option<&Model> {
     return self.schema.models.iter().find(|i| i.name == name);
   }
 
-  pub fn insert_data(&self, model: &Model, data: &[u8], structs: &[InsertStruct]) -> Result<u64, InsertError> {
+  pub fn insert_data(&self, model: &Model, data: &[u8], structs: &[InsertStruct]) -> Result<u64, MarciError> {
+    let tx = self.begin_write()?;
+    let id = self.insert_data_in(&tx, model, data, structs)?;
+    tx.commit(now_millis())?;
+    return Ok(id)
+  }
+
+  /// `insert_data` в уже открытой транзакции, без коммита
+  pub fn insert_data_in(&self, tx: &JournalTx, model: &Model, data: &[u8], structs: &[InsertStruct]) -> Result<u64, MarciError> {
+
+    let list_op = structs.iter().find_map(|st| match st {
+      InsertStruct::ListOp { field, .. } | InsertStruct::Connect { field, op: Some(_), .. } => Some(field),
+      _ => None
+    });
+    if let Some(field) = list_op {
+      return Err(InsertError::ListOperatorOnInsert(field.name.clone()).into());
+    }
 
     let foreign_keys = collect_foreign_keys(data, &model.fields, structs, &self.schema);
     
-    let id = self.next_id(model);
+    let supplied = structs.iter().find_map(|st| match st {
+      InsertStruct::Id(id) => Some(*id),
+      _ => None
+    });
+    let id = self.next_id(model, supplied)?;
+    // Id от клиента может быть уже занят
+    if supplied == Some(id) && write_tree(tx, model.name.as_bytes())?.get(&id.to_be_bytes())?.is_some() {
+      return Err(InsertError::DuplicateId(id).into());
+    }
+
+    check_foreign_keys(tx, &foreign_keys)?;
+    check_unique_key(tx, model, id, data)?;
+    let triggered = apply_triggers(model, TriggerEvent::Insert, data).map_err(|_| InsertError::CorruptedData(id))?;
+    let data = triggered.as_ref().map(|(data, _)| data.as_slice()).unwrap_or(data);
+    self.write_document(tx, model, id, data, structs)?;
+
+    return Ok(id)
+  }
+
+  /// Записывает новый документ с заданным id: само значение, структуры, связи, время жизни и индексы
+  fn write_document(&self, tx: &JournalTx, model: &Model, id: u64, data: &[u8], structs: &[InsertStruct]) -> Result<(), MarciError> {
     let mut indexes = get_indexes(data, id, model, None);
     for st in structs {
-      match st {
-        InsertStruct::One { st, data, .. } => {
-          indexes.extend(get_indexes(data, id, *st, None));
-        }
-        _ => {}
+      if let InsertStruct::One { st, data, .. } = st {
+        indexes.extend(get_indexes(data, id, *st, None));
       }
     }
 
-    let tx = self.db.begin_write().unwrap();
-    check_foreign_keys(&tx, &foreign_keys)?;
-
     // Добавляем само значение
     {
-      let mut tree = tx.get_tree(model.name.as_bytes()).unwrap().unwrap();
-      tree.insert(&id.to_be_bytes(), data).unwrap();
+      let mut tree = write_tree(tx, model.name.as_bytes())?;
+      tree.insert(&id.to_be_bytes(), &pack(model, data))?;
     }
 
     // Добавляем зависимые структуры
     for st in structs {
       match st {
         InsertStruct::Many { st, data, counter_idx, .. } => {
-          let mut tree = tx.get_tree(st.name.as_bytes()).unwrap().unwrap();
+          let mut tree = write_tree(tx, st.name.as_bytes())?;
           for (item_id, item_data) in data {
             let item_id: u64 = item_id.unwrap_or_else(|| self.next_idc(*counter_idx));
-            tree.insert(&make_key(id, item_id), item_data).unwrap();
+            tree.insert(&make_key(id, item_id), item_data)?;
             indexes.extend(get_indexes(item_data, item_id, *st, None));
           }
         },
         InsertStruct::One { st, data, .. } => {
-          let mut tree = tx.get_tree(st.name.as_bytes()).unwrap().unwrap();
-          tree.insert(&id.to_be_bytes(), data).unwrap()
+          let mut tree = write_tree(tx, st.name.as_bytes())?;
+          tree.insert(&id.to_be_bytes(), data)?;
         }
-        InsertStruct::Connect { field, ids, .. } => {
-          insert_indexes(&tx, field, id, ids);
+        InsertStruct::Connect { field, ids, payloads, .. } => {
+          insert_indexes(tx, field, id, ids, payloads)?;
         }
         _ => {}
       }
     }
 
+    // Время жизни документа: явное из `$ttl` или дефолтное из схемы
+    if let Some(ttl) = &model.ttl {
+      let seconds = structs.iter().find_map(|st| match st {
+        InsertStruct::Ttl { seconds } => Some(*seconds),
+        _ => None
+      }).unwrap_or(Some(ttl.seconds));
+      set_expiry(tx, ttl, id, seconds.map(expires_at))?;
+    }
+
     // Обновляем индексы
     for index in indexes {
-      let mut index_tree = tx.get_tree(index.tree_name).unwrap().unwrap();
-      index_tree.insert(&index.key, &[1]).unwrap();
+      let mut index_tree = write_tree(tx, index.tree_name)?;
+      index_tree.insert(&index.key, &[1])?;
     }
-    
-    tx.commit().unwrap();
+    return Ok(());
+  }
 
-    return Ok(id)
+  /// Заменяет содержимое всех моделей документами с сохраненными id (импорт выгрузки) одной транзакцией
+  pub fn import(&self, documents: &[ImportDocument]) -> Result<(), MarciError> {
+    let tx = self.begin_write()?;
+    for model in self.schema.models.iter() {
+      for tree_name in model_trees(model) {
+        let mut tree = write_tree(&tx, tree_name)?;
+        let keys: Vec<Vec<u8>> = tree.iter()?.map(|item| item.map(|(key, _)| key.to_vec())).collect::<Result<_, _>>()?;
+        for key in keys {
+          tree.delete(&key)?;
+        }
+      }
+    }
+
+    for doc in documents {
+      self.write_document(&tx, doc.model, doc.id, &doc.data, &doc.structs)?;
+      if let Some(ttl) = &doc.model.ttl {
+        set_expiry(&tx, ttl, doc.id, doc.expires_at)?;
+      }
+    }
+    tx.commit(now_millis())?;
+    self.reload_counters()?;
+    return Ok(());
   }
 
   fn process_data<U, F>(
       &self,
       id: u64,
       data: &[u8],
-      rx: &ReadTransaction,
+      rx: &ReadView,
+      select: &MarciSelect,
+      model: &dyn WithFields,
+      f: &F,
+  ) -> Result<U, MarciError>
+  where
+      F: Fn(DecodeCtx<U>) -> Result<U, DecodeError>,
+  {
+    return self.process_related(id, data, rx, select, model, None, f);
+  }
+
+  /// `process_data` для документа, прочитанного по связи с данными (`@payload`)
+  #[allow(clippy::too_many_arguments)]
+  fn process_related<U, F>(
+      &self,
+      id: u64,
+      data: &[u8],
+      rx: &ReadView,
       select: &MarciSelect,
       model: &dyn WithFields,
+      payload: Option<U>,
       f: &F,
-  ) -> U
+  ) -> Result<U, MarciError>
   where
-      F: Fn(DecodeCtx<U>) -> U,
+      F: Fn(DecodeCtx<U>) -> Result<U, DecodeError>,
   {
 
-    let includes: Vec<IncludeResult<U>> = select.includes.iter().map(|include| {
+    let includes = select.includes.iter().map(|include| {
       match include.binding {
         MarciSelectBinding::One(offset_pos) => {
-          let Some(item_id) = get_value::<8>(data, offset_pos) else {
-            return IncludeResult::None(include.field_index);
+          let Some(item_id) = get_value::<8>(data, offset_pos)? else {
+            return Ok(IncludeResult::None(include.field_index));
           };
-          let nested_tree = rx.get_tree(include.model.tree_name()).unwrap().unwrap();
-          let data = nested_tree.get(item_id).unwrap().unwrap();
-          let item_id_val = u64::from_be_bytes(*item_id);
-          let item = self.process_data(item_id_val, data.as_ref(), rx, &include.select, include.model, f);
-          return IncludeResult::One(include.field_index, item);
+          // Ссылка на документ, которого уже нет, читается как пустая
+          let item_id = u64::from_be_bytes(*item_id);
+          let nested_tree = read_tree(rx, include.model.tree_name())?;
+          let Some(data) = self.read_doc(rx, &nested_tree, include.model.tree_name(), item_id)? else {
+            return Ok(IncludeResult::None(include.field_index));
+          };
+          self.count_fetched(include, model, 1)?;
+          let item = self.process_data(item_id, data.as_ref(), rx, &include.select, include.model, f)?;
+          return Ok(IncludeResult::One(include.field_index, item));
         },
         MarciSelectBinding::Many(tree_name) => {
-          let keys = find_by_direct(rx, tree_name, id);
-          
-          if keys.is_empty() {
-            return IncludeResult::Many(include.field_index, vec![]);
+          let entries = match rx.batch.and_then(|batch| batch.entries.get(tree_name)).and_then(|entries| entries.get(&id)) {
+            Some(entries) => Cow::Borrowed(entries.as_slice()),
+            None => Cow::Owned(find_direct_entries(rx, tree_name, id)?),
+          };
+
+          if entries.is_empty() {
+            return Ok(IncludeResult::Many(include.field_index, vec![]));
           }
+          self.count_fetched(include, model, entries.len() as u64)?;
 
-          let nested_tree = rx.get_tree(include.model.tree_name()).unwrap().unwrap();
-          let items = keys.iter().map(|key| {
-            let data = nested_tree.get(&key).unwrap().unwrap();
-            let item_id = u64::from_be_bytes(key.as_slice().try_into().unwrap());
-            return self.process_data(item_id, data.as_ref(), rx, &include.select, include.model, f);
-          }).collect();
+          let payload_st = model.fields()[include.field_index].payload();
+          let payloads: HashMap<u64, &[u8]> = match payload_st {
+            Some(_) => entries.iter().map(|(item_id, value)| (*item_id, value.as_slice())).collect(),
+            None => HashMap::new(),
+          };
 
-          return IncludeResult::Many(include.field_index, items);
+          let nested_tree = read_tree(rx, include.model.tree_name())?;
+          let rows = entries.iter()
+            .filter_map(|(item_id, _)| self.read_doc(rx, &nested_tree, include.model.tree_name(), *item_id).map(|data| data.map(|data| (*item_id, data))).transpose())
+            .collect::<Result<Vec<_>, _>>()?;
+          let items = self.apply_query(rows.into_iter(), include.query.as_ref(), rx, include.model.payload_offset(), None)?.into_iter()
+            .map(|(item_id, data)| {
+              let payload = match payload_st {
+                Some(st) => decode_payload(item_id, payloads.get(&item_id).copied(), st, f)?,
+                None => None,
+              };
+              self.process_related(item_id, data.as_ref(), rx, &include.select, include.model, payload, f)
+            })
+            .collect::<Result<_, _>>()?;
+
+          return Ok(IncludeResult::Many(include.field_index, items));
         },
         MarciSelectBinding::OneStruct() => {
           let item_id = &id.to_be_bytes();
-          let st_tree = rx.get_tree(include.model.tree_name()).unwrap().unwrap();
-          let Some(data) = st_tree.get(item_id).unwrap() else {
-            return IncludeResult::None(include.field_index);
+          let st_tree = read_tree(rx, include.model.tree_name())?;
+          let Some(data) = st_tree.get(item_id)? else {
+            return Ok(IncludeResult::None(include.field_index));
           };
-          let item = self.process_data(id, data.as_ref(), rx, &include.select, include.model, f);
-          return IncludeResult::One(include.field_index, item);
+          self.count_fetched(include, model, 1)?;
+          let item = self.process_data(id, data.as_ref(), rx, &include.select, include.model, f)?;
+          return Ok(IncludeResult::One(include.field_index, item));
         },
         MarciSelectBinding::ManyStruct() => {
 
           let item_id = &id.to_be_bytes();
-          let st_tree = rx.get_tree(include.model.tree_name()).unwrap().unwrap();
-
-          let items = st_tree.prefix(item_id).unwrap().map(|item| {
-            let (key, data) = item.unwrap();
-            let st_item_id = u64::from_be_bytes(key[8..].try_into().unwrap());
-            return self.process_data(st_item_id, data.as_ref(), rx, &include.select, include.model, f);
-          }).collect();
-
-          return IncludeResult::Many(include.field_index, items);
+          let st_tree = read_tree(rx, include.model.tree_name())?;
+
+          let rows = st_tree.prefix(item_id)?.map(|item| {
+            let (key, data) = item?;
+            return Ok((key_id(key.get(8..).unwrap_or_default())?, data));
+          }).collect::<Result<Vec<_>, MarciError>>()?;
+          self.count_fetched(include, model, rows.len() as u64)?;
+          let rows = self.apply_query(rows.into_iter(), include.query.as_ref(), rx, include.model.payload_offset(), None)?;
+          let items = rows.into_iter()
+            .map(|(st_item_id, data)| self.process_data(st_item_id, data.as_ref(), rx, &include.select, include.model, f))
+            .collect::<Result<_, _>>()?;
+
+          return Ok(IncludeResult::Many(include.field_index, items));
+        },
+        MarciSelectBinding::Count(tree_name) => {
+          let index_tree = read_tree(rx, tree_name)?;
+          let count = index_tree.prefix_keys(&id.to_be_bytes())?.count();
+          return Ok(IncludeResult::Count(include.field_index, count as u64));
+        },
+        MarciSelectBinding::CountStruct() => {
+          let st_tree = read_tree(rx, include.model.tree_name())?;
+          let count = st_tree.prefix_keys(&id.to_be_bytes())?.count();
+          return Ok(IncludeResult::Count(include.field_index, count as u64));
         },
       }
-    }).collect();
+    }).collect::<Result<Vec<IncludeResult<U>>, MarciError>>()?;
+
+    let expires_at = match model.ttl() {
+      Some(ttl) if select.expires_at => get_expiry(rx, ttl, id)?,
+      _ => None
+    };
+
+    return Ok(f(DecodeCtx { id, data, fields: model.fields(), payload_offset: model.payload_offset(), select: &select.select, includes, expires_at, payload })?);
+  }
 
-    return f(DecodeCtx { id, data, fields: model.fields(), payload_offset: model.payload_offset(), select: &select.select, includes });
+  /// Учитывает прочитанные по связи записи и проверяет `include_limit`
+  fn count_fetched(&self, include: &MarciSelectInclude, model: &dyn WithFields, rows: u64) -> Result<(), DecodeError> {
+    let fetched = include.fetched.fetch_add(rows, Ordering::Relaxed) + rows;
+    if self.include_limit > 0 && fetched > self.include_limit {
+      return Err(DecodeError::IncludeLimit { field: model.fields()[include.field_index].name.clone(), limit: self.include_limit });
+    }
+    return Ok(());
   }
 
   pub fn get_all<U, F, T>(
@@ -290,171 +761,983 @@ impl MarciDB {
       model: &T,
       select: &MarciSelect,
       f: F
-  ) -> Vec<U>
+  ) -> Result<Vec<U>, MarciError>
   where
     T: WithFields,
-    F: Fn(DecodeCtx<'_, U>) -> U,
+    U: Send,
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError> + Sync,
   {
-      let rx = self.db.begin_read().unwrap();
-      let tree = rx.get_tree(model.tree_name()).unwrap().unwrap();
-
-      tree.iter().unwrap().map(|item| {
-          let (key, value) = item.unwrap();
-          let id = u64::from_be_bytes(key.as_ref().try_into().unwrap());
-          let data = value.as_ref();
-          self.process_data(id, data, &rx, select, model, &f)
-      }).collect()
+      let rx = self.db.begin_read()?;
+      let tree = read_tree(&rx, model.tree_name())?;
+
+      let rows = tree.iter()?.map(|item| -> Result<_, MarciError> {
+          let (key, value) = item?;
+          Ok((key_id(key.as_ref())?, unpack(value)))
+      }).collect::<Result<Vec<_>, _>>()?;
+      self.decode_rows(&rows, &self.read_view(&rx)?, select, model, &f)
   }
 
-  pub fn get_item<U, F: FnOnce(&[u8]) -> U>(&self, model: &Model, key: &str, f: F) -> Option<U> {
-
-    let rx = self.db.begin_read().unwrap();
-    let tree = rx.get_tree(model.name.as_bytes()).unwrap().unwrap();
-
-    return tree.get(key.as_bytes()).unwrap().map(|item| f(item.as_ref()))
+  /// `process_data` для набора документов. Без `include` и `$expiresAt` документам не нужна транзакция,
+  /// поэтому большой набор декодируется на `scan_threads` потоках, порядок документов сохраняется
+  fn decode_rows<U, F, D>(&self, rows: &[(u64, D)], rx: &ReadView, select: &MarciSelect, model: &dyn WithFields, f: &F) -> Result<Vec<U>, MarciError>
+  where
+    D: AsRef<[u8]>,
+    U: Send,
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError> + Sync,
+  {
+    // Связи всех документов читаются заранее, чтобы не делать точечные чтения на каждую строку
+    if !select.includes.is_empty() && rows.len() > 1 {
+      let mut batch = IncludeBatch::default();
+      self.prefetch_includes(rx, rows, select, &mut batch)?;
+      let rx = rx.with_batch(&batch);
+      return rows.iter().map(|(id, data)| self.process_data(*id, data.as_ref(), &rx, select, model, f)).collect();
+    }
+    let threads = self.scan_threads_for(rows.len());
+    if threads < 2 || !select.includes.is_empty() || select.expires_at {
+      return rows.iter().map(|(id, data)| self.process_data(*id, data.as_ref(), rx, select, model, f)).collect();
+    }
+    let (fields, payload_offset, bits) = (model.fields(), model.payload_offset(), &select.select);
+    let docs: Vec<(u64, &[u8])> = rows.iter().map(|(id, data)| (*id, data.as_ref())).collect();
+    let items = parallel_map(&docs, threads, |id, data| {
+      f(DecodeCtx { id, data, fields, payload_offset, select: bits, includes: vec![], expires_at: None, payload: None })
+    });
+    return Ok(items.into_iter().collect::<Result<_, _>>()?);
   }
 
-  pub fn update(&self, model: &Model, id: u64, new_data: &[u8], changed_mask: BitVec, structs: &[InsertStruct]) -> Result<u64, InsertError> {
-    
-    let foreign_keys = collect_foreign_keys(new_data, &model.fields, structs, &self.schema);
+  /// Читает связи `One` и `Many` сразу для всех документов `rows`: id собираются со всей страницы и читаются
+  /// из дерева модели по возрастанию, записи связей - из одного открытого дерева. Вложенные связи прочитанных
+  /// документов собираются так же
+  fn prefetch_includes<D: AsRef<[u8]>>(&self, rx: &ReadView, rows: &[(u64, D)], select: &MarciSelect, batch: &mut IncludeBatch) -> Result<(), MarciError> {
+    for include in &select.includes {
+      let mut ids = BTreeSet::new();
+      match include.binding {
+        MarciSelectBinding::One(offset_pos) => {
+          for (_, data) in rows {
+            if let Some(item_id) = get_value::<8>(data.as_ref(), offset_pos)? {
+              ids.insert(u64::from_be_bytes(*item_id));
+            }
+          }
+        },
+        MarciSelectBinding::Many(tree_name) => {
+          let index_tree = read_tree(rx, tree_name)?;
+          let entries = batch.entries.entry(tree_name.to_vec()).or_default();
+          for (id, _) in rows {
+            let found = direct_entries(&index_tree, *id)?;
+            ids.extend(found.iter().map(|(item_id, _)| *item_id));
+            entries.insert(*id, found);
+          }
+        },
+        _ => continue,
+      }
+      // Слишком большую выборку все равно отклонит `include_limit`, заранее ее не читаем
+      if self.include_limit > 0 && ids.len() as u64 > self.include_limit {
+        continue;
+      }
 
-    let mut indexes = get_indexes(new_data, id, model, None);
-    for st in structs {
-      match st {
-        InsertStruct::One { st, data, .. } => {
-          indexes.extend(get_indexes(data, id, *st, None));
-        }
-        _ => {}
+      let name = include.model.tree_name();
+      let tree = read_tree(rx, name)?;
+      let mut docs = Vec::with_capacity(ids.len());
+      for item_id in ids {
+        let data = self.read_doc(rx, &tree, name, item_id)?.map(|data| match data {
+          StoredDoc::Cached(data) => data,
+          data => Arc::from(data.as_ref()),
+        });
+        docs.push((item_id, data));
+      }
+      if !include.select.includes.is_empty() {
+        let found: Vec<(u64, &Arc<[u8]>)> = docs.iter().filter_map(|(item_id, data)| Some((*item_id, data.as_ref()?))).collect();
+        self.prefetch_includes(rx, &found, &include.select, batch)?;
       }
+      batch.docs.entry(name.to_vec()).or_default().extend(docs);
     }
+    return Ok(());
+  }
 
-    let mut indexes_to_remove = vec![];
-
-    let tx = self.db.begin_write().unwrap();
+  /// Документы, прошедшие фильтр, в исходном порядке. Большой набор проверяется на `scan_threads` потоках
+  fn filter_rows<D: AsRef<[u8]>>(&self, rows: Vec<(u64, D)>, filter: &MarciFilter, payload_offset: usize) -> Vec<(u64, D)> {
+    let threads = self.scan_threads_for(rows.len());
+    if threads < 2 {
+      return rows.into_iter().filter(|(id, data)| filter.matches(*id, data.as_ref(), payload_offset)).collect();
+    }
+    let docs: Vec<(u64, &[u8])> = rows.iter().map(|(id, data)| (*id, data.as_ref())).collect();
+    let matched = parallel_map(&docs, threads, |id, data| filter.matches(id, data, payload_offset));
+    return rows.into_iter().zip(matched).filter(|(_, matched)| *matched).map(|(row, _)| row).collect();
+  }
 
-    check_foreign_keys(&tx, &foreign_keys)?;
+  /// Потоков для обхода `rows` документов: не больше `scan_threads` и не меньше `PARALLEL_SCAN_ROWS` документов на поток
+  fn scan_threads_for(&self, rows: usize) -> usize {
+    return self.scan_threads.min(rows / PARALLEL_SCAN_ROWS);
+  }
 
-    // Обновляем значение. Выдаем ошибку, если значения не существует
-    {
-      let mut tree = tx.get_tree(model.name.as_bytes()).unwrap().unwrap();
+  /// Один документ по id, None - если его нет
+  pub fn find_by_id<U, F>(&self, model: &Model, id: u64, select: &MarciSelect, f: F) -> Result<Option<U>, MarciError>
+  where
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError>,
+  {
+      let rx = self.db.begin_read()?;
+      let rx = self.read_view(&rx)?;
+      let tree = read_tree(&rx, model.name.as_bytes())?;
+      let Some(data) = self.read_doc(&rx, &tree, model.name.as_bytes(), id)? else {
+          return Ok(None);
+      };
+      return self.process_data(id, data.as_ref(), &rx, select, model, &f).map(Some);
+  }
 
-      let Some(data) = tree.get(&id.to_be_bytes()).unwrap() else {
-        return Err(InsertError::ItemNotFound(id))
+  /// Документы, у которых индексированное поле равно одному из `values`. id читаются прямо из индекса без плана запроса.
+  /// None - значение не подходит к типу поля
+  pub fn find_by_index<U, F>(&self, model: &Model, field: &Field, values: &[serde_json::Value], select: &MarciSelect, f: F) -> Result<Option<Vec<U>>, MarciError>
+  where
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError>,
+  {
+      let rx = self.db.begin_read()?;
+      let rx = self.read_view(&rx)?;
+      let Some(ids) = index_lookup(&rx, field, values)? else {
+          return Ok(None);
       };
+      let tree = read_tree(&rx, model.name.as_bytes())?;
+      ids.into_iter()
+        .filter_map(|id| self.read_doc(&rx, &tree, model.name.as_bytes(), id).map(|data| data.map(|data| (id, data))).transpose())
+        .map(|row| {
+          let (id, data) = row?;
+          self.process_data(id, data.as_ref(), &rx, select, model, &f)
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+  }
 
-      let updated_data = update_data(&model.fields, model.payload_offset, &data, new_data, &changed_mask);
-      tree.insert(&id.to_be_bytes(), &updated_data).unwrap();
+  /// Обход модели с фильтром, сортировкой и пагинацией
+  pub fn find_many<U, F>(
+      &self,
+      model: &Model,
+      select: &MarciSelect,
+      query: &MarciQuery,
+      f: F
+  ) -> Result<Vec<U>, MarciError>
+  where
+    U: Send,
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError> + Sync,
+  {
+      return self.find_page(model, select, query, f).map(|(items, _)| items);
+  }
 
-      indexes_to_remove.extend(get_indexes(&data, id, model, Some(&changed_mask)));
-    };
+  /// Как будет выбран фильтр `query`: какие индексы прочитаются и сколько кандидатов они дадут (`$explain`)
+  pub fn explain(&self, model: &Model, query: &MarciQuery) -> Result<QueryPlan, MarciError> {
+    let rx = self.db.begin_read()?;
+    return query.filter.plan(&rx, model);
+  }
 
-    
-    // Добавляем зависимые структуры
-    for st in structs {
-      match st {
-        InsertStruct::Empty { st } => {
-          let mut tree = tx.get_tree(st.name.as_bytes()).unwrap().unwrap();
-          tree.delete_range(id.to_be_bytes()..(id+1).to_be_bytes()).unwrap();
+  /// `find_many` и курсор следующей страницы: ключи сортировки последнего документа, если страница заполнена до `take`
+  pub fn find_page<U, F>(
+      &self,
+      model: &Model,
+      select: &MarciSelect,
+      query: &MarciQuery,
+      f: F
+  ) -> Result<(Vec<U>, Option<Vec<serde_json::Value>>), MarciError>
+  where
+    U: Send,
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError> + Sync,
+  {
+      let rx = self.db.begin_read()?;
+      return self.find_page_at(&self.read_view(&rx)?, model, select, query, f);
+  }
 
-          // TODO: Delete old indexes here (from model_ref -> struct values)
-        }
-        InsertStruct::Many { st, data: new_data, counter_idx, .. } => {
-          let mut tree = tx.get_tree(st.name.as_bytes()).unwrap().unwrap();
-          for (item_id, item_data) in new_data {
-            let item_id: u64 = item_id.unwrap_or_else(|| self.next_idc(*counter_idx));
-            tree.insert(&make_key(id, item_id), item_data).unwrap();
-            indexes.extend(get_indexes(item_data, item_id, *st, None));
+  /// `find_page` в переданной транзакции. В транзакции записи видны ее собственные незакоммиченные изменения
+  pub fn find_page_in<U, F>(
+      &self,
+      rx: &Transaction,
+      model: &Model,
+      select: &MarciSelect,
+      query: &MarciQuery,
+      f: F
+  ) -> Result<(Vec<U>, Option<Vec<serde_json::Value>>), MarciError>
+  where
+    U: Send,
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError> + Sync,
+  {
+      return self.find_page_at(&ReadView::uncached(rx), model, select, query, f);
+  }
 
-            // TODO: Delete old indexes here (from model_ref -> struct values)
+  fn find_page_at<U, F>(
+      &self,
+      rx: &ReadView,
+      model: &Model,
+      select: &MarciSelect,
+      query: &MarciQuery,
+      f: F
+  ) -> Result<(Vec<U>, Option<Vec<serde_json::Value>>), MarciError>
+  where
+    U: Send,
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError> + Sync,
+  {
+      let tree = read_tree(rx, model.name.as_bytes())?;
+      query.filter.prepare(rx);
+      // Ошибка чтения внутри обхода останавливает его и возвращается после
+      let mut failed = None;
+
+      // Курсор по id или по индексированному полю: документы читаются с позиции курсора уже в порядке сортировки,
+      // поэтому глубокая страница стоит столько же, сколько первая
+      let take = query.take.unwrap_or(usize::MAX);
+      let mut page = vec![];
+      let mut skipped = 0;
+      let seeked = query.seek_cursor(rx, model.name.as_bytes(), |id| {
+        if page.len() >= take {
+          return false;
+        }
+        let data = match tree.get(&id.to_be_bytes()) {
+          Ok(Some(data)) => unpack(data),
+          Ok(None) => return true,
+          Err(err) => {
+            failed = Some(MarciError::from(err));
+            return false;
           }
-        },
-        InsertStruct::One { st, data: new_data, changed_mask } => {
-          let mut tree = tx.get_tree(st.name.as_bytes()).unwrap().unwrap();
-          if let Some(data) = tree.get(&id.to_be_bytes()).unwrap() {
+        };
+        if !query.filter.matches(id, data.as_ref(), model.payload_offset) {
+          return true;
+        }
+        if skipped < query.skip {
+          skipped += 1;
+          return true;
+        }
+        page.push((id, data));
+        return page.len() < take;
+      });
+      if let Some(err) = failed.take() {
+        return Err(err);
+      }
+      if seeked.is_some() {
+        let cursor = page.last()
+          .filter(|_| page.len() == take)
+          .map(|(id, data)| query.sort_keys(*id, data.as_ref(), model.payload_offset));
+        let items = self.decode_rows(&page, rx, select, model, &f)?;
+        return Ok((items, cursor));
+      }
 
-            let updated_data = update_data(&st.fields, st.payload_offset, &data.as_ref(), new_data, &changed_mask);
-            tree.insert(&id.to_be_bytes(), &updated_data).unwrap();
+      let candidates = query.filter.index_candidates(rx, model)?;
+      let projection = Projection::new(&model.fields, select);
+
+      // Фильтр без индекса, которому нужны все документы (сортировка или выборка без take): документы читаются
+      // целиком и делятся на отрезки подряд идущих id, фильтр каждого отрезка проверяется на своем потоке
+      if candidates.is_none() && self.scan_threads > 1 && !query.filter.is_empty() && (query.id_order().is_none() || query.take.is_none()) {
+        let rows = tree.iter()?.map(|item| -> Result<_, MarciError> {
+          let (key, value) = item?;
+          Ok((key_id(key.as_ref())?, unpack(value)))
+        }).collect::<Result<Vec<_>, _>>()?;
+        let rows = self.filter_rows(rows, &query.filter, model.payload_offset);
+        let in_order = query.id_order() == Some(SortOrder::Asc);
+        let (rows, cursor) = self.page_rows(rows.into_iter(), query, model.payload_offset, projection.as_ref(), in_order)?;
+        let items = self.decode_rows(&rows, rx, select, model, &f)?;
+        return Ok((items, cursor));
+      }
 
-            indexes_to_remove.extend(get_indexes(&data, id, *st, Some(&changed_mask)));
-          } else {
-            tree.insert(&id.to_be_bytes(), new_data).unwrap()
+      // Если условие покрыто индексом, читаем только документы-кандидаты.
+      // При orderBy по id убыванию дерево и кандидаты читаются с конца, чтобы не сортировать всю модель
+      let descending = query.id_order() == Some(SortOrder::Desc);
+      let rows: Box<dyn Iterator<Item = (u64, _)>> = match candidates {
+        Some(mut ids) => {
+          if descending {
+            ids.reverse();
           }
+          Box::new(until_error(ids.into_iter()
+            .filter_map(|id| tree.get(&id.to_be_bytes()).map(|value| value.map(|value| (id, unpack(value)))).transpose()), &mut failed))
         }
-        InsertStruct::Connect { field, ids, .. } => {
-          remove_indexes(&tx, &field, id);
-          insert_indexes(&tx, field, id, ids);
-        },
-        InsertStruct::None { st } => {
-          let mut tree = tx.get_tree(st.name.as_bytes()).unwrap().unwrap();
-          tree.delete(&id.to_be_bytes()).unwrap();
-        },
-        _ => {}
+        None => {
+          let items: Box<dyn Iterator<Item = _>> = if descending { Box::new(tree.iter()?.rev()) } else { Box::new(tree.iter()?) };
+          Box::new(until_error(items
+            .map(|item| -> Result<_, MarciError> {
+              let (key, value) = item?;
+              Ok((key_id(key.as_ref())?, unpack(value)))
+            }), &mut failed))
+        }
+      };
+      let page = self.apply_query_page(rows, query, rx, model.payload_offset, projection.as_ref(), query.id_order().is_some());
+      if let Some(err) = failed {
+        return Err(err);
       }
-    }
-    
-    for index in indexes_to_remove {
-      let mut index_tree = tx.get_tree(index.tree_name).unwrap().unwrap();
-      index_tree.delete(&index.key).unwrap();
-    }
+      let (rows, cursor) = page?;
+      let items = self.decode_rows(&rows, rx, select, model, &f)?;
+      return Ok((items, cursor));
+  }
+
+  /// `find_many` по состоянию модели на момент `as_of` (мс): документы восстанавливаются проигрыванием журнала
+  /// до этого времени. Связи и структуры читаются в текущем состоянии
+  pub fn find_many_as_of<U, F>(
+      &self,
+      model: &Model,
+      select: &MarciSelect,
+      query: &MarciQuery,
+      as_of: u64,
+      f: F
+  ) -> Result<Vec<U>, HistoryError>
+  where
+    F: Fn(DecodeCtx<'_, U>) -> Result<U, DecodeError>,
+  {
+      let rx = self.db.begin_read().map_err(|err| HistoryError::Read(err.into()))?;
+      let rows = documents_as_of(&rx, model, as_of)?;
+      let projection = Projection::new(&model.fields, select);
+      let view = self.read_view(&rx).map_err(HistoryError::Read)?;
+      self.apply_query(rows.into_iter(), Some(query), &rx, model.payload_offset, projection.as_ref()).map_err(HistoryError::Read)?.into_iter()
+        .map(|(id, data)| self.process_data(id, data.as_ref(), &view, select, model, &f))
+        .collect::<Result<_, _>>()
+        .map_err(HistoryError::Read)
+  }
+
+  /// Фильтр, сортировка и пагинация для набора документов.
+  /// С `projection` документы, ожидающие сортировки, хранятся урезанными до нужных ответу полей
+  fn apply_query<D: AsRef<[u8]>>(
+      &self,
+      rows: impl Iterator<Item = (u64, D)>,
+      query: Option<&MarciQuery>,
+      rx: &Transaction,
+      payload_offset: usize,
+      projection: Option<&Projection>,
+  ) -> Result<Vec<(u64, Row<D>)>, MarciError> {
+      let Some(query) = query else {
+        return Ok(rows.map(|(id, data)| (id, Row::Stored(data))).collect());
+      };
+      let in_order = query.id_order() == Some(SortOrder::Asc);
+      return self.apply_query_page(rows, query, rx, payload_offset, projection, in_order).map(|(rows, _)| rows);
+  }
+
+  /// `apply_query` и ключи сортировки последнего документа, если страница заполнена до `take`
+  fn apply_query_page<D: AsRef<[u8]>>(
+      &self,
+      rows: impl Iterator<Item = (u64, D)>,
+      query: &MarciQuery,
+      rx: &Transaction,
+      payload_offset: usize,
+      projection: Option<&Projection>,
+      in_order: bool,
+  ) -> Result<Page<D>, MarciError> {
+      query.filter.prepare(rx);
+      let rows = rows.filter(|(id, data)| query.filter.matches(*id, data.as_ref(), payload_offset));
+      return self.page_rows(rows, query, payload_offset, projection, in_order);
+  }
+
+  /// Сортировка и пагинация документов, уже прошедших фильтр.
+  /// `in_order` - документы уже идут в порядке `query` (по id), сортировать их не нужно
+  fn page_rows<D: AsRef<[u8]>>(
+      &self,
+      rows: impl Iterator<Item = (u64, D)>,
+      query: &MarciQuery,
+      payload_offset: usize,
+      projection: Option<&Projection>,
+      in_order: bool,
+  ) -> Result<Page<D>, MarciError> {
+      let take = query.take.unwrap_or(usize::MAX);
+
+      // Документы уже в нужном порядке: останавливаем обход, как только набрали take документов,
+      // курсором служит id последнего
+      if in_order {
+        let page: Vec<_> = rows
+          .filter(|(id, _)| query.after_cursor(&[serde_json::Value::from(*id)]))
+          .skip(query.skip)
+          .take(take)
+          .map(|(id, data)| (id, Row::Stored(data)))
+          .collect();
+        let cursor = page.last().filter(|_| page.len() == take).map(|(id, _)| vec![serde_json::Value::from(*id)]);
+        return Ok((page, cursor));
+      }
+
+      let mut rows: Vec<_> = rows
+        .filter_map(|(id, data)| {
+          let keys = query.sort_keys(id, data.as_ref(), payload_offset);
+          if !query.after_cursor(&keys) {
+            return None;
+          }
+          let row = match projection {
+            Some(projection) => projection.apply(data.as_ref()).map(Row::Projected),
+            None => Ok(Row::Stored(data)),
+          };
+          Some(row.map(|row| (keys, id, row)))
+        })
+        .collect::<Result<_, DecodeError>>()?;
+      rows.sort_by(|a, b| query.compare(&a.0, &b.0));
+
+      let page: Vec<_> = rows.into_iter().skip(query.skip).take(take).collect();
+      let cursor = page.last().filter(|_| page.len() == take).map(|(keys, _, _)| keys.clone());
+      Ok((page.into_iter().map(|(_, id, data)| (id, data)).collect(), cursor))
+  }
+
+  /// Документ по значению поля `@id`
+  pub fn get_item<U, F: FnOnce(&[u8]) -> U>(&self, model: &Model, key: &serde_json::Value, f: F) -> Result<Option<U>, MarciError> {
+    let Some(id) = self.find_key(model, key)? else {
+      return Ok(None);
+    };
+
+    let rx = self.db.begin_read()?;
+    let tree = read_tree(&rx, model.name.as_bytes())?;
+
+    return Ok(tree.get(&id.to_be_bytes())?.map(|item| f(&unpack(item))));
+  }
+
+  /// id документа по значению поля `@id`, None - у модели нет `@id` или такого ключа нет
+  pub fn find_key(&self, model: &Model, key: &serde_json::Value) -> Result<Option<u64>, MarciError> {
+    let Some(field) = model.key_field() else {
+      return Ok(None);
+    };
+    let rx = self.db.begin_read()?;
+    let ids = index_lookup(&rx, field, std::slice::from_ref(key))?;
+    return Ok(ids.and_then(|ids| ids.first().copied()));
+  }
+
+  /// Заменяет в теле запроса ссылки по ключу (`{ "slug": "a" }`) на `{ "id": n }` для связей с моделями, у которых есть `@id`
+  pub fn resolve_keys(&self, fields: &[Field], json: &mut serde_json::Value) -> Result<(), MarciError> {
+    let Some(obj) = json.as_object_mut() else {
+      return Ok(());
+    };
+    for field in fields {
+      let Some(value) = obj.get_mut(&field.name) else { continue };
+      match &field.ty {
+        FieldType::ModelRef(model_index) => self.resolve_ref(field, &self.schema.models[*model_index], value)?,
+        FieldType::ModelRefList(model_index) => {
+          for item in value.as_array_mut().into_iter().flatten() {
+            self.resolve_ref(field, &self.schema.models[*model_index], item)?;
+          }
+        }
+        FieldType::Struct(st) => self.resolve_keys(&st.fields, value)?,
+        FieldType::StructList(st, _) => {
+          for item in value.as_array_mut().into_iter().flatten() {
+            self.resolve_keys(&st.fields, item)?;
+          }
+        }
+        _ => {}
+      }
+    }
+    return Ok(());
+  }
+
+  fn resolve_ref(&self, field: &Field, target: &Model, value: &mut serde_json::Value) -> Result<(), MarciError> {
+    let Some(key_field) = target.key_field() else { return Ok(()) };
+    let Some(obj) = value.as_object_mut().filter(|obj| !obj.contains_key("id")) else { return Ok(()) };
+    let Some(key) = obj.get(&key_field.name).cloned() else { return Ok(()) };
+    let id = self.find_key(target, &key)?.ok_or_else(|| InsertError::KeyNotFound { field: field.name.clone(), key })?;
+    obj.insert("id".to_string(), id.into());
+    return Ok(());
+  }
+
+  pub fn update(&self, model: &Model, id: u64, new_data: &[u8], changed_mask: BitVec, structs: &[InsertStruct]) -> Result<u64, MarciError> {
+    let tx = self.begin_write()?;
+    let id = self.update_in(&tx, model, id, new_data, changed_mask, structs)?;
+    tx.commit(now_millis())?;
+    return Ok(id);
+  }
+
+  /// `update` в уже открытой транзакции, без коммита
+  pub fn update_in(&self, tx: &JournalTx, model: &Model, id: u64, new_data: &[u8], mut changed_mask: BitVec, structs: &[InsertStruct]) -> Result<u64, MarciError> {
+
+    // Поля из `@@onUpdate` записываются вместе с изменениями запроса
+    let triggered = apply_triggers(model, TriggerEvent::Update, new_data).map_err(|_| InsertError::CorruptedData(id))?;
+    let new_data = match &triggered {
+      Some((data, mask)) => {
+        for index in mask.iter_ones() {
+          changed_mask.set(index, true);
+        }
+        data.as_slice()
+      }
+      None => new_data
+    };
+    
+    let foreign_keys = collect_foreign_keys(new_data, &model.fields, structs, &self.schema);
+
+    let mut indexes = get_indexes(new_data, id, model, None);
+    for st in structs {
+      if let InsertStruct::One { st, data, .. } = st {
+        indexes.extend(get_indexes(data, id, *st, None));
+      }
+    }
+
+    let mut indexes_to_remove = vec![];
+    let list_ops: Vec<(&Field, ListOp)> = structs.iter().filter_map(|st| match st {
+      InsertStruct::ListOp { field, op } => Some((*field, *op)),
+      _ => None
+    }).collect();
+
+    check_foreign_keys(tx, &foreign_keys)?;
+    check_unique_key(tx, model, id, new_data)?;
+
+    // Обновляем значение. Выдаем ошибку, если значения не существует
+    {
+      let mut tree = write_tree(tx, model.name.as_bytes())?;
+
+      let Some(data) = tree.get(&id.to_be_bytes())?.map(unpack) else {
+        return Err(InsertError::ItemNotFound(id).into())
+      };
+
+      let updated_data = update_data(&model.fields, model.payload_offset, &data, new_data, &changed_mask, &list_ops)
+        .map_err(|_| InsertError::CorruptedData(id))?;
+      tree.insert(&id.to_be_bytes(), &pack(model, &updated_data))?;
+
+      indexes_to_remove.extend(get_indexes(&data, id, model, Some(&changed_mask)));
+      // Ключ составного индекса собирается из всего документа, а не только из измененных полей
+      indexes.extend(get_indexes(&updated_data, id, model, Some(&changed_mask)).into_iter()
+        .filter(|index| model.indexes.iter().any(|c| c.tree_name.as_bytes() == index.tree_name)));
+    };
+
+    
+    // Добавляем зависимые структуры
+    for st in structs {
+      match st {
+        InsertStruct::Empty { st } => {
+          indexes_to_remove.extend(clear_struct_rows(tx, st, id, &[])?.0);
+        }
+        // Новый список заменяет прежний: строки, id которых в нем нет, удаляются.
+        // id, которого нет в списке этого документа (или повторный), получает новую строку, а не перезаписывает чужую
+        InsertStruct::Many { st, data: new_data, counter_idx, .. } => {
+          let kept: Vec<u64> = new_data.iter().filter_map(|(item_id, _)| *item_id).collect();
+          let (removed, stored) = clear_struct_rows(tx, st, id, &kept)?;
+          indexes_to_remove.extend(removed);
+
+          let mut tree = write_tree(tx, st.name.as_bytes())?;
+          let mut used = HashSet::new();
+          for (item_id, item_data) in new_data {
+            let item_id: u64 = item_id
+              .filter(|item_id| stored.contains(item_id) && used.insert(*item_id))
+              .unwrap_or_else(|| self.next_idc(*counter_idx));
+            tree.insert(&make_key(id, item_id), item_data)?;
+            indexes.extend(get_indexes(item_data, item_id, *st, None));
+          }
+        },
+        InsertStruct::One { st, data: new_data, changed_mask } => {
+          let mut tree = write_tree(tx, st.name.as_bytes())?;
+          if let Some(data) = tree.get(&id.to_be_bytes())? {
+
+            let updated_data = update_data(&st.fields, st.payload_offset, data.as_ref(), new_data, changed_mask, &list_ops)
+              .map_err(|_| InsertError::CorruptedData(id))?;
+            tree.insert(&id.to_be_bytes(), &updated_data)?;
+
+            indexes_to_remove.extend(get_indexes(&data, id, *st, Some(changed_mask)));
+          } else if list_ops.is_empty() {
+            tree.insert(&id.to_be_bytes(), new_data)?;
+          } else {
+            // `remove` из отсутствующего списка не должен записать сами удаляемые элементы
+            let created = update_data(&st.fields, st.payload_offset, &empty_document(st.payload_offset), new_data, changed_mask, &list_ops)
+              .map_err(|_| InsertError::CorruptedData(id))?;
+            tree.insert(&id.to_be_bytes(), &created)?;
+          }
+        }
+        InsertStruct::Connect { field, ids, payloads, op, .. } => match op {
+          None => {
+            remove_indexes(tx, field, id)?;
+            insert_indexes(tx, field, id, ids, payloads)?;
+          }
+          Some(ListOp::Push) => insert_indexes(tx, field, id, ids, payloads)?,
+          Some(ListOp::Remove) => unlink_indexes(tx, field, id, ids)?,
+        },
+        InsertStruct::None { st } => {
+          let mut tree = write_tree(tx, st.name.as_bytes())?;
+          if let Some(data) = tree.get(&id.to_be_bytes())? {
+            indexes_to_remove.extend(get_indexes(data.as_ref(), id, *st, None));
+          }
+          tree.delete(&id.to_be_bytes())?;
+          drop(tree);
+          remove_nested_structs(tx, &st.fields, id)?;
+        },
+        InsertStruct::Ttl { seconds } => {
+          if let Some(ttl) = &model.ttl {
+            set_expiry(tx, ttl, id, seconds.map(expires_at))?;
+          }
+        },
+        _ => {}
+      }
+    }
+    
+    for index in indexes_to_remove {
+      let mut index_tree = write_tree(tx, index.tree_name)?;
+      index_tree.delete(&index.key)?;
+    }
 
     // Обновляем индексы (сносим старые, ставим новые)
     for index in indexes {
-      let mut index_tree = tx.get_tree(index.tree_name).unwrap().unwrap();
+      let mut index_tree = write_tree(tx, index.tree_name)?;
+      index_tree.insert(&index.key, &[1])?;
+    }
+
+    return Ok(id);
+  }
+
+  /// Удаляет документ вместе с документами, которые ссылаются на него с `@onDelete(cascade)`,
+  /// и обнуляет ссылки с `@onDelete(setNull)`. Вместе с документом удаляются его структуры, записи индексов
+  /// и элементы чужих списков связей, указывающие на него. Все в одной транзакции
+  pub fn delete(&self, model: &Model, id: u64) -> Result<(), MarciError> {
+    let tx = self.begin_write()?;
+    delete_item(&tx, &self.schema, model, id)?;
+    tx.commit(now_millis())?;
+    return Ok(());
+  }
+
+  /// `delete` в уже открытой транзакции, без коммита
+  pub fn delete_in(&self, tx: &JournalTx, model: &Model, id: u64) -> Result<(), MarciError> {
+    return delete_item(tx, &self.schema, model, id);
+  }
+
+  /// Сливает документ `source` в `target` одной транзакцией: ссылки на source (поля связей, списки связей и структуры)
+  /// переводятся на target, списки связей source добавляются к target, поля объединяются по `strategy`, source удаляется
+  pub fn merge(&self, model: &Model, target: u64, source: u64, strategy: MergeStrategy) -> Result<(), MarciError> {
+    let model_index = self.schema.models.iter().position(|m| std::ptr::eq(m, model)).unwrap();
+    let tx = self.begin_write()?;
+    {
+      let tree = write_tree(&tx, model.name.as_bytes())?;
+      for id in [target, source] {
+        if tree.get(&id.to_be_bytes())?.is_none() {
+          return Err(InsertError::ItemNotFound(id).into());
+        }
+      }
+    }
+
+    for ref_model in self.schema.models.iter() {
+      repoint_model_refs(&tx, ref_model, model_index, source, target)?;
+
+      for field in ref_model.fields.iter() {
+        match &field.ty {
+          FieldType::ModelRefList(list_model) if *list_model == model_index && field.derived_from.is_none() => {
+            repoint_list_refs(&tx, field, source, target)?;
+          }
+          _ => {}
+        }
+      }
+      for field in ref_model.struct_fields() {
+        let (FieldType::Struct(st) | FieldType::StructList(st, _)) = &field.ty else { continue };
+        repoint_struct_refs(&tx, st, model_index, source, target)?;
+      }
+    }
 
-      // Здесь удаление по префиксу по сути не нужно
-      // if let Some(prefix) = index.prefix {
-      //   let end = increment_bytes_be(prefix);
-      //   index_tree.delete_range(prefix..&end).unwrap();
-      // }
+    // Собственные списки связей source переходят к target
+    for field in model.fields.iter() {
+      let FieldType::ModelRefList(_) = field.ty else { continue };
+      if field.derived_from.is_some() { continue }
+      let Some(direct) = field.inserted_indexes.iter().find(|index| matches!(index, InsertedIndex::Direct { .. })) else { continue };
+      let (ids, payloads): (Vec<u64>, Vec<Vec<u8>>) = find_direct_entries(&tx, direct.tree_name(), source)?.into_iter().unzip();
+      insert_indexes(&tx, field, target, &ids, if field.payload().is_some() { &payloads } else { &[] })?;
+    }
 
-      index_tree.insert(&index.key, &[1]).unwrap();
+    // Документ target читается заново: он мог ссылаться на source
+    let (target_data, source_data) = {
+      let tree = write_tree(&tx, model.name.as_bytes())?;
+      let target_data = tree.get(&target.to_be_bytes())?.ok_or(InsertError::ItemNotFound(target))?;
+      let source_data = tree.get(&source.to_be_bytes())?.ok_or(InsertError::ItemNotFound(source))?;
+      (unpack(target_data).to_vec(), unpack(source_data).to_vec())
+    };
+    let mask = merge_mask(model, &target_data, &source_data, strategy);
+    if mask.any() {
+      let updated = update_data(&model.fields, model.payload_offset, &target_data, &source_data, &mask, &[])
+        .map_err(|_| InsertError::CorruptedData(target))?;
+      rewrite_row(&tx, model, &target.to_be_bytes(), target, &target_data, &updated, &mask)?;
     }
 
-    tx.commit().unwrap();
+    remove_item(&tx, model, source)?;
+    tx.commit(now_millis())?;
+    return Ok(());
+  }
 
-    return Ok(id);
+  /// Удаляет все документы с истекшим TTL. Возвращает количество удаленных документов
+  pub fn sweep_expired(&self, now: u64) -> Result<usize, MarciError> {
+    let mut removed = 0;
+    for model in self.schema.models.iter() {
+      let Some(ttl) = &model.ttl else { continue };
+
+      let expired: Vec<u64> = {
+        let rx = self.db.begin_read()?;
+        let queue = read_tree(&rx, ttl.queue_tree_name.as_bytes())?;
+        queue.range_keys(..(now + 1).to_be_bytes())?
+          .map(|key| key_id(key?.get(8..).unwrap_or_default()))
+          .collect::<Result<_, _>>()?
+      };
+      if expired.is_empty() {
+        continue;
+      }
+
+      // Любая ошибка, кроме `restrict`, откатывает транзакцию модели: удаления не попадают в базу частично
+      let tx = self.begin_write()?;
+      for id in expired {
+        match delete_item(&tx, &self.schema, model, id) {
+          Ok(()) => removed += 1,
+          // Документ, удаление которого запрещено `restrict`, остается в очереди до следующего прохода
+          Err(MarciError::Delete(DeleteError::Restricted { .. })) => {}
+          Err(err) => return Err(err),
+        }
+      }
+      tx.commit(now_millis())?;
+    }
+    return Ok(removed);
+  }
+
+  /// Прочитывает деревья моделей с `@@warm`, чтобы прогреть кэш страниц canopydb.
+  /// Возвращает количество прочитанных записей
+  pub fn warm(&self) -> usize {
+    let rx = self.db.begin_read().unwrap();
+    let mut touched = 0;
+
+    for model in self.schema.models.iter() {
+      if !model.attributes.iter().any(|attr| matches!(attr, ModelAttribute::Warm)) {
+        continue;
+      }
+
+      for tree_name in model_trees(model) {
+        let Some(tree) = rx.get_tree(tree_name).unwrap() else { continue };
+        for item in tree.iter().unwrap() {
+          let (key, value) = item.unwrap();
+          std::hint::black_box((key.as_ref(), value.as_ref()));
+          touched += 1;
+        }
+      }
+    }
+    return touched;
+  }
+
+  /// Выборочная проверка целостности: для каждой модели проверяет до `sample` случайных документов
+  /// и наличие их записей в индексах. Возвращает количество проверенных документов и найденные проблемы
+  pub fn verify(&self, sample: usize) -> (usize, Vec<String>) {
+    let rx = self.db.begin_read().unwrap();
+    let mut problems = vec![];
+    let mut checked = 0;
+    let mut seed = now_millis() | 1;
+
+    for model in self.schema.models.iter() {
+      let tree = rx.get_tree(model.name.as_bytes()).unwrap().unwrap();
+      let Some((first, _)) = tree.first().unwrap() else { continue };
+      let min_id = u64::from_be_bytes(first.as_ref().try_into().unwrap());
+      let max_id = get_max_id(&tree).unwrap();
+
+      // Небольшие модели проверяются целиком, в остальных берется документ, следующий за случайным id
+      let mut ids = vec![];
+      if tree.len() as usize <= sample {
+        ids.extend(tree.range_keys::<&[u8]>(..).unwrap().map(|key| u64::from_be_bytes(key.unwrap().as_ref().try_into().unwrap())));
+      } else {
+        for _ in 0..sample {
+          seed ^= seed << 13;
+          seed ^= seed >> 7;
+          seed ^= seed << 17;
+          let start = min_id + seed % (max_id - min_id);
+          let Some(item) = tree.range(start.to_be_bytes()..).unwrap().next() else { continue };
+          ids.push(u64::from_be_bytes(item.unwrap().0.as_ref().try_into().unwrap()));
+        }
+        ids.sort_unstable();
+        ids.dedup();
+      }
+
+      for id in ids {
+        let data = unpack(tree.get(&id.to_be_bytes()).unwrap().unwrap());
+        checked += 1;
+        if let Err(err) = verify_document(&model.fields, &data, model.payload_offset) {
+          problems.push(format!("{} {}: {:?}", model.name, id, err));
+          continue;
+        }
+        for index in get_indexes(&data, id, model, None) {
+          let index_tree = rx.get_tree(index.tree_name).unwrap().unwrap();
+          if index_tree.get(&index.key).unwrap().is_none() {
+            problems.push(format!("{} {}: missing entry in {}", model.name, id, String::from_utf8_lossy(index.tree_name)));
+          }
+        }
+      }
+    }
+    return (checked, problems);
+  }
+
+  /// Полная проверка согласованности: каждая запись индекса указывает на существующий документ и соответствует
+  /// его текущему значению, у каждого документа есть все его записи индексов, ссылки указывают на существующие документы,
+  /// у каждой строки структуры есть документ-владелец. С `repair` лишние записи и строки удаляются, недостающие записи
+  /// индексов добавляются в той же транзакции (через журнал). Ссылки на удаленные документы только выводятся
+  pub fn check(&self, repair: bool) -> Result<CheckReport, MarciError> {
+    if !repair {
+      let rx = self.db.begin_read()?;
+      let (checked, found) = find_inconsistencies(&self.schema, &rx);
+      return Ok(CheckReport { checked, problems: found.into_iter().map(|item| item.message).collect(), repaired: 0 });
+    }
+
+    let tx = self.begin_write()?;
+    let (checked, found) = find_inconsistencies(&self.schema, &tx);
+    let mut repaired = 0;
+    for item in found.iter() {
+      match &item.fix {
+        Some(Fix::Delete { tree, key }) => { write_tree(&tx, tree)?.delete(key)?; }
+        Some(Fix::Insert { tree, key }) => { write_tree(&tx, tree)?.insert(key, &[1])?; }
+        None => continue
+      }
+      repaired += 1;
+    }
+    tx.commit(now_millis())?;
+    return Ok(CheckReport { checked, problems: found.into_iter().map(|item| item.message).collect(), repaired });
   }
 
-  pub fn delete(&self, model: &Model, id: u64) -> bool {
-    let tx = self.db.begin_write().unwrap();
+  /// Избирательность поля полным обходом модели: количество документов с непустым значением и различных значений
+  pub fn field_selectivity(&self, model: &Model, field: &Field) -> (usize, usize) {
+    let rx = self.db.begin_read().unwrap();
+    let tree = rx.get_tree(model.name.as_bytes()).unwrap().unwrap();
+
+    let mut values = HashSet::new();
+    let mut documents = 0;
+    for item in tree.iter().unwrap() {
+      let data = unpack(item.unwrap().1);
+      let Ok(Some(value)) = get_value_with_len(&data, field.offset_pos, model.payload_offset) else { continue };
+      documents += 1;
+      values.insert(value.to_vec());
+    }
+    return (documents, values.len());
+  }
+
+  pub fn has_ttl(&self) -> bool {
+    return self.schema.models.iter().any(|model| model.ttl.is_some());
+  }
+
+  /// Создает деревья текущей схемы, которых нет в базе, и перечитывает счетчики id.
+  /// Нужна после замены содержимого снимком (восстановление из бэкапа)
+  pub fn ensure_trees(&self) -> Result<(), MarciError> {
+    let tx = self.db.begin_write()?;
+    create_trees(&tx, &self.schema);
+    tx.commit()?;
+    return self.reload_counters();
+  }
+
+  /// Сохраняет счетчики id в `$meta`, чтобы следующее открытие базы не искало наибольший id в каждом дереве
+  /// моделей и списков структур. Вызывается при закрытии базы
+  pub fn persist_counters(&self) -> Result<(), MarciError> {
+    let tx = self.db.begin_write()?;
     {
-      let mut tree = tx.get_tree(model.name.as_bytes()).unwrap().unwrap();
-      if !tree.delete(&id.to_be_bytes()).unwrap() {
-        return false;
+      let mut meta = tx.get_or_create_tree(META_TREE)?;
+      for (name, counter_idx) in counter_trees(&self.schema) {
+        let key = [META_COUNTER_PREFIX, name].concat();
+        meta.insert(&key, &self.counters[counter_idx].peek().to_be_bytes())?;
+      }
+      meta.insert(META_COUNTERS_SAVED, &[1])?;
+    }
+    tx.commit()?;
+    return Ok(());
+  }
+
+  /// Пересчитывает счетчики id по содержимому деревьев (после применения чужого журнала)
+  pub fn reload_counters(&self) -> Result<(), MarciError> {
+    let rx = self.db.begin_read()?;
+    for model in self.schema.models.iter() {
+      let tree = read_tree(&rx, model.name.as_bytes())?;
+      self.counters[model.counter_idx].reset(get_max_id(&tree)?);
+
+      for field in model.struct_fields() {
+        if let FieldType::StructList(st, counter_idx) = &field.ty {
+          let tree = read_tree(&rx, st.name.as_bytes())?;
+          self.counters[*counter_idx].reset(get_max_id(&tree)?);
+        }
       }
     }
-    tx.commit().unwrap();
-    return true;
+    return Ok(());
+  }
+
+}
+
+/// `f` для каждого документа на `threads` потоках: документы делятся на отрезки подряд идущих id,
+/// результаты отрезков склеиваются в исходном порядке
+fn parallel_map<T: Send, F: Fn(u64, &[u8]) -> T + Sync>(docs: &[(u64, &[u8])], threads: usize, f: F) -> Vec<T> {
+  let chunk = docs.len().div_ceil(threads.max(1)).max(1);
+  let f = &f;
+  return std::thread::scope(|scope| {
+    let workers: Vec<_> = docs.chunks(chunk)
+      .map(|chunk| scope.spawn(move || chunk.iter().map(|&(id, data)| f(id, data)).collect::<Vec<T>>()))
+      .collect();
+    workers.into_iter()
+      .flat_map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+      .collect()
+  });
+}
+
+/// Дерево модели, структуры или индекса в транзакции чтения
+fn read_tree<'a>(rx: &'a Transaction, name: &[u8]) -> Result<Tree<'a>, MarciError> {
+  return rx.get_tree(name)?.ok_or_else(|| MarciError::MissingTree(String::from_utf8_lossy(name).into_owned()));
+}
+
+/// `read_tree` в транзакции записи: изменения дерева попадают в журнал
+fn write_tree<'a>(tx: &'a JournalTx, name: &'a [u8]) -> Result<JournalTree<'a>, MarciError> {
+  return tx.get_tree(name)?.ok_or_else(|| MarciError::MissingTree(String::from_utf8_lossy(name).into_owned()));
+}
+
+/// Удаляет строки списка структур `st` документа `id`, кроме строк с id из `kept`.
+/// Возвращает записи индексов всех прежних строк (оставленные строки перезаписываются и индексируются заново) и их id
+fn clear_struct_rows<'a>(tx: &JournalTx, st: &'a Struct, id: u64, kept: &[u64]) -> Result<(Vec<IndexData<'a>>, Vec<u64>), MarciError> {
+  let mut tree = write_tree(tx, st.name.as_bytes())?;
+  let rows = tree.prefix(&id.to_be_bytes())?.map(|item| -> Result<_, MarciError> {
+    let (key, data) = item?;
+    return Ok((key_id(key.get(8..).unwrap_or_default())?, data.as_ref().to_vec()));
+  }).collect::<Result<Vec<_>, _>>()?;
+
+  let mut indexes = vec![];
+  let mut ids = vec![];
+  for (item_id, data) in rows {
+    indexes.extend(get_indexes(&data, item_id, st, None));
+    if !kept.contains(&item_id) {
+      tree.delete(&make_key(id, item_id))?;
+    }
+    ids.push(item_id);
   }
+  return Ok((indexes, ids));
+}
+
+/// id документа из ключа дерева модели
+pub(crate) fn key_id(key: &[u8]) -> Result<u64, MarciError> {
+  return key.try_into().map(u64::from_be_bytes).map_err(|_| MarciError::CorruptedKey(key.to_vec()));
+}
+
+/// Ключи с префиксом `id`: строки структур документа (`<id><item_id>`) и записи его прямых индексов.
+/// Id `u64::MAX` документам не выдается, для него диапазон пуст
+fn id_prefix(id: u64) -> Range<[u8; 8]> {
+  return id.to_be_bytes()..id.saturating_add(1).to_be_bytes();
+}
 
+/// Обход до первой ошибки чтения, ошибка сохраняется в `failed`. Так обход остается ленивым и может
+/// остановиться на `take` документов
+fn until_error<T, E: Into<MarciError>>(rows: impl Iterator<Item = Result<T, E>>, failed: &mut Option<MarciError>) -> impl Iterator<Item = T> {
+  return rows.map_while(move |row| match row {
+    Ok(row) => Some(row),
+    Err(err) => {
+      *failed = Some(err.into());
+      None
+    }
+  });
 }
 
 #[inline(always)]
-fn get_value<'a, const SIZE: usize>(
-    data: &'a [u8],
+fn get_value<const SIZE: usize>(
+    data: &[u8],
     offset_pos: usize,
-) -> Option<&'a [u8; SIZE]> {
-    let offset = get_offset(data, offset_pos);
+) -> Result<Option<&[u8; SIZE]>, DecodeError> {
+    let offset = get_offset(data, offset_pos)?;
     if offset == 0 {
-        return None;
+        return Ok(None);
     }
-    Some(data[offset..offset + SIZE].try_into().ok()?)
+    let value = data.get(offset..offset + SIZE).ok_or(DecodeError::OffsetOutOfRange)?;
+    Ok(Some(value.try_into().unwrap()))
+}
+
+/// Offset поля; 0 - поле равно null. Ошибка, если слот или сам offset выходят за пределы документа
+#[inline(always)]
+pub fn get_offset(data: &[u8], offset_pos: usize) -> Result<usize, DecodeError> {
+  // Слот за концом таблицы документа - поле, добавленное в схему после его записи
+  if offset_pos >= stored_payload_offset(data)? {
+    return Ok(0);
+  }
+  let bytes = data.get(offset_pos..offset_pos + 4).ok_or(DecodeError::OffsetOutOfRange)?;
+  let offset = u32::from_be_bytes(bytes.try_into().unwrap()) as usize;
+  if offset > data.len() {
+    return Err(DecodeError::OffsetOutOfRange);
+  }
+  return Ok(offset);
 }
 
+/// Конец таблицы offset-ов, с которым документ был записан (из заголовка).
+/// Меньше payload_offset модели, если поля добавлены в схему позже
 #[inline(always)]
-pub fn get_offset<'a>(data: &'a [u8], offset_pos: usize) -> usize {
-  return u32::from_be_bytes(data[offset_pos..offset_pos + 4].try_into().unwrap()) as usize;
+pub fn stored_payload_offset(data: &[u8]) -> Result<usize, DecodeError> {
+  let bytes = data.get(1..3).ok_or(DecodeError::BufferTooSmall)?;
+  return Ok(u16::from_be_bytes([bytes[0], bytes[1]]) as usize);
 }
 
 #[inline(always)]
-pub fn set_offset<'a>(data: &'a mut [u8], offset_pos: usize, offset: usize) {
+pub fn set_offset(data: &mut [u8], offset_pos: usize, offset: usize) {
   data[offset_pos..offset_pos+4].copy_from_slice(&(offset as u32).to_be_bytes());
 }
 
 #[inline(always)]
 pub fn get_end(data: &[u8], offset_pos: usize, payload_offset: usize) -> usize {
   for j in ((offset_pos+4)..payload_offset).step_by(4) {
-    let off_j = get_offset(data, j);
+    let off_j = get_offset(data, j).unwrap_or(data.len());
     if off_j != 0 {
       return off_j;
     }
@@ -463,7 +1746,7 @@ pub fn get_end(data: &[u8], offset_pos: usize, payload_offset: usize) -> usize {
   return data.len();
 }
 
-pub fn move_offsets<'a>(data: &'a mut [u8], offset_start: usize, offset_end: usize, diff: isize) {
+pub fn move_offsets(data: &mut [u8], offset_start: usize, offset_end: usize, diff: isize) {
   for j2 in (offset_start..offset_end).step_by(4) {
     let offset = u32::from_be_bytes(data[j2..j2+4].try_into().unwrap());
     if offset != 0 {
@@ -474,94 +1757,23 @@ pub fn move_offsets<'a>(data: &'a mut [u8], offset_start: usize, offset_end: usi
 }
 
 #[inline(always)]
-pub fn set_offset_null<'a>(data: &'a mut [u8], offset_pos: usize) {
+pub fn set_offset_null(data: &mut [u8], offset_pos: usize) {
   data[offset_pos..offset_pos+4].fill(0u8);
 }
 
-struct ManyIter<'a, const SIZE: usize> {
-    data: &'a [u8],
-    pos: usize,
-    end: usize,
-}
-
-impl<'a, const SIZE: usize> ManyIter<'a, SIZE> {
-  pub fn is_empty(&self) -> bool { return self.pos == self.end }
-}
-
-impl<'a, const SIZE: usize> ExactSizeIterator for ManyIter<'a, SIZE> {
-    fn len(&self) -> usize {
-        (self.end - self.pos) / SIZE
-    }
-}
-
-impl<'a, const SIZE: usize> Iterator for ManyIter<'a, SIZE> {
-    type Item = &'a [u8; SIZE];
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.pos >= self.end {
-            return None;
-        }
-
-        let item = self.data[self.pos..self.pos + SIZE].try_into().ok()?;
-        self.pos += SIZE;
-        Some(item)
-    }
-}
-
-#[inline(always)]
-fn increment_bytes_be(bytes: &[u8]) -> Vec<u8> {
-    let mut result = bytes.to_vec();
-    for b in result.iter_mut().rev() {
-        if *b == 0xFF {
-            *b = 0;
-        } else {
-            *b += 1;
-            return result;
-        }
-    }
-    // если было переполнение (все байты = 0xFF)
-    result.insert(0, 1);
-    result
-}
-
-fn get_array<'a, const SIZE: usize>(data: &'a[u8], offset_pos: usize) -> Option<ManyIter<'a, SIZE>> {
-
-  let offset = get_offset(data, offset_pos);
-  if offset == 0 {
-    return None;
-  }
-  
-  // читаем длину (константно 4 байта)
-  let len_bytes: &[u8; 4] = data[offset..offset+4].try_into().unwrap();
-  let len = u32::from_be_bytes(*len_bytes) as usize;
-
-  let start = offset + 4;
-  let end = start + len * SIZE;
-
-  Some(ManyIter { data, pos: start, end })
-}
-
 #[inline(always)]
-fn get_value_with_len<'a>(
-    data: &'a[u8],
+fn get_value_with_len(
+    data: &[u8],
     offset_pos: usize,
     payload_offset: usize
-) -> Option<&'a[u8]> {
-  let offset = get_offset(data, offset_pos);
+) -> Result<Option<&[u8]>, DecodeError> {
+  let offset = get_offset(data, offset_pos)?;
   if offset == 0 {
-    return None;
-  }
-
-  let mut offset_end = data.len();
-  for j in ((offset_pos+4)..payload_offset).step_by(4) {
-    let offset = get_offset(data, j);
-    if offset != 0 { 
-      offset_end = offset;
-      break;
-    }
+    return Ok(None);
   }
 
-  return Some(&data[offset..offset_end])
+  let value = data.get(offset..get_end(data, offset_pos, payload_offset)).ok_or(DecodeError::OffsetOutOfRange)?;
+  return Ok(Some(value));
 }
 
 struct ForeignKey<'a> {
@@ -576,13 +1788,9 @@ fn get_foreign_keys<'a>(data: &'a[u8], fields: &'a [Field], schema: &'a Schema)
 
   for field in fields.iter() {
     if field.derived_from.is_some() { continue; }
-    match field.ty {
-        FieldType::ModelRef(model_index) => {
-          if let Some(bytes) = get_value::<8>(data, field.offset_pos) {
-            foreign_keys.push(ForeignKey { model: &schema.models[model_index], field, id: bytes.clone() });
-          }
-        }
-        _  => { }
+    if let FieldType::ModelRef(model_index) = field.ty
+      && let Ok(Some(bytes)) = get_value::<8>(data, field.offset_pos) {
+      foreign_keys.push(ForeignKey { model: &schema.models[model_index], field, id: *bytes });
     }
   }
   return foreign_keys;
@@ -594,7 +1802,9 @@ fn collect_foreign_keys<'a>(data: &'a[u8], fields: &'a [Field], structs: &'a [In
   // Проверяем foreign_keys в дочерних структурах
   for st in structs {
     match st {
-      InsertStruct::Connect { field, ref_model, ids } => {
+      // Для `disconnect` существование документов не проверяется
+      InsertStruct::Connect { op: Some(ListOp::Remove), .. } => {}
+      InsertStruct::Connect { field, ref_model, ids, .. } => {
         for item_id in ids.iter() {
           let model = &schema.models[*ref_model];
           foreign_keys.push(ForeignKey { model, field, id: item_id.to_be_bytes() });
@@ -615,42 +1825,130 @@ fn collect_foreign_keys<'a>(data: &'a[u8], fields: &'a [Field], structs: &'a [In
 }
 
 #[inline(always)]
-fn check_foreign_keys(tx: &Transaction, foreign_keys: &[ForeignKey]) -> Result<(), InsertError> {
+fn check_foreign_keys(tx: &Transaction, foreign_keys: &[ForeignKey]) -> Result<(), MarciError> {
   for item in foreign_keys {
-    let tree = tx.get_tree(item.model.name.as_bytes()).unwrap().unwrap();
-    if tree.get(&item.id).unwrap().is_none() {
-      return Err(InsertError::ForeignKeyViolation(item.field.name.clone(), u64::from_be_bytes(item.id)))
+    let tree = read_tree(tx, item.model.name.as_bytes())?;
+    if tree.get(&item.id)?.is_none() {
+      return Err(InsertError::ForeignKeyViolation(item.field.name.clone(), u64::from_be_bytes(item.id)).into())
     }
   }
   return Ok(());
 }
 
-#[inline(always)]
-/// Находит все ключи в индексе через ключ A, возвращает массив ключей B
-fn find_by_direct(rx: &Transaction, tree_name: &[u8], item_id: u64) -> Vec<Vec<u8>> {
-  let index_tree = rx.get_tree(tree_name).unwrap()
-    .unwrap_or_else(|| panic!("Index {} not found", str::from_utf8(tree_name).unwrap()));
-
-  let iter = index_tree.prefix_keys(&item_id.to_be_bytes()).unwrap();
-  iter.map(|k| k.unwrap()[8..].to_vec()).collect()
+/// Значения `@id` и `@unique` нового или измененного документа не должны принадлежать другому документу.
+/// Поле без значения в `data` (не менялось в update) не проверяется
+fn check_unique_key(tx: &Transaction, model: &Model, id: u64, data: &[u8]) -> Result<(), MarciError> {
+  for field in model.unique_fields() {
+    if get_offset(data, field.offset_pos).map_err(|_| InsertError::CorruptedData(id))? == 0 {
+      continue;
+    }
+    let value = decode_field(field, data, model.payload_offset).map_err(|_| InsertError::CorruptedData(id))?;
+    let ids = index_lookup(tx, field, std::slice::from_ref(&value))?.unwrap_or_default();
+    if ids.iter().any(|other| *other != id) {
+      return Err(InsertError::DuplicateKey(field.name.clone()).into());
+    }
+  }
+  return Ok(());
 }
 
 #[inline(always)]
-fn make_key(a: u64, b: u64) -> [u8; 16] {
-  let mut key = [0u8; 16];
-  key[..8].copy_from_slice(&a.to_be_bytes());
-  key[8..].copy_from_slice(&b.to_be_bytes());
-  key
+/// Находит все ключи в индексе через ключ A, возвращает массив ключей B
+fn find_by_direct(rx: &Transaction, tree_name: &[u8], item_id: u64) -> Result<Vec<u64>, MarciError> {
+  let index_tree = read_tree(rx, tree_name)?;
+  return index_tree.prefix_keys(&item_id.to_be_bytes())?
+    .map(|key| key_id(key?.get(8..).unwrap_or_default()))
+    .collect();
 }
 
-#[inline(always)]
-fn insert_index(tree: &mut Tree, left: u64, right: u64) {
-    let key = make_key(left, right);
-    tree.insert(&key, &[1]).unwrap();
-}
+/// Документы модели на момент `as_of`: все записи журнала до этого времени включительно, примененные к дереву модели по порядку.
+/// Нужна полная история с первой записи: после `--journal-retention` начальное состояние неизвестно
+fn documents_as_of(rx: &Transaction, model: &Model, as_of: u64) -> Result<Vec<(u64, Vec<u8>)>, HistoryError> {
+  let tree_name = model.name.as_bytes();
+  let journal = read_tree(rx, JOURNAL_TREE).map_err(HistoryError::Read)?;
+  if let Some((key, record)) = journal.first().map_err(|err| HistoryError::Read(err.into()))? {
+    let seq = key_id(key.as_ref()).map_err(HistoryError::Read)?;
+    if seq != 1 {
+      let since = decode_record(record.as_ref()).map(|record| record.timestamp).unwrap_or(0);
+      return Err(HistoryError::Pruned { seq, since });
+    }
+  }
 
-struct IndexData<'a> {
-  tree_name: &'a[u8],
+  let mut documents: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+  for item in journal.iter().map_err(|err| HistoryError::Read(err.into()))? {
+    let (_, record) = item.map_err(|err| HistoryError::Read(err.into()))?;
+    let Some(record) = decode_record(record.as_ref()) else { continue };
+    if record.timestamp > as_of {
+      break;
+    }
+    for op in record.ops {
+      match op {
+        JournalOp::Put { tree, key, value } if tree == tree_name => {
+          documents.insert(key, unpack(value).to_vec());
+        }
+        JournalOp::Delete { tree, key } if tree == tree_name => {
+          documents.remove(&key);
+        }
+        JournalOp::DeleteRange { tree, start, end } if tree == tree_name => {
+          documents.retain(|key, _| *key < start || *key >= end);
+        }
+        _ => {}
+      }
+    }
+  }
+
+  return documents.into_iter()
+    .map(|(key, value)| Ok((key_id(&key)?, value)))
+    .collect::<Result<_, MarciError>>()
+    .map_err(HistoryError::Read);
+}
+
+/// Данные связи из значения записи прямого индекса. `[1]` - связь записана до добавления `@payload`
+fn decode_payload<U, F>(id: u64, value: Option<&[u8]>, st: &Struct, f: &F) -> Result<Option<U>, DecodeError>
+where
+    F: Fn(DecodeCtx<U>) -> Result<U, DecodeError>,
+{
+  let Some(data) = value.filter(|value| value.len() >= 3) else {
+    return Ok(None);
+  };
+  let mut select = BitVec::repeat(true, st.fields.len() + 1);
+  select.set(0, false);
+  let ctx = DecodeCtx { id, data, fields: &st.fields, payload_offset: st.payload_offset, select: &select, includes: vec![], expires_at: None, payload: None };
+  return f(ctx).map(Some);
+}
+
+/// Записи прямого индекса с ключом A: ключ B и значение (данные связи или `[1]`)
+fn find_direct_entries(rx: &Transaction, tree_name: &[u8], item_id: u64) -> Result<Vec<(u64, Vec<u8>)>, MarciError> {
+  let index_tree = read_tree(rx, tree_name)?;
+  direct_entries(&index_tree, item_id)
+}
+
+/// Записи связи документа `item_id` в уже открытом дереве связи
+fn direct_entries(index_tree: &Tree, item_id: u64) -> Result<Vec<(u64, Vec<u8>)>, MarciError> {
+  index_tree.prefix(&item_id.to_be_bytes())?
+    .map(|item| {
+      let (key, value) = item?;
+      Ok((key_id(key.get(8..).unwrap_or_default())?, value.to_vec()))
+    })
+    .collect()
+}
+
+#[inline(always)]
+fn make_key(a: u64, b: u64) -> [u8; 16] {
+  let mut key = [0u8; 16];
+  key[..8].copy_from_slice(&a.to_be_bytes());
+  key[8..].copy_from_slice(&b.to_be_bytes());
+  key
+}
+
+#[inline(always)]
+fn insert_index(tree: &mut JournalTree, left: u64, right: u64) -> Result<(), MarciError> {
+    let key = make_key(left, right);
+    tree.insert(&key, &[1])?;
+    return Ok(());
+}
+
+struct IndexData<'a> {
+  tree_name: &'a[u8],
   key: Vec<u8>
 }
 
@@ -662,13 +1960,14 @@ fn get_indexes<'a, T>(data: &[u8], item_id: u64, model: &'a T, mask: Option<&Bit
   for field in model.fields() {
     if field.offset_pos == 0 || field.inserted_indexes.is_empty() { continue; }
     if mask.is_some_and(|f| !f[field.offset_index]) { continue; }
-    let Some(value) = get_value_with_len(data, field.offset_pos, model.payload_offset()) else {
+    let Some(value) = field_bytes(data, field, model.payload_offset()) else {
       continue;
     };
+    let value = value.as_ref();
     for index in &field.inserted_indexes {
       match index {
         InsertedIndex::Rev { tree_name } => {
-          let key = [value, &item_id.to_be_bytes()].concat();
+          let key = [&index_value(&field.ty, value), &item_id.to_be_bytes()[..]].concat();
           indexes.push(IndexData { tree_name: tree_name.as_bytes(), key });
         },
         InsertedIndex::Direct { tree_name } => {
@@ -678,73 +1977,1266 @@ fn get_indexes<'a, T>(data: &[u8], item_id: u64, model: &'a T, mask: Option<&Bit
       }
     }
   }
+
+  for index in model.compound_indexes() {
+    let fields = model.fields();
+    if mask.is_some_and(|m| !index.fields.iter().any(|i| m[fields[*i].offset_index])) { continue; }
+    let Some(key) = compound_key(data, item_id, fields, index, model.payload_offset()) else { continue };
+    indexes.push(IndexData { tree_name: index.tree_name.as_bytes(), key });
+  }
   
   return indexes;
 }
 
+/// Ключ составного индекса `[a][b]...[id]`. None - одно из полей не задано, такой документ в индекс не попадает
+fn compound_key(data: &[u8], item_id: u64, fields: &[Field], index: &CompoundIndex, payload_offset: usize) -> Option<Vec<u8>> {
+  let mut key = vec![];
+  for i in &index.fields {
+    let field = &fields[*i];
+    key.extend(compound_part(&field.ty, &field_bytes(data, field, payload_offset)?));
+  }
+  key.extend(item_id.to_be_bytes());
+  return Some(key);
+}
+
+
+/// Байты значения поля. Для поля, добавленного в схему после записи документа, - закодированный `@default`
+fn field_bytes<'a>(data: &'a [u8], field: &Field, payload_offset: usize) -> Option<Cow<'a, [u8]>> {
+  if let Ok(Some(value)) = get_value_with_len(data, field.offset_pos, payload_offset) {
+    return Some(Cow::Borrowed(value));
+  }
+  if field.offset_pos < stored_payload_offset(data).ok()? {
+    return None;
+  }
+  let (Some(value), FieldType::Primitive(ty)) = (field.default_value(), &field.ty) else { return None };
+  if value.is_null() {
+    return None;
+  }
+  let mut buf = vec![];
+  encode_value(&mut buf, ty, &field.name, value).ok()?;
+  return Some(Cow::Owned(buf));
+}
+
+/// Удаляет документ по правилам `@onDelete`. Сначала собирает все затронутые документы,
+/// поэтому при `restrict` транзакция не изменяется
+fn delete_item(tx: &JournalTx, schema: &Schema, model: &Model, id: u64) -> Result<(), MarciError> {
+  let mut deleted = vec![];
+  let mut nulled = vec![];
+  collect_deleted(tx, schema, model, id, &mut deleted, &mut nulled)?;
+  if deleted.is_empty() {
+    return Err(DeleteError::ItemNotFound(id).into());
+  }
+
+  for (model, field, id) in nulled {
+    if deleted.iter().any(|(deleted_model, deleted_id)| std::ptr::eq(*deleted_model, model) && *deleted_id == id) {
+      continue;
+    }
+    set_field_null(tx, model, field, id)?;
+  }
+  for (model, id) in deleted {
+    remove_item(tx, model, id)?;
+    unlink_from_lists(tx, schema, model, id)?;
+  }
+  return Ok(());
+}
+
+/// Документы, которые будут удалены, и ссылки, которые будут обнулены при удалении `model` `id`
+fn collect_deleted<'a>(tx: &JournalTx, schema: &'a Schema, model: &'a Model, id: u64, deleted: &mut Vec<(&'a Model, u64)>, nulled: &mut Vec<(&'a Model, &'a Field, u64)>) -> Result<(), MarciError> {
+  if deleted.iter().any(|(deleted_model, deleted_id)| std::ptr::eq(*deleted_model, model) && *deleted_id == id) {
+    return Ok(());
+  }
+  if write_tree(tx, model.name.as_bytes())?.get(&id.to_be_bytes())?.is_none() {
+    return Ok(());
+  }
+  deleted.push((model, id));
+
+  for ref_model in schema.models.iter() {
+    for field in ref_model.fields.iter() {
+      let FieldType::ModelRef(target) = field.ty else { continue };
+      let Some(rule) = field.on_delete() else { continue };
+      if !std::ptr::eq(&schema.models[target], model) {
+        continue;
+      }
+      let Some(index) = field.inserted_indexes.iter().find(|index| matches!(index, InsertedIndex::Rev { .. })) else { continue };
+
+      for ref_id in find_by_direct(tx, index.tree_name(), id)? {
+        match rule {
+          OnDelete::Restrict => {
+            if !(std::ptr::eq(ref_model, model) && ref_id == id) {
+              return Err(DeleteError::Restricted { model: ref_model.name.clone(), field: field.name.clone(), id: ref_id }.into());
+            }
+          }
+          OnDelete::Cascade => collect_deleted(tx, schema, ref_model, ref_id, deleted, nulled)?,
+          OnDelete::SetNull => nulled.push((ref_model, field, ref_id)),
+        }
+      }
+    }
+  }
+  return Ok(());
+}
+
+/// Записывает null в поле связи и убирает его записи из индексов
+fn set_field_null(tx: &JournalTx, model: &Model, field: &Field, id: u64) -> Result<(), MarciError> {
+  let mut tree = write_tree(tx, model.name.as_bytes())?;
+  let Some(data) = tree.get(&id.to_be_bytes())?.map(unpack) else {
+    return Ok(());
+  };
+  let mut changed_mask = BitVec::repeat(false, model.fields.len());
+  changed_mask.set(field.offset_index, true);
+  let updated = update_data(&model.fields, model.payload_offset, &data, &empty_document(model.payload_offset), &changed_mask, &[])
+    .map_err(|_| DeleteError::CorruptedData(id))?;
+  tree.insert(&id.to_be_bytes(), &pack(model, &updated))?;
+  drop(tree);
+
+  for index in get_indexes(&data, id, model, Some(&changed_mask)) {
+    let mut index_tree = write_tree(tx, index.tree_name)?;
+    index_tree.delete(&index.key)?;
+  }
+  return Ok(());
+}
+
+/// Переводит поля связей документов `ref_model`, которые ссылаются на `from` модели `target`, на `to`.
+/// Документы находятся по обратным индексам полей, а если у какого-то поля индекса нет - полным обходом
+fn repoint_model_refs(tx: &JournalTx, ref_model: &Model, target: usize, from: u64, to: u64) -> Result<(), MarciError> {
+  let fields: Vec<&Field> = ref_model.fields.iter()
+    .filter(|field| field.offset_pos != 0 && matches!(field.ty, FieldType::ModelRef(model_index) if model_index == target))
+    .collect();
+  if fields.is_empty() {
+    return Ok(());
+  }
+  let rev_indexes: Vec<&InsertedIndex> = fields.iter()
+    .filter_map(|field| field.inserted_indexes.iter().find(|index| matches!(index, InsertedIndex::Rev { .. })))
+    .collect();
+
+  let mut rows: Vec<(Vec<u8>, u64, Vec<u8>)> = vec![];
+  {
+    let tree = write_tree(tx, ref_model.name.as_bytes())?;
+    if rev_indexes.len() == fields.len() {
+      let mut ids = vec![];
+      for index in rev_indexes {
+        ids.extend(find_by_direct(tx, index.tree_name(), from)?);
+      }
+      ids.sort_unstable();
+      ids.dedup();
+      for id in ids {
+        if let Some(data) = tree.get(&id.to_be_bytes())? {
+          rows.push((id.to_be_bytes().to_vec(), id, unpack(data).to_vec()));
+        }
+      }
+    } else {
+      for item in tree.iter()? {
+        let (key, data) = item?;
+        let data = unpack(data).to_vec();
+        if repointed_fields(&ref_model.fields, ref_model.payload_offset, &data, target, from, to).is_some() {
+          rows.push((key.to_vec(), key_id(key.as_ref())?, data));
+        }
+      }
+    }
+  }
+  return repoint_rows(tx, ref_model, rows, target, from, to);
+}
+
+/// Переводит на `to` ссылки на `from` в строках структуры
+fn repoint_struct_refs(tx: &JournalTx, st: &Struct, target: usize, from: u64, to: u64) -> Result<(), MarciError> {
+  if !st.fields.iter().any(|field| matches!(field.ty, FieldType::ModelRef(model_index) if model_index == target)) {
+    return Ok(());
+  }
+  let mut rows: Vec<(Vec<u8>, u64, Vec<u8>)> = vec![];
+  {
+    let tree = write_tree(tx, st.name.as_bytes())?;
+    for item in tree.iter()? {
+      let (key, data) = item?;
+      if repointed_fields(&st.fields, st.payload_offset, &data, target, from, to).is_none() {
+        continue;
+      }
+      // У элементов списка ключ `[id, item_id]`, индексы структуры строятся по item_id
+      let item_id = key_id(&key[key.len().saturating_sub(8)..])?;
+      rows.push((key.to_vec(), item_id, data.to_vec()));
+    }
+  }
+  return repoint_rows(tx, st, rows, target, from, to);
+}
+
+/// Переписывает строки `(key, id, data)`, в которых поля связей ссылаются на `from`
+fn repoint_rows<T: WithFields>(tx: &JournalTx, model: &T, rows: Vec<(Vec<u8>, u64, Vec<u8>)>, target: usize, from: u64, to: u64) -> Result<(), MarciError> {
+  for (key, id, data) in rows {
+    let Some((new_data, mask)) = repointed_fields(model.fields(), model.payload_offset(), &data, target, from, to) else { continue };
+    let updated = update_data(model.fields(), model.payload_offset(), &data, &new_data, &mask, &[])
+      .map_err(|_| InsertError::CorruptedData(id))?;
+    rewrite_row(tx, model, &key, id, &data, &updated, &mask)?;
+  }
+  return Ok(());
+}
+
+/// Поля связей документа, которые ссылаются на `from`: документ, в котором они равны `to`, и маска этих полей
+fn repointed_fields(fields: &[Field], payload_offset: usize, data: &[u8], target: usize, from: u64, to: u64) -> Option<(Vec<u8>, BitVec)> {
+  let mut new_data = empty_document(payload_offset);
+  let mut mask = BitVec::repeat(false, fields.len());
+  for field in fields.iter() {
+    if field.offset_pos == 0 || field.derived_from.is_some() { continue }
+    let FieldType::ModelRef(model_index) = field.ty else { continue };
+    if model_index != target || get_value::<8>(data, field.offset_pos).ok().flatten() != Some(&from.to_be_bytes()) {
+      continue;
+    }
+    let offset = new_data.len();
+    set_offset(&mut new_data, field.offset_pos, offset);
+    new_data.extend_from_slice(&to.to_be_bytes());
+    mask.set(field.offset_index, true);
+  }
+  if mask.not_any() {
+    return None;
+  }
+  return Some((new_data, mask));
+}
+
+/// Документы, в списке связей `field` которых есть `target`: по обратному индексу, а без него - обходом прямого
+fn list_owners(tx: &JournalTx, field: &Field, target: u64) -> Result<Vec<u64>, MarciError> {
+  if let Some(rev) = field.inserted_indexes.iter().find(|index| matches!(index, InsertedIndex::Rev { .. })) {
+    return find_by_direct(tx, rev.tree_name(), target);
+  }
+  let Some(direct) = field.inserted_indexes.iter().find(|index| matches!(index, InsertedIndex::Direct { .. })) else { return Ok(vec![]) };
+  let tree = write_tree(tx, direct.tree_name())?;
+  let mut owners = vec![];
+  for key in tree.keys()? {
+    let key = key?;
+    if key.get(8..) == Some(&target.to_be_bytes()[..]) {
+      owners.push(key_id(&key[..8])?);
+    }
+  }
+  return Ok(owners);
+}
+
+/// Убирает удаленный документ из списков связей других документов, которые на него указывают
+fn unlink_from_lists(tx: &JournalTx, schema: &Schema, model: &Model, id: u64) -> Result<(), MarciError> {
+  let Some(model_index) = schema.models.iter().position(|m| std::ptr::eq(m, model)) else { return Ok(()) };
+  for ref_model in schema.models.iter() {
+    for field in ref_model.fields.iter() {
+      if !matches!(field.ty, FieldType::ModelRefList(target) if target == model_index) || field.derived_from.is_some() {
+        continue;
+      }
+      for owner in list_owners(tx, field, id)? {
+        unlink_indexes(tx, field, owner, &[id])?;
+      }
+    }
+  }
+  return Ok(());
+}
+
+/// Переводит элементы списка связей `field`, указывающие на `from`, на `to`
+fn repoint_list_refs(tx: &JournalTx, field: &Field, from: u64, to: u64) -> Result<(), MarciError> {
+  let owners = list_owners(tx, field, from)?;
+  if owners.is_empty() {
+    return Ok(());
+  }
+
+  for index in field.inserted_indexes.iter() {
+    let mut tree = write_tree(tx, index.tree_name())?;
+    for &owner in owners.iter() {
+      let (old, new) = match index {
+        InsertedIndex::Direct { .. } => (make_key(owner, from), make_key(owner, to)),
+        InsertedIndex::Rev { .. } => (make_key(from, owner), make_key(to, owner)),
+      };
+      // Данные связи (`@payload`) переходят к новой записи
+      let value = tree.get(&old)?.map(|value| value.to_vec()).unwrap_or_else(|| vec![1]);
+      tree.delete(&old)?;
+      tree.insert(&new, &value)?;
+    }
+  }
+  return Ok(());
+}
+
+/// Поля target, которые при слиянии получают значение из source
+fn merge_mask(model: &Model, target: &[u8], source: &[u8], strategy: MergeStrategy) -> BitVec {
+  let mut mask = BitVec::repeat(false, model.fields.len());
+  for field in model.fields.iter() {
+    if field.offset_pos == 0 || !matches!(field.ty, FieldType::Primitive(_) | FieldType::PrimitiveList(_) | FieldType::ModelRef(_)) {
+      continue;
+    }
+    // update_data переносит байты документа, поэтому у source учитываются только сохраненные значения, без `@default`
+    let has_source = matches!(get_value_with_len(source, field.offset_pos, model.payload_offset), Ok(Some(_)));
+    let take = match strategy {
+      MergeStrategy::KeepTarget => false,
+      MergeStrategy::FillNulls => has_source && field_bytes(target, field, model.payload_offset).is_none(),
+      MergeStrategy::PreferSource => has_source,
+    };
+    mask.set(field.offset_index, take);
+  }
+  return mask;
+}
+
+/// Записывает обновленный документ и переносит записи индексов измененных полей
+fn rewrite_row<T: WithFields>(tx: &JournalTx, model: &T, key: &[u8], id: u64, data: &[u8], updated: &[u8], mask: &BitVec) -> Result<(), MarciError> {
+  {
+    let mut tree = write_tree(tx, model.tree_name())?;
+    tree.insert(key, &pack(model, updated))?;
+  }
+  for index in get_indexes(data, id, model, Some(mask)) {
+    let mut index_tree = write_tree(tx, index.tree_name)?;
+    index_tree.delete(&index.key)?;
+  }
+  for index in get_indexes(updated, id, model, Some(mask)) {
+    let mut index_tree = write_tree(tx, index.tree_name)?;
+    index_tree.insert(&index.key, &[1])?;
+  }
+  return Ok(());
+}
+
+/// Удаляет документ и связанные с ним служебные записи: индексы, структуры, связи-списки и время жизни
+fn remove_item(tx: &JournalTx, model: &Model, id: u64) -> Result<(), MarciError> {
+  {
+    let mut tree = write_tree(tx, model.name.as_bytes())?;
+    let Some(data) = tree.get(&id.to_be_bytes())?.map(unpack) else {
+      return Ok(());
+    };
+    tree.delete(&id.to_be_bytes())?;
+
+    for index in get_indexes(&data, id, model, None) {
+      let mut index_tree = write_tree(tx, index.tree_name)?;
+      index_tree.delete(&index.key)?;
+    }
+  }
+
+  for field in model.struct_fields() {
+    let (FieldType::Struct(st) | FieldType::StructList(st, _)) = &field.ty else { continue };
+    let mut tree = write_tree(tx, st.name.as_bytes())?;
+    let rows: Vec<(u64, Vec<u8>)> = tree.range(id_prefix(id))?
+      .map(|item| -> Result<_, MarciError> {
+        let (key, data) = item?;
+        // У элементов списка ключ `[id, item_id]`, индексы структуры строятся по item_id
+        let item_id = key_id(&key[key.len().saturating_sub(8)..])?;
+        Ok((item_id, data.to_vec()))
+      })
+      .collect::<Result<_, _>>()?;
+    tree.delete_range(id_prefix(id))?;
+    drop(tree);
+
+    for (item_id, data) in rows {
+      for index in get_indexes(&data, item_id, st, None) {
+        let mut index_tree = write_tree(tx, index.tree_name)?;
+        index_tree.delete(&index.key)?;
+      }
+    }
+  }
+
+  // Обратная сторона многие-ко-многим тоже хранит связи в своем дереве, поэтому чистится, как и основная
+  for field in model.fields.iter() {
+    let is_many_to_many = field.inserted_indexes.iter().any(|index| matches!(index, InsertedIndex::Rev { .. }));
+    if matches!(field.ty, FieldType::ModelRefList(_)) && (field.derived_from.is_none() || is_many_to_many) {
+      remove_indexes(tx, field, id)?;
+    }
+  }
+
+  if let Some(ttl) = &model.ttl {
+    set_expiry(tx, ttl, id, None)?;
+  }
+  return Ok(());
+}
+
+/// Применяет к документу триггеры `event` модели (`@@onInsert`/`@@onUpdate`).
+/// Возвращает документ с заданными ими полями и маску этих полей, None - у модели нет таких триггеров
+fn apply_triggers(model: &Model, event: TriggerEvent, data: &[u8]) -> Result<Option<(Vec<u8>, BitVec)>, DecodeError> {
+  let mut values = empty_document(model.payload_offset);
+  let mut mask = BitVec::repeat(false, model.fields.len());
+  // Значения дописываются в порядке полей, иначе get_end не найдет их границы
+  for field in model.fields.iter() {
+    let Some(trigger) = model.attributes.iter().rev().find_map(|attr| match attr {
+      ModelAttribute::Trigger(trigger) if trigger.event == event && trigger.field == field.name => Some(trigger),
+      _ => None
+    }) else { continue };
+    let FieldType::Primitive(ty) = &field.ty else { continue };
+
+    let value = match &trigger.action {
+      TriggerAction::Set(value) => Cow::Borrowed(value),
+      TriggerAction::Touch => Cow::Owned(serde_json::Value::from(now_millis())),
+    };
+    // null: offset остается 0, поле очищается по маске
+    if !value.is_null() {
+      let offset = values.len();
+      set_offset(&mut values, field.offset_pos, offset);
+      encode_value(&mut values, ty, &field.name, &value).unwrap();
+    }
+    mask.set(field.offset_index, true);
+  }
+  if mask.not_any() {
+    return Ok(None);
+  }
+  let data = update_data(&model.fields, model.payload_offset, data, &values, &mask, &[])?;
+  return Ok(Some((data, mask)));
+}
+
+/// Документ, в котором все поля null
+fn empty_document(payload_offset: usize) -> Vec<u8> {
+  let mut data = vec![1];
+  data.extend_from_slice(&(payload_offset as u16).to_be_bytes());
+  data.resize(payload_offset, 0);
+  return data;
+}
+
+/// Байты значения в ключе индекса. Числа со знаком переводятся в вид,
+/// в котором порядок байт совпадает с порядком значений
+pub fn index_value(ty: &FieldType, value: &[u8]) -> Vec<u8> {
+  let mut value = value.to_vec();
+  match ty {
+    FieldType::Primitive(PrimitiveFieldType::Int64 | PrimitiveFieldType::Int32 | PrimitiveFieldType::Int16 | PrimitiveFieldType::Int8 | PrimitiveFieldType::DateTime) => {
+      value[0] ^= 0x80;
+    }
+    FieldType::Primitive(PrimitiveFieldType::Float | PrimitiveFieldType::Double) => {
+      if value[0] & 0x80 != 0 {
+        value.iter_mut().for_each(|b| *b = !*b);
+      } else {
+        value[0] ^= 0x80;
+      }
+    }
+    _ => {}
+  }
+  return value;
+}
+
+/// Часть ключа составного индекса. Перед значением переменной длины пишется его длина, чтобы соседние поля не склеивались
+pub fn compound_part(ty: &FieldType, value: &[u8]) -> Vec<u8> {
+  let is_fixed = match ty {
+    FieldType::ModelRef(_) => true,
+    FieldType::Primitive(ty) => ty.width().is_some(),
+    _ => false
+  };
+  let value = index_value(ty, value);
+  if is_fixed {
+    return value;
+  }
+  return [&(value.len() as u32).to_be_bytes()[..], &value].concat();
+}
+
+/// Создает деревья моделей, индексов, структур и времени жизни текущей схемы. Пустое дерево индекса строится
+/// по существующим документам: индекс добавлен в схему позже или снимок записан до его появления
+fn create_trees(tx: &WriteTransaction, schema: &Schema) {
+  tx.get_or_create_tree(JOURNAL_TREE).unwrap();
+  tx.get_or_create_tree(META_TREE).unwrap();
+  for model in schema.models.iter() {
+    tx.get_or_create_tree(model.name.as_bytes()).unwrap();
+
+    if let Some(ttl) = &model.ttl {
+      tx.get_or_create_tree(ttl.tree_name.as_bytes()).unwrap();
+      tx.get_or_create_tree(ttl.queue_tree_name.as_bytes()).unwrap();
+    }
+
+    for field in model.fields.iter() {
+      for index in &field.inserted_indexes {
+        match index {
+          InsertedIndex::Direct { tree_name } => {
+            tx.get_or_create_tree(tree_name.as_bytes()).unwrap();
+          },
+          InsertedIndex::Rev { tree_name } => {
+            let is_empty = tx.get_or_create_tree(tree_name.as_bytes()).unwrap().first().unwrap().is_none();
+            if is_empty && matches!(field.ty, FieldType::Primitive(_) | FieldType::ModelRef(_)) {
+              backfill_index(tx, &model.name, field, model.payload_offset, tree_name);
+            }
+          },
+        };
+      }
+    }
+
+    for field in model.struct_fields() {
+      if let FieldType::Struct(st) | FieldType::StructList(st, _) = &field.ty {
+        tx.get_or_create_tree(st.name.as_bytes()).unwrap();
+      }
+    }
+
+    for index in &model.indexes {
+      let is_empty = tx.get_or_create_tree(index.tree_name.as_bytes()).unwrap().first().unwrap().is_none();
+      if is_empty {
+        backfill_compound_index(tx, model, index);
+      }
+    }
+  }
+}
+
+/// Выдает спискам структур, в том числе вложенным, счетчики id
+fn assign_struct_counters(tx: &WriteTransaction, saved: &HashMap<Vec<u8>, u64>, ty: &mut FieldType, counters: &mut Vec<Box<dyn IdGenerator>>) {
+  let st = match ty {
+    FieldType::Struct(st) => st,
+    FieldType::StructList(st, counter_idx) => {
+      let max_id = next_tree_id(tx, saved, st.name.as_bytes());
+      *counter_idx = counters.len();
+      counters.push(id_generator(IdStrategy::AutoIncrement, max_id));
+      st
+    }
+    _ => return
+  };
+  for field in st.fields.iter_mut() {
+    assign_struct_counters(tx, saved, &mut field.ty, counters);
+  }
+}
+
+/// Счетчики, сохраненные `persist_counters` при закрытии базы. Отметка о сохранении снимается: если процесс
+/// упадет, не закрыв базу, при следующем открытии счетчики пересчитаются по деревьям
+fn take_saved_counters(tx: &WriteTransaction) -> Result<HashMap<Vec<u8>, u64>, MarciError> {
+  let mut meta = tx.get_tree(META_TREE)?.ok_or_else(|| MarciError::MissingTree(String::from_utf8_lossy(META_TREE).into_owned()))?;
+  if meta.get(META_COUNTERS_SAVED)?.is_none() {
+    return Ok(HashMap::new());
+  }
+  meta.delete(META_COUNTERS_SAVED)?;
+  let mut saved = HashMap::new();
+  for item in meta.prefix(META_COUNTER_PREFIX)? {
+    let (key, value) = item?;
+    // Поврежденное значение не мешает открыть базу: счетчик найдется по последнему ключу дерева
+    if let Ok(next) = value.as_ref().try_into().map(u64::from_be_bytes) {
+      saved.insert(key.as_ref()[META_COUNTER_PREFIX.len()..].to_vec(), next);
+    }
+  }
+  return Ok(saved);
+}
+
+/// Следующий id дерева: сохраненный при закрытии базы или по последнему ключу дерева
+fn next_tree_id(tx: &WriteTransaction, saved: &HashMap<Vec<u8>, u64>, name: &[u8]) -> u64 {
+  if let Some(next) = saved.get(name) {
+    return *next;
+  }
+  return get_max_id(&tx.get_tree(name).unwrap().unwrap()).unwrap();
+}
+
+/// Деревья моделей и списков структур, в том числе вложенных, и номера их счетчиков
+fn counter_trees(schema: &Schema) -> Vec<(&[u8], usize)> {
+  fn collect<'a>(fields: &'a [Field], out: &mut Vec<(&'a [u8], usize)>) {
+    for field in fields {
+      match &field.ty {
+        FieldType::StructList(st, counter_idx) => {
+          out.push((st.name.as_bytes(), *counter_idx));
+          collect(&st.fields, out);
+        }
+        FieldType::Struct(st) => collect(&st.fields, out),
+        _ => {}
+      }
+    }
+  }
+  let mut out = vec![];
+  for model in schema.models.iter() {
+    out.push((model.name.as_bytes(), model.counter_idx));
+    collect(&model.fields, &mut out);
+  }
+  return out;
+}
+
+/// Удаляет строки структур, вложенных в структуру документа `id`
+fn remove_nested_structs(tx: &JournalTx, fields: &[Field], id: u64) -> Result<(), MarciError> {
+  for field in fields {
+    let (FieldType::Struct(st) | FieldType::StructList(st, _)) = &field.ty else { continue };
+    let mut tree = write_tree(tx, st.name.as_bytes())?;
+    tree.delete_range(id_prefix(id))?;
+    drop(tree);
+    remove_nested_structs(tx, &st.fields, id)?;
+  }
+  return Ok(());
+}
+
+/// Обходит все документы, деревья индексов и строки структур схемы, см. `MarciDB::check`
+fn find_inconsistencies(schema: &Schema, rx: &Transaction) -> (usize, Vec<Inconsistency>) {
+  let mut found = vec![];
+  let mut checked = 0;
+
+  for model in schema.models.iter() {
+    let tree = rx.get_tree(model.name.as_bytes()).unwrap().unwrap();
+    for item in tree.iter().unwrap() {
+      let (key, data) = item.unwrap();
+      let id = u64::from_be_bytes(key.as_ref().try_into().unwrap());
+      checked += 1;
+      check_row(schema, rx, model, &format!("{} {}", model.name, id), id, &unpack(data), &mut found);
+    }
+
+    // Записи индексов, которые пишут поля модели. Производные списки читают деревья другой стороны
+    let mut index_trees: Vec<(&Field, &InsertedIndex)> = vec![];
+    for field in model.fields.iter().filter(|field| field.derived_from.is_none()) {
+      index_trees.extend(field.inserted_indexes.iter().map(|index| (field, index)));
+    }
+    for (field, index) in index_trees {
+      let index_tree = rx.get_tree(index.tree_name()).unwrap().unwrap();
+      for key in index_tree.keys().unwrap() {
+        let key = key.unwrap();
+        let problem = match field.ty {
+          FieldType::ModelRefList(target) => stale_link(rx, model, &schema.models[target], field, index, key.as_ref()),
+          _ => stale_entry(rx, model, index.tree_name(), key.as_ref()),
+        };
+        if let Some(message) = problem {
+          found.push(Inconsistency { message, fix: Some(Fix::Delete { tree: index.tree_name().to_vec(), key: key.to_vec() }) });
+        }
+      }
+    }
+    for index in model.indexes.iter() {
+      let index_tree = rx.get_tree(index.tree_name.as_bytes()).unwrap().unwrap();
+      for key in index_tree.keys().unwrap() {
+        let key = key.unwrap();
+        if let Some(message) = stale_entry(rx, model, index.tree_name.as_bytes(), key.as_ref()) {
+          found.push(Inconsistency { message, fix: Some(Fix::Delete { tree: index.tree_name.as_bytes().to_vec(), key: key.to_vec() }) });
+        }
+      }
+    }
+
+    // Строки структур: ключ начинается с id документа-владельца
+    for field in model.struct_fields() {
+      let (FieldType::Struct(st) | FieldType::StructList(st, _)) = &field.ty else { continue };
+      let st_tree = rx.get_tree(st.name.as_bytes()).unwrap().unwrap();
+      for item in st_tree.iter().unwrap() {
+        let (key, data) = item.unwrap();
+        if key.len() < 8 || tree.get(&key[..8]).unwrap().is_none() {
+          let message = format!("{}: row {} has no {} document", st.name, hex_key(key.as_ref()), model.name);
+          found.push(Inconsistency { message, fix: Some(Fix::Delete { tree: st.name.as_bytes().to_vec(), key: key.to_vec() }) });
+          continue;
+        }
+        let item_id = u64::from_be_bytes(key[key.len()-8..].try_into().unwrap());
+        check_row(schema, rx, st, &format!("{} {}", st.name, hex_key(key.as_ref())), item_id, data.as_ref(), &mut found);
+      }
+    }
+  }
+  return (checked, found);
+}
+
+/// Ссылки документа или строки структуры и ее записи в индексах
+fn check_row<T: WithFields>(schema: &Schema, rx: &Transaction, row: &T, label: &str, id: u64, data: &[u8], found: &mut Vec<Inconsistency>) {
+  for field in row.fields() {
+    let FieldType::ModelRef(target) = field.ty else { continue };
+    if field.offset_pos == 0 {
+      continue;
+    }
+    let Ok(Some(value)) = get_value_with_len(data, field.offset_pos, row.payload_offset()) else { continue };
+    let target = &schema.models[target];
+    if rx.get_tree(target.name.as_bytes()).unwrap().unwrap().get(value).unwrap().is_none() {
+      let message = format!("{}: {} points to missing {} {}", label, field.name, target.name, u64::from_be_bytes(value.try_into().unwrap_or_default()));
+      found.push(Inconsistency { message, fix: None });
+    }
+  }
+
+  for index in get_indexes(data, id, row, None) {
+    if rx.get_tree(index.tree_name).unwrap().unwrap().get(&index.key).unwrap().is_none() {
+      let message = format!("{}: missing entry in {}", label, String::from_utf8_lossy(index.tree_name));
+      found.push(Inconsistency { message, fix: Some(Fix::Insert { tree: index.tree_name.to_vec(), key: index.key }) });
+    }
+  }
+}
+
+/// Запись индекса значения (`[value, id]`) или составного индекса, которой нет среди записей документа:
+/// документ удален или значение его поля изменилось
+fn stale_entry(rx: &Transaction, model: &Model, tree_name: &[u8], key: &[u8]) -> Option<String> {
+  let name = String::from_utf8_lossy(tree_name);
+  if key.len() < 8 {
+    return Some(format!("{}: malformed entry {}", name, hex_key(key)));
+  }
+  let id = u64::from_be_bytes(key[key.len()-8..].try_into().unwrap());
+  let tree = rx.get_tree(model.name.as_bytes()).unwrap().unwrap();
+  let Some(data) = tree.get(&id.to_be_bytes()).unwrap().map(unpack) else {
+    return Some(format!("{}: entry for missing {} {}", name, model.name, id));
+  };
+  if !get_indexes(&data, id, model, None).iter().any(|index| index.tree_name == tree_name && index.key == key) {
+    return Some(format!("{}: stale entry for {} {}", name, model.name, id));
+  }
+  return None;
+}
+
+/// Запись списка связей (`[owner, target]` в прямом дереве, `[target, owner]` в обратном),
+/// у которой нет одной из сторон или, для обратного дерева, прямой записи
+fn stale_link(rx: &Transaction, model: &Model, target: &Model, field: &Field, index: &InsertedIndex, key: &[u8]) -> Option<String> {
+  let name = String::from_utf8_lossy(index.tree_name());
+  if key.len() != 16 {
+    return Some(format!("{}: malformed entry {}", name, hex_key(key)));
+  }
+  let (owner, item) = match index {
+    InsertedIndex::Direct { .. } => (&key[..8], &key[8..]),
+    InsertedIndex::Rev { .. } => (&key[8..], &key[..8]),
+  };
+  let id = |bytes: &[u8]| u64::from_be_bytes(bytes.try_into().unwrap());
+  if rx.get_tree(model.name.as_bytes()).unwrap().unwrap().get(owner).unwrap().is_none() {
+    return Some(format!("{}: entry for missing {} {}", name, model.name, id(owner)));
+  }
+  if rx.get_tree(target.name.as_bytes()).unwrap().unwrap().get(item).unwrap().is_none() {
+    return Some(format!("{}: {} {} links to missing {} {}", name, model.name, id(owner), target.name, id(item)));
+  }
+  if let InsertedIndex::Rev { .. } = index {
+    let direct = field.inserted_indexes.iter().find(|index| matches!(index, InsertedIndex::Direct { .. }))?;
+    let direct_tree = rx.get_tree(direct.tree_name()).unwrap().unwrap();
+    if direct_tree.get(&[owner, item].concat()).unwrap().is_none() {
+      return Some(format!("{}: entry without a link in {}", name, String::from_utf8_lossy(direct.tree_name())));
+    }
+  }
+  return None;
+}
+
+fn hex_key(key: &[u8]) -> String {
+  return key.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+fn backfill_compound_index(tx: &Transaction, model: &Model, index: &CompoundIndex) {
+  let tree = tx.get_tree(model.name.as_bytes()).unwrap().unwrap();
+  let mut index_tree = tx.get_tree(index.tree_name.as_bytes()).unwrap().unwrap();
+
+  for item in tree.iter().unwrap() {
+    let (key, data) = item.unwrap();
+    let id = u64::from_be_bytes(key.as_ref().try_into().unwrap());
+    let data = unpack(data);
+    let Some(key) = compound_key(&data, id, &model.fields, index, model.payload_offset) else { continue };
+    index_tree.insert(&key, &[1]).unwrap();
+  }
+}
+
+fn backfill_index(tx: &Transaction, model_name: &str, field: &Field, payload_offset: usize, tree_name: &str) {
+  let tree = tx.get_tree(model_name.as_bytes()).unwrap().unwrap();
+  let mut index_tree = tx.get_tree(tree_name.as_bytes()).unwrap().unwrap();
+
+  for item in tree.iter().unwrap() {
+    let (key, data) = item.unwrap();
+    let data = unpack(data);
+    let Some(value) = field_bytes(&data, field, payload_offset) else { continue };
+    index_tree.insert(&[&index_value(&field.ty, &value), key.as_ref()].concat(), &[1]).unwrap();
+  }
+}
+
+/// Деревья, в которых хранятся данные модели: документы, индексы, структуры и время жизни
+pub fn model_trees(model: &Model) -> Vec<&[u8]> {
+  let mut tree_names: Vec<&[u8]> = vec![model.name.as_bytes()];
+  for field in model.fields.iter() {
+    tree_names.extend(field.inserted_indexes.iter().map(|index| index.tree_name()));
+  }
+  for field in model.struct_fields() {
+    if let FieldType::Struct(st) | FieldType::StructList(st, _) = &field.ty {
+      tree_names.push(st.name.as_bytes());
+    }
+  }
+  tree_names.extend(model.indexes.iter().map(|index| index.tree_name.as_bytes()));
+  if let Some(ttl) = &model.ttl {
+    tree_names.push(ttl.tree_name.as_bytes());
+    tree_names.push(ttl.queue_tree_name.as_bytes());
+  }
+  return tree_names;
+}
+
+const DATA_DIR: &str = "./data";
+/// Файл в каталоге данных, блокировку которого держит процесс, открывший каталог
+const DATA_LOCK: &str = "marci.lock";
+
+/// База в каталоге данных `./data`. Каталог остается заблокированным, пока жив возвращенный файл:
+/// второй процесс с тем же каталогом выдавал бы те же id и портил бы счетчики, поэтому он получает ошибку
+pub fn open_database() -> std::io::Result<(Database, File)> {
+  return open_database_in(Path::new(DATA_DIR));
+}
+
+pub fn open_database_in(dir: &Path) -> std::io::Result<(Database, File)> {
+  fs::create_dir_all(dir)?;
+  let lock_path = dir.join(DATA_LOCK);
+  let lock = File::options().create(true).truncate(false).write(true).open(&lock_path)
+    .map_err(|err| std::io::Error::new(err.kind(), format!("Failed to open data lock {}: {}", lock_path.display(), err)))?;
+  if lock.try_lock().is_err() {
+    let message = format!("Data directory {} is already used by another marci-db process", dir.display());
+    return Err(std::io::Error::new(std::io::ErrorKind::WouldBlock, message));
+  }
+
+  let env = Environment::new(dir).map_err(std::io::Error::other)?;
+  let db = env.get_or_create_database("mydb.db").map_err(std::io::Error::other)?;
+  return Ok((db, lock));
+}
+
+/// Суммарный размер файлов каталога (без вложенных каталогов)
+fn dir_size(dir: &Path) -> u64 {
+  let Ok(entries) = fs::read_dir(dir) else { return 0 };
+  return entries
+    .filter_map(|entry| entry.ok()?.metadata().ok())
+    .filter(|meta| meta.is_file())
+    .map(|meta| meta.len())
+    .sum();
+}
+
+pub fn now_millis() -> u64 {
+  return SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+}
+
+#[inline(always)]
+fn expires_at(seconds: u64) -> u64 {
+  return now_millis() + seconds * 1000;
+}
 
 #[inline(always)]
-pub fn get_max_id(tree: &Tree) -> u64 {
-  return tree.last().unwrap()
-    .map(|(key, _)| u64::from_be_bytes(key.as_ref().try_into().unwrap()) + 1)
-    .unwrap_or(1);
+fn get_expiry(rx: &Transaction, ttl: &ModelTtl, id: u64) -> Result<Option<u64>, MarciError> {
+  let tree = read_tree(rx, ttl.tree_name.as_bytes())?;
+  return tree.get(&id.to_be_bytes())?.map(|value| key_id(value.as_ref())).transpose();
+}
+
+/// Обновляет время истечения документа (None - документ больше не истекает)
+fn set_expiry(tx: &JournalTx, ttl: &ModelTtl, id: u64, expires_at: Option<u64>) -> Result<(), MarciError> {
+  let mut tree = write_tree(tx, ttl.tree_name.as_bytes())?;
+  let mut queue = write_tree(tx, ttl.queue_tree_name.as_bytes())?;
+
+  if let Some(old) = tree.get(&id.to_be_bytes())? {
+    let old = key_id(old.as_ref())?;
+    queue.delete(&make_key(old, id))?;
+  }
+
+  match expires_at {
+    Some(expires_at) => {
+      tree.insert(&id.to_be_bytes(), &expires_at.to_be_bytes())?;
+      queue.insert(&make_key(expires_at, id), &[])?;
+    }
+    None => {
+      tree.delete(&id.to_be_bytes())?;
+    }
+  }
+  return Ok(());
+}
+
+#[inline(always)]
+pub fn get_max_id(tree: &Tree) -> Result<u64, MarciError> {
+  return match tree.last()? {
+    Some((key, _)) => key_id(key.as_ref())?.checked_add(1).ok_or_else(|| MarciError::CorruptedKey(key.as_ref().to_vec())),
+    None => Ok(1),
+  };
 }
 
 pub fn get_offsets(data: &[u8], model: &Model) -> Vec<usize> {
   let mut arr = vec![];
   for field in model.fields.iter() {
-    let offset = get_offset(data, field.offset_pos);
+    let offset = get_offset(data, field.offset_pos).unwrap();
     arr.push(offset);
   }
   return arr;
 }
 
 #[inline(always)]
-fn insert_indexes(tx: &WriteTransaction, field: &Field, id: u64, ids: &[u64]) {
+fn insert_indexes(tx: &JournalTx, field: &Field, id: u64, ids: &[u64], payloads: &[Vec<u8>]) -> Result<(), MarciError> {
   if ids.is_empty() {
-    return;
+    return Ok(());
   }
   for index in field.inserted_indexes.iter() {
-    // println!("Insert {}", str::from_utf8(index.tree_name()).unwrap());
-    let mut tree = tx.get_tree(index.tree_name()).unwrap().unwrap();
+    let mut tree = write_tree(tx, index.tree_name())?;
 
     match index {
-      InsertedIndex::Direct { .. } => for &cid in ids { insert_index(&mut tree, id, cid); },
-      InsertedIndex::Rev { .. } => for &cid in ids { insert_index(&mut tree, cid, id); },
+      // Значение прямого индекса - данные связи (`@payload`), без них `[1]`
+      InsertedIndex::Direct { .. } => for (i, &cid) in ids.iter().enumerate() {
+        tree.insert(&make_key(id, cid), payloads.get(i).map(Vec::as_slice).unwrap_or(&[1]))?;
+      },
+      InsertedIndex::Rev { .. } => for &cid in ids { insert_index(&mut tree, cid, id)?; },
     }
   }
+  return Ok(());
 }
 
 
+/// Убирает связи документа `id` с `ids` из прямого и обратных индексов поля-списка (`disconnect`)
+fn unlink_indexes(tx: &JournalTx, field: &Field, id: u64, ids: &[u64]) -> Result<(), MarciError> {
+  for index in field.inserted_indexes.iter() {
+    let mut tree = write_tree(tx, index.tree_name())?;
+    for &cid in ids {
+      let key = match index {
+        InsertedIndex::Direct { .. } => make_key(id, cid),
+        InsertedIndex::Rev { .. } => make_key(cid, id),
+      };
+      tree.delete(&key)?;
+    }
+  }
+  return Ok(());
+}
+
 #[inline(always)]
-pub fn remove_indexes(tx: &WriteTransaction, field: &Field, id: u64) {
+pub fn remove_indexes(tx: &JournalTx, field: &Field, id: u64) -> Result<(), MarciError> {
   if field.inserted_indexes.is_empty() {
-    return;
+    return Ok(());
   }
 
-  let direct_index = field.inserted_indexes.iter()
-    .find(|i| matches!(i, InsertedIndex::Direct { tree_name: _ })).expect("Direct index must be defined for batch update");
-  
+  // Связи документа находятся только по прямому индексу, он есть у каждого хранимого списка связей
+  let Some(direct_index) = field.inserted_indexes.iter().find(|i| matches!(i, InsertedIndex::Direct { .. })) else {
+    return Err(MarciError::MissingTree(format!("direct index of {}", field.name)));
+  };
+
   let rev_indexes: Vec<&InsertedIndex> = field.inserted_indexes.iter()
     .filter(|i| matches!(i, InsertedIndex::Rev { tree_name: _ })).collect();
-  
+
   if !rev_indexes.is_empty() {
-    let keys = find_by_direct(tx, direct_index.tree_name(), id);
-    if keys.is_empty() {
-      return;
+    let ids = find_by_direct(tx, direct_index.tree_name(), id)?;
+    if ids.is_empty() {
+      return Ok(());
     }
     for index in rev_indexes {
-      let InsertedIndex::Rev { tree_name } = index else { continue };
-      let mut tree = tx.get_tree(tree_name.as_bytes()).unwrap().unwrap();
-      for key in keys.iter() {
-        tree.delete(&key).unwrap();
+      let mut tree = write_tree(tx, index.tree_name())?;
+      for &cid in ids.iter() {
+        tree.delete(&make_key(cid, id))?;
       }
     }
   }
 
   for index in field.inserted_indexes.iter() {
     let InsertedIndex::Direct { tree_name } = index else { continue };
-    let mut tree = tx.get_tree(tree_name.as_bytes()).unwrap().unwrap();
-    tree.delete_range(id.to_be_bytes()..(id+1).to_be_bytes()).unwrap();
+    let mut tree = write_tree(tx, tree_name.as_bytes())?;
+    tree.delete_range(id_prefix(id))?;
+  }
+  return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use canopydb::Transaction;
+
+  use crate::journal::META_TREE;
+  use crate::error::MarciError;
+  use crate::marci_db::{DeleteError, META_COUNTERS_SAVED, MarciDB, MarciSelect, now_millis, parallel_map};
+  use crate::marci_decoder::decode_document;
+  use crate::marci_encoder::{encode_document, encode_update};
+  use crate::marci_query::{MarciQuery, parse_find_args, parse_query};
+  use crate::schema::parse_schema;
+
+  #[test]
+  fn test_ephemeral() {
+    let schema = parse_schema("model User {\n  name String\n}\n").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let dir = db.ephemeral_dir().unwrap().to_path_buf();
+
+    let model = db.get_model("User").unwrap();
+    let mut structs = vec![];
+    let (data, _) = encode_document(model, &json!({ "name": "Alice" }), &mut structs).unwrap();
+    assert!(db.insert_data(model, &data, &structs).is_ok());
+    assert!(dir.exists());
+
+    drop(db);
+    assert!(!dir.exists());
+  }
+
+  #[test]
+  fn test_index_equality() {
+    let schema = parse_schema("
+model User {
+  email       String      @index
+  name        String
+}
+").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let model = db.get_model("User").unwrap();
+    let mut ids = vec![];
+    for email in ["a@x.io", "b@x.io", "a@x.io"] {
+      let (data, _) = encode_document(model, &json!({ "email": email, "name": "n" }), &mut vec![]).unwrap();
+      ids.push(db.insert_data(model, &data, &[]).unwrap());
+    }
+
+    let rx = db.db.begin_read().unwrap();
+    let query = parse_query(&model.fields, &json!({ "where": { "email": "a@x.io" } }), &db.schema).unwrap();
+    assert_eq!(query.filter.index_candidates(&rx, model).unwrap(), Some(vec![ids[0], ids[2]]));
+    // Поле без индекса читается полным обходом
+    let query = parse_query(&model.fields, &json!({ "where": { "name": "n" } }), &db.schema).unwrap();
+    assert_eq!(query.filter.index_candidates(&rx, model).unwrap(), None);
+  }
+
+  #[test]
+  fn test_query_plan() {
+    let schema = parse_schema("
+model Item {
+  status      String      @index
+  code        String      @index
+}
+").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let model = db.get_model("Item").unwrap();
+    let mut ids = vec![];
+    for i in 0..300 {
+      let (data, _) = encode_document(model, &json!({ "status": "a", "code": format!("c{}", i) }), &mut vec![]).unwrap();
+      ids.push(db.insert_data(model, &data, &[]).unwrap());
+    }
+
+    // Равенство по code читается первым, а status дает слишком много ключей и остается на проверку
+    let query = parse_query(&model.fields, &json!({ "where": { "status": { "in": ["a"] }, "code": "c7" } }), &db.schema).unwrap();
+    let plan = db.explain(model, &query).unwrap();
+    assert_eq!(plan.candidates, Some(vec![ids[7]]));
+    assert_eq!(plan.residual, vec!["status".to_string()]);
+    let explained = plan.to_json();
+    assert_eq!(explained["scan"], "index");
+    assert_eq!(explained["indexes"][1]["used"], false);
+  }
+
+  #[test]
+  fn test_delete_cleanup() {
+    let schema = parse_schema("
+model User {
+  name        String      @index
+  info        UserInfo
+  phones      Phone[]
+  favorites   Tag[]
+}
+
+model Tag {
+  name        String
+}
+
+struct UserInfo {
+  city        String
+}
+
+struct Phone {
+  number      String
+}
+").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let user = db.get_model("User").unwrap();
+    let tag = db.get_model("Tag").unwrap();
+
+    let insert = |model, doc| {
+      let mut structs = vec![];
+      let (data, _) = encode_document(model, &doc, &mut structs).unwrap();
+      return db.insert_data(model, &data, &structs).unwrap();
+    };
+    let tag_id = insert(tag, json!({ "name": "rust" }));
+    let user_id = insert(user, json!({ "name": "Alice", "info": { "city": "Paris" }, "phones": [{ "number": "1" }, { "number": "2" }], "favorites": [{ "id": tag_id }] }));
+
+    let entries = |tree: &str| {
+      let rx = db.db.begin_read().unwrap();
+      return rx.get_tree(tree.as_bytes()).unwrap().unwrap().len();
+    };
+    assert_eq!(entries("User.phones"), 2);
+    assert_eq!(entries("User.favorites"), 1);
+
+    db.delete(tag, tag_id).unwrap();
+    assert_eq!(entries("User.favorites"), 0);
+
+    db.delete(user, user_id).unwrap();
+    for tree in ["User", "User.info", "User.phones", "User.name.idx"] {
+      assert_eq!(entries(tree), 0, "{}", tree);
+    }
+  }
+
+  #[test]
+  fn test_uncommitted_writes() {
+    let schema = parse_schema("model User {\n  name String\n}\n").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let model = db.get_model("User").unwrap();
+    let select = MarciSelect::all(&model.fields);
+    let query = MarciQuery::all();
+    let count = |rx: &Transaction| db.find_page_in(rx, model, &select, &query, |ctx| Ok(ctx.id)).unwrap().0.len();
+
+    let (data, _) = encode_document(model, &json!({ "name": "Alice" }), &mut vec![]).unwrap();
+    let tx = db.begin_write().unwrap();
+    db.insert_data_in(&tx, model, &data, &[]).unwrap();
+    // Транзакция видит свою запись, остальные читатели - нет
+    assert_eq!(count(&tx), 1);
+    assert_eq!(count(&db.db.begin_read().unwrap()), 0);
+
+    drop(tx);
+    assert_eq!(count(&db.db.begin_read().unwrap()), 0);
+  }
+
+  #[test]
+  fn test_include_batch() {
+    let schema = parse_schema("
+model User {
+  name        String
+  favorites   Tag[]
+}
+
+model Tag {
+  name        String
+}
+
+model Post {
+  title       String
+  author      User
+}
+").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let (user, tag, post) = (db.get_model("User").unwrap(), db.get_model("Tag").unwrap(), db.get_model("Post").unwrap());
+    let insert = |model, doc| {
+      let mut structs = vec![];
+      let (data, _) = encode_document(model, &doc, &mut structs).unwrap();
+      return db.insert_data(model, &data, &structs).unwrap();
+    };
+    let rust = insert(tag, json!({ "name": "rust" }));
+    let go = insert(tag, json!({ "name": "go" }));
+    let ann = insert(user, json!({ "name": "Ann", "favorites": [{ "id": rust }, { "id": go }] }));
+    let bob = insert(user, json!({ "name": "Bob", "favorites": [{ "id": go }] }));
+    for (title, author) in [("a", ann), ("b", bob), ("c", ann)] {
+      insert(post, json!({ "title": title, "author": { "id": author } }));
+    }
+
+    // Авторы и их теги читаются заранее для всей страницы и раздаются строкам
+    let args = json!({ "select": { "title": true, "author": { "name": true, "favorites": { "name": true } } } });
+    let (select, query) = parse_find_args(&post.fields, &args, &db.schema).unwrap();
+    let posts = db.find_many(post, &select, &query, decode_document).unwrap();
+    let tags = |post: &serde_json::Value| post["author"]["favorites"].as_array().unwrap().iter()
+      .map(|tag| tag["name"].as_str().unwrap().to_string())
+      .collect::<Vec<_>>();
+    assert_eq!(posts.len(), 3);
+    assert_eq!(posts[1]["author"]["name"], "Bob");
+    assert_eq!(tags(&posts[0]), ["rust", "go"]);
+    assert_eq!(tags(&posts[1]), ["go"]);
+    assert_eq!(tags(&posts[2]), tags(&posts[0]));
+  }
+
+  #[test]
+  fn test_replace_struct_list() {
+    let schema = parse_schema("
+model User {
+  phones      Phone[]
+}
+
+struct Phone {
+  number      String
+}
+").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let model = db.get_model("User").unwrap();
+    let mut structs = vec![];
+    let (data, _) = encode_document(model, &json!({ "phones": [{ "number": "1" }, { "number": "2" }] }), &mut structs).unwrap();
+    let id = db.insert_data(model, &data, &structs).unwrap();
+
+    let phone_ids = || {
+      let rx = db.db.begin_read().unwrap();
+      return rx.get_tree(b"User.phones").unwrap().unwrap().keys().unwrap()
+        .map(|key| u64::from_be_bytes(key.unwrap().as_ref()[8..].try_into().unwrap()))
+        .collect::<Vec<_>>();
+    };
+    let update = |doc: serde_json::Value| {
+      let mut structs = vec![];
+      let (data, mask) = encode_update(model, &doc, &mut structs).unwrap();
+      db.update(model, id, &data, mask, &structs).unwrap();
+    };
+    let first = phone_ids()[0];
+
+    // Строка с id остается, строка без id добавляется, остальные удаляются
+    update(json!({ "phones": [{ "id": first, "number": "1b" }, { "number": "3" }] }));
+    let ids = phone_ids();
+    assert_eq!(ids.len(), 2);
+    assert_eq!(ids[0], first);
+    update(json!({ "phones": [] }));
+    assert!(phone_ids().is_empty());
+
+    // id строки другого документа не перезаписывает ее, а добавляет новую строку в этот документ
+    let mut structs = vec![];
+    let (data, _) = encode_document(model, &json!({ "phones": [{ "number": "4" }] }), &mut structs).unwrap();
+    let other = db.insert_data(model, &data, &structs).unwrap();
+    let foreign = phone_ids()[0];
+    update(json!({ "phones": [{ "id": foreign, "number": "5" }, { "id": 999, "number": "6" }] }));
+    let rx = db.db.begin_read().unwrap();
+    let tree = rx.get_tree(b"User.phones").unwrap().unwrap();
+    let rows = tree.iter().unwrap().map(|item| {
+      let (key, _) = item.unwrap();
+      return (u64::from_be_bytes(key.as_ref()[..8].try_into().unwrap()), u64::from_be_bytes(key.as_ref()[8..].try_into().unwrap()));
+    }).collect::<Vec<_>>();
+    assert_eq!(rows.len(), 3);
+    assert!(rows.contains(&(other, foreign)));
+    assert_eq!(rows.iter().filter(|(parent, item)| *parent == id && *item != foreign && *item != 999).count(), 2);
+  }
+
+  #[test]
+  fn test_required_ref_restrict() {
+    let schema = parse_schema("
+model Post {
+  author      User
+  reviewer    User?
+}
+
+model User {
+  name        String
+}
+").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let (post, user) = (db.get_model("Post").unwrap(), db.get_model("User").unwrap());
+    let insert = |model, doc| {
+      let (data, _) = encode_document(model, &doc, &mut vec![]).unwrap();
+      return db.insert_data(model, &data, &[]).unwrap();
+    };
+    let ann = insert(user, json!({ "name": "Ann" }));
+    let bob = insert(user, json!({ "name": "Bob" }));
+    let post_id = insert(post, json!({ "author": { "id": ann }, "reviewer": { "id": bob } }));
+
+    // Обязательная связь без @onDelete не дает удалить документ, на который ссылается
+    let err = db.delete(user, ann).unwrap_err();
+    assert!(matches!(err, MarciError::Delete(DeleteError::Restricted { id, .. }) if id == post_id));
+    assert_eq!(err.to_string(), format!("Object is referenced by Post {} (author)", post_id));
+    // Необязательная обнуляется
+    db.delete(user, bob).unwrap();
+    db.delete(post, post_id).unwrap();
+    db.delete(user, ann).unwrap();
+  }
+
+  #[test]
+  fn test_sweep_expired_restrict() {
+    let schema = parse_schema("
+model Token {
+  session     Session
+}
+
+model Session {
+  name        String
+  @@ttl(60)
+}
+").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let (token, session) = (db.get_model("Token").unwrap(), db.get_model("Session").unwrap());
+    let insert = |model, doc| {
+      let (data, _) = encode_document(model, &doc, &mut vec![]).unwrap();
+      return db.insert_data(model, &data, &[]).unwrap();
+    };
+    let kept = insert(session, json!({ "name": "kept" }));
+    insert(session, json!({ "name": "expired" }));
+    let token_id = insert(token, json!({ "session": { "id": kept } }));
+
+    // Документ, на который ссылается `restrict`, пропускается, остальные удаляются в той же транзакции
+    let later = now_millis() + 3_600_000;
+    assert_eq!(db.sweep_expired(later).unwrap(), 1);
+    assert_eq!(db.sweep_expired(later).unwrap(), 0);
+    db.delete(token, token_id).unwrap();
+    assert_eq!(db.sweep_expired(later).unwrap(), 1);
+  }
+
+  #[test]
+  fn test_id_order_take() {
+    let schema = parse_schema("
+model User {
+  name        String
+  age         Int
+}
+").unwrap();
+    let db = MarciDB::ephemeral(schema);
+    let model = db.get_model("User").unwrap();
+    for (i, name) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+      let (data, _) = encode_document(model, &json!({ "name": name, "age": i }), &mut vec![]).unwrap();
+      db.insert_data(model, &data, &[]).unwrap();
+    }
+    let names = |args: serde_json::Value| {
+      let (select, query) = parse_find_args(&model.fields, &args, &db.schema).unwrap();
+      return db.find_many(model, &select, &query, decode_document).unwrap().iter()
+        .map(|user| user["name"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    };
+
+    // orderBy по id читается из дерева модели в нужную сторону
+    assert_eq!(names(json!({ "select": { "name": true }, "orderBy": { "id": "desc" }, "take": 2 })), ["e", "d"]);
+    assert_eq!(names(json!({ "select": { "name": true }, "where": { "age": { "lt": 4 } }, "orderBy": { "id": "desc" }, "skip": 1, "take": 2 })), ["c", "b"]);
+    assert_eq!(names(json!({ "select": { "name": true }, "orderBy": { "id": "asc" }, "take": 2 })), ["a", "b"]);
+  }
+
+  #[test]
+  fn test_persisted_counters() {
+    let dir = std::env::temp_dir().join(format!("marci-counters-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let open = || MarciDB::open(parse_schema("model User {\n  name String\n}\n").unwrap(), &dir).unwrap();
+
+    let db = open();
+    let model = db.get_model("User").unwrap();
+    let (data, _) = encode_document(model, &json!({ "name": "Alice" }), &mut vec![]).unwrap();
+    db.insert_data(model, &data, &[]).unwrap();
+    let id = db.insert_data(model, &data, &[]).unwrap();
+    db.delete(model, id).unwrap();
+    drop(db);
+
+    // Счетчик взят из `$meta`, а не из дерева: id удаленного последнего документа не выдается снова
+    let db = open();
+    assert_eq!(db.peek_id(db.get_model("User").unwrap().counter_idx), id + 1);
+    // Пока база открыта, отметки нет: после сбоя счетчики пересчитаются по деревьям
+    let rx = db.db.begin_read().unwrap();
+    assert!(rx.get_tree(META_TREE).unwrap().unwrap().get(META_COUNTERS_SAVED).unwrap().is_none());
+    drop(rx);
+    drop(db);
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn test_parallel_map() {
+    let data: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; i as usize]).collect();
+    let docs: Vec<(u64, &[u8])> = data.iter().enumerate().map(|(id, data)| (id as u64, data.as_slice())).collect();
+    // Отрезки неравные, но результаты склеиваются в порядке id
+    let lens = parallel_map(&docs, 3, |id, data| (id, data.len()));
+    assert_eq!(lens, (0..10).map(|i| (i, i as usize)).collect::<Vec<_>>());
+    assert!(parallel_map(&[], 4, |id, _| id).is_empty());
   }
 }
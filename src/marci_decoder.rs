@@ -1,6 +1,12 @@
+use std::io::Write;
+use std::sync::Arc;
+
+use bitvec::vec::BitVec;
+use serde::de::IgnoredAny;
+use serde::ser::{Serialize, SerializeMap, SerializeSeq, Serializer};
 use serde_json::{Map, Value};
 
-use crate::{marci_db::{DecodeCtx, IncludeResult, get_end, get_offset}, schema::{FieldType, PrimitiveFieldType}};
+use crate::{marci_db::{DecodeCtx, IncludeResult, get_end, get_offset, stored_payload_offset}, schema::{Field, FieldType, PrimitiveFieldType, ScalarType}};
 
 #[derive(Debug)]
 pub enum DecodeError {
@@ -9,11 +15,223 @@ pub enum DecodeError {
     Utf8Error,
     TypeMismatch(String),
     OffsetOutOfRange,
+    /// Связь из `include` прочитала больше записей, чем разрешает `--include-limit`
+    IncludeLimit { field: String, limit: u64 },
+}
+
+/// Байт версии сжатого документа (`@@compress`): за ним lz4-блок с размером исходного документа
+pub const COMPRESSED_VERSION: u8 = 2;
+
+/// Документ из дерева модели: как хранится, распакованный или из кэша документов
+pub enum StoredDoc<B> {
+    Plain(B),
+    Unpacked(Vec<u8>),
+    Cached(Arc<[u8]>),
+}
+
+impl<B: AsRef<[u8]>> AsRef<[u8]> for StoredDoc<B> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            StoredDoc::Plain(data) => data.as_ref(),
+            StoredDoc::Unpacked(data) => data,
+            StoredDoc::Cached(data) => data,
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> std::ops::Deref for StoredDoc<B> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_ref()
+    }
+}
+
+/// Распаковывает документ, сжатый при записи. Поврежденный блок дает пустой документ,
+/// и декодирование вернет `BufferTooSmall`
+pub fn unpack<B: AsRef<[u8]>>(data: B) -> StoredDoc<B> {
+    if data.as_ref().first() != Some(&COMPRESSED_VERSION) {
+        return StoredDoc::Plain(data);
+    }
+    StoredDoc::Unpacked(lz4_flex::decompress_size_prepended(&data.as_ref()[1..]).unwrap_or_default())
 }
 
 pub fn decode_document(ctx: DecodeCtx<Value>) -> Result<Value, DecodeError>  {
-    let DecodeCtx { data, fields, payload_offset, id, select, includes } = ctx;
+    let DecodeCtx { data, fields, payload_offset, id, select, includes, expires_at, payload } = ctx;
+
+    let mut obj = Map::new();
+    if select[0] {
+        obj.insert("id".to_string(), Value::Number(id.into()));
+    }
+
+    decode_selected(data, fields, payload_offset, select, |field, value| {
+        obj.insert(field.name.clone(), value);
+    })?;
+
+    if let Some(expires_at) = expires_at {
+        obj.insert("$expiresAt".to_string(), Value::Number(expires_at.into()));
+    }
+
+    if let Some(payload) = payload {
+        obj.insert("$payload".to_string(), payload);
+    }
+
+    for include in includes {
+        match include {
+            IncludeResult::None(field_index) => {
+                obj.insert(fields[field_index].name.clone(), Value::Null);
+            },
+            IncludeResult::One(field_index, val) => {
+                obj.insert(fields[field_index].name.clone(), val);
+            },
+            IncludeResult::Many(field_index, val) => {
+                let vec = Value::Array(val);
+                obj.insert(fields[field_index].name.clone(), vec);
+            },
+            IncludeResult::Count(field_index, count) => {
+                let mut count_obj = Map::new();
+                count_obj.insert("_count".to_string(), Value::Number(count.into()));
+                obj.insert(fields[field_index].name.clone(), Value::Object(count_obj));
+            }
+        }
+    }
+
+    return Ok(Value::Object(obj));
+}
+
+/// Ключ объекта `Doc`: имя поля схемы (общая строка `Field::key`) или служебный ключ
+#[derive(Debug, Clone, PartialEq)]
+pub enum DocKey {
+    Field(Arc<str>),
+    Static(&'static str),
+}
+
+impl DocKey {
+    pub fn as_str(&self) -> &str {
+        match self {
+            DocKey::Field(key) => key,
+            DocKey::Static(key) => key,
+        }
+    }
+}
+
+/// Документ для ответа без `serde_json::Map`: ключи объектов не копируются для каждой строки, а значения
+/// полей - те же `Value`, что у `decode_document`. Поля идут в порядке схемы, а не по алфавиту.
+/// Сериализуется в JSON, MessagePack и CBOR напрямую
+#[derive(Debug, Clone, PartialEq)]
+pub enum Doc {
+    Value(Value),
+    Object(Vec<(DocKey, Doc)>),
+    Array(Vec<Doc>),
+}
+
+impl Doc {
+    /// Тот же документ, что вернул бы `decode_document`
+    pub fn into_value(self) -> Value {
+        match self {
+            Doc::Value(value) => value,
+            Doc::Object(entries) => Value::Object(entries.into_iter().map(|(key, doc)| (key.as_str().to_string(), doc.into_value())).collect()),
+            Doc::Array(docs) => Value::Array(docs.into_iter().map(Doc::into_value).collect()),
+        }
+    }
+}
+
+impl Serialize for DocKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl Serialize for Doc {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Doc::Value(value) => value.serialize(serializer),
+            Doc::Object(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, doc) in entries {
+                    map.serialize_entry(key, doc)?;
+                }
+                map.end()
+            }
+            Doc::Array(docs) => {
+                let mut seq = serializer.serialize_seq(Some(docs.len()))?;
+                for doc in docs {
+                    seq.serialize_element(doc)?;
+                }
+                seq.end()
+            }
+        }
+    }
+}
+
+/// `decode_document` для ответа findMany: документ собирается в `Doc`, и имена полей не копируются
+/// в `String` для каждой строки - на широких моделях это большая часть времени декодирования
+pub fn decode_doc(ctx: DecodeCtx<Doc>) -> Result<Doc, DecodeError> {
+    let DecodeCtx { data, fields, payload_offset, id, select, includes, expires_at, payload } = ctx;
+
+    let mut obj = Vec::with_capacity(select.count_ones() + includes.len() + 2);
+    if select[0] {
+        obj.push((DocKey::Static("id"), Doc::Value(Value::Number(id.into()))));
+    }
+
+    decode_selected(data, fields, payload_offset, select, |field, value| {
+        obj.push((DocKey::Field(field.key.clone()), Doc::Value(value)));
+    })?;
+
+    if let Some(expires_at) = expires_at {
+        obj.push((DocKey::Static("$expiresAt"), Doc::Value(Value::Number(expires_at.into()))));
+    }
 
+    if let Some(payload) = payload {
+        obj.push((DocKey::Static("$payload"), payload));
+    }
+
+    for include in includes {
+        let (field_index, doc) = match include {
+            IncludeResult::None(field_index) => (field_index, Doc::Value(Value::Null)),
+            IncludeResult::One(field_index, val) => (field_index, val),
+            IncludeResult::Many(field_index, val) => (field_index, Doc::Array(val)),
+            IncludeResult::Count(field_index, count) => {
+                (field_index, Doc::Object(vec![(DocKey::Static("_count"), Doc::Value(Value::Number(count.into())))]))
+            }
+        };
+        obj.push((DocKey::Field(fields[field_index].key.clone()), doc));
+    }
+
+    return Ok(Doc::Object(obj));
+}
+
+/// Проверяет заголовок документа и передает в `put` значения выбранных примитивных полей
+fn decode_selected(data: &[u8], fields: &[Field], payload_offset: usize, select: &BitVec, mut put: impl FnMut(&Field, Value)) -> Result<(), DecodeError> {
+    return visit_selected(data, fields, payload_offset, select, |field, primitive, offset, stored_offset| {
+        // Поле = null
+        if offset == 0 {
+            put(field, missing_value(field, stored_offset));
+            return Ok(());
+        }
+
+        // Декодируем
+        let value = match field.ty {
+            FieldType::PrimitiveList(_) => {
+                let end = get_end(data, field.offset_pos, payload_offset);
+                decode_list(primitive, data.get(offset..end).ok_or(DecodeError::OffsetOutOfRange)?)?
+            }
+            _ => decode_value(primitive, data, field.offset_pos, offset, payload_offset)?
+        };
+        put(field, value);
+        return Ok(());
+    });
+}
+
+/// Проверяет заголовок документа и передает в `visit` выбранные примитивные поля: поле, его тип, offset
+/// значения (0 - значения нет) и размер таблицы offset-ов, с которой документ был записан
+fn visit_selected(
+    data: &[u8],
+    fields: &[Field],
+    payload_offset: usize,
+    select: &BitVec,
+    mut visit: impl FnMut(&Field, &PrimitiveFieldType, usize, usize) -> Result<(), DecodeError>,
+) -> Result<(), DecodeError> {
     if data.len() < 3 {
         return Err(DecodeError::BufferTooSmall);
     }
@@ -23,119 +241,460 @@ pub fn decode_document(ctx: DecodeCtx<Value>) -> Result<Value, DecodeError>  {
         return Err(DecodeError::WrongVersion);
     }
 
-    if u16::from_be_bytes([data[1], data[2]]) != payload_offset as u16 {
-        let offset = u16::from_be_bytes([data[1], data[2]]);
-        return Err(DecodeError::TypeMismatch(format!("payload offset mismatch; Expected: {}, Get {}", payload_offset, offset)));
+    // Документ, записанный до добавления полей в схему, имеет более короткую таблицу offset-ов
+    let stored_offset = u16::from_be_bytes([data[1], data[2]]) as usize;
+    if stored_offset > payload_offset || stored_offset < 3 || !(stored_offset - 3).is_multiple_of(4) {
+        return Err(DecodeError::TypeMismatch(format!("payload offset mismatch; Expected: {}, Get {}", payload_offset, stored_offset)));
     }
 
-    if data.len() < payload_offset {
+    if data.len() < stored_offset {
         return Err(DecodeError::BufferTooSmall);
     }
 
-    let mut obj = Map::new();
-    if select[0] {
-        obj.insert("id".to_string(), Value::Number(id.into()));
-    }
-
-    for (field_index, field) in fields.iter().enumerate() {
-        if !select[field_index+1] {
-            continue;
-        }
+    // Обходим только выбранные поля: у широких моделей большая часть таблицы offset-ов не читается
+    for field_index in select.iter_ones().filter(|i| *i > 0).map(|i| i - 1) {
+        let Some(field) = fields.get(field_index) else {
+            break;
+        };
 
-        let FieldType::Primitive(ref primitive) = field.ty else {
+        let (FieldType::Primitive(ref primitive) | FieldType::PrimitiveList(ref primitive)) = field.ty else {
             // пропускаем derived / relation
             continue;
         };
 
         // читаем offset
-        let offset = get_offset(data, field.offset_pos);
+        let offset = get_offset(data, field.offset_pos)?;
+        if offset != 0 && offset >= data.len() {
+            return Err(DecodeError::OffsetOutOfRange);
+        }
+        visit(field, primitive, offset, stored_offset)?;
+    }
 
-        // Поле = null
-        if offset == 0 {
-          obj.insert(field.name.clone(), Value::Null);
-          continue;
+    return Ok(());
+}
+
+/// Документ в виде готового JSON-текста
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonText(pub Vec<u8>);
+
+/// `decode_document` сразу в JSON-текст: значения пишутся из бинарного документа в буфер без `Value`
+/// и без второй сериализации. Ключи и значения те же, что у `decode_doc`, в порядке схемы
+pub fn decode_json(ctx: DecodeCtx<JsonText>) -> Result<JsonText, DecodeError> {
+    let DecodeCtx { data, fields, payload_offset, id, select, includes, expires_at, payload } = ctx;
+
+    let mut out = Vec::with_capacity(data.len() + 16 * select.count_ones());
+    out.push(b'{');
+    let mut first = true;
+    let mut key = |out: &mut Vec<u8>, key: &str| {
+        if !std::mem::take(&mut first) {
+            out.push(b',');
         }
+        write_str(out, key);
+        out.push(b':');
+    };
 
-        let offset = offset as usize;
-        if offset >= data.len() {
-            return Err(DecodeError::OffsetOutOfRange);
+    if select[0] {
+        key(&mut out, "id");
+        write_number(&mut out, id);
+    }
+
+    visit_selected(data, fields, payload_offset, select, |field, primitive, offset, stored_offset| {
+        key(&mut out, &field.key);
+        if offset == 0 {
+            write_json_value(&mut out, &missing_value(field, stored_offset));
+            return Ok(());
+        }
+        match field.ty {
+            FieldType::PrimitiveList(_) => {
+                let end = get_end(data, field.offset_pos, payload_offset);
+                write_list(&mut out, primitive, data.get(offset..end).ok_or(DecodeError::OffsetOutOfRange)?)
+            }
+            _ => write_value(&mut out, primitive, data, field.offset_pos, offset, payload_offset)
         }
+    })?;
 
-        // Декодируем
-        let value = decode_value(primitive, &data, field.offset_pos, offset, payload_offset)?;
-        obj.insert(field.name.clone(), value);
+    if let Some(expires_at) = expires_at {
+        key(&mut out, "$expiresAt");
+        write_number(&mut out, expires_at);
+    }
+
+    if let Some(payload) = payload {
+        key(&mut out, "$payload");
+        out.extend_from_slice(&payload.0);
     }
 
     for include in includes {
         match include {
             IncludeResult::None(field_index) => {
-                obj.insert(fields[field_index].name.clone(), Value::Null);
-            },
+                key(&mut out, &fields[field_index].key);
+                out.extend_from_slice(b"null");
+            }
             IncludeResult::One(field_index, val) => {
-                obj.insert(fields[field_index].name.clone(), val);
-            },
+                key(&mut out, &fields[field_index].key);
+                out.extend_from_slice(&val.0);
+            }
             IncludeResult::Many(field_index, val) => {
-                let vec = Value::Array(val);
-                obj.insert(fields[field_index].name.clone(), vec);
+                key(&mut out, &fields[field_index].key);
+                write_array(&mut out, &val);
+            }
+            IncludeResult::Count(field_index, count) => {
+                key(&mut out, &fields[field_index].key);
+                out.extend_from_slice(b"{\"_count\":");
+                write_number(&mut out, count);
+                out.push(b'}');
             }
         }
     }
 
+    out.push(b'}');
+    return Ok(JsonText(out));
+}
+
+/// JSON-массив из уже готовых документов
+pub fn write_array(out: &mut Vec<u8>, docs: &[JsonText]) {
+    out.push(b'[');
+    for (i, doc) in docs.iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        out.extend_from_slice(&doc.0);
+    }
+    out.push(b']');
+}
+
+fn write_value(out: &mut Vec<u8>, ty: &PrimitiveFieldType, data: &[u8], offset_pos: usize, offset: usize, payload_offset: usize) -> Result<(), DecodeError> {
+    match ty {
+        PrimitiveFieldType::String | PrimitiveFieldType::Json | PrimitiveFieldType::Custom(ScalarType { width: None, .. }) => {
+            let end = get_end(data, offset_pos, payload_offset);
+            let bytes = data.get(offset..end).ok_or(DecodeError::OffsetOutOfRange)?;
+            write_variable(out, ty, bytes)?;
+        }
+        // Пользовательские скаляры декодируются своей функцией в `Value`
+        PrimitiveFieldType::Custom(_) => write_json_value(out, &decode_value(ty, data, offset_pos, offset, payload_offset)?),
+        PrimitiveFieldType::DateTime | PrimitiveFieldType::Int64 => write_number(out, i64::from_be_bytes(read_bytes(data, offset)?)),
+        PrimitiveFieldType::UInt64 => write_number(out, u64::from_be_bytes(read_bytes(data, offset)?)),
+        PrimitiveFieldType::Int8 => write_number(out, i8::from_be_bytes(read_bytes(data, offset)?)),
+        PrimitiveFieldType::Int16 => write_number(out, i16::from_be_bytes(read_bytes(data, offset)?)),
+        PrimitiveFieldType::Int32 => write_number(out, i32::from_be_bytes(read_bytes(data, offset)?)),
+        PrimitiveFieldType::UInt8 => write_number(out, u8::from_be_bytes(read_bytes(data, offset)?)),
+        PrimitiveFieldType::UInt16 => write_number(out, u16::from_be_bytes(read_bytes(data, offset)?)),
+        PrimitiveFieldType::UInt32 => write_number(out, u32::from_be_bytes(read_bytes(data, offset)?)),
+        PrimitiveFieldType::Float => {
+            let n = f32::from_be_bytes(read_bytes(data, offset)?) as f64;
+            if !n.is_finite() {
+                return Err(DecodeError::TypeMismatch("float is not finite".to_string()));
+            }
+            write_json_value(out, &n);
+        }
+        PrimitiveFieldType::Double => {
+            let n = f64::from_be_bytes(read_bytes(data, offset)?);
+            if !n.is_finite() {
+                return Err(DecodeError::TypeMismatch("double is not finite".to_string()));
+            }
+            write_json_value(out, &n);
+        }
+        PrimitiveFieldType::Bool => {
+            let [value] = read_bytes(data, offset)?;
+            out.extend_from_slice(if value != 0 { b"true" } else { b"false" });
+        }
+        PrimitiveFieldType::Enum(enum_type) => {
+            let ordinal = u16::from_be_bytes(read_bytes(data, offset)?);
+            let variant = enum_type.variants.get(ordinal as usize)
+                .ok_or_else(|| DecodeError::TypeMismatch(format!("unknown variant {} of enum {}", ordinal, enum_type.name)))?;
+            write_str(out, variant);
+        }
+    }
+    return Ok(());
+}
+
+fn write_list(out: &mut Vec<u8>, ty: &PrimitiveFieldType, bytes: &[u8]) -> Result<(), DecodeError> {
+    out.push(b'[');
+    for (i, item) in list_items(ty, bytes)?.into_iter().enumerate() {
+        if i > 0 {
+            out.push(b',');
+        }
+        match ty.width() {
+            None => write_variable(out, ty, &item[4..])?,
+            Some(_) => write_value(out, ty, item, 0, 0, 0)?
+        }
+    }
+    out.push(b']');
+    return Ok(());
+}
+
+fn write_variable(out: &mut Vec<u8>, ty: &PrimitiveFieldType, bytes: &[u8]) -> Result<(), DecodeError> {
+    match ty {
+        PrimitiveFieldType::Custom(_) => write_json_value(out, &decode_variable(ty, bytes)?),
+        // Хранится текст `serde_json::to_vec`, он же получился бы при повторной сериализации
+        PrimitiveFieldType::Json => {
+            serde_json::from_slice::<IgnoredAny>(bytes).map_err(|err| DecodeError::TypeMismatch(format!("invalid json: {}", err)))?;
+            out.extend_from_slice(bytes);
+        }
+        _ => write_str(out, std::str::from_utf8(bytes).map_err(|_| DecodeError::Utf8Error)?),
+    }
+    return Ok(());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_json_value(out, s);
+}
+
+fn write_number(out: &mut Vec<u8>, n: impl std::fmt::Display) {
+    write!(out, "{}", n).expect("writing to Vec never fails");
+}
+
+fn write_json_value<T: Serialize + ?Sized>(out: &mut Vec<u8>, value: &T) {
+    serde_json::to_writer(out, value).expect("writing to Vec never fails");
+}
+
+/// `idsOnly`: документ без декодирования полей - только id, а при выбранных связях
+/// `{ id, <связь>: id | [id] }`. Вложенные документы приходят в `includes` уже в таком виде
+pub fn decode_ids(ctx: DecodeCtx<Value>) -> Result<Value, DecodeError> {
+    if ctx.includes.is_empty() {
+        return Ok(Value::Number(ctx.id.into()));
+    }
+
+    let mut obj = Map::new();
+    obj.insert("id".to_string(), Value::Number(ctx.id.into()));
+    for include in ctx.includes {
+        let (field_index, value) = match include {
+            IncludeResult::None(field_index) => (field_index, Value::Null),
+            IncludeResult::One(field_index, val) => (field_index, val),
+            IncludeResult::Many(field_index, val) => (field_index, Value::Array(val)),
+            IncludeResult::Count(field_index, count) => {
+                let mut count_obj = Map::new();
+                count_obj.insert("_count".to_string(), Value::Number(count.into()));
+                (field_index, Value::Object(count_obj))
+            }
+        };
+        obj.insert(ctx.fields[field_index].name.clone(), value);
+    }
     return Ok(Value::Object(obj));
 }
 
+/// Декодирует значение одного поля документа (для фильтров и сортировки).
+/// ModelRef возвращается как id связанного документа
+pub fn decode_field(field: &Field, data: &[u8], payload_offset: usize) -> Result<Value, DecodeError> {
+    if field.offset_pos == 0 {
+        return Ok(Value::Null);
+    }
+    if data.len() < stored_payload_offset(data)?.min(payload_offset) {
+        return Err(DecodeError::BufferTooSmall);
+    }
+    let offset = get_offset(data, field.offset_pos)?;
+    if offset == 0 {
+        return Ok(missing_value(field, stored_payload_offset(data)?));
+    }
+    if offset >= data.len() {
+        return Err(DecodeError::OffsetOutOfRange);
+    }
+
+    match field.ty {
+        FieldType::Primitive(ref primitive) => decode_value(primitive, data, field.offset_pos, offset, payload_offset),
+        FieldType::ModelRef(_) => decode_value(&PrimitiveFieldType::UInt64, data, field.offset_pos, offset, payload_offset),
+        FieldType::PrimitiveList(ref primitive) => {
+            let end = get_end(data, field.offset_pos, payload_offset);
+            decode_list(primitive, data.get(offset..end).ok_or(DecodeError::OffsetOutOfRange)?)
+        }
+        _ => Ok(Value::Null)
+    }
+}
+
+/// Значение поля без offset-а: null или `@default`, если поля не было в схеме, когда документ записывался
+fn missing_value(field: &Field, stored_offset: usize) -> Value {
+    if field.offset_pos < stored_offset {
+        return Value::Null;
+    }
+    return field.default_value().cloned().unwrap_or(Value::Null);
+}
+
+/// Проверяет, что offset-ы полей не выходят за буфер и идут по возрастанию,
+/// иначе границы полей, вычисленные через get_end, некорректны
+pub fn check_offsets(data: &[u8], payload_offset: usize) -> Result<(), DecodeError> {
+    let stored_offset = stored_payload_offset(data)?;
+    if stored_offset > payload_offset || stored_offset < 3 || !(stored_offset - 3).is_multiple_of(4) {
+        return Err(DecodeError::TypeMismatch("payload offset mismatch".to_string()));
+    }
+    if data.len() < stored_offset {
+        return Err(DecodeError::BufferTooSmall);
+    }
+    let mut prev = stored_offset;
+    for offset_pos in (3..stored_offset).step_by(4) {
+        let offset = get_offset(data, offset_pos)?;
+        if offset == 0 {
+            continue;
+        }
+        if offset < prev || offset > data.len() {
+            return Err(DecodeError::OffsetOutOfRange);
+        }
+        prev = offset;
+    }
+    return Ok(());
+}
+
+/// Проверяет структуру документа: заголовок, границы и порядок offset-ов, размер и содержимое полей
+pub fn verify_document(fields: &[Field], data: &[u8], payload_offset: usize) -> Result<(), DecodeError> {
+    if data.len() < 3 {
+        return Err(DecodeError::BufferTooSmall);
+    }
+    if data[0] != 1 {
+        return Err(DecodeError::WrongVersion);
+    }
+    check_offsets(data, payload_offset)?;
+
+    for field in fields {
+        if field.offset_pos == 0 || get_offset(data, field.offset_pos)? == 0 {
+            continue;
+        }
+        let width = match field.ty {
+            FieldType::Primitive(ty) => ty.width(),
+            FieldType::PrimitiveList(_) => None,
+            FieldType::ModelRef(_) => Some(8),
+            _ => continue
+        };
+        let len = get_end(data, field.offset_pos, payload_offset) - get_offset(data, field.offset_pos)?;
+        if width.is_some_and(|width| width != len) {
+            return Err(DecodeError::TypeMismatch(format!("field {} has {} bytes", field.name, len)));
+        }
+        if len > 0 {
+            decode_field(field, data, payload_offset)?;
+        }
+    }
+
+    return Ok(());
+}
+
 #[inline(always)]
 fn decode_value(ty: &PrimitiveFieldType, data: &[u8], offset_pos: usize, offset: usize, payload_offset: usize) -> Result<Value, DecodeError> {
     match ty {
-        PrimitiveFieldType::String => {
-            if data.len() < 4 {
-                return Err(DecodeError::BufferTooSmall);
-            }
+        PrimitiveFieldType::String | PrimitiveFieldType::Json | PrimitiveFieldType::Custom(ScalarType { width: None, .. }) => {
             let end = get_end(data, offset_pos, payload_offset);
-            let s = std::str::from_utf8(&data[offset..end]).map_err(|_| DecodeError::Utf8Error)?;
-            Ok(Value::String(s.to_string()))
+            let bytes = data.get(offset..end).ok_or(DecodeError::OffsetOutOfRange)?;
+            decode_variable(ty, bytes)
+        }
+        PrimitiveFieldType::Custom(scalar) => {
+            let width = scalar.width.unwrap_or_default();
+            let bytes = data.get(offset..offset + width).ok_or(DecodeError::BufferTooSmall)?;
+            (scalar.decode)(bytes).map_err(DecodeError::TypeMismatch)
         }
         PrimitiveFieldType::DateTime => {
-            if data.len() < 8 {
-                return Err(DecodeError::BufferTooSmall);
-            }
-            let epoch = i64::from_be_bytes(data[offset..offset+8].try_into().unwrap());
+            let epoch = i64::from_be_bytes(read_bytes(data, offset)?);
             // Возвращаем как число (или можно форматировать обратно в ISO)
             Ok(Value::Number(epoch.into()))
         }
         PrimitiveFieldType::Int64 => {
-            if data.len() < 8 {
-                return Err(DecodeError::BufferTooSmall);
-            }
-            let n = i64::from_be_bytes(data[offset..offset+8].try_into().unwrap());
+            let n = i64::from_be_bytes(read_bytes(data, offset)?);
             Ok(Value::Number(n.into()))
         }
         PrimitiveFieldType::UInt64 => {
-            if data.len() < 8 {
-                return Err(DecodeError::BufferTooSmall);
-            }
-            let n = u64::from_be_bytes(data[offset..offset+8].try_into().unwrap());
+            let n = u64::from_be_bytes(read_bytes(data, offset)?);
             Ok(Value::Number(n.into()))
         }
+        PrimitiveFieldType::Int8 => Ok(Value::from(i8::from_be_bytes(read_bytes(data, offset)?))),
+        PrimitiveFieldType::Int16 => Ok(Value::from(i16::from_be_bytes(read_bytes(data, offset)?))),
+        PrimitiveFieldType::Int32 => Ok(Value::from(i32::from_be_bytes(read_bytes(data, offset)?))),
+        PrimitiveFieldType::UInt8 => Ok(Value::from(u8::from_be_bytes(read_bytes(data, offset)?))),
+        PrimitiveFieldType::UInt16 => Ok(Value::from(u16::from_be_bytes(read_bytes(data, offset)?))),
+        PrimitiveFieldType::UInt32 => Ok(Value::from(u32::from_be_bytes(read_bytes(data, offset)?))),
         PrimitiveFieldType::Float => {
-            if data.len() < 4 {
-                return Err(DecodeError::BufferTooSmall);
-            }
-            let n = f32::from_be_bytes(data[offset..offset+4].try_into().unwrap());
-            Ok(Value::Number(serde_json::Number::from_f64(n as f64).unwrap()))
+            let n = f32::from_be_bytes(read_bytes(data, offset)?);
+            serde_json::Number::from_f64(n as f64).map(Value::Number).ok_or_else(|| DecodeError::TypeMismatch("float is not finite".to_string()))
         }
         PrimitiveFieldType::Double => {
-            if data.len() < 8 {
-                return Err(DecodeError::BufferTooSmall);
-            }
-            let n = f64::from_be_bytes(data[offset..offset+8].try_into().unwrap());
-            Ok(Value::Number(serde_json::Number::from_f64(n).unwrap()))
+            let n = f64::from_be_bytes(read_bytes(data, offset)?);
+            serde_json::Number::from_f64(n).map(Value::Number).ok_or_else(|| DecodeError::TypeMismatch("double is not finite".to_string()))
         }
         PrimitiveFieldType::Bool => {
-            if data.is_empty() {
-                return Err(DecodeError::BufferTooSmall);
-            }
-            Ok(Value::Bool(data[offset] != 0))
+            let [value] = read_bytes(data, offset)?;
+            Ok(Value::Bool(value != 0))
+        }
+        PrimitiveFieldType::Enum(enum_type) => {
+            let ordinal = u16::from_be_bytes(read_bytes(data, offset)?);
+            enum_type.variants.get(ordinal as usize)
+                .map(|variant| Value::String(variant.clone()))
+                .ok_or_else(|| DecodeError::TypeMismatch(format!("unknown variant {} of enum {}", ordinal, enum_type.name)))
+        }
+    }
+}
+
+/// Элементы списка примитивов (`[count: u32]`, затем элементы) в закодированном виде, у значений переменной длины вместе с `[len: u32]`
+pub fn list_items<'a>(ty: &PrimitiveFieldType, bytes: &'a [u8]) -> Result<Vec<&'a [u8]>, DecodeError> {
+    let count = u32::from_be_bytes(read_bytes(bytes, 0)?) as usize;
+    let mut items = Vec::with_capacity(count.min(bytes.len()));
+    let mut pos = 4;
+    for _ in 0..count {
+        let len = match ty.width() {
+            Some(width) => width,
+            None => 4 + u32::from_be_bytes(read_bytes(bytes, pos)?) as usize,
+        };
+        items.push(bytes.get(pos..pos + len).ok_or(DecodeError::BufferTooSmall)?);
+        pos += len;
+    }
+    if pos != bytes.len() {
+        return Err(DecodeError::TypeMismatch("list length mismatch".to_string()));
+    }
+    return Ok(items);
+}
+
+fn decode_list(ty: &PrimitiveFieldType, bytes: &[u8]) -> Result<Value, DecodeError> {
+    let items = list_items(ty, bytes)?.into_iter().map(|item| match ty.width() {
+        None => decode_variable(ty, &item[4..]),
+        Some(_) => decode_value(ty, item, 0, 0, 0)
+    }).collect::<Result<Vec<_>, _>>()?;
+    return Ok(Value::Array(items));
+}
+
+/// Значение переменной длины без префикса
+fn decode_variable(ty: &PrimitiveFieldType, bytes: &[u8]) -> Result<Value, DecodeError> {
+    match ty {
+        PrimitiveFieldType::Custom(scalar) => (scalar.decode)(bytes).map_err(DecodeError::TypeMismatch),
+        PrimitiveFieldType::Json => serde_json::from_slice(bytes).map_err(|err| DecodeError::TypeMismatch(format!("invalid json: {}", err))),
+        _ => {
+            let s = std::str::from_utf8(bytes).map_err(|_| DecodeError::Utf8Error)?;
+            Ok(Value::String(s.to_string()))
         }
     }
 }
+
+#[inline(always)]
+fn read_bytes<const SIZE: usize>(data: &[u8], offset: usize) -> Result<[u8; SIZE], DecodeError> {
+    let bytes = data.get(offset..offset + SIZE).ok_or(DecodeError::BufferTooSmall)?;
+    return Ok(bytes.try_into().unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::marci_db::MarciSelect;
+    use crate::marci_encoder::encode_document;
+    use crate::schema::parse_schema;
+
+    #[test]
+    fn test_decode_doc() {
+        let schema = parse_schema("
+model User {
+  name        String
+  age         Int?
+  tags        String[]
+  score       Double
+  extra       Json?
+}
+").unwrap();
+        let model = &schema.models[0];
+        let input = json!({ "name": "Ann \"A\"", "tags": ["a"], "score": 1.5, "extra": { "b": [1, null] } });
+        let (data, _) = encode_document(model, &input, &mut vec![]).unwrap();
+        let select = MarciSelect::all(&model.fields);
+        fn ctx<'a, U>(data: &'a [u8], model: &'a crate::schema::Model, select: &'a BitVec) -> DecodeCtx<'a, U> {
+            DecodeCtx { id: 7, data, fields: &model.fields, payload_offset: model.payload_offset, select, includes: vec![], expires_at: None, payload: None }
+        }
+
+        let doc = decode_doc(ctx(&data, model, &select.select)).unwrap();
+        // Ключи в порядке схемы, значения те же, что у decode_document
+        let text = r#"{"id":7,"name":"Ann \"A\"","age":null,"tags":["a"],"score":1.5,"extra":{"b":[1,null]}}"#;
+        assert_eq!(serde_json::to_string(&doc).unwrap(), text);
+        assert_eq!(decode_json(ctx(&data, model, &select.select)).unwrap().0, text.as_bytes());
+        assert_eq!(doc.into_value(), decode_document(ctx(&data, model, &select.select)).unwrap());
+    }
+}
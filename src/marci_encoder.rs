@@ -1,9 +1,11 @@
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 
 use serde_json::Value;
 use bitvec::prelude::*;
 
-use crate::{marci_db::InsertStruct, schema::{FieldType, InsertedIndex, Model, PrimitiveFieldType, WithFields}};
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+
+use crate::{marci_db::{InsertStruct, now_millis}, marci_decoder::COMPRESSED_VERSION, schema::{Attribute, DefaultFn, Field, FieldType, PrimitiveFieldType, WithFields}, update_data::ListOp};
 
 #[derive(Debug)]
 pub enum EncodeError {
@@ -11,13 +13,41 @@ pub enum EncodeError {
     MissingField(String),
     TypeMismatch { field: String, expected: &'static str },
     OffsetOverflow,
-    EmptyObject
+    EmptyObject,
+    TtlNotSupported,
+    /// Значение нарушает `@min`/`@max`/`@maxLength`/`@matches` поля
+    Constraint { field: String, message: String },
 }
 
-static EMPTY_ARRAY: Value = Value::Array(vec![]);
+/// Документ в виде для записи в дерево: с `@@compress` документ от порога и больше сжимается,
+/// если это дает выигрыш. Версия документа заменяется на `COMPRESSED_VERSION`, см. `unpack`
+pub fn pack<'a, T: WithFields>(model: &T, data: &'a [u8]) -> Cow<'a, [u8]> {
+    let Some(threshold) = model.compress_threshold() else {
+        return Cow::Borrowed(data);
+    };
+    if data.len() < threshold {
+        return Cow::Borrowed(data);
+    }
+    let mut packed = vec![COMPRESSED_VERSION];
+    packed.extend_from_slice(&lz4_flex::compress_prepend_size(data));
+    match packed.len() < data.len() {
+        true => Cow::Owned(packed),
+        false => Cow::Borrowed(data),
+    }
+}
 
-/// Кодируем JSON-документ для заданной модели в бинарный формат
+/// Кодируем JSON-документ для заданной модели в бинарный формат.
+/// Отсутствующие поля получают значение из `@default(...)`, остальные - null
 pub fn encode_document<'a, T>(model: &'a T, json: &Value, structs: &mut Vec<InsertStruct<'a>>) -> Result<(Vec<u8>, BitVec), EncodeError> where T: WithFields {
+    return encode_fields(model, json, structs, true);
+}
+
+/// Кодируем тело `update`: отсутствующие поля не попадают в маску и остаются как есть
+pub fn encode_update<'a, T>(model: &'a T, json: &Value, structs: &mut Vec<InsertStruct<'a>>) -> Result<(Vec<u8>, BitVec), EncodeError> where T: WithFields {
+    return encode_fields(model, json, structs, false);
+}
+
+fn encode_fields<'a, T>(model: &'a T, json: &Value, structs: &mut Vec<InsertStruct<'a>>, insert: bool) -> Result<(Vec<u8>, BitVec), EncodeError> where T: WithFields {
     let obj = json
         .as_object()
         .ok_or(EncodeError::NotAnObject)?;
@@ -36,21 +66,47 @@ pub fn encode_document<'a, T>(model: &'a T, json: &Value, structs: &mut Vec<Inse
 
     let initial_size = buf.len();
 
+    // Свое время жизни документа, `null` делает документ постоянным
+    if let Some(value) = obj.get("$ttl") {
+        if model.ttl().is_none() {
+            return Err(EncodeError::TtlNotSupported);
+        }
+        if value.is_null() {
+            structs.push(InsertStruct::Ttl { seconds: None });
+        } else {
+            let seconds = value.as_u64().ok_or_else(|| EncodeError::TypeMismatch { field: "$ttl".to_string(), expected: "uint64 (seconds)" })?;
+            structs.push(InsertStruct::Ttl { seconds: Some(seconds) });
+        }
+    }
+
+    // Id от клиента для `@@id(external)`. В `update` поле `id` указывает на документ, а не задает значение
+    if insert && model.is_model() && let Some(value) = obj.get("id") {
+        let id = value.as_u64().ok_or_else(|| EncodeError::TypeMismatch { field: "id".to_string(), expected: "uint64" })?;
+        structs.push(InsertStruct::Id(id));
+    }
+
     let max_offset_index = model.fields().iter().map(|a| a.offset_index).max().unwrap();
     let mut changed_mask = bitvec![0; max_offset_index+1];
 
     // Тело
     for field in model.fields() {
-        let value_opt: Option<&Value> = obj.get(&field.name);
-        let Some(value) = value_opt else {
-            // TODO: set default value here. Now it setting null (offset = 0)
-            continue;
+        let default;
+        let value = match obj.get(&field.name) {
+            Some(value) => value,
+            None if insert => match insert_default(field) {
+                Some(value) => {
+                    default = value;
+                    &default
+                }
+                None => continue
+            },
+            None => continue
         };
 
         if value.is_null() {
             match field.ty {
                 FieldType::Struct(ref st) => {
-                    structs.push(InsertStruct::None { st: &st });
+                    structs.push(InsertStruct::None { st });
                 },
                 FieldType::StructList(_, _) => {
                     return Err(EncodeError::TypeMismatch { field: field.name.clone(), expected: "Array" })
@@ -74,6 +130,7 @@ pub fn encode_document<'a, T>(model: &'a T, json: &Value, structs: &mut Vec<Inse
                 buf[field.offset_pos..field.offset_pos + 4].copy_from_slice(&start.to_be_bytes());
 
                 // Кодируем само значение
+                check_constraints(field, value)?;
                 encode_value(&mut buf, &primitive_type, &field.name, value)?;
             }
             FieldType::ModelRef(_) => {
@@ -93,6 +150,15 @@ pub fn encode_document<'a, T>(model: &'a T, json: &Value, structs: &mut Vec<Inse
                 encode_value(&mut buf, &PrimitiveFieldType::UInt64, &field.name, item_id)?;
             }
             FieldType::ModelRefList(model_index) => {
+                // `{ connect: [...] }` / `{ disconnect: [...] }` добавляют и убирают связи, не трогая остальные
+                let (op, value) = match value {
+                    Value::Object(obj) if obj.len() == 1 => match obj.iter().next().unwrap() {
+                        (key, items) if key == "connect" => (Some(ListOp::Push), items),
+                        (key, items) if key == "disconnect" => (Some(ListOp::Remove), items),
+                        _ => return Err(EncodeError::TypeMismatch { field: field.name.clone(), expected: "Array<{ id: u64 }> or { connect } or { disconnect }" })
+                    },
+                    _ => (None, value)
+                };
                 let Some(value) = value.as_array() else {
                     return Err(EncodeError::TypeMismatch { field: field.name.clone(), expected: "Array<{ id: u64 }>" })
                 };
@@ -108,46 +174,124 @@ pub fn encode_document<'a, T>(model: &'a T, json: &Value, structs: &mut Vec<Inse
                     })
                     .collect::<Result<_, _>>()?; // <---- вот здесь вся магия
 
-                structs.push(InsertStruct::Connect { field, ref_model: model_index, ids: ids.clone() });
+                // Данные связи (`@payload`) лежат в том же объекте, что и id: `{ id, role }`
+                let payloads: Vec<Vec<u8>> = match field.payload() {
+                    Some(st) if op != Some(ListOp::Remove) => value.iter()
+                        .map(|item| encode_document(st, item, &mut vec![]).map(|(data, _)| data))
+                        .collect::<Result<_, _>>()?,
+                    _ => vec![],
+                };
+
+                structs.push(InsertStruct::Connect { field, ref_model: model_index, ids: ids.clone(), payloads, op });
             }
             FieldType::Struct(ref st) => {
-                let (data, changed_values) = encode_document(st, value, structs)?;
+                let (data, changed_values) = encode_fields(st, value, structs, insert)?;
                 structs.push(InsertStruct::One { st, changed_mask: changed_values, data });
             }
             FieldType::StructList(ref st, counter_idx) => {
                 let Some(value) = value.as_array() else {
                     return Err(EncodeError::TypeMismatch { field: field.name.clone(), expected: "Array" })
                 };
-                if value.len() == 0 {
+                if value.is_empty() {
                     structs.push(InsertStruct::Empty { st });
                 } else {
                     let mut vec_many = Vec::with_capacity(value.len());
                     for item in value {
                         if let Some(id) = item.get("id").and_then(|a|a.as_u64()) {
-                            let (data, _) = encode_document(st, item, structs)?;
+                            let (data, _) = encode_fields(st, item, structs, insert)?;
                             vec_many.push((Some(id), data));
                         } else {
-                            let (data, _) = encode_document(st, item, structs)?;
+                            let (data, _) = encode_fields(st, item, structs, true)?;
                             vec_many.push((None, data));
                         }
                     }
                     structs.push(InsertStruct::Many { st, data: vec_many, counter_idx });
                 }
             }
+            FieldType::PrimitiveList(ref primitive_type) => {
+                changed_mask.set(field.offset_index, true);
+
+                // `{ push: x }` / `{ remove: x }` - в поле пишутся только добавляемые или удаляемые элементы,
+                // update_data объединяет их с текущим списком
+                let mut removing = false;
+                let items = match value {
+                    Value::Array(items) => items.as_slice(),
+                    Value::Object(obj) if obj.len() == 1 => {
+                        let (op, items) = match obj.iter().next().unwrap() {
+                            (key, items) if key == "push" => (ListOp::Push, items),
+                            (key, items) if key == "remove" => {
+                                removing = true;
+                                (ListOp::Remove, items)
+                            }
+                            _ => return Err(EncodeError::TypeMismatch { field: field.name.clone(), expected: "Array or { push } or { remove }" })
+                        };
+                        structs.push(InsertStruct::ListOp { field, op });
+                        items.as_array().map(|items| items.as_slice()).unwrap_or(std::slice::from_ref(items))
+                    }
+                    _ => return Err(EncodeError::TypeMismatch { field: field.name.clone(), expected: "Array or { push } or { remove }" })
+                };
+
+                // Удаляемые значения ограничениям не подчиняются
+                if !removing {
+                    items.iter().try_for_each(|item| check_constraints(field, item))?;
+                }
+
+                let start = buf.len() as u32;
+                buf[field.offset_pos..field.offset_pos + 4].copy_from_slice(&start.to_be_bytes());
+
+                encode_list(&mut buf, primitive_type, &field.name, items)?;
+            }
             _ => {
 
             }
         }
     }
 
-    if buf.len() == initial_size && structs.len() == 0 {
+    if buf.len() == initial_size && structs.is_empty() {
         return Err(EncodeError::EmptyObject);
     }
 
     Ok((buf, changed_mask))
 }
 
-/// Кодирует массив значений и дописывает в конец `dst`
+/// Значение поля, которого нет в теле `insert`: `@default(value)`, текущее время или новый UUID
+fn insert_default(field: &Field) -> Option<Value> {
+    if let Some(value) = field.default_value() {
+        return Some(value.clone());
+    }
+    return match field.default_fn()? {
+        DefaultFn::Now => Some(Value::from(now_millis())),
+        DefaultFn::Uuid => Some(Value::String(uuid_v4())),
+        DefaultFn::Uuid7 => Some(Value::String(uuid_v7(now_millis()))),
+    };
+}
+
+/// Случайный UUID версии 4 в виде `xxxxxxxx-xxxx-4xxx-yxxx-xxxxxxxxxxxx`
+fn uuid_v4() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    return format_uuid(&bytes);
+}
+
+/// UUID версии 7: 48 бит времени в миллисекундах, остальное случайно. Строки сортируются по времени создания
+fn uuid_v7(millis: u64) -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes[6..]);
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    bytes[6] = (bytes[6] & 0x0f) | 0x70;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    return format_uuid(&bytes);
+}
+
+fn format_uuid(bytes: &[u8; 16]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    return format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]);
+}
+
+/// Кодирует массив значений и дописывает в конец `dst`: `[count: u32]`, затем элементы.
+/// Длина строки (и другого значения переменной длины) в списке не следует из offset-ов, поэтому перед ней пишется `[len: u32]`
 fn encode_list<T>(
     dst: &mut Vec<u8>,
     ty: &PrimitiveFieldType,
@@ -156,14 +300,22 @@ fn encode_list<T>(
 )  -> Result<(), EncodeError> where T: Borrow<Value> {
     dst.extend_from_slice(&(v.len() as u32).to_be_bytes());
     for (index, val) in v.iter().enumerate() {
+        let start = dst.len();
+        if ty.width().is_none() {
+            dst.extend_from_slice(&[0; 4]);
+        }
         // TODO: remove format! from this
         encode_value(dst, ty, &format!("{}[{}]", field_name, index), val.borrow())?;
+        if ty.width().is_none() {
+            let len = (dst.len() - start - 4) as u32;
+            dst[start..start + 4].copy_from_slice(&len.to_be_bytes());
+        }
     }
     Ok(())
 }
 
 /// Кодирует одно значение и дописывает в конец `dst`
-fn encode_value(
+pub fn encode_value(
     dst: &mut Vec<u8>,
     ty: &PrimitiveFieldType,
     field_name: &str,
@@ -300,14 +452,68 @@ fn encode_value(
                 })?;
             dst.push(if b { 1 } else { 0 });
         }
+        PrimitiveFieldType::Int8 => dst.extend_from_slice(&small_int::<i8>(v, field_name, "int8")?.to_be_bytes()),
+        PrimitiveFieldType::Int16 => dst.extend_from_slice(&small_int::<i16>(v, field_name, "int16")?.to_be_bytes()),
+        PrimitiveFieldType::Int32 => dst.extend_from_slice(&small_int::<i32>(v, field_name, "int32")?.to_be_bytes()),
+        PrimitiveFieldType::UInt8 => dst.extend_from_slice(&small_int::<u8>(v, field_name, "uint8")?.to_be_bytes()),
+        PrimitiveFieldType::UInt16 => dst.extend_from_slice(&small_int::<u16>(v, field_name, "uint16")?.to_be_bytes()),
+        PrimitiveFieldType::UInt32 => dst.extend_from_slice(&small_int::<u32>(v, field_name, "uint32")?.to_be_bytes()),
+        // Значение сохраняется как есть, без проверки структуры
+        PrimitiveFieldType::Json => {
+            dst.extend_from_slice(&serde_json::to_vec(v).unwrap());
+        }
+        PrimitiveFieldType::Enum(enum_type) => {
+            let ordinal = v.as_str().and_then(|variant| enum_type.ordinal(variant))
+                .ok_or_else(|| EncodeError::TypeMismatch {
+                    field: field_name.to_string(),
+                    expected: enum_type.name.as_str(),
+                })?;
+            dst.extend_from_slice(&ordinal.to_be_bytes());
+        }
+        PrimitiveFieldType::Custom(scalar) => {
+            let bytes = (scalar.encode)(v).map_err(|_| EncodeError::TypeMismatch {
+                field: field_name.to_string(),
+                expected: scalar.name.as_str(),
+            })?;
+            if scalar.width.is_some_and(|width| width != bytes.len()) {
+                return Err(EncodeError::TypeMismatch { field: field_name.to_string(), expected: scalar.name.as_str() });
+            }
+            dst.extend_from_slice(&bytes);
+        }
     }
 
     Ok(())
 }
 
+/// Проверяет значение по `@min`/`@max`/`@maxLength`/`@matches` поля
+fn check_constraints(field: &Field, value: &Value) -> Result<(), EncodeError> {
+    for attr in field.attributes.iter() {
+        let violation = match attr {
+            Attribute::Min(min) => value.as_f64().is_some_and(|v| v < *min).then(|| format!("must be at least {}", min)),
+            Attribute::Max(max) => value.as_f64().is_some_and(|v| v > *max).then(|| format!("must be at most {}", max)),
+            Attribute::MaxLength(len) => value.as_str().is_some_and(|s| s.chars().count() > *len).then(|| format!("must be at most {} characters long", len)),
+            Attribute::Matches(regex) => value.as_str().is_some_and(|s| !regex.is_match(s)).then(|| format!("must match {}", regex.as_str())),
+            _ => None,
+        };
+        if let Some(message) = violation {
+            return Err(EncodeError::Constraint { field: field.name.clone(), message });
+        }
+    }
+    Ok(())
+}
+
+/// Целое, которое должно поместиться в тип меньше 8 байт
+fn small_int<T: TryFrom<i64> + TryFrom<u64>>(v: &Value, field_name: &str, expected: &'static str) -> Result<T, EncodeError> {
+    let n = match v.as_i64() {
+        Some(n) => T::try_from(n).ok(),
+        None => v.as_u64().and_then(|n| T::try_from(n).ok()),
+    };
+    n.ok_or_else(|| EncodeError::TypeMismatch { field: field_name.to_string(), expected })
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{marci_db::get_end, marci_encoder::encode_document, schema::{FieldType, Model, PrimitiveFieldType}};
+    use crate::{marci_db::get_end, marci_encoder::{encode_document, uuid_v7}, schema::{FieldType, Model, PrimitiveFieldType}};
     use serde_json::json;
 
     #[test]
@@ -319,6 +525,7 @@ mod tests {
             fields: vec![
                 crate::schema::Field {
                     name: "name".to_string(),
+                    key: "name".into(),
                     ty: FieldType::Primitive(PrimitiveFieldType::String),
                     offset_index: 0,
                     offset_pos: 3,
@@ -329,9 +536,10 @@ mod tests {
                 },
                 crate::schema::Field {
                     name: "age".to_string(),
+                    key: "age".into(),
                     ty: FieldType::Primitive(PrimitiveFieldType::Int64),
                     offset_index: 1,
-                    offset_pos: 3 + 1 * 4,
+                    offset_pos: 3 + 4,
                     derived_from: None,
                     is_nullable: false,
                     inserted_indexes: vec![], select_index: None,
@@ -339,6 +547,7 @@ mod tests {
                 },
                 crate::schema::Field {
                     name: "profile".to_string(),
+                    key: "profile".into(),
                     ty: FieldType::ModelRef(1),
                     offset_index: 2,
                     offset_pos: 3 + 2 * 4,
@@ -348,7 +557,10 @@ mod tests {
                     attributes: vec![]
                 },
             ],
-            payload_offset: 3 + 3 * 4
+            payload_offset: 3 + 3 * 4,
+            attributes: vec![],
+            ttl: None,
+            indexes: vec![]
         };
 
         let input = json!({
@@ -387,5 +599,75 @@ mod tests {
         let age_value = i64::from_be_bytes(age_bytes.try_into().unwrap());
         assert_eq!(age_value, 30);
     }
+
+    #[test]
+    fn test_uuid_v7_order() {
+        let first = uuid_v7(1_700_000_000_000);
+        let second = uuid_v7(1_700_000_000_001);
+        assert_eq!(first.len(), 36);
+        assert_eq!(&first[14..15], "7");
+        assert!(first < second);
+        assert!(first.starts_with("018bcfe5-6800"));
+    }
+
+    #[test]
+    fn test_small_ints() {
+        let schema = crate::schema::parse_schema("
+model Pixel {
+  x           Int16
+  alpha       UInt8
+}
+").unwrap();
+        let model = &schema.models[0];
+        let (data, _) = encode_document(model, &json!({ "x": -300, "alpha": 255 }), &mut vec![]).unwrap();
+        let x = &model.fields[0];
+        assert_eq!(get_end(&data, x.offset_pos, model.payload_offset) - crate::marci_db::get_offset(&data, x.offset_pos).unwrap(), 2);
+        assert_eq!(crate::marci_decoder::decode_field(&model.fields[0], &data, model.payload_offset).unwrap(), json!(-300));
+        assert_eq!(crate::marci_decoder::decode_field(&model.fields[1], &data, model.payload_offset).unwrap(), json!(255));
+        assert!(encode_document(model, &json!({ "alpha": 256 }), &mut vec![]).is_err());
+        assert!(encode_document(model, &json!({ "x": 40000 }), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_constraints() {
+        let schema = crate::schema::parse_schema(r#"
+model User {
+  login       String      @maxLength(8) @matches("^[a-z]+$")
+  age         Int?        @min(0) @max(150)
+  tags        String[]    @maxLength(3)
+}
+"#).unwrap();
+        let model = &schema.models[0];
+        assert!(encode_document(model, &json!({ "login": "alice", "age": 30, "tags": ["a"] }), &mut vec![]).is_ok());
+        for doc in [json!({ "login": "alice_1" }), json!({ "login": "abcdefghi" }), json!({ "login": "bob", "age": -1 }), json!({ "login": "bob", "tags": ["long"] })] {
+            assert!(matches!(encode_document(model, &doc, &mut vec![]), Err(super::EncodeError::Constraint { .. })), "{}", doc);
+        }
+        // Удаление из списка не проверяется
+        assert!(crate::marci_encoder::encode_update(model, &json!({ "tags": { "remove": "long" } }), &mut vec![]).is_ok());
+    }
+
+    #[test]
+    fn test_pack() {
+        let schema = crate::schema::parse_schema("
+model Note {
+  text        String
+  @@compress(64)
 }
 
+model Tag {
+  text        String
+}
+").unwrap();
+        let text = "lorem ipsum ".repeat(20);
+        let (data, _) = encode_document(&schema.models[0], &json!({ "text": text }), &mut vec![]).unwrap();
+        let packed = crate::marci_encoder::pack(&schema.models[0], &data);
+        assert_eq!(packed[0], crate::marci_decoder::COMPRESSED_VERSION);
+        assert!(packed.len() < data.len());
+        assert_eq!(&*crate::marci_decoder::unpack(&packed[..]), &data[..]);
+
+        // Без `@@compress` и ниже порога документ хранится как есть
+        assert_eq!(&*crate::marci_encoder::pack(&schema.models[1], &data), &data[..]);
+        let (short, _) = encode_document(&schema.models[0], &json!({ "text": "a" }), &mut vec![]).unwrap();
+        assert_eq!(&*crate::marci_encoder::pack(&schema.models[0], &short), &short[..]);
+    }
+}
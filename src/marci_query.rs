@@ -0,0 +1,1149 @@
+use std::{cmp::Ordering, collections::HashSet, ops::Bound, sync::OnceLock};
+
+use canopydb::Transaction;
+use serde_json::{Map, Value, json};
+
+use crate::{error::MarciError, marci_db::{MarciSelect, compound_part, index_value, key_id}, marci_decoder::{decode_field, unpack}, marci_encoder::encode_value, marci_select::{MarciSelectError, SelectPlans, parse_select}, schema::{CompoundIndex, Field, FieldType, InsertedIndex, Model, PrimitiveFieldType, Schema}};
+
+/// Аргументы запроса, которые отличают новый формат тела findMany от голого select
+const QUERY_ARGS: [&str; 7] = ["select", "where", "orderBy", "skip", "take", "asOf", "cursor"];
+
+#[derive(Debug)]
+pub enum MarciQueryError {
+  Select(MarciSelectError),
+  UnknownField(String),
+  UnknownOperator(String),
+  TypeMismatch { field: String, expected: &'static str },
+}
+
+/// Поле для фильтрации или сортировки. `id` хранится в ключе дерева, а не в документе
+#[derive(Clone)]
+pub enum QueryField<'a> {
+  Id,
+  Field(&'a Field),
+  /// Ключ верхнего уровня поля `Json` (`path` в where)
+  JsonKey(&'a Field, String),
+}
+
+pub enum FilterOp {
+  Equals(Value),
+  Not(Value),
+  In(Vec<Value>),
+  NotIn(Vec<Value>),
+  Lt(Value),
+  Lte(Value),
+  Gt(Value),
+  Gte(Value),
+  Contains(String),
+  StartsWith(String),
+  EndsWith(String),
+  /// У значения поля `Json` есть ключ верхнего уровня
+  HasKey(String),
+}
+
+pub struct FieldFilter<'a> {
+  pub field: QueryField<'a>,
+  pub op: FilterOp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelationMode {
+  Some,
+  Every,
+  None,
+}
+
+/// Условие на связанные документы списка ModelRefList (`posts: { some: { ... } }`)
+pub struct RelationFilter<'a> {
+  /// Индекс связи с ключами `[parent_id, child_id]`
+  pub tree_name: &'a [u8],
+  pub model: &'a Model,
+  pub mode: RelationMode,
+  pub filter: MarciFilter<'a>,
+  /// Для some/none - родители, у которых есть подходящий документ,
+  /// для every - родители, у которых есть неподходящий. Заполняется в prepare
+  ids: OnceLock<HashSet<u64>>,
+}
+
+/// Дерево условий where. Ключи одного объекта объединяются через AND
+pub enum MarciFilter<'a> {
+  And(Vec<MarciFilter<'a>>),
+  Or(Vec<MarciFilter<'a>>),
+  Not(Box<MarciFilter<'a>>),
+  Field(FieldFilter<'a>),
+  Relation(Box<RelationFilter<'a>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+  Asc,
+  Desc
+}
+
+pub struct OrderBy<'a> {
+  pub field: QueryField<'a>,
+  pub order: SortOrder,
+}
+
+pub struct MarciQuery<'a> {
+  pub filter: MarciFilter<'a>,
+  pub order_by: Vec<OrderBy<'a>>,
+  pub skip: usize,
+  pub take: Option<usize>,
+  /// Ключи сортировки последнего документа предыдущей страницы: выборка начинается сразу после него
+  pub cursor: Option<Vec<Value>>,
+}
+
+impl<'a> MarciQuery<'a> {
+  /// Добавляет условие, которое должно выполняться вместе с where запроса (правила `@@allow`)
+  pub fn restrict(&mut self, filter: MarciFilter<'a>) {
+    let filter = MarciFilter::And(vec![std::mem::replace(&mut self.filter, MarciFilter::empty()), filter]);
+    self.filter = filter;
+  }
+}
+
+impl MarciQuery<'_> {
+  pub fn all<'a>() -> MarciQuery<'a> {
+    return MarciQuery { filter: MarciFilter::empty(), order_by: vec![], skip: 0, take: None, cursor: None };
+  }
+
+  /// Порядок только по id (или без orderBy - тогда по возрастанию id): документы идут в нем прямо из дерева модели,
+  /// поэтому обход можно остановить на `take` документов без сортировки. None - нужна сортировка
+  pub fn id_order(&self) -> Option<SortOrder> {
+    return match self.order_by.as_slice() {
+      [] => Some(SortOrder::Asc),
+      [OrderBy { field: QueryField::Id, order }] => Some(*order),
+      _ => None,
+    };
+  }
+
+  /// Документ с ключами сортировки `keys` идет после курсора
+  pub fn after_cursor(&self, keys: &[Value]) -> bool {
+    return self.cursor.as_ref().is_none_or(|cursor| self.compare(keys, cursor) == Ordering::Greater);
+  }
+
+  /// Обходит id документов в порядке сортировки, начиная сразу после курсора, пока `visit` возвращает true.
+  /// Порядок читается из ключей: по id - из дерева модели `tree_name`, по одному полю - из его индекса `[value, id]`.
+  /// None - курсора нет или порядок нельзя прочитать из ключей, нужна обычная сортировка
+  pub fn seek_cursor(&self, rx: &Transaction, tree_name: &[u8], mut visit: impl FnMut(u64) -> bool) -> Option<()> {
+    let cursor = self.cursor.as_ref()?;
+    let last_id = cursor.last()?.as_u64()?;
+
+    match self.order_by.as_slice() {
+      [OrderBy { field: QueryField::Id, order }] => {
+        let tree = rx.get_tree(tree_name).unwrap()?;
+        let id = last_id.to_be_bytes();
+        let keys: Box<dyn Iterator<Item = _>> = match order {
+          SortOrder::Asc => Box::new(tree.range_keys::<&[u8]>((Bound::Excluded(&id[..]), Bound::Unbounded)).unwrap()),
+          SortOrder::Desc => Box::new(tree.range_keys::<&[u8]>((Bound::Unbounded, Bound::Excluded(&id[..]))).unwrap().rev()),
+        };
+        for key in keys {
+          if !visit(u64::from_be_bytes(key.unwrap().as_ref().try_into().unwrap())) {
+            break;
+          }
+        }
+      }
+      [OrderBy { field: QueryField::Field(field), order }, OrderBy { field: QueryField::Id, order: SortOrder::Asc }] => {
+        // Документов без значения нет в индексе: по возрастанию они идут раньше любого значения,
+        // поэтому после курсора со значением их может не быть только у обязательного поля или при asc
+        if cursor[0].is_null() || (*order == SortOrder::Desc && field.is_nullable) {
+          return None;
+        }
+        if matches!(field.ty, FieldType::Primitive(PrimitiveFieldType::Custom(_))) {
+          return None;
+        }
+        let width = fixed_width(field)?;
+        let tree = rx.get_tree(value_index(field)?).unwrap()?;
+        let value = index_key(field, &cursor[0])?;
+        let id = |key: &[u8]| u64::from_be_bytes(key[width..].try_into().unwrap());
+
+        match order {
+          SortOrder::Asc => {
+            let start = [value.as_slice(), &last_id.to_be_bytes()].concat();
+            for key in tree.range_keys::<Vec<u8>>((Bound::Excluded(start), Bound::Unbounded)).unwrap() {
+              if !visit(id(key.unwrap().as_ref())) {
+                break;
+              }
+            }
+          }
+          // Ключи читаются с конца, но при равных значениях id идут по возрастанию:
+          // собираем группу одного значения и отдаем ее в обратном порядке
+          SortOrder::Desc => {
+            let end = [value.as_slice(), &[0xFFu8; 8]].concat();
+            let mut group: Vec<u64> = vec![];
+            let mut group_value: Vec<u8> = vec![];
+            let mut flush = |group: &mut Vec<u64>, group_value: &[u8]| {
+              // В группе значения курсора пропускаем документы до него включительно
+              let at_cursor = group_value == value.as_slice();
+              return !group.drain(..).rev().filter(|id| !at_cursor || *id > last_id).any(|id| !visit(id));
+            };
+            for key in tree.range_keys::<Vec<u8>>((Bound::Unbounded, Bound::Included(end))).unwrap().rev() {
+              let key = key.unwrap();
+              if key.as_ref()[..width] != group_value[..] {
+                if !flush(&mut group, &group_value) {
+                  return Some(());
+                }
+                group_value = key.as_ref()[..width].to_vec();
+              }
+              group.push(id(key.as_ref()));
+            }
+            flush(&mut group, &group_value);
+          }
+        }
+      }
+      _ => return None,
+    }
+    return Some(());
+  }
+
+  /// Значения полей сортировки для документа
+  pub fn sort_keys(&self, id: u64, data: &[u8], payload_offset: usize) -> Vec<Value> {
+    return self.order_by.iter().map(|order| order.field.value(id, data, payload_offset)).collect();
+  }
+
+  pub fn compare(&self, a: &[Value], b: &[Value]) -> Ordering {
+    for (index, order) in self.order_by.iter().enumerate() {
+      // null всегда меньше любого значения
+      let ord = match (&a[index], &b[index]) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Less,
+        (_, Value::Null) => Ordering::Greater,
+        (a, b) => compare_values(a, b).unwrap_or(Ordering::Equal)
+      };
+      let ord = if order.order == SortOrder::Desc { ord.reverse() } else { ord };
+      if ord != Ordering::Equal {
+        return ord;
+      }
+    }
+    return Ordering::Equal;
+  }
+}
+
+impl<'a> MarciFilter<'a> {
+  pub fn empty() -> MarciFilter<'a> {
+    return MarciFilter::And(vec![]);
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return matches!(self, MarciFilter::And(items) if items.iter().all(|item| item.is_empty()));
+  }
+
+  /// Собирает id родителей для фильтров по связям. Нужно вызвать до matches
+  pub fn prepare(&self, rx: &Transaction) {
+    match self {
+      MarciFilter::And(items) | MarciFilter::Or(items) => items.iter().for_each(|item| item.prepare(rx)),
+      MarciFilter::Not(item) => item.prepare(rx),
+      MarciFilter::Field(_) => {}
+      MarciFilter::Relation(relation) => relation.prepare(rx),
+    }
+  }
+
+  pub fn matches(&self, id: u64, data: &[u8], payload_offset: usize) -> bool {
+    match self {
+      MarciFilter::And(items) => items.iter().all(|item| item.matches(id, data, payload_offset)),
+      MarciFilter::Or(items) => items.iter().any(|item| item.matches(id, data, payload_offset)),
+      MarciFilter::Not(item) => !item.matches(id, data, payload_offset),
+      MarciFilter::Field(condition) => condition.matches(id, data, payload_offset),
+      MarciFilter::Relation(relation) => relation.matches(id),
+    }
+  }
+
+  /// Условия на поля, которые должны выполняться всегда (AND верхнего уровня)
+  fn required_conditions<'s>(&'s self, out: &mut Vec<&'s FieldFilter<'a>>) {
+    match self {
+      MarciFilter::And(items) => items.iter().for_each(|item| item.required_conditions(out)),
+      MarciFilter::Field(condition) => out.push(condition),
+      _ => {}
+    }
+  }
+
+  /// Поля из AND верхнего уровня (только они могут читаться из индекса) и есть ли у каждого индекс
+  pub fn indexable_fields(&self) -> Vec<(&'a Field, bool)> {
+    let mut conditions = vec![];
+    self.required_conditions(&mut conditions);
+
+    let mut fields: Vec<(&'a Field, bool)> = vec![];
+    for condition in conditions {
+      let QueryField::Field(field) = condition.field else { continue };
+      if !fields.iter().any(|(f, _)| std::ptr::eq(*f, field)) {
+        fields.push((field, value_index(field).is_some()));
+      }
+    }
+    return fields;
+  }
+
+  /// Отсортированные id кандидатов по индексам полей (`@index` или обратный индекс связи) и составным индексам модели.
+  /// None - индекс не подходит, нужен полный обход. Кандидаты - надмножество результата,
+  /// документы все равно проверяются через matches. Как выбраны индексы, описывает `plan`
+  pub fn index_candidates(&self, rx: &Transaction, model: &Model) -> Result<Option<Vec<u64>>, MarciError> {
+    return Ok(self.plan(rx, model)?.candidates);
+  }
+
+  /// Выбор индексов для условий из AND верхнего уровня. Индексы читаются от самого избирательного по виду условия:
+  /// составной индекс с несколькими равенствами, равенство, `in`, `startsWith`, диапазон. Первый индекс отбрасывается,
+  /// если дает больше половины документов модели, следующие - если дают во много раз больше уже найденных кандидатов:
+  /// такие условия дешевле проверить фильтром на прочитанных документах. Кандидаты индексов пересекаются
+  pub fn plan(&self, rx: &Transaction, model: &Model) -> Result<QueryPlan, MarciError> {
+    let mut conditions = vec![];
+    self.required_conditions(&mut conditions);
+
+    let mut options: Vec<IndexOption> = vec![];
+    for condition in conditions.iter() {
+      let QueryField::Field(field) = condition.field else { continue };
+      if options.iter().any(|option| option.fields.len() == 1 && std::ptr::eq(option.fields[0], field)) {
+        continue;
+      }
+      let Some(tree_name) = value_index(field) else { continue };
+      let ops: Vec<&FilterOp> = conditions.iter()
+        .filter(|c| matches!(c.field, QueryField::Field(f) if std::ptr::eq(f, field)))
+        .map(|c| &c.op)
+        .collect();
+      let rank = ops.iter().map(|op| match op {
+        FilterOp::Equals(_) => 1,
+        FilterOp::In(_) => 2,
+        FilterOp::StartsWith(_) => 3,
+        _ => 4,
+      }).min().unwrap_or(4);
+      options.push(IndexOption { fields: vec![field], tree_name, rank, scan: IndexRead::Field(ops) });
+    }
+    if let Some((index, prefix, matched)) = compound_prefix(model, &conditions) {
+      let fields = index.fields[..matched].iter().map(|i| &model.fields[*i]).collect();
+      options.push(IndexOption { fields, tree_name: index.tree_name.as_bytes(), rank: 0, scan: IndexRead::Prefix(prefix) });
+    }
+    options.sort_by_key(|option| option.rank);
+
+    let documents = rx.get_tree(model.name.as_bytes())?.map_or(0, |tree| tree.len());
+    let mut candidates: Option<Vec<u64>> = None;
+    let mut probes = vec![];
+    for option in options {
+      if candidates.as_ref().is_some_and(|ids| ids.is_empty()) {
+        break;
+      }
+      let limit = match &candidates {
+        None => (documents as usize / 2).max(MIN_PROBE_ROWS),
+        Some(ids) => (ids.len() * INTERSECT_FACTOR).max(MIN_PROBE_ROWS),
+      };
+      let scan = match &option.scan {
+        IndexRead::Field(ops) => scan_index(rx, option.tree_name, option.fields[0], ops.iter().copied(), limit)?,
+        IndexRead::Prefix(prefix) => scan_prefix(rx, option.tree_name, prefix, limit)?,
+      };
+      let Some(scan) = scan else { continue };
+      let mut probe = IndexProbe {
+        fields: option.fields.iter().map(|field| field.name.clone()).collect(),
+        tree: String::from_utf8_lossy(option.tree_name).into_owned(),
+        rows: 0,
+        used: false,
+      };
+      match scan {
+        IndexScan::Ids(ids) => {
+          probe.rows = ids.len();
+          probe.used = true;
+          candidates = Some(match candidates {
+            None => ids,
+            Some(prev) => prev.into_iter().filter(|id| ids.binary_search(id).is_ok()).collect()
+          });
+        }
+        IndexScan::TooMany(rows) => probe.rows = rows,
+      }
+      probes.push(probe);
+    }
+
+    let indexed: HashSet<&str> = probes.iter().filter(|probe| probe.used).flat_map(|probe| probe.fields.iter().map(String::as_str)).collect();
+    let mut residual = vec![];
+    for condition in conditions.iter() {
+      let name = match &condition.field {
+        QueryField::Id => "id",
+        QueryField::Field(field) | QueryField::JsonKey(field, _) => field.name.as_str(),
+      };
+      if !indexed.contains(name) && !residual.iter().any(|n: &String| n == name) {
+        residual.push(name.to_string());
+      }
+    }
+    return Ok(QueryPlan { documents, candidates, probes, residual, other: self.has_other_conditions() });
+  }
+
+  /// Есть ли OR, NOT или условия по связям вне AND верхнего уровня
+  fn has_other_conditions(&self) -> bool {
+    match self {
+      MarciFilter::And(items) => items.iter().any(|item| item.has_other_conditions()),
+      MarciFilter::Field(_) => false,
+      MarciFilter::Or(_) | MarciFilter::Not(_) | MarciFilter::Relation(_) => true,
+    }
+  }
+}
+
+/// Столько ключей индекса читается всегда, даже если это больше половины документов модели
+const MIN_PROBE_ROWS: usize = 256;
+/// Следующий индекс отбрасывается, если дает больше кандидатов, чем уже найдено, умноженное на это число
+const INTERSECT_FACTOR: usize = 8;
+
+/// Индекс, который может ответить на часть условий
+struct IndexOption<'q, 'a> {
+  fields: Vec<&'a Field>,
+  tree_name: &'a [u8],
+  /// Меньше - избирательнее по виду условия
+  rank: u8,
+  scan: IndexRead<'q>,
+}
+
+enum IndexRead<'q> {
+  /// Условия одного поля в индексе `[value, id]`
+  Field(Vec<&'q FilterOp>),
+  /// Ключ составного индекса с равенствами первых полей
+  Prefix(Vec<u8>),
+}
+
+/// Результат чтения индекса с ограничением числа ключей
+enum IndexScan {
+  Ids(Vec<u64>),
+  /// Ключей больше ограничения, прочитано столько
+  TooMany(usize),
+}
+
+/// Как выбраны индексы для фильтра (`$explain` в findMany)
+#[derive(Debug)]
+pub struct QueryPlan {
+  /// Документов в модели
+  pub documents: u64,
+  /// Кандидаты из индексов, None - полный обход
+  pub candidates: Option<Vec<u64>>,
+  /// Прочитанные индексы в порядке чтения
+  pub probes: Vec<IndexProbe>,
+  /// Поля условий, которые проверяются только фильтром на прочитанных документах
+  pub residual: Vec<String>,
+  /// В фильтре есть OR, NOT или условия по связям - они тоже проверяются только фильтром
+  pub other: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexProbe {
+  /// Поля индекса, по которым заданы условия
+  pub fields: Vec<String>,
+  pub tree: String,
+  /// Прочитано ключей индекса
+  pub rows: usize,
+  /// false - индекс дал слишком много ключей и отброшен
+  pub used: bool,
+}
+
+impl QueryPlan {
+  pub fn to_json(&self) -> Value {
+    let probes: Vec<Value> = self.probes.iter().map(|probe| json!({
+      "fields": probe.fields,
+      "tree": probe.tree,
+      "rows": probe.rows,
+      "used": probe.used,
+    })).collect();
+    return json!({
+      "scan": if self.candidates.is_some() { "index" } else { "full" },
+      "documents": self.documents,
+      "candidates": self.candidates.as_ref().map(|ids| ids.len()),
+      "indexes": probes,
+      "residual": self.residual,
+      "otherConditions": self.other,
+    });
+  }
+}
+
+/// Составной индекс, у которого равенством заданы первые поля, ключ этих полей и их количество. Из подходящих
+/// индексов берется тот, где таких полей больше всего. Одно поле читается отсюда, только если у него нет своего индекса
+fn compound_prefix<'m>(model: &'m Model, conditions: &[&FieldFilter]) -> Option<(&'m CompoundIndex, Vec<u8>, usize)> {
+  let equals = |field: &Field| conditions.iter().find_map(|c| match (&c.field, &c.op) {
+    (QueryField::Field(f), FilterOp::Equals(value)) if std::ptr::eq(*f, field) => Some(value),
+    _ => None
+  });
+
+  let mut best: Option<(&CompoundIndex, Vec<u8>, usize)> = None;
+  for index in &model.indexes {
+    let mut prefix = vec![];
+    let mut matched = 0;
+    for i in &index.fields {
+      let field = &model.fields[*i];
+      let Some(part) = equals(field).and_then(|value| compound_key_part(field, value)) else { break };
+      prefix.extend(part);
+      matched += 1;
+    }
+    if matched == 0 || (matched == 1 && value_index(&model.fields[index.fields[0]]).is_some()) {
+      continue;
+    }
+    if best.as_ref().is_none_or(|(_, _, prev)| matched > *prev) {
+      best = Some((index, prefix, matched));
+    }
+  }
+  return best;
+}
+
+/// id по ключам составного индекса с префиксом `prefix`
+fn scan_prefix(rx: &Transaction, tree_name: &[u8], prefix: &[u8], limit: usize) -> Result<Option<IndexScan>, MarciError> {
+  let Some(tree) = rx.get_tree(tree_name)? else {
+    return Ok(None);
+  };
+  let mut ids = vec![];
+  for key in tree.prefix_keys(prefix)? {
+    let key = key?;
+    ids.push(key_id(&key[key.len().saturating_sub(8)..])?);
+    if ids.len() > limit {
+      return Ok(Some(IndexScan::TooMany(ids.len())));
+    }
+  }
+  ids.sort_unstable();
+  ids.dedup();
+  return Ok(Some(IndexScan::Ids(ids)));
+}
+
+/// id документов, у которых поле равно одному из `values`, прямо из индекса `[value, id]`, по возрастанию.
+/// None - у поля нет индекса или значение не подходит к его типу
+pub fn index_lookup(rx: &Transaction, field: &Field, values: &[Value]) -> Result<Option<Vec<u64>>, MarciError> {
+  let Some(tree_name) = value_index(field) else {
+    return Ok(None);
+  };
+  return match scan_index(rx, tree_name, field, [FilterOp::In(values.to_vec())].iter(), usize::MAX)? {
+    Some(IndexScan::Ids(ids)) => Ok(Some(ids)),
+    Some(IndexScan::TooMany(_)) | None => Ok(None),
+  };
+}
+
+/// Индекс с ключами `[value, id]` для поля
+pub fn value_index(field: &Field) -> Option<&[u8]> {
+  return field.inserted_indexes.iter().find_map(|index| match index {
+    InsertedIndex::Rev { tree_name } => Some(tree_name.as_bytes()),
+    _ => None
+  });
+}
+
+/// Размер значения в ключе индекса, None - переменная длина (строки и пользовательские типы без фиксированного размера).
+/// enum в индексе упорядочен по номеру варианта, а сравнивается по имени, поэтому диапазоны по нему не читаются
+fn fixed_width(field: &Field) -> Option<usize> {
+  match &field.ty {
+    FieldType::ModelRef(_) => Some(8),
+    FieldType::Primitive(PrimitiveFieldType::Enum(_)) => None,
+    FieldType::Primitive(ty) => ty.width(),
+    _ => None
+  }
+}
+
+/// Значение из запроса в байтах ключа индекса
+fn index_key(field: &Field, value: &Value) -> Option<Vec<u8>> {
+  let ty = match &field.ty {
+    FieldType::Primitive(ty) => ty,
+    FieldType::ModelRef(_) => &PrimitiveFieldType::UInt64,
+    _ => return None
+  };
+  let mut buf = vec![];
+  encode_value(&mut buf, ty, &field.name, value).ok()?;
+  return Some(index_value(&field.ty, &buf));
+}
+
+/// Значение из запроса в байтах части ключа составного индекса
+fn compound_key_part(field: &Field, value: &Value) -> Option<Vec<u8>> {
+  let ty = match &field.ty {
+    FieldType::Primitive(ty) => ty,
+    FieldType::ModelRef(_) => &PrimitiveFieldType::UInt64,
+    _ => return None
+  };
+  let mut buf = vec![];
+  encode_value(&mut buf, ty, &field.name, value).ok()?;
+  return Some(compound_part(&field.ty, &buf));
+}
+
+/// None - условия не читаются из индекса (или индекса нет в базе), нужен полный обход
+fn scan_index<'a>(rx: &Transaction, tree_name: &[u8], field: &Field, ops: impl Iterator<Item = &'a FilterOp>, limit: usize) -> Result<Option<IndexScan>, MarciError> {
+  let width = fixed_width(field);
+  let mut values: Option<Vec<Vec<u8>>> = None;
+  let mut lower: Option<Vec<u8>> = None;
+  let mut upper: Option<Vec<u8>> = None;
+  let mut prefix: Option<Vec<u8>> = None;
+
+  for op in ops {
+    match op {
+      FilterOp::Equals(value) => match index_key(field, value) {
+        Some(key) => values = Some(vec![key]),
+        None => return Ok(None),
+      },
+      FilterOp::In(list) if values.is_none() => match list.iter().map(|v| index_key(field, v)).collect::<Option<_>>() {
+        Some(keys) => values = Some(keys),
+        None => return Ok(None),
+      },
+      // Границы берем включительно: точное сравнение все равно делает matches
+      FilterOp::Gt(value) | FilterOp::Gte(value) if width.is_some() => {
+        let Some(key) = index_key(field, value) else { return Ok(None) };
+        lower = Some(lower.map_or(key.clone(), |prev| prev.max(key)));
+      }
+      FilterOp::Lt(value) | FilterOp::Lte(value) if width.is_some() => {
+        let Some(key) = index_key(field, value) else { return Ok(None) };
+        upper = Some(upper.map_or(key.clone(), |prev| prev.min(key)));
+      }
+      // Строки лежат в ключе как есть, поэтому startsWith - это префикс ключа
+      FilterOp::StartsWith(s) if matches!(field.ty, FieldType::Primitive(PrimitiveFieldType::String)) => {
+        prefix = Some(prefix.filter(|prev| prev.len() >= s.len()).unwrap_or_else(|| s.as_bytes().to_vec()));
+      }
+      _ => {}
+    }
+  }
+
+  let Some(tree) = rx.get_tree(tree_name)? else {
+    return Ok(None);
+  };
+  let mut ids = vec![];
+
+  if let Some(values) = values {
+    for value in values {
+      for key in tree.prefix_keys(&value)? {
+        let key = key?;
+        if key.len() == value.len() + 8 {
+          ids.push(key_id(&key[value.len()..])?);
+        }
+        if ids.len() > limit {
+          return Ok(Some(IndexScan::TooMany(ids.len())));
+        }
+      }
+    }
+  } else if let Some(prefix) = prefix {
+    // Ключ короче префикса значения может совпасть с ним байтами id: кандидаты все равно проверяет matches
+    for key in tree.prefix_keys(&prefix)? {
+      let key = key?;
+      ids.push(key_id(&key[key.len().saturating_sub(8)..])?);
+      if ids.len() > limit {
+        return Ok(Some(IndexScan::TooMany(ids.len())));
+      }
+    }
+  } else if lower.is_some() || upper.is_some() {
+    let width = width.unwrap();
+    let start = lower.map_or(Bound::Unbounded, |v| Bound::Included([v, vec![0u8; 8]].concat()));
+    let end = upper.map_or(Bound::Unbounded, |v| Bound::Included([v, vec![0xFFu8; 8]].concat()));
+    for key in tree.range_keys::<Vec<u8>>((start, end))? {
+      let key = key?;
+      ids.push(key_id(key.get(width..).unwrap_or_default())?);
+      if ids.len() > limit {
+        return Ok(Some(IndexScan::TooMany(ids.len())));
+      }
+    }
+  } else {
+    return Ok(None);
+  }
+
+  ids.sort_unstable();
+  ids.dedup();
+  return Ok(Some(IndexScan::Ids(ids)));
+}
+
+impl RelationFilter<'_> {
+  fn prepare(&self, rx: &Transaction) {
+    self.filter.prepare(rx);
+    self.ids.get_or_init(|| {
+      let index_tree = rx.get_tree(self.tree_name).unwrap().unwrap();
+      let tree = rx.get_tree(self.model.name.as_bytes()).unwrap().unwrap();
+
+      let mut ids = HashSet::new();
+      for key in index_tree.keys().unwrap() {
+        let key = key.unwrap();
+        let parent_id = u64::from_be_bytes(key[..8].try_into().unwrap());
+        if ids.contains(&parent_id) {
+          continue;
+        }
+        let Some(data) = tree.get(&key[8..]).unwrap().map(unpack) else { continue };
+        let child_id = u64::from_be_bytes(key[8..].try_into().unwrap());
+        let matched = self.filter.matches(child_id, data.as_ref(), self.model.payload_offset);
+        if matched != (self.mode == RelationMode::Every) {
+          ids.insert(parent_id);
+        }
+      }
+      ids
+    });
+  }
+
+  fn matches(&self, id: u64) -> bool {
+    let ids = self.ids.get().expect("Relation filter must be prepared");
+    match self.mode {
+      RelationMode::Some => ids.contains(&id),
+      RelationMode::Every | RelationMode::None => !ids.contains(&id),
+    }
+  }
+}
+
+impl QueryField<'_> {
+  pub fn value(&self, id: u64, data: &[u8], payload_offset: usize) -> Value {
+    match self {
+      QueryField::Id => Value::from(id),
+      // Битые документы не совпадают ни с одним фильтром
+      QueryField::Field(field) => decode_field(field, data, payload_offset).unwrap_or(Value::Null),
+      QueryField::JsonKey(field, key) => match decode_field(field, data, payload_offset) {
+        Ok(Value::Object(mut obj)) => obj.remove(key).unwrap_or(Value::Null),
+        _ => Value::Null,
+      }
+    }
+  }
+}
+
+impl FieldFilter<'_> {
+  pub fn matches(&self, id: u64, data: &[u8], payload_offset: usize) -> bool {
+    let value = self.field.value(id, data, payload_offset);
+    match &self.op {
+      FilterOp::Equals(expected) => values_eq(&value, expected),
+      FilterOp::Not(expected) => !values_eq(&value, expected),
+      FilterOp::In(list) => list.iter().any(|expected| values_eq(&value, expected)),
+      FilterOp::NotIn(list) => !list.iter().any(|expected| values_eq(&value, expected)),
+      FilterOp::Lt(expected) => compare_values(&value, expected) == Some(Ordering::Less),
+      FilterOp::Lte(expected) => matches!(compare_values(&value, expected), Some(Ordering::Less | Ordering::Equal)),
+      FilterOp::Gt(expected) => compare_values(&value, expected) == Some(Ordering::Greater),
+      FilterOp::Gte(expected) => matches!(compare_values(&value, expected), Some(Ordering::Greater | Ordering::Equal)),
+      FilterOp::Contains(s) => value.as_str().is_some_and(|v| v.contains(s.as_str())),
+      FilterOp::StartsWith(s) => value.as_str().is_some_and(|v| v.starts_with(s.as_str())),
+      FilterOp::EndsWith(s) => value.as_str().is_some_and(|v| v.ends_with(s.as_str())),
+      FilterOp::HasKey(key) => value.as_object().is_some_and(|obj| obj.contains_key(key)),
+    }
+  }
+}
+
+pub fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+  match (a, b) {
+    (Value::Null, Value::Null) => Some(Ordering::Equal),
+    (Value::Number(a), Value::Number(b)) => {
+      if let (Some(a), Some(b)) = (a.as_i64(), b.as_i64()) {
+        return Some(a.cmp(&b));
+      }
+      if let (Some(a), Some(b)) = (a.as_u64(), b.as_u64()) {
+        return Some(a.cmp(&b));
+      }
+      a.as_f64()?.partial_cmp(&b.as_f64()?)
+    }
+    (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+    (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+    _ => None
+  }
+}
+
+#[inline(always)]
+fn values_eq(a: &Value, b: &Value) -> bool {
+  // Объекты и массивы (значения `Json`) равны только целиком
+  return a == b || compare_values(a, b) == Some(Ordering::Equal);
+}
+
+/// Тело findMany/findFirst: либо `{ select, where, orderBy, skip, take }`, либо select целиком
+pub fn parse_find_args<'a>(fields: &'a [Field], json: &Value, schema: &'a Schema) -> Result<(MarciSelect<'a>, MarciQuery<'a>), MarciQueryError> {
+  return parse_find_args_with(fields, json, schema, |select| parse_select(fields, select, schema));
+}
+
+/// `parse_find_args` модели с выборкой из кэша разобранных выборок
+pub fn parse_model_find_args<'a>(model: &'a Model, json: &Value, schema: &'a Schema, plans: &SelectPlans) -> Result<(MarciSelect<'a>, MarciQuery<'a>), MarciQueryError> {
+  return parse_find_args_with(&model.fields, json, schema, |select| plans.parse(model, select, schema));
+}
+
+fn parse_find_args_with<'a>(
+  fields: &'a [Field],
+  json: &Value,
+  schema: &'a Schema,
+  select_of: impl Fn(&Value) -> Result<MarciSelect<'a>, MarciSelectError>,
+) -> Result<(MarciSelect<'a>, MarciQuery<'a>), MarciQueryError> {
+  if !is_query_args(fields, json) {
+    let select = select_of(json).map_err(MarciQueryError::Select)?;
+    return Ok((select, MarciQuery::all()));
+  }
+
+  let select = match json.get("select") {
+    Some(select) => select_of(select).map_err(MarciQueryError::Select)?,
+    None => MarciSelect::all(fields)
+  };
+  return Ok((select, parse_query(fields, json, schema)?));
+}
+
+/// `asOf`: время, на которое читаются документы - ISO-8601 строка или миллисекунды
+pub fn parse_as_of(json: &Value) -> Result<Option<u64>, MarciQueryError> {
+  let Some(value) = json.get("asOf") else {
+    return Ok(None);
+  };
+  if let Some(millis) = value.as_u64() {
+    return Ok(Some(millis));
+  }
+  let dt: chrono::DateTime<chrono::Utc> = value.as_str().and_then(|s| s.parse().ok())
+    .ok_or_else(|| type_mismatch("asOf", "ISO-8601 datetime string or milliseconds"))?;
+  return Ok(Some(dt.timestamp_millis().max(0) as u64));
+}
+
+pub fn is_query_args(fields: &[Field], json: &Value) -> bool {
+  let Some(obj) = json.as_object() else {
+    return false;
+  };
+  return obj.keys().any(|key| QUERY_ARGS.contains(&key.as_str()) && !fields.iter().any(|f| &f.name == key));
+}
+
+pub fn parse_query<'a>(fields: &'a [Field], json: &Value, schema: &'a Schema) -> Result<MarciQuery<'a>, MarciQueryError> {
+  let filter = match json.get("where") {
+    Some(val) => parse_where(fields, val, schema)?,
+    None => MarciFilter::empty()
+  };
+  let mut order_by = match json.get("orderBy") {
+    Some(val) => parse_order_by(fields, val)?,
+    None => vec![]
+  };
+  // При равных значениях порядок задает id, чтобы страницы skip/take не теряли и не повторяли документы
+  if !order_by.is_empty() && !order_by.iter().any(|order| matches!(order.field, QueryField::Id)) {
+    order_by.push(OrderBy { field: QueryField::Id, order: SortOrder::Asc });
+  }
+  let skip = match json.get("skip") {
+    Some(val) => val.as_u64().ok_or_else(|| type_mismatch("skip", "uint64"))? as usize,
+    None => 0
+  };
+  let take = match json.get("take") {
+    Some(val) => Some(val.as_u64().ok_or_else(|| type_mismatch("take", "uint64"))? as usize),
+    None => None
+  };
+  // `cursor`: ключи сортировки последнего документа (`nextCursor` из `$meta`), по одному на каждое поле orderBy и id
+  let cursor = match json.get("cursor") {
+    Some(val) => {
+      if order_by.is_empty() {
+        order_by.push(OrderBy { field: QueryField::Id, order: SortOrder::Asc });
+      }
+      let keys = val.as_array().filter(|keys| keys.len() == order_by.len() && keys.last().is_some_and(|id| id.as_u64().is_some()))
+        .ok_or_else(|| type_mismatch("cursor", "array of orderBy values ending with id"))?;
+      Some(keys.clone())
+    }
+    None => None
+  };
+
+  return Ok(MarciQuery { filter, order_by, skip, take, cursor });
+}
+
+pub fn parse_where<'a>(fields: &'a [Field], json: &Value, schema: &'a Schema) -> Result<MarciFilter<'a>, MarciQueryError> {
+  let obj = json.as_object().ok_or_else(|| type_mismatch("where", "object"))?;
+
+  let mut conditions = vec![];
+  for (key, val) in obj {
+    match key.as_str() {
+      "AND" => conditions.push(MarciFilter::And(parse_where_list(fields, key, val, schema)?)),
+      "OR" => {
+        if !val.is_array() {
+          return Err(type_mismatch(key, "Array"));
+        }
+        conditions.push(MarciFilter::Or(parse_where_list(fields, key, val, schema)?));
+      }
+      // NOT: [a, b] - не выполняется ни одно из условий
+      "NOT" => {
+        let items = parse_where_list(fields, key, val, schema)?;
+        conditions.extend(items.into_iter().map(|item| MarciFilter::Not(Box::new(item))));
+      }
+      _ => {
+        if let Some(field) = fields.iter().find(|f| &f.name == key && matches!(f.ty, FieldType::ModelRefList(_))) {
+          parse_relation_filter(field, val, schema, &mut conditions)?;
+          continue;
+        }
+        let field = find_field(fields, key)?;
+        parse_field_filter(field, key, val, &mut conditions)?;
+      }
+    }
+  }
+  return Ok(MarciFilter::And(conditions));
+}
+
+/// Объект или массив вложенных where
+fn parse_where_list<'a>(fields: &'a [Field], name: &str, json: &Value, schema: &'a Schema) -> Result<Vec<MarciFilter<'a>>, MarciQueryError> {
+  match json {
+    Value::Array(items) => items.iter().map(|item| parse_where(fields, item, schema)).collect(),
+    Value::Object(_) => Ok(vec![parse_where(fields, json, schema)?]),
+    _ => Err(type_mismatch(name, "object or Array"))
+  }
+}
+
+fn parse_relation_filter<'a>(field: &'a Field, json: &Value, schema: &'a Schema, conditions: &mut Vec<MarciFilter<'a>>) -> Result<(), MarciQueryError> {
+  let FieldType::ModelRefList(model_index) = field.ty else { unreachable!() };
+  let model = &schema.models[model_index];
+  let tree_name = field.select_index.as_ref().expect("Index not found").as_bytes();
+  let obj = json.as_object().ok_or_else(|| type_mismatch(&field.name, "{ some | every | none }"))?;
+
+  for (op, val) in obj {
+    let mode = match op.as_str() {
+      "some" => RelationMode::Some,
+      "every" => RelationMode::Every,
+      "none" => RelationMode::None,
+      _ => return Err(MarciQueryError::UnknownOperator(format!("{}.{}", field.name, op)))
+    };
+    let filter = parse_where(&model.fields, val, schema)?;
+    conditions.push(MarciFilter::Relation(Box::new(RelationFilter { tree_name, model, mode, filter, ids: OnceLock::new() })));
+  }
+  return Ok(());
+}
+
+fn parse_field_filter<'a>(field: QueryField<'a>, name: &str, json: &Value, conditions: &mut Vec<MarciFilter<'a>>) -> Result<(), MarciQueryError> {
+  let is_ref = matches!(field, QueryField::Field(Field { ty: FieldType::ModelRef(_), .. }));
+
+  // `Json`: `{ path: "key", equals: ... }` сравнивает значение ключа верхнего уровня, `{ hasKey: "key" }` проверяет его наличие.
+  // Значение без операторов сравнивается целиком
+  let json_field = match &field {
+    QueryField::Field(f) if matches!(f.ty, FieldType::Primitive(PrimitiveFieldType::Json)) => Some(*f),
+    _ => None,
+  };
+  if let Some(json_field) = json_field {
+    let Some(obj) = json.as_object() else {
+      conditions.push(MarciFilter::Field(FieldFilter { field, op: FilterOp::Equals(json.clone()) }));
+      return Ok(());
+    };
+    let mut ops = obj.clone();
+    if let Some(key) = ops.remove("hasKey") {
+      conditions.push(MarciFilter::Field(FieldFilter { field: field.clone(), op: FilterOp::HasKey(as_string(name, &key)?) }));
+    }
+    let field = match ops.remove("path") {
+      Some(path) => QueryField::JsonKey(json_field, as_string(name, &path)?),
+      None => field,
+    };
+    return parse_field_ops(field, name, &ops, conditions);
+  }
+
+  let Some(obj) = json.as_object().filter(|_| !(is_ref && json.get("id").is_some())) else {
+    let value = normalize_value(&field, name, json)?;
+    conditions.push(MarciFilter::Field(FieldFilter { field, op: FilterOp::Equals(value) }));
+    return Ok(());
+  };
+
+  return parse_field_ops(field, name, obj, conditions);
+}
+
+fn parse_field_ops<'a>(field: QueryField<'a>, name: &str, obj: &Map<String, Value>, conditions: &mut Vec<MarciFilter<'a>>) -> Result<(), MarciQueryError> {
+  for (op, val) in obj {
+    let op = match op.as_str() {
+      "equals" => FilterOp::Equals(normalize_value(&field, name, val)?),
+      "not" => FilterOp::Not(normalize_value(&field, name, val)?),
+      "in" => FilterOp::In(normalize_list(&field, name, val)?),
+      "notIn" => FilterOp::NotIn(normalize_list(&field, name, val)?),
+      "lt" => FilterOp::Lt(normalize_value(&field, name, val)?),
+      "lte" => FilterOp::Lte(normalize_value(&field, name, val)?),
+      "gt" => FilterOp::Gt(normalize_value(&field, name, val)?),
+      "gte" => FilterOp::Gte(normalize_value(&field, name, val)?),
+      "contains" => FilterOp::Contains(as_string(name, val)?),
+      "startsWith" => FilterOp::StartsWith(as_string(name, val)?),
+      "endsWith" => FilterOp::EndsWith(as_string(name, val)?),
+      _ => return Err(MarciQueryError::UnknownOperator(format!("{}.{}", name, op)))
+    };
+    conditions.push(MarciFilter::Field(FieldFilter { field: field.clone(), op }));
+  }
+  return Ok(());
+}
+
+pub fn parse_order_by<'a>(fields: &'a [Field], json: &Value) -> Result<Vec<OrderBy<'a>>, MarciQueryError> {
+  let items = match json {
+    Value::Array(items) => items.iter().collect(),
+    _ => vec![json]
+  };
+
+  let mut order_by = vec![];
+  for item in items {
+    let obj = item.as_object().ok_or_else(|| type_mismatch("orderBy", "object"))?;
+    for (key, val) in obj {
+      let order = match val.as_str() {
+        Some("asc") => SortOrder::Asc,
+        Some("desc") => SortOrder::Desc,
+        _ => return Err(type_mismatch(key, "\"asc\" | \"desc\""))
+      };
+      let field = find_field(fields, key)?;
+      if matches!(field, QueryField::Field(Field { ty: FieldType::Primitive(PrimitiveFieldType::Json), .. })) {
+        return Err(type_mismatch(key, "orderable field"));
+      }
+      order_by.push(OrderBy { field, order });
+    }
+  }
+  return Ok(order_by);
+}
+
+fn find_field<'a>(fields: &'a [Field], name: &str) -> Result<QueryField<'a>, MarciQueryError> {
+  if name == "id" {
+    return Ok(QueryField::Id);
+  }
+  fields.iter()
+    .find(|f| f.name == name && f.offset_pos != 0 && matches!(f.ty, FieldType::Primitive(_) | FieldType::ModelRef(_)))
+    .map(QueryField::Field)
+    .ok_or_else(|| MarciQueryError::UnknownField(name.to_string()))
+}
+
+/// Приводит значение из запроса к виду, в котором его возвращает decode_field
+fn normalize_value(field: &QueryField, name: &str, val: &Value) -> Result<Value, MarciQueryError> {
+  let QueryField::Field(field) = field else {
+    return Ok(val.clone());
+  };
+  match (&field.ty, val) {
+    (FieldType::Primitive(PrimitiveFieldType::DateTime), Value::String(s)) => {
+      let dt: chrono::DateTime<chrono::Utc> = s.parse().map_err(|_| type_mismatch(name, "valid ISO-8601 datetime string"))?;
+      Ok(Value::from(dt.timestamp_millis()))
+    }
+    (FieldType::ModelRef(_), Value::Object(obj)) => {
+      obj.get("id").filter(|id| id.is_u64()).cloned().ok_or_else(|| type_mismatch(name, "{ id: u64 }"))
+    }
+    _ => Ok(val.clone())
+  }
+}
+
+fn normalize_list(field: &QueryField, name: &str, val: &Value) -> Result<Vec<Value>, MarciQueryError> {
+  let list = val.as_array().ok_or_else(|| type_mismatch(name, "Array"))?;
+  return list.iter().map(|item| normalize_value(field, name, item)).collect();
+}
+
+fn as_string(name: &str, val: &Value) -> Result<String, MarciQueryError> {
+  return val.as_str().map(|s| s.to_string()).ok_or_else(|| type_mismatch(name, "string"));
+}
+
+#[inline(always)]
+fn type_mismatch(field: &str, expected: &'static str) -> MarciQueryError {
+  return MarciQueryError::TypeMismatch { field: field.to_string(), expected };
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::{marci_encoder::encode_document, marci_query::{index_key, parse_model_find_args, parse_query}, schema::parse_schema};
+
+  #[test]
+  fn test_filter_and_order() {
+    let schema = parse_schema("
+model User {
+  name        String
+  age         Int
+}
+").unwrap();
+    let model = &schema.models[0];
+    let docs: Vec<Vec<u8>> = [json!({ "name": "Bob", "age": 30 }), json!({ "name": "Alice", "age": 25 }), json!({ "name": "Carol" })]
+      .iter()
+      .map(|doc| encode_document(model, doc, &mut vec![]).unwrap().0)
+      .collect();
+
+    let query = parse_query(&model.fields, &json!({ "where": { "age": { "gte": 25, "lt": 30 } } }), &schema).unwrap();
+    let matched: Vec<usize> = docs.iter().enumerate()
+      .filter(|(id, data)| query.filter.matches(*id as u64, data, model.payload_offset))
+      .map(|(id, _)| id)
+      .collect();
+    assert_eq!(matched, vec![1]);
+
+    let query = parse_query(&model.fields, &json!({ "where": { "name": { "not": "Bob" }, "id": { "in": [0, 2] } } }), &schema).unwrap();
+    assert!(!query.filter.matches(0, &docs[0], model.payload_offset));
+    assert!(query.filter.matches(2, &docs[2], model.payload_offset));
+
+    let query = parse_query(&model.fields, &json!({ "where": { "OR": [{ "age": 30 }, { "name": "Carol" }], "NOT": { "name": "Bob" } } }), &schema).unwrap();
+    let matched: Vec<usize> = docs.iter().enumerate()
+      .filter(|(id, data)| query.filter.matches(*id as u64, data, model.payload_offset))
+      .map(|(id, _)| id)
+      .collect();
+    assert_eq!(matched, vec![2]);
+
+    // null сортируется первым
+    let query = parse_query(&model.fields, &json!({ "orderBy": [{ "age": "asc" }] }), &schema).unwrap();
+    let mut keys: Vec<(Vec<serde_json::Value>, usize)> = docs.iter().enumerate()
+      .map(|(id, data)| (query.sort_keys(id as u64, data, model.payload_offset), id))
+      .collect();
+    keys.sort_by(|a, b| query.compare(&a.0, &b.0));
+    assert_eq!(keys.iter().map(|(_, id)| *id).collect::<Vec<_>>(), vec![2, 1, 0]);
+
+    // Равные значения упорядочены по id
+    let query = parse_query(&model.fields, &json!({ "orderBy": { "name": "desc" } }), &schema).unwrap();
+    let mut keys: Vec<(Vec<serde_json::Value>, u64)> = [3, 1, 2].iter()
+      .map(|id| (query.sort_keys(*id, &docs[0], model.payload_offset), *id))
+      .collect();
+    keys.sort_by(|a, b| query.compare(&a.0, &b.0));
+    assert_eq!(keys.iter().map(|(_, id)| *id).collect::<Vec<_>>(), vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn test_cursor() {
+    let schema = parse_schema("
+model Event {
+  at          DateTime    @index
+}
+").unwrap();
+    let model = &schema.models[0];
+
+    let query = parse_query(&model.fields, &json!({ "orderBy": { "at": "desc" }, "cursor": [1000, 7] }), &schema).unwrap();
+    assert!(query.after_cursor(&[json!(1000), json!(8)]));
+    assert!(query.after_cursor(&[json!(999), json!(1)]));
+    assert!(!query.after_cursor(&[json!(1000), json!(7)]));
+    assert!(!query.after_cursor(&[json!(1001), json!(9)]));
+
+    // Без orderBy курсор - id последнего документа
+    let query = parse_query(&model.fields, &json!({ "cursor": [5] }), &schema).unwrap();
+    assert!(query.after_cursor(&[json!(6)]));
+    assert!(parse_query(&model.fields, &json!({ "orderBy": { "at": "asc" }, "cursor": [5] }), &schema).is_err());
+  }
+
+  #[test]
+  fn test_json_field() {
+    let schema = parse_schema("
+model Event {
+  meta        Json?
+}
+").unwrap();
+    let model = &schema.models[0];
+    let meta = json!({ "color": "red", "size": 3, "tags": ["a", { "b": null }] });
+    let data = encode_document(model, &json!({ "meta": meta }), &mut vec![]).unwrap().0;
+    assert_eq!(crate::marci_decoder::decode_field(&model.fields[0], &data, model.payload_offset).unwrap(), meta);
+
+    for (filter, expected) in [
+      (json!({ "meta": { "path": "color", "equals": "red" } }), true),
+      (json!({ "meta": { "path": "size", "gt": 5 } }), false),
+      (json!({ "meta": { "hasKey": "tags" } }), true),
+      (json!({ "meta": { "hasKey": "owner" } }), false),
+      (json!({ "meta": { "equals": meta } }), true),
+    ] {
+      let query = parse_query(&model.fields, &json!({ "where": filter }), &schema).unwrap();
+      assert_eq!(query.filter.matches(1, &data, model.payload_offset), expected, "{}", filter);
+    }
+  }
+
+  #[test]
+  fn test_enum_field() {
+    let schema = parse_schema("
+model Ticket {
+  status      Status
+}
+
+enum Status {
+  Open
+  Closed
+}
+").unwrap();
+    let model = &schema.models[0];
+    let data = encode_document(model, &json!({ "status": "Closed" }), &mut vec![]).unwrap().0;
+    assert_eq!(crate::marci_decoder::decode_field(&model.fields[0], &data, model.payload_offset).unwrap(), json!("Closed"));
+    assert!(encode_document(model, &json!({ "status": "Done" }), &mut vec![]).is_err());
+
+    let query = parse_query(&model.fields, &json!({ "where": { "status": "Closed" } }), &schema).unwrap();
+    assert!(query.filter.matches(1, &data, model.payload_offset));
+  }
+
+  #[test]
+  fn test_index_key_order() {
+    let schema = parse_schema("
+model Item {
+  score       Int       @index
+  price       Double    @index
+}
+").unwrap();
+    let model = &schema.models[0];
+    for (field, values) in [(&model.fields[0], json!([-100, -1, 0, 3, 1000])), (&model.fields[1], json!([-2.5, -0.5, 0.0, 0.25, 10.0]))] {
+      let keys: Vec<Vec<u8>> = values.as_array().unwrap().iter().map(|v| index_key(field, v).unwrap()).collect();
+      assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    }
+  }
+
+  #[test]
+  fn test_select_plans() {
+    let schema = parse_schema("
+model User {
+  name        String
+  posts       Post[]
+}
+
+model Post {
+  title       String
+  author      User
+}
+").unwrap();
+    let model = &schema.models[0];
+    let plans = crate::marci_select::SelectPlans::default();
+    let args = json!({ "select": { "name": true, "posts": { "where": { "title": "a" }, "take": 2 } }, "take": 5 });
+    let (select, query) = parse_model_find_args(model, &args, &schema, &plans).unwrap();
+    assert_eq!(query.take, Some(5));
+    assert_eq!(select.includes[0].query.as_ref().unwrap().take, Some(2));
+
+    // Тот же select с другим порядком ключей берется из кэша
+    let (again, _) = parse_model_find_args(model, &json!({ "select": { "posts": { "take": 2, "where": { "title": "a" } }, "name": true } }), &schema, &plans).unwrap();
+    assert_eq!(plans.len(), 1);
+    assert_eq!(again.select, select.select);
+    assert!(parse_model_find_args(model, &json!({ "select": { "posts": { "where": { "missing": 1 } } } }), &schema, &plans).is_err());
+  }
+}
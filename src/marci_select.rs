@@ -1,23 +1,131 @@
-use serde_json::Value;
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}};
+
+use serde_json::{Map, Value};
 use bitvec::prelude::*;
 
-use crate::{marci_db::{MarciSelect, MarciSelectBinding, MarciSelectInclude, MarciSelectVirtual}, schema::{Field, FieldType, Model, Schema}};
+use crate::{marci_db::{MarciSelect, MarciSelectBinding, MarciSelectInclude}, marci_query::{MarciQueryError, is_query_args, parse_query}, schema::{Field, FieldType, Model, Schema, WithFields}};
+
+/// Сколько разобранных выборок держит `SelectPlans`. Переполненный кэш очищается и набирается заново
+const PLAN_CACHE_SIZE: usize = 1024;
 
 #[derive(Debug)]
 pub enum MarciSelectError {
-  MissingField(String)
+  MissingField(String),
+  /// Ошибка в where/orderBy/skip/take вложенной выборки
+  Query(Box<MarciQueryError>)
 }
 
 impl MarciSelect<'_> {
   pub fn all(fields: &'_[Field]) -> MarciSelect<'_> {
-    return MarciSelect { select: bitvec![1; fields.len()+1], includes: vec![] };
+    return MarciSelect { select: bitvec![1; fields.len()+1], includes: vec![], expires_at: true };
   }
 }
 
 pub fn parse_select<'a>(fields: &'a [Field], json: &Value, schema: &'a Schema) -> Result<MarciSelect<'a>, MarciSelectError> {
+  return plan_select(fields, json, schema)?.instantiate(fields, schema);
+}
+
+/// Разобранная выборка без ссылок на схему. `instantiate` собирает из нее `MarciSelect` для запроса,
+/// не обходя JSON и поля модели; у связей со своим where/orderBy заново разбирается только запрос
+#[derive(Clone)]
+pub struct SelectPlan {
+  select: BitVec,
+  includes: Vec<IncludePlan>,
+  expires_at: bool,
+}
+
+#[derive(Clone)]
+struct IncludePlan {
+  field_index: usize,
+  select: SelectPlan,
+  /// `{ where, orderBy, skip, take }` списка связанных записей
+  query: Option<Value>,
+  /// `{ _count: true }`
+  count: bool,
+}
+
+impl SelectPlan {
+  pub fn all(fields: &[Field]) -> SelectPlan {
+    return SelectPlan { select: bitvec![1; fields.len()+1], includes: vec![], expires_at: true };
+  }
+
+  /// `MarciSelect` для полей `fields`, по которым построен план. Счетчики прочитанных записей у каждой выборки свои
+  pub fn instantiate<'a>(&self, fields: &'a [Field], schema: &'a Schema) -> Result<MarciSelect<'a>, MarciSelectError> {
+    let includes = self.includes.iter().map(|include| {
+      let field = &fields[include.field_index];
+      let model: &'a (dyn WithFields + Sync) = match &field.ty {
+        FieldType::ModelRef(model_index) | FieldType::ModelRefList(model_index) => &schema.models[*model_index],
+        FieldType::Struct(st) | FieldType::StructList(st, _) => st,
+        _ => unreachable!("Field {} is not a relation", field.name),
+      };
+      let binding = match &field.ty {
+        FieldType::ModelRef(_) => MarciSelectBinding::One(field.offset_pos),
+        FieldType::ModelRefList(_) => {
+          let tree_name = field.select_index.as_ref().expect("Index not found").as_bytes();
+          if include.count { MarciSelectBinding::Count(tree_name) } else { MarciSelectBinding::Many(tree_name) }
+        }
+        FieldType::Struct(_) => MarciSelectBinding::OneStruct(),
+        _ if include.count => MarciSelectBinding::CountStruct(),
+        _ => MarciSelectBinding::ManyStruct(),
+      };
+      let query = match &include.query {
+        Some(json) => Some(parse_query(model.fields(), json, schema).map_err(query_error)?),
+        None => None,
+      };
+      Ok(MarciSelectInclude {
+        field_index: include.field_index,
+        model,
+        select: include.select.instantiate(model.fields(), schema)?,
+        query,
+        binding,
+        fetched: AtomicU64::new(0)
+      })
+    }).collect::<Result<_, MarciSelectError>>()?;
+
+    return Ok(MarciSelect { select: self.select.clone(), includes, expires_at: self.expires_at });
+  }
+}
+
+/// Кэш разобранных выборок по модели и тексту select. Ключи объектов serde_json хранятся отсортированными,
+/// поэтому выборки, которые отличаются только порядком ключей, делят один план
+#[derive(Default)]
+pub struct SelectPlans {
+  plans: Mutex<HashMap<(String, String), Arc<SelectPlan>>>,
+}
+
+impl SelectPlans {
+  /// `parse_select` для модели: план берется из кэша или разбирается и запоминается
+  pub fn parse<'a>(&self, model: &'a Model, json: &Value, schema: &'a Schema) -> Result<MarciSelect<'a>, MarciSelectError> {
+    let key = (model.name.clone(), json.to_string());
+    let cached = self.plans.lock().unwrap().get(&key).cloned();
+    let plan = match cached {
+      Some(plan) => plan,
+      None => {
+        let plan = Arc::new(plan_select(&model.fields, json, schema)?);
+        let mut plans = self.plans.lock().unwrap();
+        if plans.len() >= PLAN_CACHE_SIZE {
+          plans.clear();
+        }
+        plans.insert(key, plan.clone());
+        plan
+      }
+    };
+    return plan.instantiate(&model.fields, schema);
+  }
+
+  pub fn len(&self) -> usize {
+    return self.plans.lock().unwrap().len();
+  }
+
+  pub fn is_empty(&self) -> bool {
+    return self.plans.lock().unwrap().is_empty();
+  }
+}
+
+pub fn plan_select(fields: &[Field], json: &Value, schema: &Schema) -> Result<SelectPlan, MarciSelectError> {
 
   if json.is_boolean() {
-    return Ok(MarciSelect::all(fields));
+    return Ok(SelectPlan::all(fields));
   }
 
   let mut changed_mask = bitvec![0; fields.len()+1];
@@ -26,6 +134,7 @@ pub fn parse_select<'a>(fields: &'a [Field], json: &Value, schema: &'a Schema) -
   if json.get("id").and_then(|i|i.as_bool()).is_some_and(|f| f) {
     changed_mask.set(0, true);
   }
+  let expires_at = json.get("$expiresAt").and_then(|i|i.as_bool()).is_some_and(|f| f);
 
   for (field_index, field) in fields.iter().enumerate() {
     let Some(val) = json.get(&field.name) else {
@@ -38,46 +147,22 @@ pub fn parse_select<'a>(fields: &'a [Field], json: &Value, schema: &'a Schema) -
     match &field.ty {
       FieldType::ModelRef(model_index) => {
         let model = &schema.models[*model_index];
-        let select = parse_select(&model.fields, &val, schema)?;
-
-        includes.push(MarciSelectInclude {
-          field_index,
-          model,
-          select,
-          binding: MarciSelectBinding::One(field.offset_pos)
-        });
+        let select = plan_select(&model.fields, val, schema)?;
+        includes.push(IncludePlan { field_index, select, query: None, count: false });
       },
       FieldType::ModelRefList(model_index) => {
         let model = &schema.models[*model_index];
-        let select = parse_select(&model.fields, &val, schema)?;
-        let tree_name = field.select_index.as_ref().expect("Index not found").as_bytes();
-        includes.push(MarciSelectInclude {
-          field_index,
-          model,
-          select,
-          binding: MarciSelectBinding::Many(tree_name)
-        });
+        includes.push(plan_include(field_index, &model.fields, val, schema)?);
       },
       FieldType::Struct(st) => {
-        let mut select = parse_select(&st.fields, &val, schema)?;
+        let mut select = plan_select(&st.fields, val, schema)?;
         if matches!(val, Value::Bool(true)) {
           select.select.set(0, false);
         }
-        includes.push(MarciSelectInclude {
-          field_index,
-          model: st,
-          select,
-          binding: MarciSelectBinding::OneStruct()
-        });
+        includes.push(IncludePlan { field_index, select, query: None, count: false });
       },
       FieldType::StructList(st, _) => {
-        let select = parse_select(&st.fields, &val, schema)?;
-        includes.push(MarciSelectInclude {
-          field_index,
-          model: st,
-          select,
-          binding: MarciSelectBinding::ManyStruct()
-        });
+        includes.push(plan_include(field_index, &st.fields, val, schema)?);
       },
       _ => {
         changed_mask.set(field_index+1, true);
@@ -146,5 +231,76 @@ pub fn parse_select<'a>(fields: &'a [Field], json: &Value, schema: &'a Schema) -
     // }
   }
 
-  return Ok(MarciSelect { select: changed_mask, includes: includes })
-}
\ No newline at end of file
+  return Ok(SelectPlan { select: changed_mask, includes, expires_at })
+}
+
+/// Сколько записей прочитала каждая связь из выборки: `{ "posts": 120, "posts.comments": 900 }`
+pub fn fetched_rows(select: &MarciSelect, fields: &[Field]) -> Value {
+  let mut rows = Map::new();
+  collect_fetched(select, fields, "", &mut rows);
+  return Value::Object(rows);
+}
+
+fn collect_fetched(select: &MarciSelect, fields: &[Field], prefix: &str, rows: &mut Map<String, Value>) {
+  for include in select.includes.iter() {
+    if matches!(include.binding, MarciSelectBinding::Count(_) | MarciSelectBinding::CountStruct()) {
+      continue;
+    }
+    let path = format!("{}{}", prefix, fields[include.field_index].name);
+    rows.insert(path.clone(), Value::from(include.fetched.load(Ordering::Relaxed)));
+    collect_fetched(&include.select, include.model.fields(), &format!("{}.", path), rows);
+  }
+}
+
+/// `{ _count: true }` вместо выборки полей связанных записей
+fn is_count(json: &Value) -> bool {
+  return json.get("_count").and_then(|v| v.as_bool()).is_some_and(|f| f);
+}
+
+/// Выборка списка связанных записей: `_count`, набор полей или `{ select, where, orderBy, skip, take }`
+fn plan_include(field_index: usize, fields: &[Field], json: &Value, schema: &Schema) -> Result<IncludePlan, MarciSelectError> {
+  if is_count(json) {
+    return Ok(IncludePlan { field_index, select: SelectPlan::all(fields), query: None, count: true });
+  }
+  if !is_query_args(fields, json) {
+    return Ok(IncludePlan { field_index, select: plan_select(fields, json, schema)?, query: None, count: false });
+  }
+  let select = match json.get("select") {
+    Some(select) => plan_select(fields, select, schema)?,
+    None => SelectPlan::all(fields)
+  };
+  // Запрос разбирается и при сборке выборки, здесь он только проверяется, чтобы ошибка where пришла сразу
+  parse_query(fields, json, schema).map_err(query_error)?;
+  return Ok(IncludePlan { field_index, select, query: Some(json.clone()), count: false });
+}
+
+fn query_error(err: MarciQueryError) -> MarciSelectError {
+  match err {
+    MarciQueryError::Select(err) => err,
+    err => MarciSelectError::Query(Box::new(err))
+  }
+}
+
+/// Выборка для ответа insert/update: `select` как в findMany либо `include` - все хранимые поля и перечисленные связи.
+/// Ключ считается выборкой, только если у модели нет поля с таким именем
+pub fn parse_returning<'a>(fields: &'a [Field], json: &Value, schema: &'a Schema) -> Result<Option<MarciSelect<'a>>, MarciSelectError> {
+  let arg = |name: &str| json.get(name).filter(|_| !fields.iter().any(|f| f.name == name));
+
+  if let Some(select) = arg("select") {
+    return Ok(Some(parse_select(fields, select, schema)?));
+  }
+  let Some(include) = arg("include") else {
+    return Ok(None);
+  };
+
+  let mut select = serde_json::Map::new();
+  select.insert("id".to_string(), Value::Bool(true));
+  select.insert("$expiresAt".to_string(), Value::Bool(true));
+  for field in fields.iter().filter(|f| matches!(f.ty, FieldType::Primitive(_) | FieldType::PrimitiveList(_))) {
+    select.insert(field.name.clone(), Value::Bool(true));
+  }
+  for (key, value) in include.as_object().into_iter().flatten() {
+    select.insert(key.clone(), value.clone());
+  }
+  return Ok(Some(parse_select(fields, &Value::Object(select), schema)?));
+}
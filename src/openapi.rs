@@ -0,0 +1,176 @@
+use serde_json::{Map, Value, json};
+
+use crate::json_schema::{BodyKind, model_document_schema, model_json_schema};
+use crate::schema::Schema;
+
+/// OpenAPI 3.1 (схемы тел - JSON Schema 2020-12, как у `/$schema/<Model>/json-schema`): действия каждой модели
+/// и схемы их тел и ответов. Служебные `$`-маршруты не описываются
+pub fn openapi_document(schema: &Schema) -> Value {
+    let mut paths = Map::new();
+    let mut schemas = Map::new();
+    for model in &schema.models {
+        let name = &model.name;
+        for (suffix, kind) in [("Insert", BodyKind::Insert), ("Update", BodyKind::Update)] {
+            let mut body = model_json_schema(schema, model, kind);
+            body.as_object_mut().unwrap().remove("$schema");
+            schemas.insert(format!("{}{}", name, suffix), body);
+        }
+        schemas.insert(name.clone(), model_document_schema(schema, model));
+
+        let document = component(name);
+        let documents = json!({ "type": "array", "items": document });
+        let tag = json!([name]);
+
+        paths.insert(format!("/{}/findMany", name), json!({
+            "get": {
+                "tags": tag,
+                "summary": format!("All {} documents", name),
+                "parameters": [{ "name": "idsOnly", "in": "query", "schema": { "type": "boolean" } }],
+                "responses": { "200": json_response("Documents", &documents) },
+            },
+            "post": {
+                "tags": tag,
+                "summary": format!("{} documents matching where, orderBy, skip, take, cursor and select/include", name),
+                "requestBody": json_body(&find_args()),
+                "responses": { "200": json_response("Documents, or { data, meta } with $meta", &documents) },
+            },
+        }));
+        paths.insert(format!("/{}/findFirst", name), json!({
+            "post": {
+                "tags": tag,
+                "summary": format!("First {} document matching the query, null if none", name),
+                "requestBody": json_body(&find_args()),
+                "responses": { "200": json_response("Document or null", &json!({ "anyOf": [document, { "type": "null" }] })) },
+            },
+        }));
+        paths.insert(format!("/{}/byIndex", name), json!({
+            "get": {
+                "tags": tag,
+                "summary": format!("{} documents whose indexed field equals one of the values", name),
+                "parameters": [
+                    { "name": "field", "in": "query", "required": true, "schema": { "type": "string" } },
+                    { "name": "value", "in": "query", "required": true, "schema": { "type": "string" }, "explode": true },
+                ],
+                "responses": { "200": json_response("Documents", &documents) },
+            },
+        }));
+        paths.insert(format!("/{}/insert", name), json!({
+            "post": {
+                "tags": tag,
+                "summary": format!("Insert a {} document", name),
+                "requestBody": json_body(&component(&format!("{}Insert", name))),
+                "responses": { "200": json_response("Id and journal sequence, or the document with select/include", &written()) },
+            },
+        }));
+        paths.insert(format!("/{}/update", name), json!({
+            "post": {
+                "tags": tag,
+                "summary": format!("Update a {} document by id", name),
+                "requestBody": json_body(&component(&format!("{}Update", name))),
+                "responses": { "200": json_response("Id and journal sequence, or the document with select/include", &written()) },
+            },
+        }));
+        paths.insert(format!("/{}/delete", name), json!({
+            "post": {
+                "tags": tag,
+                "summary": format!("Delete a {} document by id", name),
+                "requestBody": json_body(&json!({
+                    "type": "object",
+                    "properties": { "id": { "type": "integer", "minimum": 0 } },
+                    "required": ["id"],
+                })),
+                "responses": {
+                    "200": json_response("Id and journal sequence", &written()),
+                    "409": { "description": "The document is referenced with @onDelete(restrict)" },
+                },
+            },
+        }));
+    }
+
+    // Все ошибки отвечают одним телом `{ code, message, field?, details? }`
+    schemas.insert("Error".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "code": { "type": "string" },
+            "message": { "type": "string" },
+            "field": { "type": "string" },
+            "details": {},
+        },
+        "required": ["code", "message"],
+    }));
+    for operation in paths.values_mut().flat_map(|path| path.as_object_mut().into_iter().flat_map(|path| path.values_mut())) {
+        operation["responses"]["default"] = json_response("Error", &component("Error"));
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": { "title": "MarciDB", "version": env!("CARGO_PKG_VERSION") },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } },
+        },
+    })
+}
+
+fn component(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn json_body(schema: &Value) -> Value {
+    json!({ "required": true, "content": { "application/json": { "schema": schema } } })
+}
+
+fn json_response(description: &str, schema: &Value) -> Value {
+    json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+/// Аргументы findMany/findFirst. Поля where и select зависят от модели и здесь не раскрываются
+fn find_args() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "where": { "type": "object" },
+            "orderBy": { "anyOf": [{ "type": "object" }, { "type": "array", "items": { "type": "object" } }] },
+            "skip": { "type": "integer", "minimum": 0 },
+            "take": { "type": "integer", "minimum": 0 },
+            "cursor": { "type": "array" },
+            "select": { "type": "object" },
+            "include": { "type": "object" },
+        },
+    })
+}
+
+fn written() -> Value {
+    json!({
+        "type": "object",
+        "properties": { "id": { "type": "integer", "minimum": 0 }, "sequence": { "type": "integer", "minimum": 0 } },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::parse_schema;
+
+    #[test]
+    fn test_openapi_document() {
+        let schema = parse_schema("
+model User {
+  name        String
+}
+
+model Post {
+  title       String?
+  author      User
+}
+").unwrap();
+        let document = openapi_document(&schema);
+        assert_eq!(document["paths"]["/Post/insert"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/PostInsert");
+        let insert = &document["components"]["schemas"]["PostInsert"];
+        assert_eq!(insert["required"], json!(["author"]));
+        assert!(insert.get("$schema").is_none());
+        assert_eq!(document["components"]["schemas"]["User"]["required"], json!(["id"]));
+        assert_eq!(document["paths"]["/User/delete"]["post"]["responses"]["default"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/Error");
+    }
+}
@@ -0,0 +1,119 @@
+use serde_json::{Map, Value};
+
+use crate::marci_db::MarciSelect;
+use crate::schema::{AllowOperation, Model, Schema};
+
+/// Правила `@@allow` применяются к запросам с JWT: claims токена подставляются в условия на поля документа.
+/// Запросы по ключу API и сервер без аутентификации правилами не ограничиваются
+#[derive(Debug)]
+pub enum PolicyError {
+    /// У модели есть `@@allow`, но ни одно правило не разрешает операцию этому токену
+    Denied,
+}
+
+/// Условие where на документы, к которым токен имеет доступ для `op`: правила объединяются через OR.
+/// None - у модели нет `@@allow`
+pub fn allowed_where(model: &Model, op: AllowOperation, claims: &Value) -> Result<Option<Value>, PolicyError> {
+    if model.allow_rules().next().is_none() {
+        return Ok(None);
+    }
+    let conditions: Vec<Value> = model.allow_rules()
+        .filter(|rule| rule.operations.contains(&op))
+        .filter_map(|rule| {
+            let value = claims.get(&rule.claim).filter(|value| !value.is_null())?;
+            let mut condition = Map::new();
+            condition.insert(rule.field.clone(), value.clone());
+            Some(Value::Object(condition))
+        })
+        .collect();
+    if conditions.is_empty() {
+        return Err(PolicyError::Denied);
+    }
+    let mut filter = Map::new();
+    filter.insert("OR".to_string(), Value::Array(conditions));
+    Ok(Some(Value::Object(filter)))
+}
+
+/// Тело `insert`: отсутствующие поля правил заполняются значениями claims, после чего документ
+/// должен подходить хотя бы под одно правило create
+pub fn check_create(model: &Model, body: &mut Value, claims: &Value) -> Result<(), PolicyError> {
+    if model.allow_rules().next().is_none() {
+        return Ok(());
+    }
+    let Some(obj) = body.as_object_mut() else {
+        return Err(PolicyError::Denied);
+    };
+    let mut allowed = false;
+    for rule in model.allow_rules().filter(|rule| rule.operations.contains(&AllowOperation::Create)) {
+        let Some(value) = claims.get(&rule.claim).filter(|value| !value.is_null()) else { continue };
+        let field = obj.entry(rule.field.clone()).or_insert_with(|| value.clone());
+        allowed |= field == value;
+    }
+    match allowed {
+        true => Ok(()),
+        false => Err(PolicyError::Denied),
+    }
+}
+
+/// Тело `update` не может переписать поле правила update на значение, отличное от claim, то есть
+/// передать документ другому владельцу
+pub fn check_update(model: &Model, body: &Value, claims: &Value) -> Result<(), PolicyError> {
+    for rule in model.allow_rules().filter(|rule| rule.operations.contains(&AllowOperation::Update)) {
+        if let Some(value) = body.get(&rule.field) && claims.get(&rule.claim) != Some(value) {
+            return Err(PolicyError::Denied);
+        }
+    }
+    Ok(())
+}
+
+/// Связанные документы из `include` не фильтруются правилами, поэтому включать модели с `@@allow` нельзя
+pub fn check_includes(schema: &Schema, select: &MarciSelect) -> Result<(), PolicyError> {
+    for include in select.includes.iter() {
+        let restricted = schema.models.iter()
+            .find(|model| include.model.is_model() && model.name.as_bytes() == include.model.tree_name())
+            .is_some_and(|model| model.allow_rules().next().is_some());
+        if restricted {
+            return Err(PolicyError::Denied);
+        }
+        check_includes(schema, &include.select)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::schema::parse_schema;
+
+    #[test]
+    fn test_allow_rules() {
+        let schema = parse_schema("
+model Post {
+  title       String
+  ownerId     String
+  @@allow(read: ownerId == $claims.team)
+  @@allow(write: ownerId == $claims.sub)
+}
+").unwrap();
+        let post = &schema.models[0];
+        let claims = json!({ "sub": "u1", "team": "t1" });
+        assert_eq!(allowed_where(post, AllowOperation::Read, &claims).unwrap(), Some(json!({ "OR": [{ "ownerId": "t1" }] })));
+        assert!(matches!(allowed_where(post, AllowOperation::Delete, &json!({})), Err(PolicyError::Denied)));
+
+        let mut body = json!({ "title": "a" });
+        check_create(post, &mut body, &claims).unwrap();
+        assert_eq!(body["ownerId"], "u1");
+        assert!(check_create(post, &mut json!({ "ownerId": "u2" }), &claims).is_err());
+        assert!(check_update(post, &json!({ "ownerId": "u2" }), &claims).is_err());
+
+        let err = parse_schema("
+model Post {
+  title       String
+  @@allow(read: owner == $claims.sub)
+}
+").unwrap_err();
+        assert_eq!(err.message, "Unknown field owner in @@allow");
+    }
+}
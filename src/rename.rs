@@ -0,0 +1,118 @@
+use canopydb::Database;
+
+use crate::journal::{JOURNAL_TREE, JournalTx, META_TREE};
+use crate::marci_db::now_millis;
+
+#[derive(Debug)]
+pub enum RenameError {
+    InvalidName(String),
+    /// Дерево с новым именем уже содержит данные
+    TargetExists(String),
+}
+
+impl std::fmt::Display for RenameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenameError::InvalidName(name) => write!(f, "{} is not a valid model name", name),
+            RenameError::TargetExists(tree) => write!(f, "tree {} already has data", tree),
+        }
+    }
+}
+
+/// `marci-db rename-model Old New`: переносит деревья модели под новое имя одной транзакцией.
+/// Деревья модели - это `Old` и все `Old.*` (структуры, индексы, время жизни), в том числе обратные индексы,
+/// которые названы по полю этой модели. Изменения пишутся в журнал, поэтому реплики повторят переименование.
+/// Возвращает количество перенесенных деревьев
+pub fn rename_model_trees(db: &Database, old: &str, new: &str) -> Result<usize, RenameError> {
+    for name in [old, new] {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(RenameError::InvalidName(name.to_string()));
+        }
+    }
+
+    let tx = JournalTx::new(db.begin_write().unwrap());
+    let prefix = format!("{}.", old);
+    let names: Vec<Vec<u8>> = tx.list_trees().unwrap().into_iter()
+        .map(|name| name.to_vec())
+        .filter(|name| name.as_slice() != JOURNAL_TREE && name.as_slice() != META_TREE)
+        .filter(|name| name.as_slice() == old.as_bytes() || name.starts_with(prefix.as_bytes()))
+        .collect();
+
+    let mut renamed = 0;
+    for name in names.iter() {
+        let new_name = [new.as_bytes(), &name[old.len()..]].concat();
+        if tx.get_tree(&new_name).unwrap().is_some_and(|tree| tree.first().unwrap().is_some()) {
+            return Err(RenameError::TargetExists(String::from_utf8_lossy(&new_name).into_owned()));
+        }
+
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = {
+            let tree = tx.get_tree(name).unwrap().unwrap();
+            tree.iter().unwrap().map(|item| {
+                let (key, value) = item.unwrap();
+                (key.to_vec(), value.to_vec())
+            }).collect()
+        };
+
+        tx.get_or_create_tree(&new_name).unwrap();
+        let mut new_tree = tx.get_tree(&new_name).unwrap().unwrap();
+        for (key, value) in entries.iter() {
+            new_tree.insert(key, value).unwrap();
+        }
+        drop(new_tree);
+
+        let mut tree = tx.get_tree(name).unwrap().unwrap();
+        for (key, _) in entries.iter() {
+            tree.delete(key).unwrap();
+        }
+        renamed += 1;
+    }
+
+    tx.commit(now_millis()).unwrap();
+    return Ok(renamed);
+}
+
+/// Переименовывает модель в тексте схемы: объявление `model Old`, типы полей (`Old`, `Old?`, `Old[]`)
+/// и ссылки `@derived(Old.field)`
+pub fn rename_in_schema(input: &str, old: &str, new: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for line in input.split_inclusive('\n') {
+        let mut renamed = String::with_capacity(line.len());
+        let mut rest = line;
+        // Сохраняем пробелы между словами как есть, заменяем только целые слова
+        while !rest.is_empty() {
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (word, tail) = rest.split_at(word_end);
+            renamed.push_str(&rename_word(word, old, new));
+            let space_end = tail.find(|c: char| !c.is_whitespace()).unwrap_or(tail.len());
+            renamed.push_str(&tail[..space_end]);
+            rest = &tail[space_end..];
+        }
+        out.push_str(&renamed);
+    }
+    return out;
+}
+
+fn rename_word(word: &str, old: &str, new: &str) -> String {
+    let base = word.trim_end_matches('?').trim_end_matches("[]").trim_end_matches('{');
+    if base == old {
+        return format!("{}{}", new, &word[old.len()..]);
+    }
+    let derived = format!("@derived({}.", old);
+    if let Some(field) = word.strip_prefix(&derived) {
+        return format!("@derived({}.{}", new, field);
+    }
+    return word.to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rename::rename_in_schema;
+
+    #[test]
+    fn test_rename_in_schema() {
+        let schema = "model User {\n  name   String\n  posts  Post[]   @derived(Post.author)\n}\n\nmodel Post {\n  author  User?\n  editor  UserRole\n}\n";
+        let renamed = rename_in_schema(schema, "Post", "Article");
+        assert_eq!(renamed, "model User {\n  name   String\n  posts  Article[]   @derived(Article.author)\n}\n\nmodel Article {\n  author  User?\n  editor  UserRole\n}\n");
+        assert_eq!(rename_in_schema(schema, "User", "Account").matches("Account").count(), 2);
+    }
+}
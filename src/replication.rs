@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper::client::conn::http1::{self, SendRequest};
+use hyper::{Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde_json::{Value, json};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+
+use crate::error::MarciError;
+use crate::journal::{JOURNAL_TREE, Reader, applied_seq, apply_record, journal_seq, last_seq, load_snapshot, write_snapshot};
+use crate::marci_db::{MarciDB, now_millis};
+use crate::shutdown::sleep;
+
+/// Пауза между опросами журнала, когда реплика догнала основной сервер
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Как часто процесс без лидерства пытается захватить блокировку
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Сколько записей журнала реплика забирает за один запрос
+const LOG_BATCH: usize = 1000;
+/// Реплика считается отключенной, если не приходила за журналом дольше этого времени
+const REPLICA_TIMEOUT_MS: u64 = 5000;
+
+pub const REPLICA_ID_HEADER: &str = "x-replica-id";
+pub const LAST_SEQ_HEADER: &str = "x-last-sequence";
+
+#[derive(Debug)]
+pub enum LogError {
+    /// Нужных записей уже нет в журнале, реплике нужен полный снимок
+    Pruned,
+}
+
+/// Что основной сервер знает о подключенной реплике
+struct ReplicaInfo {
+    applied_seq: u64,
+    last_seen: u64,
+}
+
+/// Состояние репликации. На основном сервере отдает журнал и снимки,
+/// на реплике в фоне применяет журнал основного сервера
+pub struct Replication {
+    db: Arc<MarciDB>,
+    /// Адрес основного сервера (`host:port`), если этот процесс - реплика
+    primary: Option<String>,
+    /// Ключ API основного сервера, если он требует ключи
+    primary_key: Option<String>,
+    id: String,
+    /// Реплика следует за основным сервером, пока не станет лидером
+    following: AtomicBool,
+    /// Файл блокировки лидера. Писать может только процесс, который держит блокировку
+    leader_lock: Option<String>,
+    lock_file: Mutex<Option<File>>,
+
+    applied_seq: AtomicU64,
+    primary_seq: AtomicU64,
+    connected: AtomicBool,
+    last_contact: AtomicU64,
+    caught_up_at: AtomicU64,
+    resync_requested: AtomicBool,
+    last_error: Mutex<Option<String>>,
+
+    replicas: Mutex<HashMap<String, ReplicaInfo>>,
+}
+
+impl Replication {
+    pub fn new(db: Arc<MarciDB>, primary: Option<String>, primary_key: Option<String>, leader_lock: Option<String>) -> Replication {
+        let (applied, local) = {
+            let rx = db.db.begin_read().unwrap();
+            (applied_seq(&rx), journal_seq(&rx).unwrap())
+        };
+
+        // Если в базе есть записи, не полученные от основного сервера, реплика разошлась с ним
+        let resync = primary.is_some() && applied.unwrap_or(0) != local;
+
+        Replication {
+            db,
+            following: AtomicBool::new(primary.is_some()),
+            primary,
+            primary_key,
+            id: format!("{}-{}", std::process::id(), now_millis()),
+            leader_lock,
+            lock_file: Mutex::new(None),
+            applied_seq: AtomicU64::new(local),
+            primary_seq: AtomicU64::new(0),
+            connected: AtomicBool::new(false),
+            last_contact: AtomicU64::new(0),
+            caught_up_at: AtomicU64::new(now_millis()),
+            resync_requested: AtomicBool::new(resync),
+            last_error: Mutex::new(None),
+            replicas: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.following.load(Ordering::Relaxed)
+    }
+
+    /// Адрес основного сервера, пока реплика следует за ним
+    pub fn primary(&self) -> Option<&str> {
+        self.primary.as_deref().filter(|_| self.is_replica())
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.lock_file.lock().unwrap().is_some()
+    }
+
+    /// Принимает ли процесс запись. С блокировкой лидера пишет только ее владелец,
+    /// чтобы после переключения старый основной сервер не принимал запись параллельно с новым
+    pub fn can_write(&self) -> bool {
+        match self.leader_lock {
+            Some(_) => self.is_leader(),
+            None => !self.is_replica(),
+        }
+    }
+
+    /// Пытается захватить блокировку лидера. Реплика, получившая блокировку, перестает
+    /// следовать за основным сервером и начинает принимать запись
+    pub fn try_acquire_leadership(&self) -> bool {
+        let Some(path) = &self.leader_lock else { return false };
+        let mut lock_file = self.lock_file.lock().unwrap();
+        if lock_file.is_some() {
+            return true;
+        }
+
+        let file = File::options().create(true).truncate(false).write(true).open(path)
+            .unwrap_or_else(|err| panic!("Failed to open leader lock {}: {}", path, err));
+        if file.try_lock().is_err() {
+            return false;
+        }
+        // Счетчики id не обновляются при применении журнала. Без них новый лидер выдал бы занятые id,
+        // поэтому при ошибке блокировка отпускается и реплика продолжает следовать за основным сервером
+        if self.following.load(Ordering::Relaxed) && let Err(err) = self.db.reload_counters() {
+            eprintln!("Failed to reload id counters, staying a replica: {}", err);
+            return false;
+        }
+        *lock_file = Some(file);
+
+        if self.following.swap(false, Ordering::Relaxed) {
+            println!("Acquired leader lock {}, promoted to primary at sequence {}", path, self.applied_seq.load(Ordering::Relaxed));
+        } else {
+            println!("Acquired leader lock {}", path);
+        }
+        true
+    }
+
+    /// Записи журнала после `after`: `[seq: u64][len: u32][record]...`. Возвращает последний номер журнала
+    pub fn read_log(&self, replica_id: Option<&str>, after: u64, limit: usize) -> Result<(u64, Vec<u8>), LogError> {
+        let rx = self.db.db.begin_read().unwrap();
+        let journal = rx.get_tree(JOURNAL_TREE).unwrap().unwrap();
+        let last = last_seq(&journal).unwrap();
+
+        if let Some(replica_id) = replica_id {
+            let mut replicas = self.replicas.lock().unwrap();
+            replicas.insert(replica_id.to_string(), ReplicaInfo { applied_seq: after, last_seen: now_millis() });
+        }
+
+        if after > last {
+            return Err(LogError::Pruned);
+        }
+
+        let mut out = vec![];
+        for (i, item) in journal.range((after + 1).to_be_bytes()..).unwrap().take(limit).enumerate() {
+            let (key, record) = item.unwrap();
+            let seq = u64::from_be_bytes(key.as_ref().try_into().unwrap());
+            if i == 0 && seq != after + 1 {
+                return Err(LogError::Pruned);
+            }
+            out.extend_from_slice(&seq.to_be_bytes());
+            out.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            out.extend_from_slice(&record);
+        }
+        Ok((last, out))
+    }
+
+    /// Полный снимок базы для первичной синхронизации реплики
+    pub fn snapshot(&self) -> Result<Vec<u8>, MarciError> {
+        let rx = self.db.db.begin_read()?;
+        let mut out = vec![];
+        write_snapshot(&rx, journal_seq(&rx)?, &mut out)?;
+        Ok(out)
+    }
+
+    /// Просит реплику заново загрузить снимок. Возвращает false на основном сервере
+    pub fn request_resync(&self) -> bool {
+        if !self.is_replica() {
+            return false;
+        }
+        self.resync_requested.store(true, Ordering::Relaxed);
+        true
+    }
+
+    pub fn status(&self) -> Result<Value, MarciError> {
+        let now = now_millis();
+        let leader = self.leader_lock.as_ref().map(|_| self.is_leader());
+        let primary = self.primary.as_ref().filter(|_| self.is_replica());
+        let Some(primary) = primary else {
+            let last = self.db.last_seq()?;
+            let replicas = self.replicas.lock().unwrap();
+            let replicas: Vec<Value> = replicas.iter().map(|(id, info)| json!({
+                "id": id,
+                "appliedSequence": info.applied_seq,
+                "lag": last.saturating_sub(info.applied_seq),
+                "connected": now.saturating_sub(info.last_seen) < REPLICA_TIMEOUT_MS,
+                "lastSeenMs": now.saturating_sub(info.last_seen),
+            })).collect();
+            return Ok(json!({ "role": "primary", "leader": leader, "lastSequence": last, "replicas": replicas }));
+        };
+
+        let applied = self.applied_seq.load(Ordering::Relaxed);
+        let primary_seq = self.primary_seq.load(Ordering::Relaxed);
+        let last_contact = self.last_contact.load(Ordering::Relaxed);
+        let lag_ms = if applied >= primary_seq { 0 } else { now.saturating_sub(self.caught_up_at.load(Ordering::Relaxed)) };
+
+        Ok(json!({
+            "role": "replica",
+            "leader": leader,
+            "primary": primary,
+            "appliedSequence": applied,
+            "primarySequence": primary_seq,
+            "lag": primary_seq.saturating_sub(applied),
+            "lagMs": lag_ms,
+            "connected": self.connected.load(Ordering::Relaxed),
+            "lastContactMs": (last_contact != 0).then(|| now.saturating_sub(last_contact)),
+            "lastError": self.last_error.lock().unwrap().clone(),
+            "resyncPending": self.resync_requested.load(Ordering::Relaxed),
+        }))
+    }
+
+    /// Фоновый цикл: реплика забирает журнал основного сервера и применяет его,
+    /// процесс без лидерства периодически пытается захватить блокировку.
+    /// По сигналу `stop` выходит после текущей порции: примененный номер журнала сохраняется в той же транзакции
+    pub async fn run(self: Arc<Self>, mut stop: watch::Receiver<bool>) {
+        let mut client: Option<SendRequest<Empty<Bytes>>> = None;
+
+        while !*stop.borrow() {
+            // Проверяем блокировку между порциями журнала, чтобы не применять его после повышения
+            let leader = self.try_acquire_leadership();
+            let Some(primary) = self.primary.as_deref().filter(|_| self.is_replica()) else {
+                if leader || self.leader_lock.is_none() || !sleep(&mut stop, LOCK_RETRY_INTERVAL).await {
+                    return;
+                }
+                continue;
+            };
+
+            let result = if self.resync_requested.load(Ordering::Relaxed) {
+                self.resync(primary, &mut client).await.map(|_| 0)
+            } else {
+                self.poll(primary, &mut client).await
+            };
+
+            match result {
+                Ok(applied) => {
+                    self.connected.store(true, Ordering::Relaxed);
+                    self.last_contact.store(now_millis(), Ordering::Relaxed);
+                    if applied == 0 && !sleep(&mut stop, POLL_INTERVAL).await {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Replication error: {}", err);
+                    client = None;
+                    self.connected.store(false, Ordering::Relaxed);
+                    *self.last_error.lock().unwrap() = Some(err);
+                    if !sleep(&mut stop, RETRY_INTERVAL).await {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Применяет очередную порцию журнала. Возвращает количество примененных записей
+    async fn poll(&self, primary: &str, client: &mut Option<SendRequest<Empty<Bytes>>>) -> Result<usize, String> {
+        let after = self.applied_seq.load(Ordering::Relaxed);
+        let path = format!("/$replication/log?after={}&limit={}", after, LOG_BATCH);
+        let (status, last, body) = self.fetch(primary, &path, client).await?;
+
+        if status == StatusCode::GONE {
+            self.resync_requested.store(true, Ordering::Relaxed);
+            return Ok(0);
+        }
+        if status != StatusCode::OK {
+            return Err(format!("Primary responded with {}", status));
+        }
+        self.primary_seq.store(last, Ordering::Relaxed);
+
+        let mut reader = Reader { data: &body, pos: 0 };
+        let mut applied = 0;
+        while !reader.is_empty() {
+            let (Some(seq), Some(record)) = (reader.u64(), reader.bytes32()) else {
+                return Err("Malformed replication log".to_string());
+            };
+            let applied_record = apply_record(&self.db.db, seq, record).map_err(|err| format!("{:?}", err))?;
+            if let Some(cache) = &self.db.doc_cache {
+                cache.committed(seq, &applied_record.ops);
+            }
+            self.applied_seq.store(seq, Ordering::Relaxed);
+            applied += 1;
+        }
+
+        if self.applied_seq.load(Ordering::Relaxed) >= last {
+            self.caught_up_at.store(now_millis(), Ordering::Relaxed);
+        }
+        Ok(applied)
+    }
+
+    async fn resync(&self, primary: &str, client: &mut Option<SendRequest<Empty<Bytes>>>) -> Result<(), String> {
+        let (status, _, body) = self.fetch(primary, "/$replication/snapshot", client).await?;
+        if status != StatusCode::OK {
+            return Err(format!("Primary responded with {}", status));
+        }
+
+        let Some(seq) = load_snapshot(&self.db.db, &body, None) else {
+            return Err("Malformed replication snapshot".to_string());
+        };
+        println!("Loaded replication snapshot at sequence {}", seq);
+        if let Some(cache) = &self.db.doc_cache {
+            cache.reset(seq);
+        }
+
+        self.applied_seq.store(seq, Ordering::Relaxed);
+        self.caught_up_at.store(now_millis(), Ordering::Relaxed);
+        self.resync_requested.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// GET-запрос к основному серверу. Соединение переиспользуется между опросами
+    async fn fetch(&self, primary: &str, path: &str, client: &mut Option<SendRequest<Empty<Bytes>>>) -> Result<(StatusCode, u64, Bytes), String> {
+        if client.as_ref().is_none_or(|sender| sender.is_closed()) {
+            let stream = TcpStream::connect(primary).await.map_err(|err| err.to_string())?;
+            let (sender, conn) = http1::handshake(TokioIo::new(stream)).await.map_err(|err| err.to_string())?;
+            tokio::task::spawn(conn);
+            *client = Some(sender);
+        }
+        let sender = client.as_mut().unwrap();
+
+        let mut req = Request::get(path)
+            .header(hyper::header::HOST, primary)
+            .header(REPLICA_ID_HEADER, &self.id);
+        if let Some(key) = &self.primary_key {
+            req = req.header(hyper::header::AUTHORIZATION, format!("Bearer {}", key));
+        }
+        let req = req.body(Empty::new()).unwrap();
+
+        let res = sender.send_request(req).await.map_err(|err| err.to_string())?;
+        let status = res.status();
+        let last = res.headers().get(LAST_SEQ_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+        let body = res.into_body().collect().await.map_err(|err| err.to_string())?.to_bytes();
+
+        Ok((status, last, body))
+    }
+}
@@ -1,4 +1,10 @@
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::marci_encoder::encode_value;
 
 #[derive(Debug)]
 pub struct Schema {
@@ -46,7 +52,98 @@ pub struct Model {
     pub fields: Vec<Field>,
     pub counter_idx: usize,
     // Count of fields
-    pub payload_offset: usize
+    pub payload_offset: usize,
+    pub attributes: Vec<ModelAttribute>,
+    pub ttl: Option<ModelTtl>,
+    /// Составные индексы из `@@index([a, b])`
+    pub indexes: Vec<CompoundIndex>
+}
+
+/// Атрибуты уровня модели (`@@name(...)`)
+#[derive(Debug,Clone)]
+pub enum ModelAttribute {
+    /// Документы истекают через заданное число секунд
+    Ttl(u64),
+    /// Деревья модели читаются при запуске, чтобы прогреть кэш страниц
+    Warm,
+    /// `@@onInsert(...)`/`@@onUpdate(...)`: поле задается внутри транзакции записи
+    Trigger(Trigger),
+    /// `findMany` и `$export` возвращают не больше стольких документов модели
+    MaxRows(usize),
+    /// `@@index([a, b])`: составной индекс по нескольким полям
+    Index(Vec<String>),
+    /// `@@compress(bytes)`: документы не меньше этого размера хранятся сжатыми lz4
+    Compress(usize),
+    /// `@@id(strategy)`: как выдаются id новых документов
+    Id(IdStrategy),
+    /// `@@allow(read: userId == $claims.sub)`: доступ к документам для запросов с JWT
+    Allow(AllowRule),
+}
+
+#[derive(Debug,Clone)]
+pub struct AllowRule {
+    pub operations: Vec<AllowOperation>,
+    /// Поле документа, которое сравнивается с claim
+    pub field: String,
+    /// Имя claim токена (`sub` для `$claims.sub`)
+    pub claim: String,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum AllowOperation {
+    Read,
+    Create,
+    Update,
+    Delete,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum IdStrategy {
+    /// Последовательные id, по умолчанию
+    AutoIncrement,
+    /// id по времени: миллисекунды в старших битах, номер в младших 22 битах
+    Snowflake,
+    /// id передает клиент в теле insert
+    External,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TriggerEvent {
+    Insert,
+    Update,
+}
+
+#[derive(Debug,Clone)]
+pub struct Trigger {
+    pub event: TriggerEvent,
+    pub field: String,
+    pub action: TriggerAction,
+}
+
+#[derive(Debug,Clone)]
+pub enum TriggerAction {
+    /// `set: field = value`
+    Set(Value),
+    /// `touch: field`: текущее время в миллисекундах
+    Touch,
+}
+
+#[derive(Debug,Clone)]
+pub struct ModelTtl {
+    /// Время жизни по умолчанию в секундах
+    pub seconds: u64,
+    /// `<id>` -> `<expires_at>`
+    pub tree_name: String,
+    /// `<expires_at><id>`, по времени истечения, для очистки
+    pub queue_tree_name: String,
+}
+
+/// Составной индекс: ключи `[a][b]...[id]`, части ключа - значения полей в порядке объявления
+#[derive(Debug,Clone)]
+pub struct CompoundIndex {
+    /// Номера полей в `Model.fields`
+    pub fields: Vec<usize>,
+    pub tree_name: String,
 }
 
 #[derive(Debug,Clone)]
@@ -67,6 +164,8 @@ impl InsertedIndex {
 #[derive(Debug,Clone)]
 pub struct Field {
     pub name: String,
+    /// Имя поля для ключей ответа: общая строка вместо копии `name` в каждом документе
+    pub key: Arc<str>,
     pub ty: FieldType,
     // field offset index. In bytes offset is (3 + offset_index*3)
     pub offset_index: usize,
@@ -78,6 +177,53 @@ pub struct Field {
     pub derived_from: Option<ModelRef>
 }
 
+impl Field {
+    /// Значение из `@default(...)`
+    pub fn default_value(&self) -> Option<&Value> {
+        return self.attributes.iter().find_map(|attr| match attr {
+            Attribute::Default(value) => Some(value),
+            _ => None
+        });
+    }
+
+    /// `now()` / `uuid()` из `@default(...)`
+    pub fn default_fn(&self) -> Option<DefaultFn> {
+        return self.attributes.iter().find_map(|attr| match attr {
+            Attribute::DefaultFn(default_fn) => Some(*default_fn),
+            _ => None
+        });
+    }
+
+    /// Правило из `@onDelete(...)`. Без него необязательная связь обнуляется, а обязательная запрещает удаление,
+    /// чтобы не оставлять ссылок на удаленные документы
+    pub fn on_delete(&self) -> Option<OnDelete> {
+        let rule = self.attributes.iter().find_map(|attr| match attr {
+            Attribute::OnDelete(rule) => Some(*rule),
+            _ => None
+        });
+        if rule.is_none() && matches!(self.ty, FieldType::ModelRef(_)) {
+            return Some(if self.is_nullable { OnDelete::SetNull } else { OnDelete::Restrict });
+        }
+        return rule;
+    }
+
+    /// Имя связи из `@relation(...)`
+    pub fn relation(&self) -> Option<&str> {
+        return self.attributes.iter().find_map(|attr| match attr {
+            Attribute::Relation(name) => Some(name.as_str()),
+            _ => None
+        });
+    }
+
+    /// Структура данных связи из `@payload(...)`
+    pub fn payload(&self) -> Option<&Struct> {
+        return self.attributes.iter().find_map(|attr| match attr {
+            Attribute::Payload(st) => Some(st),
+            _ => None
+        });
+    }
+}
+
 #[derive(Debug,Clone)]
 pub struct Struct {
     /// Полное имя (для таблицы) (base_table + base_field)
@@ -91,18 +237,85 @@ pub trait WithFields {
     fn fields(&self) -> &[Field];
     fn payload_offset(&self) -> usize;
     fn is_model(&self) -> bool;
+    fn ttl(&self) -> Option<&ModelTtl>;
+    fn compound_indexes(&self) -> &[CompoundIndex];
+    /// Документы не меньше этого размера хранятся сжатыми (`@@compress`)
+    fn compress_threshold(&self) -> Option<usize>;
+}
+impl Model {
+    /// Поле с `@id`
+    pub fn key_field(&self) -> Option<&Field> {
+        return self.fields.iter().find(|field| field.attributes.iter().any(|attr| matches!(attr, Attribute::Id)));
+    }
+
+    /// Предел `@@maxRows` для выборок модели
+    pub fn max_rows(&self) -> Option<usize> {
+        return self.attributes.iter().find_map(|attr| match attr {
+            ModelAttribute::MaxRows(rows) => Some(*rows),
+            _ => None,
+        });
+    }
+
+    /// Способ выдачи id из `@@id(...)`, по умолчанию последовательные
+    pub fn id_strategy(&self) -> IdStrategy {
+        return self.attributes.iter().find_map(|attr| match attr {
+            ModelAttribute::Id(strategy) => Some(*strategy),
+            _ => None,
+        }).unwrap_or(IdStrategy::AutoIncrement);
+    }
+
+    /// Правила `@@allow` модели. Пустой список - доступ к документам не ограничен
+    pub fn allow_rules(&self) -> impl Iterator<Item = &AllowRule> {
+        return self.attributes.iter().filter_map(|attr| match attr {
+            ModelAttribute::Allow(rule) => Some(rule),
+            _ => None,
+        });
+    }
+
+    /// Поля-структуры и списки структур, включая вложенные в другие структуры
+    pub fn struct_fields(&self) -> Vec<&Field> {
+        let mut out = vec![];
+        collect_struct_fields(&self.fields, &mut out);
+        return out;
+    }
+
+    /// Поля с `@id` или `@unique`: значения не повторяются между документами
+    pub fn unique_fields(&self) -> impl Iterator<Item = &Field> {
+        return self.fields.iter().filter(|field| field.attributes.iter().any(|attr| matches!(attr, Attribute::Id | Attribute::Unique)));
+    }
+}
+
+fn collect_struct_fields<'a>(fields: &'a [Field], out: &mut Vec<&'a Field>) {
+    for field in fields {
+        if let FieldType::Struct(st) | FieldType::StructList(st, _) = &field.ty {
+            out.push(field);
+            collect_struct_fields(&st.fields, out);
+        }
+    }
 }
+
 impl WithFields for Model {
-    fn tree_name(&self) -> &[u8] { &self.name.as_bytes() }
+    fn tree_name(&self) -> &[u8] { self.name.as_bytes() }
     fn fields(&self) -> &[Field] { &self.fields }
     fn payload_offset(&self) -> usize { self.payload_offset }
     fn is_model(&self) -> bool { true }
+    fn ttl(&self) -> Option<&ModelTtl> { self.ttl.as_ref() }
+    fn compound_indexes(&self) -> &[CompoundIndex] { &self.indexes }
+    fn compress_threshold(&self) -> Option<usize> {
+        self.attributes.iter().find_map(|attr| match attr {
+            ModelAttribute::Compress(bytes) => Some(*bytes),
+            _ => None,
+        })
+    }
 }
 impl WithFields for Struct {
-    fn tree_name(&self) -> &[u8] { &self.name.as_bytes() }
+    fn tree_name(&self) -> &[u8] { self.name.as_bytes() }
     fn fields(&self) -> &[Field] { &self.fields }
     fn payload_offset(&self) -> usize { self.payload_offset }
     fn is_model(&self) -> bool { false }
+    fn ttl(&self) -> Option<&ModelTtl> { None }
+    fn compound_indexes(&self) -> &[CompoundIndex] { &[] }
+    fn compress_threshold(&self) -> Option<usize> { None }
 }
 
 #[derive(Debug,Clone,PartialEq, Eq,Hash,PartialOrd)]
@@ -134,10 +347,80 @@ pub enum PrimitiveFieldType {
     String,
     Int64,
     UInt64,
+    Int8,
+    Int16,
+    Int32,
+    UInt8,
+    UInt16,
+    UInt32,
     Float,
     Double,
     Bool,
     DateTime,
+    Custom(&'static ScalarType),
+    /// `enum` из схемы, хранится как номер варианта (u16)
+    Enum(&'static EnumType),
+    /// Произвольное JSON-значение, хранится сериализованным
+    Json,
+}
+
+impl PrimitiveFieldType {
+    /// Целые и числа с плавающей точкой
+    pub fn is_numeric(&self) -> bool {
+        matches!(self,
+            PrimitiveFieldType::Int64 | PrimitiveFieldType::Int32 | PrimitiveFieldType::Int16 | PrimitiveFieldType::Int8 |
+            PrimitiveFieldType::UInt64 | PrimitiveFieldType::UInt32 | PrimitiveFieldType::UInt16 | PrimitiveFieldType::UInt8 |
+            PrimitiveFieldType::Float | PrimitiveFieldType::Double)
+    }
+
+    /// Размер значения в байтах, None - переменная длина
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            PrimitiveFieldType::String | PrimitiveFieldType::Json => None,
+            PrimitiveFieldType::Bool | PrimitiveFieldType::Int8 | PrimitiveFieldType::UInt8 => Some(1),
+            PrimitiveFieldType::Int16 | PrimitiveFieldType::UInt16 => Some(2),
+            PrimitiveFieldType::Int32 | PrimitiveFieldType::UInt32 => Some(4),
+            PrimitiveFieldType::Float => Some(4),
+            PrimitiveFieldType::Custom(scalar) => scalar.width,
+            PrimitiveFieldType::Enum(_) => Some(2),
+            _ => Some(8)
+        }
+    }
+}
+
+/// Пользовательский скалярный тип (`Money`, `IPAddr`), на который можно сослаться в схеме.
+/// Регистрируется расширением до разбора схемы
+#[derive(Debug)]
+pub struct ScalarType {
+    pub name: String,
+    /// Размер закодированного значения, None - переменная длина
+    pub width: Option<usize>,
+    /// Проверяет значение из запроса и кодирует его
+    pub encode: fn(&Value) -> Result<Vec<u8>, String>,
+    pub decode: fn(&[u8]) -> Result<Value, String>,
+}
+
+/// `enum Name { A B }` из схемы
+#[derive(Debug)]
+pub struct EnumType {
+    pub name: String,
+    pub variants: Vec<String>,
+}
+
+impl EnumType {
+    /// Номер варианта, под которым он хранится
+    pub fn ordinal(&self, variant: &str) -> Option<u16> {
+        return self.variants.iter().position(|v| v == variant).map(|index| index as u16);
+    }
+}
+
+/// Типы, на которые схема может ссылаться помимо встроенных: скаляры расширений и `enum`-ы самой схемы
+pub struct SchemaTypes<'a> {
+    pub scalars: &'a [&'static ScalarType],
+    pub enums: Vec<&'static EnumType>,
+    /// Объявленные модели с именами полей
+    pub models: HashMap<String, Vec<String>>,
+    pub structs: HashSet<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -158,19 +441,154 @@ pub enum FieldType {
 #[derive(Debug,Clone)]
 pub enum Attribute {
     Index,
+    /// `@unique`: значения поля не повторяются, поле индексируется как `@index`
+    Unique,
+    /// `@id`: естественный ключ документа - уникальное значение с индексом, по которому документ находится вместо числового id
+    Id,
     DerivedUnresolved { model: String, field: String },
+    /// `@relation("name")`: связывает список связей с полем-ссылкой целевой модели, у которого то же имя связи
+    Relation(String),
+    /// `@default(value)`: значение поля при вставке без него и в документах, записанных до его добавления в схему
+    Default(Value),
+    /// `@default(now())` / `@default(uuid())`: значение вычисляется при вставке каждого документа
+    DefaultFn(DefaultFn),
+    /// `@onDelete(...)` на связи: что делать с документом, когда удаляется тот, на кого он ссылается
+    OnDelete(OnDelete),
+    PayloadUnresolved(String),
+    /// `@payload(Struct)` на списке связей: у каждой связи свои значения полей структуры (роль, дата добавления),
+    /// они хранятся в значении записи прямого индекса
+    Payload(Struct),
+    /// `@min(n)` / `@max(n)`: границы числового значения, включительно
+    Min(f64),
+    Max(f64),
+    /// `@maxLength(n)`: длина строки в символах
+    MaxLength(usize),
+    /// `@matches("regex")`: строка должна совпасть с регулярным выражением
+    Matches(Regex),
 }
 
-fn parse_fields(lines: &mut std::iter::Peekable<std::str::Lines<'_>>) -> (Vec<Field>, usize) {
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum DefaultFn {
+    /// Текущее время в миллисекундах
+    Now,
+    /// Случайный UUID v4 строкой
+    Uuid,
+    /// UUID v7 строкой: начинается с времени в миллисекундах, поэтому ключи упорядочены по времени создания
+    Uuid7,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum OnDelete {
+    /// Удалить ссылающийся документ вместе с целью
+    Cascade,
+    /// Запретить удаление, пока на документ есть ссылки
+    Restrict,
+    /// Записать в поле null (поле должно быть `?`)
+    SetNull,
+}
+
+/// Ошибка разбора схемы: строка и колонка (с 1) и описание
+#[derive(Debug)]
+pub struct SchemaError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Строки схемы вместе с номерами (с 0)
+type SchemaLines<'a> = std::iter::Peekable<std::iter::Enumerate<std::str::Lines<'a>>>;
+
+/// Ошибка в строке `index`; `part` - подстрока `line`, на начало которой указывает колонка
+fn error_at(index: usize, line: &str, part: &str, message: String) -> SchemaError {
+    let offset = (part.as_ptr() as usize).wrapping_sub(line.as_ptr() as usize);
+    let column = if offset <= line.len() { offset + 1 } else { 1 };
+    return SchemaError { line: index + 1, column, message };
+}
+
+/// Убирает `//`-комментарии: строки целиком и хвосты после полей. `//` внутри строковых значений (`@default("http://...")`) остается
+fn strip_comments(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for line in input.lines() {
+        let mut in_string = false;
+        let mut end = line.len();
+        let mut chars = line.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => in_string = !in_string,
+                '/' if !in_string && chars.peek().is_some_and(|(_, next)| *next == '/') => {
+                    end = i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        out.push_str(line[..end].trim_end());
+        out.push('\n');
+    }
+    return out;
+}
+
+/// Заголовок блока `model`, `struct` или `enum`
+fn is_block_start(line: &str) -> bool {
+    line.starts_with("model ") || line.starts_with("struct ") || line.starts_with("enum ")
+}
+
+fn block_name(rest: &str) -> String {
+    rest.trim_end_matches('{').trim().to_string()
+}
+
+/// Поля блока до закрывающей `}`. `start` и `block` - строка и название заголовка, на него указывает ошибка о незакрытом блоке
+fn parse_fields(lines: &mut SchemaLines<'_>, types: &SchemaTypes, start: usize, block: &str) -> Result<(Vec<Field>, usize, Vec<ModelAttribute>), SchemaError> {
     let mut offset_index: usize = 0;
-    let mut fields = Vec::new();
+    let mut fields: Vec<Field> = Vec::new();
+    let mut attributes = Vec::new();
+    // Поле триггера может быть объявлено ниже `@@onInsert`, поэтому триггеры и составные индексы проверяются после всех полей
+    let mut triggers = Vec::new();
+    let mut compound = Vec::new();
+    let mut allows = Vec::new();
 
-    for line in lines {
-        let line = line.trim();
+    loop {
+        let Some((index, raw)) = lines.next() else {
+            return Err(SchemaError { line: start + 1, column: 1, message: format!("Missing closing }} for {}", block) });
+        };
+        let line = raw.trim();
         if line == "}" { break }
         if line.is_empty() { continue; }
+        if is_block_start(line) && line.ends_with('{') {
+            return Err(SchemaError { line: start + 1, column: 1, message: format!("Missing closing }} for {} before line {}", block, index + 1) });
+        }
+
+        if let Some(attr) = line.strip_prefix("@@") {
+            let attribute = parse_block_attribute(attr).map_err(|message| error_at(index, raw, attr, message))?;
+            if let Some(attribute) = attribute {
+                match attribute {
+                    ModelAttribute::Trigger(_) => triggers.push((attributes.len(), index, raw, attr)),
+                    ModelAttribute::Index(_) => compound.push((attributes.len(), index, raw, attr)),
+                    ModelAttribute::Allow(_) => allows.push((attributes.len(), index, raw, attr)),
+                    _ => {}
+                }
+                attributes.push(attribute);
+            }
+            continue;
+        }
+
+        let mut field = parse_field_raw(index, raw, types)?;
 
-        let mut field = parse_field_raw(line);
+        // Ссылки из структур не попадают в обратные индексы, поэтому удаление документа их не проверяет
+        if block.starts_with("struct ") && let Some(pos) = raw.find("@onDelete") {
+            return Err(error_at(index, raw, &raw[pos..], format!("@onDelete is not supported on struct fields ({})", field.name)));
+        }
+
+        let is_key = |f: &Field| f.attributes.iter().any(|attr| matches!(attr, Attribute::Id));
+        if is_key(&field) && fields.iter().any(is_key) {
+            return Err(error_at(index, raw, line, format!("{} has more than one @id field", block)));
+        }
 
         let is_derived = field.attributes.iter().any(|f| matches!(f, Attribute::DerivedUnresolved { .. }));
         let is_virtual = matches!(field.ty, FieldType::RefListUnresolved(_));
@@ -182,60 +600,163 @@ fn parse_fields(lines: &mut std::iter::Peekable<std::str::Lines<'_>>) -> (Vec<Fi
         }
         fields.push(field);
     }
-    return (fields, offset_index);
+
+    for (i, index, raw, attr) in triggers {
+        let ModelAttribute::Trigger(trigger) = &attributes[i] else { continue };
+        check_trigger(trigger, &fields).map_err(|message| error_at(index, raw, attr, message))?;
+    }
+    for (i, index, raw, attr) in compound {
+        let ModelAttribute::Index(names) = &attributes[i] else { continue };
+        check_compound_index(names, &fields, types).map_err(|message| error_at(index, raw, attr, message))?;
+    }
+    for (i, index, raw, attr) in allows {
+        let ModelAttribute::Allow(rule) = &attributes[i] else { continue };
+        check_allow_rule(rule, &fields).map_err(|message| error_at(index, raw, attr, message))?;
+    }
+    return Ok((fields, offset_index, attributes));
+}
+
+/// Поле правила `@@allow` - скаляр или ссылка, хранящиеся в самом документе
+fn check_allow_rule(rule: &AllowRule, fields: &[Field]) -> Result<(), String> {
+    let Some(field) = fields.iter().find(|f| f.name == rule.field) else {
+        return Err(format!("Unknown field {} in @@allow", rule.field));
+    };
+    if field.offset_pos == 0 || !matches!(field.ty, FieldType::Primitive(_) | FieldType::ModelRef(_)) {
+        return Err(format!("@@allow field {} must be a scalar or a relation to one document", rule.field));
+    }
+    return Ok(());
+}
+
+/// Поля составного индекса хранятся в документе и имеют одно значение: скаляр или ссылка на модель.
+/// Вычисляемые поля и списки в индекс не попадают
+fn check_compound_index(names: &[String], fields: &[Field], types: &SchemaTypes) -> Result<(), String> {
+    if names.len() < 2 {
+        return Err("Compound index needs at least two fields, use @index for one".to_string());
+    }
+    for (i, name) in names.iter().enumerate() {
+        if names[..i].contains(name) {
+            return Err(format!("Field {} is repeated in index", name));
+        }
+        let field = fields.iter().find(|f| &f.name == name)
+            .ok_or_else(|| format!("Index field {} not found", name))?;
+        let supported = match &field.ty {
+            FieldType::Primitive(PrimitiveFieldType::Json) => false,
+            FieldType::Primitive(_) => true,
+            FieldType::RefUnresolved(target) => types.models.contains_key(target),
+            _ => false,
+        };
+        if !supported || field.attributes.iter().any(|attr| matches!(attr, Attribute::DerivedUnresolved { .. })) {
+            return Err(format!("Field {} can not be part of a compound index", name));
+        }
+    }
+    return Ok(());
 }
 
-pub fn parse_model_block(name: String, lines: &mut std::iter::Peekable<std::str::Lines<'_>>) -> Model {
+fn check_trigger(trigger: &Trigger, fields: &[Field]) -> Result<(), String> {
+    let field = fields.iter().find(|f| f.name == trigger.field)
+        .ok_or_else(|| format!("Trigger field {} not found", trigger.field))?;
+    let FieldType::Primitive(primitive) = &field.ty else {
+        return Err(format!("Triggers are only supported on primitive fields ({})", field.name));
+    };
+    match &trigger.action {
+        TriggerAction::Set(value) => {
+            if !value.is_null() && encode_value(&mut vec![], primitive, &field.name, value).is_err() {
+                return Err(format!("Invalid trigger value for {}: {}", field.name, value));
+            }
+        }
+        TriggerAction::Touch => {
+            if !matches!(primitive, PrimitiveFieldType::DateTime | PrimitiveFieldType::Int64 | PrimitiveFieldType::UInt64) {
+                return Err(format!("touch requires a DateTime, Int or UInt field ({})", field.name));
+            }
+        }
+    }
+    return Ok(());
+}
 
-    let (fields, offset_index) = parse_fields(lines);
+fn parse_model_block(name: String, lines: &mut SchemaLines<'_>, types: &SchemaTypes, start: usize) -> Result<Model, SchemaError> {
+
+    let (fields, offset_index, attributes) = parse_fields(lines, types, start, &format!("model {}", name))?;
+
+    let ttl = attributes.iter().find_map(|attr| match attr {
+        ModelAttribute::Ttl(seconds) => Some(ModelTtl {
+            seconds: *seconds,
+            tree_name: format!("{}.$ttl", name),
+            queue_tree_name: format!("{}.$ttl.queue", name),
+        }),
+        _ => None
+    });
+
+    let indexes = attributes.iter().filter_map(|attr| match attr {
+        ModelAttribute::Index(names) => Some(CompoundIndex {
+            fields: names.iter().map(|n| fields.iter().position(|f| &f.name == n).unwrap()).collect(),
+            tree_name: format!("{}.{}.idx", name, names.join("+")),
+        }),
+        _ => None
+    }).collect();
 
     let payload_offset = 3 + offset_index * 4;
-    return Model { name, fields, payload_offset, counter_idx: 0 };
+    return Ok(Model { name, fields, payload_offset, counter_idx: 0, attributes, ttl, indexes });
 }
 
-pub fn parse_struct_block(lines: &mut std::iter::Peekable<std::str::Lines<'_>>) -> Struct {
-    let (fields, offset_index) = parse_fields(lines);
+fn parse_struct_block(name: &str, lines: &mut SchemaLines<'_>, types: &SchemaTypes, start: usize) -> Result<Struct, SchemaError> {
+    let (fields, offset_index, _) = parse_fields(lines, types, start, &format!("struct {}", name))?;
     let payload_offset = 3 + offset_index * 4;
 
-    return Struct { name: String::new(), fields: fields, payload_offset }
+    return Ok(Struct { name: String::new(), fields, payload_offset })
+}
+
+pub fn parse_schema(input: &str) -> Result<Schema, SchemaError> {
+    return parse_schema_with(input, &[]);
 }
 
-pub fn parse_schema(input: &str) -> Schema {
+/// Разбор схемы, в которой встречаются пользовательские скалярные типы
+pub fn parse_schema_with(input: &str, scalars: &[&'static ScalarType]) -> Result<Schema, SchemaError> {
+    // Комментарии вырезаются до разбора, строки остаются на своих местах, поэтому позиции ошибок не сдвигаются
+    let input = &strip_comments(input);
     let mut models = Vec::new();
     let mut structs: HashMap<String, Struct> = HashMap::new();
-    let mut lines = input.lines().peekable();
+    // `enum`-ы и имена моделей собираются заранее: модель может сослаться на тип, объявленный ниже нее
+    let (model_fields, struct_names) = parse_declarations(input);
+    let types = SchemaTypes { scalars, enums: parse_enums(input)?, models: model_fields, structs: struct_names };
+    let mut lines = input.lines().enumerate().peekable();
 
-    while let Some(line) = lines.next() {
-        let line = line.trim();
-        if !line.starts_with("model ") && !line.starts_with("struct ") && !line.starts_with("enum ") {
+    while let Some((index, raw)) = lines.next() {
+        let line = raw.trim();
+        if !is_block_start(line) {
             continue;
         }
-        let (kind, rest) = line.trim().split_once(' ').unwrap(); 
-        let name = rest.trim_end_matches('{').trim().to_string();
+        let Some((kind, rest)) = line.split_once(' ') else { continue };
+        if !rest.ends_with('{') {
+            return Err(error_at(index, raw, line, format!("Expected {{ after {} {}", kind, rest.trim())));
+        }
+        let name = block_name(rest);
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(error_at(index, raw, rest, format!("Invalid {} name {}", kind, name)));
+        }
 
-        match kind.trim() {
+        match kind {
             "model" => {
-                models.push(parse_model_block(name, &mut lines));
+                models.push(parse_model_block(name, &mut lines, &types, index)?);
             },
             "struct" => {
-                structs.insert(name, parse_struct_block(&mut lines));
+                let st = parse_struct_block(&name, &mut lines, &types, index)?;
+                structs.insert(name, st);
             },
             "enum" => {
-
+                // Уже разобран в parse_enums, пропускаем тело
+                lines.by_ref().take_while(|(_, line)| line.trim() != "}").for_each(drop);
             }
             _ => {}
         }
     }
 
     let mut schema = Schema { models };
+    resolve_relations(&mut schema, input)?;
 
     // build name maps
     let model_by_name = build_model_map(&schema);
     let field_by_name = build_field_map(&schema);
 
-    let model_names: Vec<String> = schema.models.iter().map(|i| i.name.clone()).collect();
-
-    let mut indexes: Vec<ModelRef> = vec![];
     let mut bindings: HashSet<(ModelRef,ModelRef)> = HashSet::new();
 
     // resolve types and attributes
@@ -243,11 +764,20 @@ pub fn parse_schema(input: &str) -> Schema {
         let model_name = schema.models[field_ref.model_index].name.clone();
         let field = schema.get_field_mut(&field_ref);
 
-        resolve_field_type(&mut field.ty, &model_by_name, &structs);
+        let path = format!("{}.{}", model_name, field.name);
+        resolve_field_type(&mut field.ty, &model_by_name, &structs, &path, &mut vec![])
+            .map_err(|message| field_error(input, &model_name, &field.name, message))?;
 
-        if let FieldType::Struct(st) = &mut field.ty {
-            st.name = format!("{}.{}", model_name, field.name)
+        for attr in field.attributes.iter_mut() {
+            let Attribute::PayloadUnresolved(st_name) = attr else { continue };
+            let mut st = structs[st_name.as_str()].clone();
+            if let Some(bad) = st.fields.iter().find(|f| !matches!(f.ty, FieldType::Primitive(_) | FieldType::PrimitiveList(_))) {
+                return Err(field_error(input, &model_name, &field.name, format!("Payload struct {} can only have primitive fields ({})", st_name, bad.name)));
+            }
+            st.name = format!("{}.{}.payload", model_name, field.name);
+            *attr = Attribute::Payload(st);
         }
+
         if let FieldType::ModelRefList(_) = &field.ty {
             let index_name = format!("{}.{}", model_name, field.name);
             field.inserted_indexes.push(InsertedIndex::Direct { tree_name: index_name.clone() });
@@ -261,15 +791,16 @@ pub fn parse_schema(input: &str) -> Schema {
                 let derived_ref = ModelRef::new(m, f);
                 field.derived_from = Some(derived_ref.clone());
                 let field_ref = field_ref.clone();
-                let key: (ModelRef,ModelRef) = if derived_ref > field_ref { (field_ref,derived_ref) } else { (field_ref,derived_ref) };
+                let key: (ModelRef,ModelRef) = if derived_ref > field_ref { (field_ref,derived_ref) } else { (derived_ref,field_ref) };
                 bindings.insert(key);
             }
         }
 
-        // let is_index = field.attributes.iter().any(|i| matches!(i, Attribute::Index));
-        // if is_index {
-        //     field.index_name = Some(format!("{}.{}.idx", model.name, field.name));
-        // }
+        // `@index` на скалярном поле: ключи `[value, id]`, как у обратного индекса связи
+        let is_index = field.attributes.iter().any(|i| matches!(i, Attribute::Index | Attribute::Unique | Attribute::Id));
+        if is_index && field.offset_pos != 0 && matches!(field.ty, FieldType::Primitive(_) | FieldType::ModelRef(_)) {
+            field.inserted_indexes.push(InsertedIndex::Rev { tree_name: format!("{}.{}.idx", model_name, field.name) });
+        }
     }
 
     for (a, b) in bindings {
@@ -280,68 +811,495 @@ pub fn parse_schema(input: &str) -> Schema {
         schema.get_field_mut(&b).inserted_indexes.extend(indexes_b);
     }
 
-    for model in schema.models.iter() {
-        println!("{:#?}", model);
+    // Для `@onDelete` нужен обратный индекс `[target_id, id]`, по которому находятся ссылающиеся документы
+    for field_ref in schema.iter() {
+        let model_name = schema.models[field_ref.model_index].name.clone();
+        let field = schema.get_field_mut(&field_ref);
+        if field.on_delete().is_none() { continue }
+        if !field.inserted_indexes.iter().any(|index| matches!(index, InsertedIndex::Rev { .. })) {
+            field.inserted_indexes.push(InsertedIndex::Rev { tree_name: format!("{}.{}.ref", model_name, field.name) });
+        }
     }
 
-    schema
+    Ok(schema)
 }
 
-fn parse_field_raw(line: &str) -> Field {
+/// Пары сторон связи. Список с `@relation("name")` становится обратной стороной поля целевой модели с тем же именем связи,
+/// как с `@derived(Model.field)`. Так различаются несколько ссылок на одну модель (автор и рецензент).
+/// Два списка моделей друг на друга (`Post.tags` и `Tag.posts`) - связь многие-ко-многим: позже объявленный список
+/// становится обратной стороной первого, и оба читают и пишут одни и те же деревья индексов.
+/// Без имен связи списки объединяются, только если других списков между этими моделями нет
+fn resolve_relations(schema: &mut Schema, input: &str) -> Result<(), SchemaError> {
+    let is_list = |field: &Field| matches!(field.ty, FieldType::RefListUnresolved(_));
+    let is_derived = |field: &Field| field.attributes.iter().any(|attr| matches!(attr, Attribute::DerivedUnresolved { .. }));
+    let model_index = |name: &str| schema.models.iter().position(|m| m.name == name);
+
+    let mut derived = vec![];
+    for (model_index_a, model) in schema.models.iter().enumerate() {
+        for (field_index, field) in model.fields.iter().enumerate() {
+            let Some(relation) = field.relation() else { continue };
+            let is_self_list = |f: &Field| matches!(&f.ty, FieldType::RefListUnresolved(target) if target == &model.name);
+            let repeated = model.fields[..field_index].iter()
+                .any(|f| f.relation() == Some(relation) && is_list(f) == is_list(field) && !(is_self_list(f) && is_self_list(field)));
+            if repeated {
+                return Err(field_error(input, &model.name, &field.name, format!("Relation {} is declared twice in {}", relation, model.name)));
+            }
+            let FieldType::RefListUnresolved(target) = &field.ty else { continue };
+
+            let target_index = model_index(target).unwrap();
+            let other = schema.models[target_index].fields.iter().enumerate()
+                .find(|(_, f)| f.relation() == Some(relation) && !std::ptr::eq(*f, field));
+            match other.map(|(i, f)| (i, f, &f.ty)) {
+                Some((_, other, FieldType::RefUnresolved(other_target))) if other_target == &model.name => {
+                    derived.push((model_index_a, field_index, target.clone(), other.name.clone()));
+                }
+                Some((other_index, other, FieldType::RefListUnresolved(other_target))) if other_target == &model.name => {
+                    if (target_index, other_index) < (model_index_a, field_index) {
+                        derived.push((model_index_a, field_index, target.clone(), other.name.clone()));
+                    }
+                }
+                _ => {
+                    return Err(field_error(input, &model.name, &field.name, format!("Relation {} not found in {}", relation, target)));
+                }
+            }
+        }
+    }
+
+    // Неназванные списки: у каждой из двух разных моделей ровно один свободный список на другую
+    let free_lists = |a: usize, b: &str| schema.models[a].fields.iter().enumerate()
+        .filter(|(_, f)| matches!(&f.ty, FieldType::RefListUnresolved(target) if target == b))
+        .filter(|(_, f)| !is_derived(f) && f.relation().is_none())
+        .filter(|(_, f)| !f.attributes.iter().any(|attr| matches!(attr, Attribute::PayloadUnresolved(_))))
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+    for (a, model) in schema.models.iter().enumerate() {
+        for (field_index, field) in model.fields.iter().enumerate() {
+            let FieldType::RefListUnresolved(target) = &field.ty else { continue };
+            let Some(b) = model_index(target).filter(|b| *b < a) else { continue };
+            if free_lists(a, target) != [field_index] {
+                continue;
+            }
+            if let [other_index] = free_lists(b, &model.name)[..] {
+                derived.push((a, field_index, target.clone(), schema.models[b].fields[other_index].name.clone()));
+            }
+        }
+    }
+
+    for (model_index, field_index, model, field) in derived {
+        schema.models[model_index].fields[field_index].attributes.push(Attribute::DerivedUnresolved { model, field });
+    }
+    return Ok(());
+}
+
+/// Ошибка, найденная после разбора всех блоков: позиция ищется по имени модели и поля
+fn field_error(input: &str, model: &str, field: &str, message: String) -> SchemaError {
+    let header = format!("model {}", model);
+    let mut lines = input.lines().enumerate();
+    for (_, raw) in lines.by_ref() {
+        if block_name(raw.trim()) == header { break }
+    }
+    for (index, raw) in lines {
+        let line = raw.trim();
+        if line == "}" { break }
+        if line.split_whitespace().next() == Some(field) {
+            return error_at(index, raw, line, message);
+        }
+    }
+    return SchemaError { line: 1, column: 1, message };
+}
+
+/// Модели с именами их полей и имена структур. Собираются заранее, чтобы ссылку на тип или поле,
+/// объявленные ниже по файлу, можно было проверить в строке, где она записана
+fn parse_declarations(input: &str) -> (HashMap<String, Vec<String>>, HashSet<String>) {
+    let mut models = HashMap::new();
+    let mut structs = HashSet::new();
+    let mut lines = input.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        if !line.ends_with('{') { continue }
+        if let Some(rest) = line.strip_prefix("model ") {
+            let fields = lines.by_ref().take_while(|line| *line != "}")
+                .filter(|line| !line.starts_with("@@"))
+                .filter_map(|line| line.split_whitespace().next().map(str::to_string))
+                .collect();
+            models.insert(block_name(rest), fields);
+        } else if let Some(rest) = line.strip_prefix("struct ") {
+            structs.insert(block_name(rest));
+        }
+    }
+    return (models, structs);
+}
+
+/// Все блоки `enum Name { ... }`: варианты через пробел или перевод строки.
+/// Типы живут до конца процесса вместе со схемой, поэтому не освобождаются
+fn parse_enums(input: &str) -> Result<Vec<&'static EnumType>, SchemaError> {
+    let mut enums = vec![];
+    let mut lines = input.lines().enumerate();
+    while let Some((start, raw)) = lines.next() {
+        let Some(rest) = raw.trim().strip_prefix("enum ") else { continue };
+        let name = block_name(rest);
+        let mut variants: Vec<String> = vec![];
+        let mut closed = false;
+        for (index, raw) in lines.by_ref() {
+            let line = raw.trim();
+            if line == "}" {
+                closed = true;
+                break
+            }
+            for variant in line.split_whitespace() {
+                if variants.iter().any(|v| v == variant) {
+                    return Err(error_at(index, raw, variant, format!("Duplicate variant {} in enum {}", variant, name)));
+                }
+                variants.push(variant.to_string());
+            }
+        }
+        if !closed {
+            return Err(SchemaError { line: start + 1, column: 1, message: format!("Missing closing }} for enum {}", name) });
+        }
+        if variants.is_empty() || variants.len() > u16::MAX as usize {
+            return Err(error_at(start, raw, rest, format!("Enum {} must have between 1 and {} variants", name, u16::MAX)));
+        }
+        enums.push(&*Box::leak(Box::new(EnumType { name, variants })));
+    }
+    return Ok(enums);
+}
+
+fn parse_field_raw(index: usize, raw: &str, types: &SchemaTypes) -> Result<Field, SchemaError> {
+    let line = raw.trim();
     // имя и тип
     let mut parts = line.split_whitespace();
-    let name = parts.next().unwrap().to_string();
+    let name_str = parts.next().unwrap_or(line);
+    let name = name_str.to_string();
+    let fail = |message: String| error_at(index, raw, name_str, message);
 
-    let type_str = parts.next().unwrap();
+    let Some(type_str) = parts.next().filter(|s| !s.starts_with('@')) else {
+        return Err(fail(format!("Field {} has no type", name)));
+    };
     let is_nullable = type_str.ends_with("?");
-    let ty = parse_type(if is_nullable { &type_str[0..type_str.len()-1] } else { type_str });
+    let ty = parse_type(if is_nullable { &type_str[0..type_str.len()-1] } else { type_str }, types);
+    if let FieldType::RefUnresolved(target) | FieldType::RefListUnresolved(target) = &ty
+        && !types.models.contains_key(target) && !types.structs.contains(target) {
+        return Err(error_at(index, raw, type_str, format!("Unknown type {}", target)));
+    }
 
     // атрибуты
-    let attributes = line.split_once('@')
-        .map(|(_, attr)| parse_attribute(attr.trim()))
-        .unwrap_or_else(Vec::new);
+    let mut attributes: Vec<Attribute> = Vec::new();
+    let mut seen: Vec<&str> = Vec::new();
+    for at in split_attributes(line) {
+        let attr = &at[1..];
+        let attr_name = attr.split('(').next().unwrap_or(attr).trim();
+        if seen.contains(&attr_name) {
+            return Err(error_at(index, raw, at, format!("Duplicate attribute @{} on {}", attr_name, name)));
+        }
+        seen.push(attr_name);
+        let parsed = parse_attribute(attr.trim()).map_err(|message| error_at(index, raw, at, message))?;
+        attributes.extend(parsed);
+    }
+    let is_json = matches!(ty, FieldType::Primitive(PrimitiveFieldType::Json) | FieldType::PrimitiveList(PrimitiveFieldType::Json));
+    if is_json && attributes.iter().any(|attr| matches!(attr, Attribute::Index | Attribute::Unique | Attribute::Id)) {
+        return Err(error_at(index, raw, type_str, format!("Json fields can not be indexed ({})", name)));
+    }
+    for attr in attributes.iter() {
+        match attr {
+            Attribute::Default(_) | Attribute::DefaultFn(_) => {}
+            Attribute::Id => {
+                if !matches!(ty, FieldType::Primitive(_)) || is_nullable {
+                    return Err(fail(format!("@id requires a non-nullable primitive field ({})", name)));
+                }
+                continue;
+            }
+            Attribute::Unique => {
+                if !matches!(ty, FieldType::Primitive(_)) {
+                    return Err(fail(format!("@unique requires a primitive field ({})", name)));
+                }
+                continue;
+            }
+            Attribute::OnDelete(rule) => {
+                if !matches!(&ty, FieldType::RefUnresolved(target) if types.models.contains_key(target)) {
+                    return Err(fail(format!("@onDelete is only supported on relation fields ({})", name)));
+                }
+                if *rule == OnDelete::SetNull && !is_nullable {
+                    return Err(fail(format!("@onDelete(setNull) requires a nullable field ({})", name)));
+                }
+                continue;
+            }
+            Attribute::DerivedUnresolved { model, field } => {
+                if !types.models.get(model).is_some_and(|fields| fields.contains(field)) {
+                    return Err(fail(format!("Derived field {}.{} not found", model, field)));
+                }
+                continue;
+            }
+            Attribute::Relation(_) => {
+                if !matches!(&ty, FieldType::RefUnresolved(target) | FieldType::RefListUnresolved(target) if types.models.contains_key(target)) {
+                    return Err(fail(format!("@relation is only supported on relation fields ({})", name)));
+                }
+                if attributes.iter().any(|attr| matches!(attr, Attribute::DerivedUnresolved { .. })) {
+                    return Err(fail(format!("@relation can not be combined with @derived ({})", name)));
+                }
+                continue;
+            }
+            Attribute::Min(_) | Attribute::Max(_) => {
+                if !matches!(ty, FieldType::Primitive(primitive) | FieldType::PrimitiveList(primitive) if primitive.is_numeric()) {
+                    return Err(fail(format!("@min and @max require a numeric field ({})", name)));
+                }
+                continue;
+            }
+            Attribute::MaxLength(_) | Attribute::Matches(_) => {
+                if !matches!(ty, FieldType::Primitive(PrimitiveFieldType::String) | FieldType::PrimitiveList(PrimitiveFieldType::String)) {
+                    return Err(fail(format!("@maxLength and @matches require a String field ({})", name)));
+                }
+                continue;
+            }
+            Attribute::PayloadUnresolved(st) => {
+                let is_derived = attributes.iter().any(|attr| matches!(attr, Attribute::DerivedUnresolved { .. } | Attribute::Relation(_)));
+                if is_derived || !matches!(&ty, FieldType::RefListUnresolved(target) if types.models.contains_key(target)) {
+                    return Err(fail(format!("@payload is only supported on relation lists ({})", name)));
+                }
+                if !types.structs.contains(st) {
+                    return Err(fail(format!("Unknown payload struct {}", st)));
+                }
+                continue;
+            }
+            _ => continue,
+        }
+        let FieldType::Primitive(primitive) = &ty else {
+            return Err(fail(format!("@default is only supported on primitive fields ({})", name)));
+        };
+        match attr {
+            Attribute::Default(value) if !value.is_null() && encode_value(&mut vec![], primitive, &name, value).is_err() => {
+                return Err(fail(format!("Invalid default value for {}: {}", name, value)));
+            }
+            Attribute::DefaultFn(DefaultFn::Now) if !matches!(primitive, PrimitiveFieldType::DateTime | PrimitiveFieldType::Int64 | PrimitiveFieldType::UInt64) => {
+                return Err(fail(format!("@default(now()) requires a DateTime, Int or UInt field ({})", name)));
+            }
+            Attribute::DefaultFn(DefaultFn::Uuid | DefaultFn::Uuid7) if !matches!(primitive, PrimitiveFieldType::String) => {
+                return Err(fail(format!("Generated UUIDs require a String field ({})", name)));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Field { key: Arc::from(name.as_str()), name, ty, offset_index: 0, offset_pos: 0, attributes, is_nullable, derived_from: None, inserted_indexes: vec![], select_index: None })
+}
 
-    Field { name, ty, offset_index: 0, offset_pos: 0, attributes, is_nullable, derived_from: None, inserted_indexes: vec![], select_index: None }
+/// Атрибуты поля вместе с `@`, чтобы ошибка указывала на его позицию. `@` внутри строк (`@default("a@b")`) атрибут не начинает
+fn split_attributes(line: &str) -> Vec<&str> {
+    let mut attributes = vec![];
+    let mut start = None;
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '@' if !in_string => {
+                if let Some(start) = start {
+                    attributes.push(&line[start..i]);
+                }
+                start = Some(i);
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = start {
+        attributes.push(&line[start..]);
+    }
+    return attributes;
 }
 
-fn parse_attribute(s: &str) -> Vec<Attribute> {
+fn parse_attribute(s: &str) -> Result<Vec<Attribute>, String> {
     if s.starts_with("index") {
-        return vec![Attribute::Index];
+        return Ok(vec![Attribute::Index]);
+    }
+
+    if s == "unique" {
+        return Ok(vec![Attribute::Unique]);
+    }
+
+    if s.trim() == "id" {
+        return Ok(vec![Attribute::Id]);
+    }
+    // `@id(uuid7)`: ключ, который генерируется при вставке
+    if let Some(inside) = s.strip_prefix("id(").and_then(|x| x.trim().strip_suffix(')')) {
+        return match inside.trim() {
+            "uuid" => Ok(vec![Attribute::Id, Attribute::DefaultFn(DefaultFn::Uuid)]),
+            "uuid7" => Ok(vec![Attribute::Id, Attribute::DefaultFn(DefaultFn::Uuid7)]),
+            other => Err(format!("Unknown id strategy {}", other)),
+        };
+    }
+
+    if let Some(inside) = s.strip_prefix("default(").and_then(|x| x.strip_suffix(')')) {
+        match inside.trim() {
+            "now()" => return Ok(vec![Attribute::DefaultFn(DefaultFn::Now)]),
+            "uuid()" => return Ok(vec![Attribute::DefaultFn(DefaultFn::Uuid)]),
+            "uuid7()" => return Ok(vec![Attribute::DefaultFn(DefaultFn::Uuid7)]),
+            _ => {}
+        }
+        let value = serde_json::from_str(inside.trim()).map_err(|_| format!("Invalid default value {}", inside))?;
+        return Ok(vec![Attribute::Default(value)]);
+    }
+
+    if let Some(inside) = s.strip_prefix("onDelete(").and_then(|x| x.strip_suffix(')')) {
+        let rule = match inside.trim() {
+            "cascade" => OnDelete::Cascade,
+            "restrict" => OnDelete::Restrict,
+            "setNull" => OnDelete::SetNull,
+            other => return Err(format!("Unknown onDelete rule {}", other)),
+        };
+        return Ok(vec![Attribute::OnDelete(rule)]);
+    }
+
+    if let Some(inside) = s.strip_prefix("min(").and_then(|x| x.strip_suffix(')')) {
+        let min = inside.trim().parse().map_err(|_| format!("Invalid @min value {}", inside))?;
+        return Ok(vec![Attribute::Min(min)]);
+    }
+    if let Some(inside) = s.strip_prefix("max(").and_then(|x| x.strip_suffix(')')) {
+        let max = inside.trim().parse().map_err(|_| format!("Invalid @max value {}", inside))?;
+        return Ok(vec![Attribute::Max(max)]);
+    }
+    if let Some(inside) = s.strip_prefix("maxLength(").and_then(|x| x.strip_suffix(')')) {
+        let len = inside.trim().parse().map_err(|_| format!("Invalid @maxLength value {}", inside))?;
+        return Ok(vec![Attribute::MaxLength(len)]);
+    }
+    // Шаблон записывается JSON-строкой: `@matches("^\\w+$")`
+    if let Some(inside) = s.strip_prefix("matches(").and_then(|x| x.strip_suffix(')')) {
+        let pattern: String = serde_json::from_str(inside.trim()).map_err(|_| format!("Invalid @matches pattern {}, expected a string", inside))?;
+        let regex = Regex::new(&pattern).map_err(|err| format!("Invalid @matches pattern {}: {}", inside, err))?;
+        return Ok(vec![Attribute::Matches(regex)]);
+    }
+
+    if let Some(inside) = s.strip_prefix("payload(").and_then(|x| x.strip_suffix(')')) {
+        return Ok(vec![Attribute::PayloadUnresolved(inside.trim().to_string())]);
+    }
+
+    if let Some(inside) = s.strip_prefix("relation(").and_then(|x| x.strip_suffix(')')) {
+        let name: String = serde_json::from_str(inside.trim()).map_err(|_| format!("Invalid @relation name {}, expected a string", inside))?;
+        return Ok(vec![Attribute::Relation(name)]);
     }
 
     if let Some(inside) = s.strip_prefix("derived(").and_then(|x| x.strip_suffix(')')) {
-        let mut parts = inside.split('.');
-        let model = parts.next().unwrap().to_string();
-        let field = parts.next().unwrap().to_string();
-        return vec![Attribute::DerivedUnresolved { model, field }];
+        let (model, field) = inside.split_once('.').ok_or_else(|| format!("Invalid @derived({}), expected Model.field", inside))?;
+        return Ok(vec![Attribute::DerivedUnresolved { model: model.trim().to_string(), field: field.trim().to_string() }]);
+    }
+
+    Err(format!("Unknown attribute @{}", s))
+}
+
+/// Порог `@@compress` без аргумента: документы меньше сжимаются плохо
+const DEFAULT_COMPRESS_THRESHOLD: usize = 256;
+
+fn parse_block_attribute(s: &str) -> Result<Option<ModelAttribute>, String> {
+    if let Some(inside) = s.strip_prefix("ttl(").and_then(|x| x.strip_suffix(')')) {
+        let seconds = inside.trim().parse().map_err(|_| format!("Invalid ttl value {}", inside))?;
+        return Ok(Some(ModelAttribute::Ttl(seconds)));
+    }
+    if s.trim() == "warm" {
+        return Ok(Some(ModelAttribute::Warm));
+    }
+    if s.trim() == "compress" {
+        return Ok(Some(ModelAttribute::Compress(DEFAULT_COMPRESS_THRESHOLD)));
+    }
+    if let Some(inside) = s.strip_prefix("compress(").and_then(|x| x.strip_suffix(')')) {
+        let bytes = inside.trim().parse().map_err(|_| format!("Invalid compress threshold {}", inside))?;
+        return Ok(Some(ModelAttribute::Compress(bytes)));
+    }
+    if let Some(inside) = s.strip_prefix("maxRows(").and_then(|x| x.strip_suffix(')')) {
+        let rows = inside.trim().parse().ok().filter(|rows| *rows > 0).ok_or_else(|| format!("Invalid maxRows value {}", inside))?;
+        return Ok(Some(ModelAttribute::MaxRows(rows)));
+    }
+    if let Some(inside) = s.strip_prefix("id(").and_then(|x| x.strip_suffix(')')) {
+        let strategy = match inside.trim() {
+            "autoincrement" => IdStrategy::AutoIncrement,
+            "snowflake" => IdStrategy::Snowflake,
+            "external" => IdStrategy::External,
+            _ => return Err(format!("Unknown id strategy {}, expected autoincrement, snowflake or external", inside)),
+        };
+        return Ok(Some(ModelAttribute::Id(strategy)));
+    }
+    if let Some(inside) = s.strip_prefix("allow(").and_then(|x| x.strip_suffix(')')) {
+        return Ok(Some(ModelAttribute::Allow(parse_allow_rule(inside)?)));
+    }
+    if let Some(inside) = s.strip_prefix("index([").and_then(|x| x.strip_suffix("])")) {
+        let names = inside.split(',').map(|name| name.trim().to_string()).collect();
+        return Ok(Some(ModelAttribute::Index(names)));
+    }
+    for (prefix, event) in [("onInsert(", TriggerEvent::Insert), ("onUpdate(", TriggerEvent::Update)] {
+        if let Some(inside) = s.strip_prefix(prefix).and_then(|x| x.strip_suffix(')')) {
+            return Ok(Some(ModelAttribute::Trigger(parse_trigger(event, inside)?)));
+        }
     }
 
-    Vec::new()
+    Ok(None)
 }
 
-fn parse_type(s: &str) -> FieldType {
+/// `read, update: field == $claims.name`. `write` - create, update и delete, `all` - все операции
+fn parse_allow_rule(s: &str) -> Result<AllowRule, String> {
+    let (ops, condition) = s.split_once(':').ok_or_else(|| format!("Invalid @@allow({}), expected operations: field == $claims.name", s))?;
+    let mut operations = vec![];
+    for op in ops.split(',').map(|op| op.trim()) {
+        match op {
+            "read" => operations.push(AllowOperation::Read),
+            "create" => operations.push(AllowOperation::Create),
+            "update" => operations.push(AllowOperation::Update),
+            "delete" => operations.push(AllowOperation::Delete),
+            "write" => operations.extend([AllowOperation::Create, AllowOperation::Update, AllowOperation::Delete]),
+            "all" => operations.extend([AllowOperation::Read, AllowOperation::Create, AllowOperation::Update, AllowOperation::Delete]),
+            _ => return Err(format!("Unknown @@allow operation {}", op)),
+        }
+    }
+    let (field, claim) = condition.split_once("==").ok_or_else(|| format!("Invalid @@allow condition {}, expected field == $claims.name", condition.trim()))?;
+    let claim = claim.trim().strip_prefix("$claims.").filter(|claim| !claim.is_empty())
+        .ok_or_else(|| format!("Invalid @@allow value {}, expected $claims.name", claim.trim()))?;
+    return Ok(AllowRule { operations, field: field.trim().to_string(), claim: claim.to_string() });
+}
+
+/// `set: field = value` или `touch: field`
+fn parse_trigger(event: TriggerEvent, s: &str) -> Result<Trigger, String> {
+    let (kind, rest) = s.split_once(':').ok_or_else(|| format!("Invalid trigger {}", s))?;
+    match kind.trim() {
+        "set" => {
+            let (field, value) = rest.split_once('=').ok_or_else(|| format!("Invalid trigger {}", s))?;
+            let value = serde_json::from_str(value.trim()).map_err(|_| format!("Invalid trigger value {}", value))?;
+            Ok(Trigger { event, field: field.trim().to_string(), action: TriggerAction::Set(value) })
+        }
+        "touch" => Ok(Trigger { event, field: rest.trim().to_string(), action: TriggerAction::Touch }),
+        other => Err(format!("Unknown trigger action {}", other)),
+    }
+}
+
+fn parse_type(s: &str, types: &SchemaTypes) -> FieldType {
     if let Some(inner) = s.strip_suffix("[]") {
-        if let Some(primitive_field) = get_primitive_type(inner) {
+        if let Some(primitive_field) = get_primitive_type(inner, types) {
             FieldType::PrimitiveList(primitive_field)
         } else {
             FieldType::RefListUnresolved(inner.to_string())
         }
-    } else if let Some(primitive_field) = get_primitive_type(s) {
+    } else if let Some(primitive_field) = get_primitive_type(s, types) {
         FieldType::Primitive(primitive_field)
     } else {
         FieldType::RefUnresolved(s.to_string())
     }
 }
 
-fn get_primitive_type(s: &str) -> Option<PrimitiveFieldType> {
+fn get_primitive_type(s: &str, types: &SchemaTypes) -> Option<PrimitiveFieldType> {
+    if let Some(scalar) = types.scalars.iter().find(|scalar| scalar.name == s) {
+        return Some(PrimitiveFieldType::Custom(scalar));
+    }
+    if let Some(enum_type) = types.enums.iter().find(|enum_type| enum_type.name == s) {
+        return Some(PrimitiveFieldType::Enum(enum_type));
+    }
     match s {
         "String" => Some(PrimitiveFieldType::String),
         "Bool" => Some(PrimitiveFieldType::Bool),
         "Int" => Some(PrimitiveFieldType::Int64),
         "UInt" => Some(PrimitiveFieldType::UInt64),
+        "Int8" => Some(PrimitiveFieldType::Int8),
+        "Int16" => Some(PrimitiveFieldType::Int16),
+        "Int32" => Some(PrimitiveFieldType::Int32),
+        "UInt8" => Some(PrimitiveFieldType::UInt8),
+        "UInt16" => Some(PrimitiveFieldType::UInt16),
+        "UInt32" => Some(PrimitiveFieldType::UInt32),
         "Float" => Some(PrimitiveFieldType::Float),
         "Double" => Some(PrimitiveFieldType::Double),
         "DateTime" => Some(PrimitiveFieldType::DateTime),
+        "Json" => Some(PrimitiveFieldType::Json),
         _ => None
     }
 }
@@ -350,24 +1308,44 @@ fn get_primitive_type(s: &str) -> Option<PrimitiveFieldType> {
 //     matches!(s, "String" | "DateTime" | "Bool" | "Int" | "Float")
 // }
 
-fn resolve_field_type(ty: &mut FieldType, model_by_name: &HashMap<String, usize>, structs: &HashMap<String, Struct>) {
-    match ty {
-        FieldType::RefUnresolved(name) => {
-            if let Some(st) = structs.get(name) {
-                *ty = FieldType::Struct(st.clone());
-            } else {
-                *ty = FieldType::ModelRef(*model_by_name.get(name).expect(&format!("Not found type {}", name)));
+/// Ссылка на модель или структуру. Структура получает имя дерева `path`, ее поля разрешаются так же, с путями `path.field`.
+/// Строки вложенных структур хранятся по id документа, поэтому в элементах списка структур их быть не может.
+/// `stack` - структуры выше по вложенности, по нему находятся циклы
+fn resolve_field_type(ty: &mut FieldType, model_by_name: &HashMap<String, usize>, structs: &HashMap<String, Struct>, path: &str, stack: &mut Vec<String>) -> Result<(), String> {
+    let (name, is_list) = match ty {
+        FieldType::RefUnresolved(name) => (name.clone(), false),
+        FieldType::RefListUnresolved(name) => (name.clone(), true),
+        _ => return Ok(()),
+    };
+    let Some(st) = structs.get(&name) else {
+        // Неизвестный тип отклоняется еще в parse_field_raw
+        let model_index = model_by_name[name.as_str()];
+        *ty = if is_list { FieldType::ModelRefList(model_index) } else { FieldType::ModelRef(model_index) };
+        return Ok(());
+    };
+    if stack.contains(&name) {
+        return Err(format!("Struct {} contains itself", name));
+    }
+
+    let mut st = st.clone();
+    st.name = path.to_string();
+    stack.push(name.clone());
+    for field in st.fields.iter_mut() {
+        match &field.ty {
+            FieldType::RefUnresolved(inner) | FieldType::RefListUnresolved(inner) if is_list && structs.contains_key(inner) => {
+                return Err(format!("Struct {} is used in a list and can not contain structs ({})", name, field.name));
             }
-        }
-        FieldType::RefListUnresolved(name) => {
-            if let Some(st) = structs.get(name) {
-                *ty = FieldType::StructList(st.clone(),0);
-            } else {
-                *ty = FieldType::ModelRefList(*model_by_name.get(name).expect(&format!("Not found type {}", name)));
+            FieldType::RefListUnresolved(inner) if !structs.contains_key(inner) => {
+                return Err(format!("Relation lists are not supported in structs ({}.{})", name, field.name));
             }
+            _ => {}
         }
-        _ => {}
+        resolve_field_type(&mut field.ty, model_by_name, structs, &format!("{}.{}", path, field.name), stack)?;
     }
+    stack.pop();
+
+    *ty = if is_list { FieldType::StructList(st, 0) } else { FieldType::Struct(st) };
+    return Ok(());
 }
 
 fn build_model_map(schema: &Schema) -> HashMap<String, usize> {
@@ -396,4 +1374,234 @@ fn rev_indexes(field: &Field) -> Vec<InsertedIndex> {
             _ => None,
         })
         .collect()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::schema::{FieldType, OnDelete, parse_schema};
+
+    #[test]
+    fn test_schema_errors() {
+        let err = parse_schema("
+model Post {
+  title       String
+  author      Usr
+}
+").unwrap_err();
+        assert_eq!((err.line, err.column, err.message.as_str()), (4, 15, "Unknown type Usr"));
+
+        let err = parse_schema("
+model Post {
+  title       String
+
+model User {
+  name        String
+}
+").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 1));
+        assert!(err.message.starts_with("Missing closing }"));
+
+        let err = parse_schema("
+model Post {
+  views       Int           @default(\"many\")
+}
+").unwrap_err();
+        assert_eq!(err.line, 3);
+        assert_eq!(err.to_string(), "3:3: Invalid default value for views: \"many\"");
+
+        let err = parse_schema("
+model Post {
+  title       String
+  @@maxRows(0)
+}
+").unwrap_err();
+        assert_eq!(err.to_string(), "4:5: Invalid maxRows value 0");
+
+        let err = parse_schema("
+model Session {
+  token       String
+  @@ttl(1h)
+}
+").unwrap_err();
+        assert_eq!(err.to_string(), "4:5: Invalid ttl value 1h");
+
+        let err = parse_schema("
+model Post {
+  @@index([title, tags])
+  title       String
+  tags        String[]
+}
+").unwrap_err();
+        assert_eq!(err.to_string(), "3:5: Field tags can not be part of a compound index");
+
+        let err = parse_schema("
+model User {
+  name        String
+}
+
+struct Review {
+  author      User          @onDelete(cascade)
+}
+").unwrap_err();
+        assert_eq!(err.to_string(), "7:29: @onDelete is not supported on struct fields (author)");
+    }
+
+    #[test]
+    fn test_relation_names() {
+        let schema = parse_schema("
+model Post {
+  author      User          @relation(\"authored\")
+  reviewer    User?         @relation(\"reviewed\")
+}
+
+model User {
+  authored    Post[]        @relation(\"authored\")
+  reviewed    Post[]        @relation(\"reviewed\")
+}
+").unwrap();
+        let user = &schema.models[1];
+        assert_eq!(user.fields[0].derived_from.as_ref().map(|r| r.field_index), Some(0));
+        assert_eq!(user.fields[1].derived_from.as_ref().map(|r| r.field_index), Some(1));
+
+        let err = parse_schema("
+model Post {
+  author      User
+}
+
+model User {
+  posts       Post[]        @relation(\"authored\")
+}
+").unwrap_err();
+        assert_eq!(err.to_string(), "7:3: Relation authored not found in Post");
+    }
+
+    #[test]
+    fn test_optional_ref_set_null() {
+        let schema = parse_schema("
+model Post {
+  author      User
+  reviewer    User?
+}
+
+model User {
+  name        String
+}
+").unwrap();
+        let fields = &schema.models[0].fields;
+        assert_eq!(fields[0].on_delete(), Some(OnDelete::Restrict));
+        assert_eq!(fields[1].on_delete(), Some(OnDelete::SetNull));
+        assert_eq!(fields[1].inserted_indexes[0].tree_name(), b"Post.reviewer.ref");
+    }
+
+    #[test]
+    fn test_many_to_many() {
+        let schema = parse_schema("
+model Post {
+  tags        Tag[]
+}
+
+model Tag {
+  posts       Post[]
+}
+").unwrap();
+        let (post, tag) = (&schema.models[0], &schema.models[1]);
+        assert!(post.fields[0].derived_from.is_none());
+        assert_eq!(tag.fields[0].derived_from.as_ref().map(|r| (r.model_index, r.field_index)), Some((0, 0)));
+        // Обе стороны пишут в оба дерева
+        assert_eq!(post.fields[0].inserted_indexes.len(), 2);
+        assert_eq!(tag.fields[0].inserted_indexes.len(), 2);
+    }
+
+    #[test]
+    fn test_nested_structs() {
+        let schema = parse_schema("
+model User {
+  info        UserInfo
+}
+
+struct UserInfo {
+  address     Address
+  phones      Phone[]
+}
+
+struct Address {
+  city        String
+}
+
+struct Phone {
+  number      String
+}
+").unwrap();
+        let FieldType::Struct(info) = &schema.models[0].fields[0].ty else { panic!("info is not a struct") };
+        assert!(matches!(&info.fields[0].ty, FieldType::Struct(st) if st.name == "User.info.address"));
+        assert!(matches!(&info.fields[1].ty, FieldType::StructList(st, _) if st.name == "User.info.phones"));
+        assert_eq!(schema.models[0].struct_fields().len(), 3);
+
+        let err = parse_schema("
+model User {
+  phones      Phone[]
+}
+
+struct Phone {
+  address     Address
+}
+
+struct Address {
+  city        String
+}
+").unwrap_err();
+        assert_eq!(err.to_string(), "3:3: Struct Phone is used in a list and can not contain structs (address)");
+    }
+
+    #[test]
+    fn test_compound_index() {
+        let schema = parse_schema("
+model Post {
+  author      User
+  title       String
+  @@index([author, title])
+}
+
+model User {
+  name        String
+}
+").unwrap();
+        let index = &schema.models[0].indexes[0];
+        assert_eq!(index.fields, vec![0, 1]);
+        assert_eq!(index.tree_name, "Post.author+title.idx");
+    }
+
+    #[test]
+    fn test_comments() {
+        let schema = parse_schema("
+// Публикации
+model Post {
+  // заголовок
+  title       String        // trailing comment
+  url         String        @default(\"http://example.com\") // ссылка
+}
+").unwrap();
+        let fields = &schema.models[0].fields;
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[1].default_value(), Some(&serde_json::json!("http://example.com")));
+    }
+
+    #[test]
+    fn test_multiple_attributes() {
+        let schema = parse_schema("
+model User {
+  email       String        @unique @index @default(\"a@b\")
+}
+").unwrap();
+        let field = &schema.models[0].fields[0];
+        assert_eq!(field.attributes.len(), 3);
+        assert_eq!(field.default_value(), Some(&serde_json::json!("a@b")));
+        assert_eq!(schema.models[0].unique_fields().count(), 1);
+
+        let err = parse_schema("
+model User {
+  email       String        @index @uniq
+}
+").unwrap_err();
+        assert_eq!((err.line, err.column, err.message.as_str()), (3, 36, "Unknown attribute @uniq"));
+    }
+}
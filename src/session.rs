@@ -0,0 +1,111 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::error::MarciError;
+use crate::journal::JournalTx;
+use crate::marci_db::{MarciDB, now_millis};
+
+/// Заголовок с токеном интерактивной транзакции
+pub const TRANSACTION_HEADER: &str = "x-transaction";
+
+/// Интерактивные транзакции: клиент открывает транзакцию записи, читает и пишет в ней несколькими запросами
+/// и фиксирует или откатывает ее по токену. Транзакция записи в базе одна, поэтому открытая сессия тоже
+/// одна: остальные записи ждут ее в `writer`, а брошенная сессия откатывается через `timeout` без запросов
+pub struct Sessions {
+    writer: Arc<Semaphore>,
+    current: Mutex<Option<Session>>,
+    timeout: Duration,
+}
+
+struct Session {
+    token: String,
+    tx: JournalTx,
+    expires: Instant,
+    // Освобождается после отката или коммита `tx`
+    _writer: OwnedSemaphorePermit,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// Другая транзакция не завершилась за `timeout`
+    Busy,
+    /// Токен неизвестен, транзакция уже завершена или откатилась по таймауту
+    NotFound,
+    /// Транзакция не открылась или не зафиксировалась
+    Storage(MarciError),
+}
+
+impl Sessions {
+    pub fn new(timeout: Duration) -> Sessions {
+        Sessions { writer: Arc::new(Semaphore::new(1)), current: Mutex::new(None), timeout }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Право на запись вне сессии. Пока открыта сессия, ждет ее завершения
+    pub async fn writer(&self) -> OwnedSemaphorePermit {
+        self.writer.clone().acquire_owned().await.unwrap()
+    }
+
+    /// Открывает транзакцию и возвращает ее токен
+    pub async fn begin(&self, db: &MarciDB) -> Result<String, SessionError> {
+        let Ok(permit) = tokio::time::timeout(self.timeout, self.writer()).await else {
+            return Err(SessionError::Busy);
+        };
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let session = Session { token: token.clone(), tx: db.begin_write().map_err(SessionError::Storage)?, expires: Instant::now() + self.timeout, _writer: permit };
+        *self.current.lock().unwrap() = Some(session);
+        Ok(token)
+    }
+
+    /// Выполняет `f` в транзакции сессии и продлевает ее таймаут
+    pub fn with<T>(&self, token: &str, f: impl FnOnce(&JournalTx) -> T) -> Result<T, SessionError> {
+        let mut current = self.current.lock().unwrap();
+        let session = Self::find(&mut current, token)?;
+        session.expires = Instant::now() + self.timeout;
+        Ok(f(&session.tx))
+    }
+
+    /// Фиксирует транзакцию. Возвращает номер записи в журнале (0, если ничего не изменилось)
+    pub fn commit(&self, token: &str) -> Result<u64, SessionError> {
+        let mut current = self.current.lock().unwrap();
+        Self::find(&mut current, token)?;
+        let session = current.take().unwrap();
+        session.tx.commit(now_millis()).map_err(|err| SessionError::Storage(err.into()))
+    }
+
+    pub fn rollback(&self, token: &str) -> Result<(), SessionError> {
+        let mut current = self.current.lock().unwrap();
+        Self::find(&mut current, token)?;
+        // Незакоммиченная транзакция откатывается при drop
+        current.take();
+        Ok(())
+    }
+
+    /// Откатывает сессию, к которой не обращались дольше `timeout`. true - сессия была откачена
+    pub fn expire(&self) -> bool {
+        let mut current = self.current.lock().unwrap();
+        if current.as_ref().is_some_and(|session| session.expires <= Instant::now()) {
+            current.take();
+            return true;
+        }
+        false
+    }
+
+    fn find<'a>(current: &'a mut Option<Session>, token: &str) -> Result<&'a mut Session, SessionError> {
+        if current.as_ref().is_some_and(|session| session.expires <= Instant::now()) {
+            current.take();
+        }
+        current.as_mut().filter(|session| session.token == token).ok_or(SessionError::NotFound)
+    }
+}
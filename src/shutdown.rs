@@ -0,0 +1,120 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Interval};
+
+/// Как часто остановка проверяет, закончились ли запросы
+const DRAIN_POLL: Duration = Duration::from_millis(20);
+
+/// Порядок остановки сервера: прием новых запросов прекращается, запросы в работе дорабатывают,
+/// фоновые задачи завершают текущую итерацию (бэкап, очистку) и выходят из цикла
+pub struct Shutdown {
+    stop: watch::Sender<bool>,
+    requests: Arc<AtomicUsize>,
+    tasks: Mutex<Vec<(&'static str, JoinHandle<()>)>>,
+}
+
+/// Запрос в работе. Счетчик уменьшается при drop
+pub struct RequestGuard {
+    requests: Arc<AtomicUsize>,
+}
+
+/// Что не успело завершиться за время остановки
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    pub requests: usize,
+    pub tasks: Vec<&'static str>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown { stop: watch::channel(false).0, requests: Arc::new(AtomicUsize::new(0)), tasks: Mutex::new(vec![]) }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.stop.borrow()
+    }
+
+    /// Сигнал остановки для фоновой задачи, см. `tick` и `sleep`
+    pub fn signal(&self) -> watch::Receiver<bool> {
+        self.stop.subscribe()
+    }
+
+    /// Фоновая задача, завершения которой остановка дожидается
+    pub fn spawn<F>(&self, name: &'static str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.lock().unwrap().push((name, tokio::task::spawn(task)));
+    }
+
+    pub fn request(&self) -> RequestGuard {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        RequestGuard { requests: self.requests.clone() }
+    }
+
+    /// Останавливает фоновые задачи и ждет запросы и задачи не дольше `timeout`
+    pub async fn run(&self, timeout: Duration) -> ShutdownReport {
+        self.stop.send_replace(true);
+        let deadline = Instant::now() + timeout;
+
+        while self.requests.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+
+        let mut report = ShutdownReport { requests: self.requests.load(Ordering::Relaxed), tasks: vec![] };
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        for (name, mut task) in tasks {
+            if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+                task.abort();
+                report.tasks.push(name);
+            }
+        }
+        report
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        self.requests == 0 && self.tasks.is_empty()
+    }
+}
+
+/// Следующий тик интервала. false - пришел сигнал остановки, задача должна выйти
+pub async fn tick(stop: &mut watch::Receiver<bool>, interval: &mut Interval) -> bool {
+    tokio::select! {
+        _ = interval.tick() => !*stop.borrow(),
+        _ = stop.changed() => false,
+    }
+}
+
+/// Пауза до `duration`. false - пришел сигнал остановки
+pub async fn sleep(stop: &mut watch::Receiver<bool>, duration: Duration) -> bool {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => !*stop.borrow(),
+        _ = stop.changed() => false,
+    }
+}
+
+/// Ctrl+C или SIGTERM
+pub async fn termination() {
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
@@ -0,0 +1,53 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{self, ServerConfig};
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(String, io::Error),
+    /// В файле сертификата нет ни одного PEM-блока CERTIFICATE
+    NoCertificates(String),
+    /// В файле ключа нет PEM-блока с закрытым ключом (PKCS#8, PKCS#1 или SEC1)
+    NoPrivateKey(String),
+    /// Ключ не подходит к сертификату или не поддерживается
+    Config(rustls::Error),
+}
+
+impl std::fmt::Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io(path, err) => write!(f, "{}: {}", path, err),
+            TlsError::NoCertificates(path) => write!(f, "{} has no certificates", path),
+            TlsError::NoPrivateKey(path) => write!(f, "{} has no private key", path),
+            TlsError::Config(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// TLS-терминация из PEM-файлов: цепочка сертификатов (сначала сертификат сервера) и закрытый ключ
+pub fn load_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| TlsError::Io(cert_path.to_string(), err))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(cert_path.to_string()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|err| TlsError::Io(key_path.to_string(), err))?
+        .ok_or_else(|| TlsError::NoPrivateKey(key_path.to_string()))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(TlsError::Config)?;
+    // Сервер говорит только HTTP/1.1
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn open(path: &str) -> Result<BufReader<File>, TlsError> {
+    File::open(path).map(BufReader::new).map_err(|err| TlsError::Io(path.to_string(), err))
+}
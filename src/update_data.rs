@@ -1,9 +1,28 @@
+use std::borrow::Cow;
+
 use bitvec::vec::BitVec;
 
-use crate::{marci_db::{get_end, get_offset, move_offsets, set_offset, set_offset_null}, schema::Field};
+use crate::{marci_decoder::{DecodeError, check_offsets, list_items}, marci_db::{get_end, get_offset, move_offsets, set_offset, set_offset_null, stored_payload_offset}, marci_encoder::encode_value, schema::{Field, FieldType}};
+
+/// Операция над списком: у списка примитивов в новом документе лежат только добавляемые или удаляемые элементы,
+/// у списка связей это `connect`/`disconnect`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListOp {
+  Push,
+  Remove,
+}
 
-pub fn update_data(fields: &[Field], payload_offset: usize, data: &[u8], new_data: &[u8], changed_mask: &BitVec) -> Vec<u8> {
-  let mut data = data.to_vec();
+/// Переносит измененные поля из new_data в документ. Offset-ы обоих буферов проверяются заранее,
+/// чтобы поврежденный документ давал ошибку, а не выход за границы.
+/// Поля из `list_ops` не заменяются, а объединяются с текущим списком
+pub fn update_data(fields: &[Field], payload_offset: usize, data: &[u8], new_data: &[u8], changed_mask: &BitVec, list_ops: &[(&Field, ListOp)]) -> Result<Vec<u8>, DecodeError> {
+  check_offsets(data, payload_offset)?;
+  check_offsets(new_data, payload_offset)?;
+  let mut data = if stored_payload_offset(data)? < payload_offset {
+    upgrade_document(fields, data, payload_offset)?
+  } else {
+    data.to_vec()
+  };
 
   for field in fields.iter() {
 
@@ -11,28 +30,36 @@ pub fn update_data(fields: &[Field], payload_offset: usize, data: &[u8], new_dat
       continue;
     }
 
-    let update_offset = get_offset(new_data, field.offset_pos);
+    let update_offset = get_offset(new_data, field.offset_pos)?;
     // Skip if hasn't new data
-    if !changed_mask[field.offset_index] {
+    if !changed_mask.get(field.offset_index).is_some_and(|changed| *changed) {
       continue;
     }
 
-    let offset = get_offset(&mut data, field.offset_pos);
-    
-    if offset == 0 && update_offset == 0 {
-      continue;
+    let offset = get_offset(&data, field.offset_pos)?;
+    let end = get_end(&data, field.offset_pos, payload_offset);
+
+    let mut update = if update_offset == 0 {
+      None
+    } else {
+      Some(Cow::Borrowed(&new_data[update_offset..get_end(new_data, field.offset_pos, payload_offset)]))
+    };
+    if let Some(op) = list_ops.iter().find(|(op_field, _)| std::ptr::eq(*op_field, field)).map(|(_, op)| *op) {
+      let current = if offset == 0 { None } else { Some(&data[offset..end]) };
+      update = apply_list_op(field, op, current, update.as_deref())?.map(Cow::Owned);
     }
 
-    let end = get_end(&data, field.offset_pos, payload_offset);
-    let update_end = if update_offset == 0 { 0 } else { get_end(new_data, field.offset_pos, payload_offset) };
+    if offset == 0 && update.is_none() {
+      continue;
+    }
 
-    let update_len = if update_offset == 0 { 0 } else { update_end-update_offset };
+    let update_len = update.as_ref().map(|update| update.len()).unwrap_or(0);
     let len = if offset == 0 { 0 } else { end - offset };
 
     let diff = update_len as isize - len as isize;
     
     let new_offset = if offset == 0 { end } else { offset };
-    let new_end = (new_offset + update_len) as usize;
+    let new_end = new_offset + update_len;
 
     // Сдвигаем offsets, если изменилась длина поля
     if diff != 0 {
@@ -40,18 +67,68 @@ pub fn update_data(fields: &[Field], payload_offset: usize, data: &[u8], new_dat
       move_offsets(&mut data, field.offset_pos+4, payload_offset, diff);
     }
 
-    if update_offset == 0 {
-      set_offset_null(&mut data, field.offset_pos);
-    } else {
-      data[new_offset..new_end].copy_from_slice(&new_data[update_offset..update_end]);
+    match update {
+      None => set_offset_null(&mut data, field.offset_pos),
+      Some(update) => {
+        data[new_offset..new_end].copy_from_slice(&update);
 
-      if new_offset != offset {
-        set_offset(&mut data, field.offset_pos, new_offset);
+        if new_offset != offset {
+          set_offset(&mut data, field.offset_pos, new_offset);
+        }
       }
     }
   }
 
-  return data;
+  return Ok(data);
+}
+
+/// Расширяет таблицу offset-ов документа, записанного до добавления полей в схему.
+/// Добавленные поля получают значения из `@default`, остальные - null
+fn upgrade_document(fields: &[Field], data: &[u8], payload_offset: usize) -> Result<Vec<u8>, DecodeError> {
+  let stored_offset = stored_payload_offset(data)?;
+  let diff = payload_offset - stored_offset;
+
+  let mut upgraded = Vec::with_capacity(data.len() + diff);
+  upgraded.push(data[0]);
+  upgraded.extend_from_slice(&(payload_offset as u16).to_be_bytes());
+  upgraded.extend_from_slice(&data[3..stored_offset]);
+  upgraded.resize(payload_offset, 0);
+  upgraded.extend_from_slice(&data[stored_offset..]);
+  move_offsets(&mut upgraded, 3, stored_offset, diff as isize);
+
+  for field in fields.iter().filter(|field| field.offset_pos >= stored_offset) {
+    let (Some(value), FieldType::Primitive(ty)) = (field.default_value(), &field.ty) else { continue };
+    if value.is_null() {
+      continue;
+    }
+    let start = upgraded.len();
+    encode_value(&mut upgraded, ty, &field.name, value).map_err(|_| DecodeError::TypeMismatch(format!("invalid default for {}", field.name)))?;
+    set_offset(&mut upgraded, field.offset_pos, start);
+  }
+  return Ok(upgraded);
+}
+
+/// Новое значение списка: текущие элементы плюс добавленные или без удаляемых (все вхождения).
+/// `remove` из null оставляет null
+fn apply_list_op(field: &Field, op: ListOp, current: Option<&[u8]>, items: Option<&[u8]>) -> Result<Option<Vec<u8>>, DecodeError> {
+  let FieldType::PrimitiveList(ty) = &field.ty else {
+    return Ok(items.map(|items| items.to_vec()));
+  };
+  let current_items = current.map(|current| list_items(ty, current)).transpose()?.unwrap_or_default();
+  let items = items.map(|items| list_items(ty, items)).transpose()?.unwrap_or_default();
+
+  let result: Vec<&[u8]> = match op {
+    ListOp::Push => current_items.into_iter().chain(items).collect(),
+    ListOp::Remove if current.is_none() => return Ok(None),
+    ListOp::Remove => current_items.into_iter().filter(|item| !items.contains(item)).collect(),
+  };
+
+  let mut list = Vec::with_capacity(4 + result.iter().map(|item| item.len()).sum::<usize>());
+  list.extend_from_slice(&(result.len() as u32).to_be_bytes());
+  for item in result {
+    list.extend_from_slice(item);
+  }
+  return Ok(Some(list));
 }
 
 #[inline(always)]
@@ -78,7 +155,7 @@ fn shift_and_resize(data: &mut Vec<u8>, from: usize, to: usize, diff: isize) {
 mod tests {
     use serde_json::json;
 
-    use crate::{marci_db::{InsertStruct, get_offsets}, marci_encoder::encode_document, schema::parse_schema, update_data::update_data};
+    use crate::{marci_db::{InsertStruct, get_offsets}, marci_decoder::decode_field, marci_encoder::{encode_document, encode_update}, schema::parse_schema, update_data::{ListOp, update_data}};
 
 
   #[test]
@@ -90,7 +167,7 @@ model User {
   age         Int
 }
 ";
-    let schema = parse_schema(schema_str);
+    let schema = parse_schema(schema_str).unwrap();
 
     let mut structs: Vec<InsertStruct> = vec![];
     let json = json!({
@@ -110,9 +187,9 @@ model User {
     let json_update = json!({
       "age": 30
     });
-    let (new_data, changed_mask) = encode_document(model, &json_update, &mut structs).unwrap();
+    let (new_data, changed_mask) = encode_update(model, &json_update, &mut structs).unwrap();
 
-    data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask);
+    data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask, &[]).unwrap();
 
     let payload_offset = u16::from_be_bytes(data[1..3].try_into().unwrap()) as usize;
     assert_eq!(payload_offset, 3 + 4 * 3);
@@ -124,9 +201,9 @@ model User {
       "name": "Bobber",
       "surname": "Tester"
     });
-    let (new_data, changed_mask) = encode_document(model, &json_update, &mut structs).unwrap();
+    let (new_data, changed_mask) = encode_update(model, &json_update, &mut structs).unwrap();
 
-    data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask);
+    data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask, &[]).unwrap();
 
     let payload_offset = u16::from_be_bytes(data[1..3].try_into().unwrap()) as usize;
     assert_eq!(payload_offset, 3 + 4 * 3);
@@ -138,14 +215,93 @@ model User {
       "surname": "",
       "age": 80
     });
-    let (new_data, changed_mask) = encode_document(model, &json_update, &mut structs).unwrap();
+    let (new_data, changed_mask) = encode_update(model, &json_update, &mut structs).unwrap();
 
-    data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask);
+    data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask, &[]).unwrap();
 
     let payload_offset = u16::from_be_bytes(data[1..3].try_into().unwrap()) as usize;
     assert_eq!(payload_offset, 3 + 4 * 3);
     assert_eq!(get_offsets(&data, model), vec![0, payload_offset, payload_offset]);
 
+    // Обрезанный документ дает ошибку вместо паники
+    data.truncate(payload_offset - 2);
+    assert!(update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask, &[]).is_err());
+  }
+
+  #[test]
+  fn test_list_ops() {
+    let schema = parse_schema("
+model Post {
+  tags        String[]
+  title       String
+}
+").unwrap();
+    let model = &schema.models[0];
+    let tags = &model.fields[0];
+
+    let (mut data, _) = encode_document(model, &json!({ "tags": ["a", "b"], "title": "Post" }), &mut vec![]).unwrap();
+
+    let mut structs = vec![];
+    let (new_data, changed_mask) = encode_update(model, &json!({ "tags": { "push": ["c", "a"] } }), &mut structs).unwrap();
+    assert!(matches!(structs[..], [InsertStruct::ListOp { op: ListOp::Push, .. }]));
+    data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask, &[(tags, ListOp::Push)]).unwrap();
+    assert_eq!(decode_field(tags, &data, model.payload_offset).unwrap(), json!(["a", "b", "c", "a"]));
+
+    let (new_data, changed_mask) = encode_update(model, &json!({ "tags": { "remove": "a" } }), &mut vec![]).unwrap();
+    data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask, &[(tags, ListOp::Remove)]).unwrap();
+    assert_eq!(decode_field(tags, &data, model.payload_offset).unwrap(), json!(["b", "c"]));
+    assert_eq!(decode_field(&model.fields[1], &data, model.payload_offset).unwrap(), json!("Post"));
+  }
+
+  #[test]
+  fn test_legacy_document() {
+    let old_schema = parse_schema("
+model User {
+  name        String
+}
+").unwrap();
+    let schema = parse_schema("
+model User {
+  name        String
+  age         Int           @default(18)
+  nick        String?
+}
+").unwrap();
+    let model = &schema.models[0];
+    let (data, _) = encode_document(&old_schema.models[0], &json!({ "name": "Bob" }), &mut vec![]).unwrap();
+
+    // Поля, которых не было при записи, читаются как `@default` или null
+    assert_eq!(decode_field(&model.fields[0], &data, model.payload_offset).unwrap(), json!("Bob"));
+    assert_eq!(decode_field(&model.fields[1], &data, model.payload_offset).unwrap(), json!(18));
+    assert_eq!(decode_field(&model.fields[2], &data, model.payload_offset).unwrap(), json!(null));
+
+    // Обновление переписывает документ с текущей таблицей offset-ов
+    let (new_data, changed_mask) = encode_update(model, &json!({ "nick": "B" }), &mut vec![]).unwrap();
+    let data = update_data(&model.fields, model.payload_offset, &data, &new_data, &changed_mask, &[]).unwrap();
+    assert_eq!(get_offsets(&data, model), vec![model.payload_offset, model.payload_offset + 3, model.payload_offset + 11]);
+    assert_eq!(decode_field(&model.fields[1], &data, model.payload_offset).unwrap(), json!(18));
+    assert_eq!(decode_field(&model.fields[2], &data, model.payload_offset).unwrap(), json!("B"));
   }
 
-}
\ No newline at end of file
+  #[test]
+  fn test_insert_defaults() {
+    let schema = parse_schema("
+model Post {
+  title       String
+  views       Int           @default(0)
+  created     DateTime      @default(now())
+  key         String        @default(uuid())
+}
+").unwrap();
+    let model = &schema.models[0];
+    let (data, _) = encode_document(model, &json!({ "title": "Post" }), &mut vec![]).unwrap();
+    assert_eq!(decode_field(&model.fields[1], &data, model.payload_offset).unwrap(), json!(0));
+    assert!(decode_field(&model.fields[2], &data, model.payload_offset).unwrap().as_i64().is_some_and(|millis| millis > 0));
+    let key = decode_field(&model.fields[3], &data, model.payload_offset).unwrap();
+    assert_eq!(key.as_str().unwrap().len(), 36);
+
+    // В update отсутствующие поля не трогаются
+    let (_, changed_mask) = encode_update(model, &json!({ "title": "New" }), &mut vec![]).unwrap();
+    assert_eq!(changed_mask.count_ones(), 1);
+  }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde_json::{Value, json};
+
+use crate::marci_db::MarciDB;
+use crate::marci_query::MarciQuery;
+use crate::schema::{FieldType, Model};
+
+/// Статистика фильтров по полям без индекса, из нее строятся рекомендации `@index`
+pub struct Workload {
+    fields: Mutex<HashMap<(String, String), FieldUsage>>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct FieldUsage {
+    /// Запросы с условием на поле в AND верхнего уровня
+    filters: u64,
+    /// Из них запросы, в которых ни одно условие не покрыто индексом (полный обход модели)
+    full_scans: u64,
+}
+
+impl Workload {
+    pub fn new() -> Workload {
+        Workload { fields: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn record(&self, model: &Model, query: &MarciQuery) {
+        let fields = query.filter.indexable_fields();
+        if fields.iter().all(|(_, indexed)| *indexed) {
+            return;
+        }
+        let full_scan = !fields.iter().any(|(_, indexed)| *indexed);
+
+        let mut usage = self.fields.lock().unwrap();
+        for (field, _) in fields.iter().filter(|(_, indexed)| !indexed) {
+            let entry = usage.entry((model.name.clone(), field.name.clone())).or_default();
+            entry.filters += 1;
+            if full_scan {
+                entry.full_scans += 1;
+            }
+        }
+    }
+
+    /// Поля, для которых стоит добавить `@index`, по убыванию числа полных обходов.
+    /// С `selectivity` каждое поле оценивается полным обходом модели прямо в запросе: сколько документов
+    /// со значением и сколько различных значений. Индексы при этом не строятся и не сохраняются
+    pub fn suggestions(&self, db: &MarciDB, selectivity: bool) -> Value {
+        let mut usage: Vec<((String, String), FieldUsage)> = self.fields.lock().unwrap()
+            .iter().map(|(key, usage)| (key.clone(), *usage)).collect();
+        usage.sort_by(|a, b| (b.1.full_scans, b.1.filters).cmp(&(a.1.full_scans, a.1.filters)).then_with(|| a.0.cmp(&b.0)));
+
+        let items: Vec<Value> = usage.into_iter().filter_map(|((model_name, field_name), usage)| {
+            let model = db.get_model(&model_name)?;
+            let field = model.fields.iter().find(|field| field.name == field_name)?;
+            let FieldType::Primitive(_) = field.ty else { return None };
+
+            let mut item = json!({
+                "model": model_name,
+                "field": field_name,
+                "filters": usage.filters,
+                "fullScans": usage.full_scans,
+                "suggestion": format!("@index on {}.{}", model_name, field_name),
+            });
+            if selectivity {
+                let (documents, distinct) = db.field_selectivity(model, field);
+                item["selectivity"] = json!({
+                    "documents": documents,
+                    "distinct": distinct,
+                    "avgMatches": if distinct > 0 { documents as f64 / distinct as f64 } else { 0.0 },
+                });
+            }
+            Some(item)
+        }).collect();
+        Value::Array(items)
+    }
+}